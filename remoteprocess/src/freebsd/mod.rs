@@ -12,7 +12,7 @@ use std::cell::RefCell;
 use std::convert::TryInto;
 use std::rc::{Rc, Weak};
 
-use super::{ProcessMemory, Error};
+use super::{ProcessMemory, Error, ReadCounter, ReadStats};
 use freebsd::lock::ProcessLock;
 use freebsd::registers::Registers;
 
@@ -22,12 +22,15 @@ pub type Tid = lwpid_t;
 pub struct Process {
     pub pid: Pid,
     lock: Rc<RefCell<Weak<ProcessLock>>>,
+    reads: ReadCounter,
 }
 
 pub struct Thread {
     pub tid: lwpid_t,
     pid: pid_t,
     active: bool,
+    // total cpu time used by the thread, in microseconds
+    cpu_time: u64,
     lock: Rc<RefCell<Weak<ProcessLock>>>,
 }
 
@@ -46,7 +49,7 @@ fn process_lock(pid: Pid, container: &RefCell<Weak<ProcessLock>>)
 
 impl Process {
     pub fn new(pid: Pid) -> Result<Process, Error> {
-        Ok(Process { pid, lock: Rc::new(RefCell::new(Weak::new())) })
+        Ok(Process { pid, lock: Rc::new(RefCell::new(Weak::new())), reads: ReadCounter::default() })
     }
 
     pub fn exe(&self) -> Result<String, Error> {
@@ -69,6 +72,7 @@ impl Process {
             Thread {
                 tid: th.ki_tid,
                 active: th.ki_stat == 2,
+                cpu_time: th.ki_runtime,
                 pid: self.pid,
                 lock: Rc::clone(&self.lock),
             }
@@ -84,6 +88,11 @@ impl Process {
     pub fn unwinder(&self) -> Result<(), Error> {
         unimplemented!("No unwinding yet!")
     }
+
+    /// The number of reads (and bytes read) from the process's memory so far
+    pub fn read_stats(&self) -> ReadStats {
+        self.reads.stats()
+    }
 }
 
 impl Thread {
@@ -95,6 +104,11 @@ impl Thread {
         Ok(self.active)
     }
 
+    /// Returns the total cpu time used by the thread
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        Ok(std::time::Duration::from_micros(self.cpu_time))
+    }
+
     pub fn lock(&self) -> Result<Rc<ProcessLock>, Error> {
         process_lock(self.pid, &self.lock)
     }
@@ -112,6 +126,7 @@ impl Thread {
 
 impl ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.reads.record(buf.len());
         let handle: ProcessHandle = self.pid.try_into()?;
         Ok(handle.copy_address(addr, buf)?)
     }
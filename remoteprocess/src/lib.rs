@@ -59,7 +59,7 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
-#[cfg(target_os="linux")]
+#[cfg(any(target_os="linux", target_os="android"))]
 extern crate nix;
 #[cfg(target_os="linux")]
 extern crate object;
@@ -85,9 +85,10 @@ mod osx;
 #[cfg(target_os="macos")]
 pub use osx::*;
 
-#[cfg(target_os="linux")]
+// android uses the same /proc and ptrace interfaces as linux (with bionic instead of glibc)
+#[cfg(any(target_os="linux", target_os="android"))]
 mod linux;
-#[cfg(target_os="linux")]
+#[cfg(any(target_os="linux", target_os="android"))]
 pub use linux::*;
 
 #[cfg(target_os="freebsd")]
@@ -112,7 +113,7 @@ pub enum Error {
     Other(String),
     #[cfg(all(target_os="linux", unwind))]
     LibunwindError(linux::libunwind::Error),
-    #[cfg(target_os="linux")]
+    #[cfg(any(target_os="linux", target_os="android"))]
     NixError(nix::Error),
     #[cfg(target_os="macos")]
     CompactUnwindError(osx::compact_unwind::CompactUnwindError),
@@ -130,7 +131,7 @@ impl std::fmt::Display for Error {
             Error::Other(ref e) => write!(f, "{}", e),
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => e.fmt(f),
-            #[cfg(target_os="linux")]
+            #[cfg(any(target_os="linux", target_os="android"))]
             Error::NixError(ref e) => e.fmt(f),
             #[cfg(target_os="macos")]
             Error::CompactUnwindError(ref e) => e.fmt(f),
@@ -147,7 +148,7 @@ impl std::error::Error for Error {
             Error::IOError(ref e) => e.description(),
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => e.description(),
-            #[cfg(target_os="linux")]
+            #[cfg(any(target_os="linux", target_os="android"))]
             Error::NixError(ref e) => e.description(),
             #[cfg(target_os="macos")]
             Error::CompactUnwindError(ref e) => e.description(),
@@ -162,7 +163,7 @@ impl std::error::Error for Error {
             Error::IOError(ref e) => Some(e),
             #[cfg(all(target_os="linux", unwind))]
             Error::LibunwindError(ref e) => Some(e),
-            #[cfg(target_os="linux")]
+            #[cfg(any(target_os="linux", target_os="android"))]
             Error::NixError(ref e) => Some(e),
             #[cfg(target_os="macos")]
             Error::CompactUnwindError(ref e) => Some(e),
@@ -189,7 +190,7 @@ impl From<std::io::Error> for Error {
     }
 }
 
-#[cfg(target_os="linux")]
+#[cfg(any(target_os="linux", target_os="android"))]
 impl From<nix::Error> for Error {
     fn from(err: nix::Error) -> Error {
         Error::NixError(err)
@@ -213,6 +214,8 @@ impl From<osx::compact_unwind::CompactUnwindError> for Error {
 #[derive(Debug, Clone)]
 pub struct StackFrame {
     pub line: Option<u64>,
+    /// The line the function starts at, if known
+    pub first_line: Option<u64>,
     pub filename: Option<String>,
     pub function: Option<String>,
     pub module: String,
@@ -230,6 +233,42 @@ impl std::fmt::Display for StackFrame {
     }
 }
 
+/// Counts of the reads made from a process's memory
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReadStats {
+    /// The number of reads
+    pub calls: u64,
+    /// The total number of bytes read
+    pub bytes: u64,
+}
+
+impl ReadStats {
+    /// The reads made since an earlier snapshot of the stats
+    pub fn since(&self, earlier: &ReadStats) -> ReadStats {
+        ReadStats{calls: self.calls.saturating_sub(earlier.calls), bytes: self.bytes.saturating_sub(earlier.bytes)}
+    }
+}
+
+/// Keeps count of a process's memory reads, so that they can be counted without needing &mut
+#[derive(Debug, Default)]
+pub(crate) struct ReadCounter {
+    calls: std::sync::atomic::AtomicU64,
+    bytes: std::sync::atomic::AtomicU64,
+}
+
+impl ReadCounter {
+    pub(crate) fn record(&self, bytes: usize) {
+        use std::sync::atomic::Ordering;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ReadStats {
+        use std::sync::atomic::Ordering;
+        ReadStats{calls: self.calls.load(Ordering::Relaxed), bytes: self.bytes.load(Ordering::Relaxed)}
+    }
+}
+
 pub trait ProcessMemory {
     /// Copies memory from another process into an already allocated
     /// byte buffer
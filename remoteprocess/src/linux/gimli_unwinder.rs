@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::path::Path;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
@@ -14,17 +14,30 @@ use goblin::elf::program_header::*;
 use gimli::EndianRcSlice;
 type RcReader = EndianRcSlice<NativeEndian>;
 
-use super::super::{ProcessMemory, Error};
+use super::super::{ProcessMemory, Error, ReadStats};
 use crate::dwarf_unwind::{UnwindInfo, Registers};
 
 use crate::linux::symbolication::{SymbolData};
 use super::super::StackFrame;
-use super::{Pid, Thread, Process};
+use super::{Pid, Thread, Process, host_path, process_root};
 
 pub struct Unwinder {
     binaries: BTreeMap<u64, BinaryInfo>,
     process: Process,
-    pid: Pid
+    pid: Pid,
+    // where to find the process's binaries, when they're in a different mount namespace
+    root: Option<PathBuf>,
+}
+
+/// A binary mapped into the process that the unwinder has loaded
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    pub filename: String,
+    /// The start address of the executable mapping of the binary, and its size
+    pub address: u64,
+    pub size: u64,
+    /// The difference between the addresses in the binary and where it was loaded
+    pub offset: u64,
 }
 
 pub struct Cursor<'a> {
@@ -36,11 +49,22 @@ pub struct Cursor<'a> {
 impl Unwinder {
     pub fn new(pid: Pid) -> Result<Unwinder, Error> {
         let process = Process::new(pid)?;
-        let mut ret = Unwinder{binaries: BTreeMap::new(), process, pid};
+        let mut ret = Unwinder{binaries: BTreeMap::new(), process, pid, root: process_root(pid)};
         ret.reload()?;
         Ok(ret)
     }
 
+    /// The reads made from the process's memory while unwinding
+    pub fn read_stats(&self) -> ReadStats {
+        self.process.read_stats()
+    }
+
+    /// The binaries loaded so far, ordered by address
+    pub fn modules(&self) -> Vec<LoadedModule> {
+        self.binaries.values().map(|binary| LoadedModule{filename: binary.filename.clone(), address: binary.address,
+                                                         size: binary.size, offset: binary.offset}).collect()
+    }
+
     pub fn reload(&mut self) -> Result<(), Error> {
         info!("reloading process binaries");
 
@@ -69,8 +93,9 @@ impl Unwinder {
             let mmapped_file;
             let vdso_data;
 
-            let buffer = if Path::new(filename).exists() {
-                file = File::open(Path::new(filename))?;
+            let path = host_path(self.root.as_ref().map(PathBuf::as_path), filename);
+            let buffer = if path.exists() {
+                file = File::open(&path)?;
                 mmapped_file = unsafe { Mmap::map(&file)? };
                 &mmapped_file[..]
             } else if filename != "[vsyscall]" {
@@ -189,20 +214,21 @@ impl Unwinder {
             let mut symbols = binary.symbols.borrow_mut();
             if symbols.is_none() {
                 info!("loading symbols from {}", binary.filename);
-                *symbols = Some(SymbolData::new(&binary.filename, binary.offset));
+                let path = host_path(self.root.as_ref().map(PathBuf::as_path), &binary.filename);
+                *symbols = Some(SymbolData::new(&binary.filename, &path, binary.offset));
             }
             match symbols.as_ref() {
                 Some(Ok(symbols)) => symbols.symbolicate(addr, line_info, callback),
                 _ => {
                     // we probably failed to load the symbols (maybe goblin v0.15 dependency causing error
                     // in gimli/object crate). Rather than fail add a stub
-                    callback(&StackFrame{line: None, addr, function: None, filename: None, module: binary.filename.clone()});
+                    callback(&StackFrame{line: None, first_line: None, addr, function: None, filename: None, module: binary.filename.clone()});
                     Ok(())
                 }
             }
         } else {
             // TODO: allow symbolication code to access vdso data
-            callback(&StackFrame{line: None, addr, function: None, filename: None, module: binary.filename.clone()});
+            callback(&StackFrame{line: None, first_line: None, addr, function: None, filename: None, module: binary.filename.clone()});
             Ok(())
         }
     }
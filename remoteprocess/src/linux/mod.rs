@@ -11,12 +11,13 @@ use libc::c_void;
 use nix::{self, sys::wait, sys::ptrace, {sched::{setns, CloneFlags}}};
 use std::convert::TryInto;
 use std::io::Read;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
 #[cfg(unwind)]
 use crate::dwarf_unwind::Registers;
-use super::Error;
+use super::{Error, ReadCounter, ReadStats};
 
 #[cfg(unwind)]
 pub use self::gimli_unwinder::*;
@@ -32,6 +33,10 @@ pub type Tid = pid_t;
 
 pub struct Process {
     pub pid: Pid,
+    // a pidfd for the process (on kernels that support them). Unlike the pid this will
+    // never refer to a different process, which lets us detect if the pid gets reused
+    pidfd: Option<File>,
+    reads: ReadCounter,
 }
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
@@ -41,7 +46,39 @@ pub struct Thread {
 
 impl Process {
     pub fn new(pid: Pid) -> Result<Process, Error> {
-        Ok(Process{pid})
+        Ok(Process{pid, pidfd: pidfd_open(pid), reads: ReadCounter::default()})
+    }
+
+    /// Creates a process from a pidfd referring to it (the fd is duplicated, and
+    /// can be closed by the caller). Errors if the process has already exited
+    pub fn from_pidfd(fd: RawFd) -> Result<Process, Error> {
+        let pid = get_pidfd_pid(fd)?;
+        let dup = unsafe { libc::dup(fd) };
+        if dup < 0 {
+            return Err(Error::IOError(std::io::Error::last_os_error()));
+        }
+
+        let process = Process{pid, pidfd: Some(unsafe { File::from_raw_fd(dup) }), reads: ReadCounter::default()};
+        if process.exited()? {
+            return Err(Error::Other(format!("Process {} referred to by pidfd {} has exited", pid, fd)));
+        }
+        Ok(process)
+    }
+
+    /// Returns true if the process has exited. When we have a pidfd for the process, this
+    /// is also true if the pid has since been reused by a different process
+    pub fn exited(&self) -> Result<bool, Error> {
+        match self.pidfd.as_ref() {
+            Some(pidfd) => {
+                // pidfds become readable when the process exits
+                let mut pollfd = libc::pollfd{fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0};
+                if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+                    return Err(Error::IOError(std::io::Error::last_os_error()));
+                }
+                Ok(pollfd.revents & libc::POLLIN != 0)
+            },
+            None => Ok(std::fs::metadata(format!("/proc/{}", self.pid)).is_err())
+        }
     }
 
     pub fn exe(&self) -> Result<String, Error> {
@@ -67,6 +104,20 @@ impl Process {
         Ok(ret)
     }
 
+    /// Returns the pids of the processes that were started by this process
+    pub fn child_processes(&self) -> Result<Vec<Pid>, Error> {
+        let mut children = Vec::new();
+        for pid in processes()? {
+            // processes can exit while we're iterating, so ignore any that we can't read
+            if let Ok(stat) = std::fs::read(format!("/proc/{}/stat", pid)) {
+                if get_parent_pid(&stat) == Some(self.pid) {
+                    children.push(pid);
+                }
+            }
+        }
+        Ok(children)
+    }
+
     pub fn lock(&self) -> Result<Lock, Error> {
         let mut locks = Vec::new();
         let mut locked = std::collections::HashSet::new();
@@ -81,7 +132,14 @@ impl Process {
             for thread in self.threads()? {
                 let threadid = thread.id()?;
                 if !locked.contains(&threadid) {
-                    locks.push(thread.lock()?);
+                    match thread.lock() {
+                        Ok(lock) => locks.push(lock),
+                        // threads can exit after being listed, leaving nothing to lock
+                        Err(Error::NixError(nix::Error::Sys(nix::errno::Errno::ESRCH))) => {
+                            debug!("thread {} exited before it could be locked", threadid);
+                        },
+                        Err(e) => return Err(e)
+                    }
                     locked.insert(threadid);
                     done = false;
                 }
@@ -114,10 +172,16 @@ impl Process {
     pub fn unwinder(&self) -> Result<Unwinder, Error> {
         Unwinder::new(self.pid)
     }
+
+    /// The number of reads (and bytes read) from the process's memory so far
+    pub fn read_stats(&self) -> ReadStats {
+        self.reads.stats()
+    }
 }
 
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.reads.record(buf.len());
         let handle: ProcessHandle = self.pid.try_into()?;
         Ok(handle.copy_address(addr, buf)?)
     }
@@ -160,6 +224,19 @@ impl Thread {
             None => Err(Error::Other(format!("Failed to parse /proc/{}/stat", self.tid)))
         }
     }
+
+    /// Returns the total cpu time (user and system) used by the thread
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        let stat = std::fs::read(format!("/proc/{}/stat", self.tid))?;
+        let ticks = get_cpu_ticks(&stat)
+            .ok_or_else(|| Error::Other(format!("Failed to parse /proc/{}/stat", self.tid)))?;
+
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks_per_second <= 0 {
+            return Err(Error::IOError(std::io::Error::last_os_error()));
+        }
+        Ok(std::time::Duration::from_micros(ticks * 1_000_000 / ticks_per_second as u64))
+    }
 }
 
 /// This locks a target process using ptrace, and prevents it from running while this
@@ -223,6 +300,66 @@ impl Drop for Namespace {
     }
 }
 
+/// Returns the root directory of a process ('/proc/<pid>/root') when it's running in a different
+/// mount namespace than us, like processes in containers. The paths to files in the process's memory
+/// maps are relative to its own root, so need to be prefixed with this to be opened from here
+pub fn process_root(pid: Pid) -> Option<PathBuf> {
+    let self_mnt = std::fs::read_link("/proc/self/ns/mnt").ok()?;
+    let target_mnt = std::fs::read_link(format!("/proc/{}/ns/mnt", pid)).ok()?;
+    if self_mnt == target_mnt {
+        return None;
+    }
+    Some(PathBuf::from(format!("/proc/{}/root", pid)))
+}
+
+/// Returns the path to open a file at, given its path in a process with the root from process_root
+pub fn host_path(root: Option<&Path>, filename: &str) -> PathBuf {
+    match root {
+        Some(root) => root.join(filename.trim_start_matches('/')),
+        None => PathBuf::from(filename)
+    }
+}
+
+/// Returns the pids of all the processes currently running
+pub fn processes() -> Result<Vec<Pid>, Error> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        if let Some(pid) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            pids.push(pid);
+        }
+    }
+    Ok(pids)
+}
+
+/// Opens a pidfd for the process. Returns None on kernels without pidfd support (pre 5.3)
+fn pidfd_open(pid: Pid) -> Option<File> {
+    // SYS_pidfd_open isn't in the version of libc we're using, but has the same number on every arch
+    const SYS_PIDFD_OPEN: libc::c_long = 434;
+    let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid, 0) };
+    if fd < 0 {
+        info!("Failed to open pidfd for process {}: {}", pid, std::io::Error::last_os_error());
+        return None;
+    }
+    Some(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Gets the pid of the process a pidfd refers to
+fn get_pidfd_pid(fd: RawFd) -> Result<Pid, Error> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))?;
+    match get_fdinfo_pid(&fdinfo) {
+        Some(pid) if pid > 0 => Ok(pid),
+        Some(_) => Err(Error::Other(format!("Process referred to by pidfd {} has exited", fd))),
+        None => Err(Error::Other(format!("File descriptor {} isn't a pidfd", fd)))
+    }
+}
+
+fn get_fdinfo_pid(fdinfo: &str) -> Option<Pid> {
+    // pidfds have a 'Pid:' line in their fdinfo, which is -1 once the process has exited
+    fdinfo.lines()
+        .find(|line| line.starts_with("Pid:"))
+        .and_then(|line| line["Pid:".len()..].trim().parse().ok())
+}
+
 fn get_active_status(stat: &[u8]) -> Option<u8> {
     // find the first ')' character, and return the active status
     // field which comes after it
@@ -233,6 +370,45 @@ fn get_active_status(stat: &[u8]) -> Option<u8> {
     }
 }
 
+#[test]
+fn test_parse_fdinfo_pid() {
+    assert_eq!(get_fdinfo_pid("pos:\t0\nflags:\t02000002\nmnt_id:\t15\nPid:\t1234\n"), Some(1234));
+    assert_eq!(get_fdinfo_pid("pos:\t0\nPid:\t-1\n"), Some(-1));
+    assert_eq!(get_fdinfo_pid("pos:\t0\nflags:\t02\n"), None);
+}
+
+fn get_cpu_ticks(stat: &[u8]) -> Option<u64> {
+    // utime and stime are the 11th and 12th fields after the process name
+    let end = stat.iter().rposition(|x| *x == b')')?;
+    let fields = std::str::from_utf8(&stat[end + 1..]).ok()?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn get_parent_pid(stat: &[u8]) -> Option<Pid> {
+    // the parent pid is the 2nd field after the process name
+    let end = stat.iter().rposition(|x| *x == b')')?;
+    let fields = std::str::from_utf8(&stat[end + 1..]).ok()?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[test]
+fn test_parse_parent_pid() {
+    assert_eq!(get_parent_pid(b"1234 (bash) S 1233 1234 1234"), Some(1233));
+    assert_eq!(get_parent_pid(b"1234 (with ) space) S 1 1234"), Some(1));
+    assert_eq!(get_parent_pid(b"1234 (bash) S"), None);
+}
+
+#[test]
+fn test_parse_cpu_ticks() {
+    let stat = b"1234 (with ) space) S 1 1234 1234 0 -1 4194304 150 0 0 0 25 17 0 0 20 0 1 0 123 1000 100";
+    assert_eq!(get_cpu_ticks(stat), Some(42));
+    assert_eq!(get_cpu_ticks(b"1234 (bash) S 1233"), None);
+    assert_eq!(get_cpu_ticks(b"1234"), None);
+}
+
 #[test]
 fn test_parse_stat() {
     assert_eq!(get_active_status(b"1234 (bash) S 1233"), Some(b'S'));
@@ -1,4 +1,5 @@
 use std::fs::File;
+use std::path::Path;
 use memmap;
 
 use object::{self, Object};
@@ -17,10 +18,11 @@ pub struct SymbolData {
 }
 
 impl SymbolData {
-    pub fn new(filename: &str, offset: u64) -> Result<SymbolData, Error> {
-        info!("opening {} for symbols", filename);
+    /// Loads the symbols for the binary at filename (as the process sees it), opening it at path
+    pub fn new(filename: &str, path: &Path, offset: u64) -> Result<SymbolData, Error> {
+        info!("opening {} for symbols", path.display());
 
-        let file = File::open(filename)?;
+        let file = File::open(path)?;
         let map = unsafe { memmap::Mmap::map(&file)? };
         let file = match object::File::parse(&*map) {
             Ok(f) => f,
@@ -52,7 +54,7 @@ impl SymbolData {
     }
 
     pub fn symbolicate(&self, addr: u64, line_info: bool, callback: &mut FnMut(&StackFrame)) -> Result<(), Error> {
-        let mut ret = StackFrame{line:None, filename: None, function: None, addr, module: self.filename.clone()};
+        let mut ret = StackFrame{line:None, first_line: None, filename: None, function: None, addr, module: self.filename.clone()};
 
         // get the address before relocations
         let offset = addr - self.offset;
@@ -68,19 +70,37 @@ impl SymbolData {
             let error_handler = |e| Error::Other(format!("addr2line error: {:?}", e));
 
             // if we have debugging info, get the appropiate stack frames for the adresss
+            let mut symbolicated = Vec::new();
             let mut frames = self.ctx.find_frames(offset).map_err(error_handler)?;
             while let Some(frame) = frames.next().map_err(error_handler)? {
                 has_debug_info = true;
+                // each inlined function gets its own name/file/line, rather than inheriting
+                // whatever was set by the frame inlined into it
+                let mut inline_frame = ret.clone();
                 if let Some(func) = frame.function {
-                    ret.function = Some(func.raw_name().map_err(error_handler)?.to_string());
+                    inline_frame.function = Some(func.raw_name().map_err(error_handler)?.to_string());
                 }
                 if let Some(loc) = frame.location {
-                    ret.line = loc.line;
+                    inline_frame.line = loc.line;
                     if let Some(file) = loc.file.as_ref() {
-                        ret.filename = Some(file.to_string());
+                        inline_frame.filename = Some(file.to_string());
                     }
                 }
-                callback(&ret);
+                symbolicated.push(inline_frame);
+            }
+
+            // frames are returned innermost first, with the last one being the function that
+            // actually contains the address (the others were inlined into it). The line program
+            // entry for the start of that function gives the line it was defined on
+            if let Some(outer) = symbolicated.last_mut() {
+                if let Some(start) = self.symbol_start(offset) {
+                    if let Ok(Some(loc)) = self.ctx.find_location(start) {
+                        outer.first_line = loc.line;
+                    }
+                }
+            }
+            for frame in &symbolicated {
+                callback(frame);
             }
 
             if has_debug_info {
@@ -111,4 +131,19 @@ impl SymbolData {
         callback(&ret);
         Ok(())
     }
+
+    /// Returns the (unrelocated) start address of the symbol containing an offset
+    fn symbol_start(&self, offset: u64) -> Option<u64> {
+        let i = match self.symbols.binary_search_by(|sym| sym.0.cmp(&offset)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+        let symbol = &self.symbols[i];
+        if offset >= symbol.0 && offset < (symbol.0 + symbol.1) {
+            Some(symbol.0)
+        } else {
+            None
+        }
+    }
 }
@@ -11,7 +11,7 @@ use std;
 use std::convert::TryInto;
 use mach;
 
-use super::{ProcessMemory, Error};
+use super::{ProcessMemory, Error, ReadCounter, ReadStats};
 use mach::kern_return::{KERN_SUCCESS};
 use mach::port::{mach_port_name_t, MACH_PORT_NULL};
 use mach::traps::{task_for_pid, mach_task_self};
@@ -36,7 +36,8 @@ pub type Tid = u32;
 
 pub struct Process {
     pub pid: Pid,
-    pub task: mach_port_name_t
+    pub task: mach_port_name_t,
+    reads: ReadCounter,
 }
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
@@ -51,7 +52,7 @@ impl Process {
         if result != KERN_SUCCESS {
             return Err(Error::IOError(std::io::Error::last_os_error()));
         }
-        Ok(Process{pid, task})
+        Ok(Process{pid, task, reads: ReadCounter::default()})
     }
 
     pub fn exe(&self) -> Result<String, Error> {
@@ -128,10 +129,16 @@ impl Process {
     pub fn unwinder(&self) -> Result<Unwinder, Error> {
         Ok(Unwinder::new(self.pid)?)
     }
+
+    /// The number of reads (and bytes read) from the process's memory so far
+    pub fn read_stats(&self) -> ReadStats {
+        self.reads.stats()
+    }
 }
 
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.reads.record(buf.len());
         let handle: ProcessHandle = self.task.try_into()?;
         Ok(handle.copy_address(addr, buf)?)
     }
@@ -162,6 +169,13 @@ impl Thread {
            info.flags & TH_FLAGS_IDLE as i32 == 0)
     }
 
+    /// Returns the total cpu time (user and system) used by the thread
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        let info = self.get_thread_basic_info()?;
+        let micros = |time: &mach_thread_bindings::time_value| time.seconds as u64 * 1_000_000 + time.microseconds as u64;
+        Ok(std::time::Duration::from_micros(micros(&info.user_time) + micros(&info.system_time)))
+    }
+
     pub fn lock(&self) -> Result<ThreadLock, Error> {
         Ok(ThreadLock::new(self.tid)?)
     }
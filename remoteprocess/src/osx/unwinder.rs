@@ -12,7 +12,7 @@ use super::{Error, Thread, Pid, Process, ProcessMemory};
 use goblin::error::Error as GoblinError;
 use mach::structs::x86_thread_state64_t;
 
-use super::super::StackFrame;
+use super::super::{StackFrame, ReadStats};
 
 use super::compact_unwind::{get_compact_unwind_info, get_dwarf_offset, compact_unwind};
 use super::symbolication;
@@ -36,6 +36,11 @@ impl Unwinder {
         Ok(unwinder)
     }
 
+    /// The reads made from the process's memory while unwinding
+    pub fn read_stats(&self) -> ReadStats {
+        self.process.read_stats()
+    }
+
     pub fn reload(&mut self) -> Result<(), GoblinError> {
         info!("reloading binaries");
         // Get __TEXT dyld info for the process
@@ -110,7 +115,7 @@ impl Unwinder {
             }
             line = Some(symbol.lineno as u64);
         }
-        callback(&StackFrame{function, filename, line, module, addr});
+        callback(&StackFrame{function, filename, line, first_line: None, module, addr});
         Ok(())
     }
 }
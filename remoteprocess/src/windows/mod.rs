@@ -1,20 +1,22 @@
-use winapi::um::processthreadsapi::{OpenProcess, OpenThread, GetThreadId, SuspendThread, ResumeThread};
+use winapi::um::processthreadsapi::{OpenProcess, OpenThread, GetThreadId, GetThreadTimes, SuspendThread, ResumeThread};
 use winapi::um::winnt::{ACCESS_MASK, MAXIMUM_ALLOWED, PROCESS_QUERY_INFORMATION,
                         PROCESS_VM_READ, PROCESS_SUSPEND_RESUME, THREAD_QUERY_INFORMATION, THREAD_GET_CONTEXT, THREAD_ALL_ACCESS,
-                        WCHAR, HANDLE};
-use winapi::shared::minwindef::{FALSE, DWORD, MAX_PATH, ULONG};
+                        WCHAR, HANDLE, HRESULT};
+use winapi::shared::minwindef::{FALSE, DWORD, FILETIME, MAX_PATH, ULONG};
 use winapi::um::handleapi::{CloseHandle};
-use winapi::um::winbase::QueryFullProcessImageNameW;
+use winapi::um::winbase::{QueryFullProcessImageNameW, LocalFree};
+use winapi::um::libloaderapi::{GetModuleHandleA, GetProcAddress};
 use winapi::um::winnt::OSVERSIONINFOEXW;
 use std::ffi::OsString;
 use std::os::windows::ffi::{OsStringExt};
+use libc::wcslen;
 use winapi::shared::ntdef::{PVOID, NTSTATUS, USHORT, VOID, NULL};
 
 pub use read_process_memory::{Pid, ProcessHandle, CopyAddress};
 
 pub type Tid = Pid;
 
-use super::Error;
+use super::{Error, ReadCounter, ReadStats};
 
 mod unwinder;
 mod syscalls_x64;
@@ -24,7 +26,8 @@ pub use self::unwinder::Unwinder;
 
 pub struct Process {
     pub pid: Pid,
-    pub handle: ProcessHandle
+    pub handle: ProcessHandle,
+    reads: ReadCounter,
 }
 
 #[link(name="ntdll")]
@@ -58,7 +61,7 @@ impl Process {
             if handle == (0 as std::os::windows::io::RawHandle) {
                 return Err(Error::from(std::io::Error::last_os_error()));
             }
-            Ok(Process{pid, handle: ProcessHandle(handle)})
+            Ok(Process{pid, handle: ProcessHandle(handle), reads: ReadCounter::default()})
         }
     }
 
@@ -111,6 +114,11 @@ impl Process {
     pub fn unwinder(&self) -> Result<unwinder::Unwinder, Error> {
         unwinder::Unwinder::new(self.handle.0)
     }
+
+    /// The number of reads (and bytes read) from the process's memory so far
+    pub fn read_stats(&self) -> ReadStats {
+        self.reads.stats()
+    }
 }
 
 impl Drop for Process {
@@ -121,6 +129,7 @@ impl Drop for Process {
 
 impl super::ProcessMemory for Process {
     fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.reads.record(buf.len());
         Ok(self.handle.copy_address(addr, buf)?)
     }
 }
@@ -150,6 +159,56 @@ impl Thread {
         unsafe { Ok(GetThreadId(self.thread.0)) }
     }
 
+    /// Returns the total cpu time (user and kernel) used by the thread
+    pub fn cpu_time(&self) -> Result<std::time::Duration, Error> {
+        unsafe {
+            let mut creation = std::mem::zeroed::<FILETIME>();
+            let mut exit = std::mem::zeroed::<FILETIME>();
+            let mut kernel = std::mem::zeroed::<FILETIME>();
+            let mut user = std::mem::zeroed::<FILETIME>();
+            if GetThreadTimes(self.thread.0, &mut creation, &mut exit, &mut kernel, &mut user) == FALSE {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+
+            // FILETIME's are in units of 100 nanoseconds
+            let ticks = |time: &FILETIME| (u64::from(time.dwHighDateTime) << 32) | u64::from(time.dwLowDateTime);
+            Ok(std::time::Duration::from_nanos((ticks(&kernel) + ticks(&user)) * 100))
+        }
+    }
+
+    /// Returns the description set with SetThreadDescription, or None if the thread hasn't been
+    /// named. This api was only added in Windows 10 1607, so is looked up at runtime rather
+    /// than linked against
+    pub fn name(&self) -> Result<Option<String>, Error> {
+        type GetThreadDescription = unsafe extern "system" fn(HANDLE, *mut *mut WCHAR) -> HRESULT;
+        unsafe {
+            let kernel32 = GetModuleHandleA(b"kernel32.dll\0".as_ptr() as *const i8);
+            if kernel32.is_null() {
+                return Ok(None);
+            }
+            let get_description = GetProcAddress(kernel32, b"GetThreadDescription\0".as_ptr() as *const i8);
+            if get_description.is_null() {
+                return Ok(None);
+            }
+            let get_description: GetThreadDescription = std::mem::transmute(get_description);
+
+            let mut description: *mut WCHAR = std::ptr::null_mut();
+            let ret = get_description(self.thread.0, &mut description);
+            if ret < 0 {
+                return Err(Error::from(std::io::Error::from_raw_os_error(ret)));
+            }
+            if description.is_null() {
+                return Ok(None);
+            }
+
+            let name = OsString::from_wide(std::slice::from_raw_parts(description, wcslen(description)));
+            LocalFree(description as *mut _);
+
+            let name = name.to_string_lossy().to_string();
+            Ok(if name.is_empty() { None } else { Some(name) })
+        }
+    }
+
     pub fn active(&self) -> Result<bool, Error> {
         // Getting whether a thread is active or not is suprisingly difficult on windows
         // we're getting the syscall the thread is doing here, and then checking against a list
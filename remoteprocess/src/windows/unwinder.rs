@@ -68,7 +68,7 @@ impl Unwinder {
                 filename = Some(f);
             }
         }
-        callback(&StackFrame{function, filename, line, module, addr});
+        callback(&StackFrame{function, filename, line, first_line: None, module, addr});
         Ok(())
     }
 
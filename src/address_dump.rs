@@ -0,0 +1,339 @@
+/* Recording native stacks as raw return addresses, and symbolicating them later.
+
+Symbolicating native stacks is the expensive part of native profiling, and needs the debug info
+for every library the process has loaded. This lets the addresses be recorded cheaply on one
+machine, along with the modules they're in, and then symbolicated somewhere else that has copies
+of the same binaries (and hopefully their debug symbols).
+*/
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use failure::{Error, ResultExt};
+use goblin::Object;
+use memmap::Mmap;
+use remoteprocess::{Pid, SymbolData};
+
+use crate::binary_parser::module_version;
+use crate::config::{Config, FileFormat, RecordDuration};
+use crate::cython;
+use crate::flamegraph::Flamegraph;
+use crate::native_stack_trace::{demangle, ignore_frame};
+use crate::speedscope;
+use crate::stack_trace::{Frame, FrameOrigin, StackTrace};
+use crate::timer::Timer;
+use crate::utils::create_output;
+
+/// An executable region of a binary mapped into the process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Module {
+    filename: String,
+    start: u64,
+    size: u64,
+    /// The build-id (or soname) of the binary, to check that the binary used to symbolicate is the same
+    build_id: Option<String>,
+}
+
+/// A native stack as return addresses (innermost first), along with how many times it was seen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AddressStack {
+    addresses: Vec<u64>,
+    count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AddressDump {
+    pid: Pid,
+    sampling_rate: u64,
+    modules: Vec<Module>,
+    stacks: Vec<AddressStack>,
+}
+
+/// Records the native stack of every thread in the process as raw return addresses, writing them
+/// out with the modules they're in for symbolicate_addresses to resolve later
+pub fn record_addresses(pid: Pid, config: &Config) -> Result<(), Error> {
+    let filename = match config.filename.as_ref() {
+        Some(filename) => filename,
+        None => return Err(format_err!("A filename is required to record addresses"))
+    };
+
+    let process = remoteprocess::Process::new(pid)?;
+    let mut unwinder = process.unwinder()?;
+    let mut modules = BTreeMap::new();
+    load_modules(pid, &mut modules)?;
+
+    let max_samples = match (config.sample_limit, &config.duration) {
+        (Some(samples), _) => Some(samples),
+        (None, RecordDuration::Seconds(sec)) => Some(sec * config.sampling_rate),
+        (None, RecordDuration::Unlimited) => None
+    };
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })?;
+
+    if filename != "-" {
+        println!("Recording native return addresses {} times a second. Press Control-C to exit.", config.sampling_rate);
+    }
+
+    let mut stacks: HashMap<Vec<u64>, usize> = HashMap::new();
+    let mut samples = 0;
+    let mut errors = 0;
+    for _ in Timer::new(config.sampling_rate as f64) {
+        if !running.load(Ordering::SeqCst) || process.exited().unwrap_or(true) {
+            break;
+        }
+
+        match sample_addresses(&process, &unwinder, config.include_idle) {
+            Ok(sampled) => {
+                for addresses in sampled {
+                    *stacks.entry(addresses).or_insert(0) += 1;
+                }
+                samples += 1;
+            },
+            Err(e) => {
+                // most failures are from the process loading new libraries that we don't know about yet
+                info!("Failed to get native stacks, reloading modules: {}", e);
+                unwinder.reload()?;
+                load_modules(pid, &mut modules)?;
+                errors += 1;
+            }
+        }
+
+        if max_samples.map_or(false, |max_samples| samples >= max_samples) {
+            break;
+        }
+    }
+
+    let dump = AddressDump{pid, sampling_rate: config.sampling_rate, modules: modules.into_iter().map(|(_, module)| module).collect(),
+                           stacks: stacks.into_iter().map(|(addresses, count)| AddressStack{addresses, count}).collect()};
+    let mut out_file = create_output(filename)?;
+    serde_json::to_writer(&mut out_file, &dump)?;
+    out_file.flush()?;
+
+    let message = format!("Wrote {} samples ({} errors) of raw addresses to '{}'. Use 'py-spy symbolicate' to resolve them",
+                          samples, errors, filename);
+    if filename == "-" { eprintln!("{}", message); } else { println!("{}", message); }
+    Ok(())
+}
+
+/// Gets the return addresses of each thread's native stack (skipping idle threads unless include_idle is set)
+fn sample_addresses(process: &remoteprocess::Process, unwinder: &remoteprocess::Unwinder, include_idle: bool) -> Result<Vec<Vec<u64>>, Error> {
+    // thread activity has to be checked before locking the process, since locked threads are always idle
+    let mut threads = Vec::new();
+    for thread in process.threads()?.into_iter() {
+        if include_idle || thread.active()? {
+            threads.push(thread);
+        }
+    }
+
+    let _lock = process.lock().context("Failed to suspend process")?;
+    let mut stacks = Vec::new();
+    for thread in threads.iter() {
+        let mut addresses = Vec::new();
+        for ip in unwinder.cursor(thread)? {
+            addresses.push(ip?);
+        }
+        stacks.push(addresses);
+    }
+    Ok(stacks)
+}
+
+/// Adds the executable regions of the binaries mapped into the process that we don't already have
+fn load_modules(pid: Pid, modules: &mut BTreeMap<u64, Module>) -> Result<(), Error> {
+    let root = remoteprocess::process_root(pid);
+    for map in proc_maps::get_process_maps(pid)?.iter().filter(|m| m.is_exec() && !m.is_write() && m.is_read()) {
+        let filename = match map.filename() {
+            Some(filename) => filename,
+            None => continue
+        };
+        if modules.contains_key(&(map.start() as u64)) {
+            continue;
+        }
+        let path = match root.as_ref() {
+            Some(root) => root.join(filename.trim_start_matches('/')).to_string_lossy().to_string(),
+            None => filename.clone()
+        };
+        modules.insert(map.start() as u64, Module{filename: filename.clone(), start: map.start() as u64, size: map.size() as u64,
+                                                  build_id: module_version(&path)});
+    }
+    Ok(())
+}
+
+/// Symbolicates a file written by record_addresses, using the binaries on this machine (looked for
+/// under config.symbol_root if it's set), and writes out a flamegraph, raw or speedscope file
+pub fn symbolicate_addresses(config: &Config) -> Result<(), Error> {
+    let filename = match config.filename.as_ref() {
+        Some(filename) => filename,
+        None => return Err(format_err!("A filename is required to symbolicate addresses"))
+    };
+    let input = match config.merge_inputs.first() {
+        Some(input) => input,
+        None => return Err(format_err!("An address dump is required to symbolicate"))
+    };
+
+    let file = File::open(input).context(format!("Failed to open '{}'", input))?;
+    let dump: AddressDump = serde_json::from_reader(std::io::BufReader::new(file))
+        .context(format!("Failed to load addresses from '{}'", input))?;
+
+    let mut symbolicator = Symbolicator::new(dump.modules, config.symbol_root.as_ref().map(PathBuf::from));
+    let mut flamegraph = Flamegraph::new(config.show_line_numbers);
+    let mut samples = 0;
+    for stack in dump.stacks.iter() {
+        let mut frames = Vec::new();
+        for addr in stack.addresses.iter() {
+            frames.extend(symbolicator.symbolicate(*addr));
+        }
+        let trace = StackTrace{thread_id: 0, os_thread_id: None, active: true, owns_gil: false, thread_name: None,
+                               interpreter_id: None, pure_native: true, frames};
+        for _ in 0..stack.count {
+            flamegraph.increment(&trace)?;
+        }
+        samples += stack.count;
+    }
+
+    let mut out_file = create_output(filename)?;
+    match config.format {
+        Some(FileFormat::raw) => flamegraph.write_raw(&mut out_file)?,
+        Some(FileFormat::speedscope) => speedscope::write_collapsed(&flamegraph.counts, &mut out_file)?,
+        _ => flamegraph.write(&mut out_file)?
+    }
+    out_file.flush()?;
+
+    let message = format!("Symbolicated {} samples from process {} to '{}'", samples, dump.pid, filename);
+    if filename == "-" { eprintln!("{}", message); } else { println!("{}", message); }
+    Ok(())
+}
+
+struct Symbolicator {
+    modules: Vec<Module>,
+    root: Option<PathBuf>,
+    // the symbols for each module, keyed by its start address. This is None for modules that
+    // couldn't be found locally, or that are a different build than the one that was recorded
+    symbols: HashMap<u64, Option<SymbolData>>,
+}
+
+impl Symbolicator {
+    fn new(modules: Vec<Module>, root: Option<PathBuf>) -> Symbolicator {
+        Symbolicator{modules, root, symbols: HashMap::new()}
+    }
+
+    /// Returns the frames for an address (with several frames when functions were inlined)
+    fn symbolicate(&mut self, addr: u64) -> Vec<Frame> {
+        let module = match self.modules.iter().find(|m| addr >= m.start && addr < m.start + m.size) {
+            Some(module) => module.clone(),
+            None => return vec![native_frame(format!("0x{:x}", addr), String::from("?"), None)]
+        };
+
+        let root = self.root.as_ref();
+        let symbols = self.symbols.entry(module.start).or_insert_with(|| load_symbols(&module, root));
+        let symbols = match symbols {
+            Some(symbols) => symbols,
+            None => {
+                let basename = Path::new(&module.filename).file_name()
+                    .map_or_else(|| module.filename.clone(), |f| f.to_string_lossy().to_string());
+                return vec![native_frame(format!("{}+0x{:x}", basename, addr - module.start), module.filename.clone(),
+                                         Some(module.filename))];
+            }
+        };
+
+        let mut frames = Vec::new();
+        let symbolicated = symbols.symbolicate(addr, true, &mut |frame: &remoteprocess::StackFrame| {
+            let name = match frame.function.as_ref() {
+                Some(function) if ignore_frame(function, &frame.module) => return,
+                Some(function) => cython::demangle(&demangle(function).unwrap_or_else(|| function.clone())).to_owned(),
+                None => format!("0x{:x}", frame.addr)
+            };
+            let mut translated = native_frame(name, frame.filename.clone().unwrap_or_else(|| frame.module.clone()), Some(frame.module.clone()));
+            translated.line = frame.line.unwrap_or(0) as i32;
+            translated.first_line = frame.first_line.map(|line| line as i32);
+            frames.push(translated);
+        });
+        if let Err(e) = symbolicated {
+            debug!("Failed to symbolicate 0x{:x} in {}: {}", addr, module.filename, e);
+            return vec![native_frame(format!("0x{:x}", addr), module.filename.clone(), Some(module.filename))];
+        }
+        frames
+    }
+}
+
+/// Loads the symbols for a module from the local copy of its binary, checking that it's the same build
+fn load_symbols(module: &Module, root: Option<&PathBuf>) -> Option<SymbolData> {
+    let path = match root {
+        Some(root) => root.join(module.filename.trim_start_matches('/')),
+        None => PathBuf::from(&module.filename)
+    };
+    if !path.exists() {
+        warn!("Couldn't find {}, leaving its addresses unsymbolicated", path.display());
+        return None;
+    }
+
+    let build_id = module_version(&path.to_string_lossy());
+    if let Some(recorded) = module.build_id.as_ref() {
+        if build_id.as_ref() != Some(recorded) {
+            warn!("{} is a different build than the one that was recorded ({} instead of {}), leaving its addresses unsymbolicated",
+                  path.display(), build_id.unwrap_or_else(|| String::from("unknown")), recorded);
+            return None;
+        }
+    }
+
+    // symbols are relative to the virtual address of the executable segment, like the unwinder does
+    let base = match executable_vaddr(&path) {
+        Ok(vaddr) => module.start - vaddr,
+        Err(e) => {
+            warn!("Failed to parse {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match SymbolData::new(&module.filename, &path, base) {
+        Ok(symbols) => Some(symbols),
+        Err(e) => {
+            warn!("Failed to load symbols from {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// The virtual address of the executable PT_LOAD segment of an ELF binary
+fn executable_vaddr(path: &Path) -> Result<u64, Error> {
+    let file = File::open(path)?;
+    let buffer = unsafe { Mmap::map(&file)? };
+    let elf = match Object::parse(&buffer)? {
+        Object::Elf(elf) => elf,
+        _ => return Err(format_err!("{} isn't an ELF binary", path.display()))
+    };
+    elf.program_headers.iter()
+        .find(|header| header.p_type == goblin::elf::program_header::PT_LOAD &&
+                       header.p_flags & goblin::elf::program_header::PF_X != 0)
+        .map(|header| header.p_vaddr)
+        .ok_or_else(|| format_err!("Failed to find executable PT_LOAD program header in {}", path.display()))
+}
+
+fn native_frame(name: String, filename: String, module: Option<String>) -> Frame {
+    Frame{name, filename, module, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_modules() {
+        let modules = vec![Module{filename: String::from("/does/not/exist/libfoo.so"), start: 0x1000, size: 0x1000, build_id: None}];
+        let mut symbolicator = Symbolicator::new(modules, None);
+
+        // addresses in modules we don't have are left as offsets into the module
+        let frames = symbolicator.symbolicate(0x1234);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].name, "libfoo.so+0x234");
+        assert_eq!(frames[0].module, Some(String::from("/does/not/exist/libfoo.so")));
+
+        // and addresses outside of any module are just shown as addresses
+        assert_eq!(symbolicator.symbolicate(0x4000)[0].name, "0x4000");
+    }
+}
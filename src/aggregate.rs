@@ -0,0 +1,103 @@
+use std::path::{Component, Path};
+
+use regex::Regex;
+
+use crate::stack_trace::{Frame, FrameOrigin};
+
+/// Replaces each frame with a frame for the top level python package (or native shared library)
+/// that it came from, collapsing consecutive frames from the same package into a single frame
+pub fn aggregate_by_module(frames: &[Frame]) -> Vec<Frame> {
+    let mut aggregated: Vec<Frame> = Vec::new();
+    for frame in frames {
+        let module = match module_name(frame) {
+            Some(module) => module,
+            // frames we added ourselves (like the 'thread' or 'process' frames) are kept as is
+            None => {
+                aggregated.push(frame.clone());
+                continue;
+            }
+        };
+
+        if aggregated.last().map_or(false, |last| last.name == module && last.origin == frame.origin) {
+            continue;
+        }
+        aggregated.push(Frame{name: module.clone(), filename: module, module: frame.module.clone(),
+                              short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: frame.origin, application: false});
+    }
+    aggregated
+}
+
+/// Re-roots a stack at the deepest frame whose function name matches the --focus regex, dropping the
+/// frames that called it. Returns None when no frame matches, so that the sample can be dropped
+pub fn focus_frames(frames: &[Frame], focus: &Regex) -> Option<Vec<Frame>> {
+    // frames go from the leaf to the root, so the deepest match is the first one
+    let index = frames.iter().position(|frame| focus.is_match(&frame.name))?;
+    Some(frames[..=index].to_vec())
+}
+
+/// Returns the name of the package a frame is in: the first component of the short filename for
+/// python code ('numpy' for 'numpy/core/numeric.py'), and the shared library for native code
+fn module_name(frame: &Frame) -> Option<String> {
+    if frame.origin == FrameOrigin::Native {
+        if let Some(module) = frame.module.as_ref() {
+            return Path::new(module).file_name().map(|name| name.to_string_lossy().to_string());
+        }
+    }
+
+    if frame.filename.is_empty() {
+        return None;
+    }
+
+    // modules that aren't in a package (like the main script) are named after the file
+    let package = match frame.short_filename.as_ref() {
+        Some(short_filename) => Path::new(short_filename).components().find_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None
+        })?,
+        None => Path::new(&frame.filename).file_name()?.to_string_lossy().to_string()
+    };
+    Some(package.trim_end_matches(".py").to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, short_filename: Option<&str>, module: Option<&str>, origin: FrameOrigin) -> Frame {
+        Frame{name: name.to_owned(), filename: format!("/usr/lib/{}", short_filename.unwrap_or(name)),
+              module: module.map(|m| m.to_owned()), short_filename: short_filename.map(|f| f.to_owned()),
+              line: 10, locals: None, first_line: None, lasti: None, origin, application: false}
+    }
+
+    #[test]
+    fn test_aggregate_by_module() {
+        let frames = vec![frame("dot", None, Some("/usr/lib/libopenblas.so.0"), FrameOrigin::Native),
+                          frame("gemm", None, Some("/usr/lib/libopenblas.so.0"), FrameOrigin::Native),
+                          frame("dot", Some("numpy/core/multiarray.py"), None, FrameOrigin::Python),
+                          frame("matmul", Some("numpy/linalg.py"), None, FrameOrigin::Python),
+                          frame("run", Some("myapp.py"), None, FrameOrigin::Python),
+                          Frame{name: "thread 1".to_owned(), filename: String::new(), module: None, short_filename: None,
+                                line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}];
+
+        let names: Vec<String> = aggregate_by_module(&frames).into_iter().map(|frame| frame.name).collect();
+        assert_eq!(names, vec!["libopenblas.so.0", "numpy", "myapp", "thread 1"]);
+
+        // without a short filename, python frames are grouped by file
+        let frames = vec![frame("main", None, None, FrameOrigin::Python)];
+        assert_eq!(aggregate_by_module(&frames)[0].name, "main");
+    }
+
+    #[test]
+    fn test_focus_frames() {
+        let frames = vec![frame("query", Some("db.py"), None, FrameOrigin::Python),
+                          frame("handle_request", Some("app.py"), None, FrameOrigin::Python),
+                          frame("dispatch", Some("framework.py"), None, FrameOrigin::Python),
+                          frame("serve", Some("framework.py"), None, FrameOrigin::Python)];
+
+        let focus = Regex::new("^handle_").unwrap();
+        let names: Vec<String> = focus_frames(&frames, &focus).unwrap().into_iter().map(|frame| frame.name).collect();
+        assert_eq!(names, vec!["query", "handle_request"]);
+
+        assert!(focus_frames(&frames[2..], &focus).is_none());
+    }
+}
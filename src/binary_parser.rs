@@ -15,7 +15,9 @@ pub struct BinaryInfo {
     pub bss_size: u64,
     pub offset: u64,
     pub addr: u64,
-    pub size: u64
+    pub size: u64,
+    /// Whether the binary was built for 64 bit or 32 bit pointers
+    pub is_64: bool,
 }
 
 impl BinaryInfo {
@@ -74,7 +76,7 @@ pub fn parse_binary(filename: &str, addr: u64, size: u64) -> Result<BinaryInfo,
 
                 }
             }
-            Ok(BinaryInfo{filename: filename.to_owned(), symbols, bss_addr, bss_size, offset, addr, size})
+            Ok(BinaryInfo{filename: filename.to_owned(), symbols, bss_addr, bss_size, offset, addr, size, is_64: mach.is_64})
         }
 
         Object::Elf(elf) => {
@@ -102,7 +104,8 @@ pub fn parse_binary(filename: &str, addr: u64, size: u64) -> Result<BinaryInfo,
                           bss_size: bss_header.sh_size,
                           offset,
                           addr,
-                          size})
+                          size,
+                          is_64: elf.is_64})
         },
         Object::PE(pe) => {
             for export in pe.exports {
@@ -119,10 +122,35 @@ pub fn parse_binary(filename: &str, addr: u64, size: u64) -> Result<BinaryInfo,
             let bss_addr = u64::from(data_section.virtual_address) + offset;
             let bss_size = u64::from(data_section.virtual_size);
 
-            Ok(BinaryInfo{filename: filename.to_owned(), symbols, bss_addr, bss_size, offset, addr, size})
+            Ok(BinaryInfo{filename: filename.to_owned(), symbols, bss_addr, bss_size, offset, addr, size, is_64: pe.is_64})
         },
         _ => {
             Err(format_err!("Unhandled binary type"))
         }
     }
 }
+
+/// Describes which build of a shared library a file is, using the GNU build-id note when the library
+/// has one and the soname otherwise (like 'build-id 4f3a...' or 'soname libssl.so.3'). Returns None
+/// for files that aren't ELF binaries, or that have neither
+pub fn module_version(filename: &str) -> Option<String> {
+    let file = File::open(Path::new(filename)).ok()?;
+    let buffer = unsafe { Mmap::map(&file).ok()? };
+
+    let elf = match Object::parse(&buffer).ok()? {
+        Object::Elf(elf) => elf,
+        _ => return None
+    };
+
+    if let Some(notes) = elf.iter_note_headers(&buffer) {
+        for note in notes {
+            if let Ok(note) = note {
+                if note.n_type == goblin::elf::note::NT_GNU_BUILD_ID && note.name == "GNU" {
+                    let build_id: String = note.desc.iter().map(|b| format!("{:02x}", b)).collect();
+                    return Some(format!("build-id {}", build_id));
+                }
+            }
+        }
+    }
+    elf.soname.map(|soname| format!("soname {}", soname))
+}
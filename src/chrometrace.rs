@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use remoteprocess::Pid;
+
+use crate::stack_trace::{Frame, StackTrace, FORMAT_VERSION};
+
+/// Writes out samples in the Chrome trace event format (which can be loaded in chrome://tracing
+/// or Perfetto), with a track for each thread. Consecutive samples with the same frame
+/// are merged into a single 'complete' event, so that the event nesting mirrors the call stack
+pub struct ChromeTrace {
+    show_linenumbers: bool,
+    /// Time between samples in microseconds, used for how long the last sample of a process lasts
+    interval: f64,
+    events: Vec<Event>,
+    tracks: HashMap<(Pid, u64), Track>,
+    /// When the most recent sample of each process was taken, in microseconds since the unix epoch
+    last_sample: HashMap<Pid, f64>,
+}
+
+/// The frames currently open on a thread, along with the time (in microseconds since the unix
+/// epoch) of the sample they started at. Using the real sample times lines the trace up with other
+/// traces and logs, even when samples were taken late or skipped
+#[derive(Default)]
+struct Track {
+    thread_name: Option<String>,
+    stack: Vec<(String, f64)>,
+}
+
+#[derive(Serialize)]
+struct Event {
+    name: String,
+    ph: &'static str,
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: Pid,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<HashMap<&'static str, String>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    #[serde(rename = "py_spy_format_version")]
+    format_version: u32,
+    #[serde(rename = "py_spy_version")]
+    version: &'static str,
+    trace_events: &'a [&'a Event],
+    display_time_unit: &'static str,
+}
+
+impl ChromeTrace {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool) -> ChromeTrace {
+        ChromeTrace{show_linenumbers, interval: 1_000_000.0 / sampling_rate as f64,
+                    events: Vec::new(), tracks: HashMap::new(), last_sample: HashMap::new()}
+    }
+
+    /// Sets the rate samples were taken at, which determines how long the last sample lasts
+    pub fn set_sampling_rate(&mut self, rate: f64) {
+        self.interval = 1_000_000.0 / rate;
+    }
+
+    /// Adds all the stack traces from a single sample of a process, taken at the given time
+    pub fn increment(&mut self, pid: Pid, time: SystemTime, traces: &[StackTrace]) {
+        let sample = time.duration_since(UNIX_EPOCH).map_or(0.0, |t| t.as_micros() as f64);
+        self.last_sample.insert(pid, sample);
+
+        let mut sampled = Vec::new();
+        for trace in traces {
+            let tid = trace.os_thread_id.unwrap_or(trace.thread_id);
+            sampled.push(tid);
+
+            let stack: Vec<String> = trace.frames.iter().rev().map(|frame| self.frame_name(frame)).collect();
+            let mut track = self.tracks.remove(&(pid, tid)).unwrap_or_default();
+            if trace.thread_name.is_some() {
+                track.thread_name = trace.thread_name.clone();
+            }
+
+            // keep the frames that are shared with the previous sample open, and close the rest
+            let common = track.stack.iter().zip(stack.iter()).take_while(|((open, _), name)| open == *name).count();
+            self.close_frames(pid, tid, &mut track, common, sample);
+            for name in stack.into_iter().skip(common) {
+                track.stack.push((name, sample));
+            }
+            self.tracks.insert((pid, tid), track);
+        }
+
+        // threads that didn't show up in this sample (because they were idle, or exited) aren't
+        // running anything anymore
+        let finished: Vec<(Pid, u64)> = self.tracks.iter()
+            .filter(|((track_pid, tid), track)| *track_pid == pid && !track.stack.is_empty() && !sampled.contains(tid))
+            .map(|(key, _)| *key)
+            .collect();
+        for (pid, tid) in finished {
+            let mut track = self.tracks.remove(&(pid, tid)).unwrap_or_default();
+            self.close_frames(pid, tid, &mut track, 0, sample);
+            self.tracks.insert((pid, tid), track);
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut events = Vec::new();
+
+        // name each track after its thread
+        let mut tracks: Vec<_> = self.tracks.iter().collect();
+        tracks.sort_by_key(|(key, _)| *key);
+        for ((pid, tid), track) in &tracks {
+            let name = track.thread_name.clone().unwrap_or_else(|| tid.to_string());
+            let mut args = HashMap::new();
+            args.insert("name", name);
+            events.push(Event{name: "thread_name".to_owned(), ph: "M", ts: 0.0, dur: None, pid: *pid, tid: *tid, args: Some(args)});
+        }
+
+        // frames still open at the end of the recording finish one interval after the last sample
+        let mut open = Vec::new();
+        for ((pid, tid), track) in &tracks {
+            let end = self.last_sample.get(pid).map_or(0.0, |last| last + self.interval);
+            for (name, start) in track.stack.iter().rev() {
+                open.push(self.complete_event(name.clone(), *pid, *tid, *start, end));
+            }
+        }
+
+        let events: Vec<&Event> = events.iter().chain(self.events.iter()).chain(open.iter()).collect();
+        serde_json::to_writer(w, &Trace{format_version: FORMAT_VERSION, version: env!("CARGO_PKG_VERSION"),
+                                          trace_events: &events, display_time_unit: "ms"})?;
+        Ok(())
+    }
+
+    fn frame_name(&self, frame: &Frame) -> String {
+        let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+        if self.show_linenumbers && frame.line != 0 {
+            format!("{} ({}:{})", frame.name, filename, frame.line)
+        } else {
+            format!("{} ({})", frame.name, filename)
+        }
+    }
+
+    /// Closes all the frames on the track above depth, as of the given sample
+    fn close_frames(&mut self, pid: Pid, tid: u64, track: &mut Track, depth: usize, sample: f64) {
+        while track.stack.len() > depth {
+            let (name, start) = track.stack.pop().unwrap();
+            let event = self.complete_event(name, pid, tid, start, sample);
+            self.events.push(event);
+        }
+    }
+
+    fn complete_event(&self, name: String, pid: Pid, tid: u64, start: f64, end: f64) -> Event {
+        Event{name, ph: "X", ts: start, dur: Some(end - start), pid, tid, args: None}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::FrameOrigin;
+    use std::time::Duration;
+
+    fn trace(tid: u64, names: &[&str]) -> StackTrace {
+        let frames = names.iter().map(|name| {
+            Frame{name: name.to_string(), filename: "test.py".to_owned(), module: None, short_filename: None,
+                  line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+        }).collect();
+        StackTrace{thread_id: tid, os_thread_id: None, active: true, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    #[test]
+    fn test_merge_consecutive_frames() {
+        let mut chrome = ChromeTrace::new(1000, true);
+        let time = |micros| UNIX_EPOCH + Duration::from_secs(1) + Duration::from_micros(micros);
+        chrome.increment(1, time(0), &[trace(1, &["a", "main"])]);
+        // the second sample was taken late, which shouldn't change when it started
+        chrome.increment(1, time(1500), &[trace(1, &["b", "main"])]);
+        chrome.increment(1, time(2500), &[]);
+
+        // 'a' finishes at the second sample, then 'b' and 'main' finish when the thread goes idle
+        let events: Vec<(&str, f64, Option<f64>)> = chrome.events.iter()
+            .map(|event| (event.name.as_str(), event.ts - 1_000_000.0, event.dur))
+            .collect();
+        assert_eq!(events, vec![("a (test.py)", 0.0, Some(1500.0)),
+                                ("b (test.py)", 1500.0, Some(1000.0)),
+                                ("main (test.py)", 0.0, Some(2500.0))]);
+
+        // changing the sampling rate afterwards doesn't move the events that have been recorded
+        chrome.set_sampling_rate(500.0);
+        assert_eq!(chrome.events[1].ts, 1_001_500.0);
+        assert_eq!(chrome.events[1].dur, Some(1000.0));
+    }
+}
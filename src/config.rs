@@ -1,13 +1,16 @@
 use clap::{App, Arg};
+use regex::Regex;
 use remoteprocess::Pid;
 
 /// Options on how to collect samples from a python process
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     /// Whether or not we should stop the python process when taking samples.
     /// Setting this to false will reduce the performance impact on the target
     /// python process, but can lead to incorrect results like partial stack
-    /// traces being returned or a higher sampling error rate
+    /// traces being returned or a higher sampling error rate. Samples where a
+    /// thread's stack changed while it was being read are dropped rather than
+    /// returned, and show up as errors
     pub non_blocking: bool,
 
     /// Whether or not to profile native extensions. Note: this option can not be
@@ -15,40 +18,295 @@ pub struct Config {
     /// the native stack traces
     pub native: bool,
 
+    /// Only get native stack traces, skipping the python stacks entirely. The python eval loop
+    /// functions are left in the native stacks as they are, instead of being replaced by python frames
+    pub only_native: bool,
+
+    /// Native modules that shouldn't be symbolicated. Frames from modules containing one of these
+    /// strings are reported as 'module+offset' instead of with function names
+    pub no_symbolicate_modules: Vec<String>,
+
+    /// Read the kernel stack of each idle thread from /proc (linux only, and needs root), adding
+    /// the kernel frames below the thread's other frames
+    pub kernel_stacks: bool,
+
+    /// If set, only native frames from modules containing one of these strings are symbolicated.
+    /// Each run of frames from other modules is collapsed into a single '<external>' frame
+    pub native_modules: Vec<String>,
+
+    /// Additional native frames to remove from stack traces, as (module, function) pairs. Modules
+    /// are matched as substrings (with an empty module matching everything), functions exactly
+    pub ignored_native_frames: Vec<(String, String)>,
+
+    /// Additional native functions that mean a thread is idle when they're the leaf frame of its
+    /// stack, as (module, function) pairs matched the same way as ignored_native_frames
+    pub blocking_native_frames: Vec<(String, String)>,
+
+    /// The native functions that run python's eval loop. Each call to one of these in a native stack
+    /// is replaced with the python frame it was running, so patched or newer interpreters with
+    /// differently named eval loops need their names added here
+    pub eval_frame_functions: Vec<String>,
+
+    /// Keep consecutive native frames for the same function and line, rather than collapsing them
+    /// into a single frame. These show up with tail calls and thunks in optimized builds
+    pub keep_duplicate_native_frames: bool,
+
+    /// Fail when a cython source map can't be loaded, rather than leaving the frames that needed it
+    /// as frames for the generated c/c++ code
+    pub require_cython_maps: bool,
+
+    /// Label the module of each native frame with the build of the library that was loaded, using
+    /// its ELF build-id (or soname when it doesn't have one), like 'libssl.so.3 (build-id 4f3a...)'
+    pub native_build_ids: bool,
+    /// A file to write the modules loaded by the native unwinder to (with their address ranges
+    /// and build-ids), which is rewritten each time the modules are reloaded. Linux only
+    pub emit_modules: Option<String>,
+    /// Leave the names of native functions mangled, and don't translate cython frames back to
+    /// their .pyx source. This makes native profiling cheaper, with the names demangled afterwards
+    pub raw_symbols: bool,
+    /// Which of the native frames that start the process or its threads (like main, Py_RunMain or
+    /// start_thread) are shown below the outermost python frame
+    pub show_entry: ShowEntry,
+
+    /// Rewrites the filenames in stack traces, as (from, to) pairs of path prefixes. The longest
+    /// matching prefix is used, for when programs were built somewhere other than where their
+    /// source lives now
+    pub path_remaps: Vec<(String, String)>,
+
+    /// Directories holding the program's own code. Frames from files under any of these (after
+    /// remapping) are marked as application code, and everything else as library code
+    pub app_roots: Vec<String>,
+
+    /// The python binary and libpython loaded by the process, as they appear in its memory maps.
+    /// When set these are used instead of detecting them, for programs that embed python in
+    /// ways that the detection doesn't handle
+    pub python_binary: Option<String>,
+    pub libpython: Option<String>,
+
+    /// Only get stack traces for the thread currently holding the GIL. Other threads are
+    /// skipped before their stacks are read, so this also avoids unwinding their native stacks
+    pub gil_only: bool,
+
+    /// Only get stack traces for these threads, matching either the python or OS thread id.
+    /// Like gil_only, other threads are skipped before their stacks are read. Empty means all threads
+    pub thread_ids: Vec<u64>,
+
+    /// Decide whether each thread is idle by checking if it has used any cpu time since the
+    /// last sample, instead of by asking the OS whether the thread is currently running
+    pub cpu_time: bool,
+
+    /// Copy the local variables for each python frame into Frame::locals. This is slower
+    /// than just getting the stack, so is off by default
+    pub dump_locals: bool,
+
+    /// Also get the stacks of asyncio tasks that are suspended (waiting rather than running on a
+    /// thread), which are returned as extra stack traces named after the task. Python 3.7 to 3.9 only
+    pub suspended_tasks: bool,
+
+    /// Only get this many python frames for each thread, starting from the innermost call. Deeper
+    /// stacks are cut short with a '... N more frames' frame in place of the outermost calls
+    pub max_depth: Option<usize>,
+
+    /// A module global to read for each thread, as a (module, path) pair where the path is the name
+    /// of the global optionally followed by '.attribute'. Its value is added as a 'context: ...' frame
+    /// at the root of each stack, so that samples can be split by things like the current request
+    pub context_attr: Option<(String, String)>,
+
+    /// Leave the process stopped (with SIGSTOP) after getting its stack traces, so that a debugger
+    /// can be attached to it. The signal is sent once the stack traces have been printed.
+    /// Only supported on unix
+    pub stop_process: bool,
+
     // The following config options only apply when using py-spy as an application
     #[doc(hidden)]
     pub command: String,
     #[doc(hidden)]
     pub pid: Option<Pid>,
     #[doc(hidden)]
+    pub pidfd: Option<i32>,
+    #[doc(hidden)]
+    pub process_name: Option<String>,
+    #[doc(hidden)]
+    pub container_pid: Option<Pid>,
+    #[doc(hidden)]
     pub python_program: Option<Vec<String>>,
     #[doc(hidden)]
+    pub subprocesses: bool,
+    #[doc(hidden)]
+    pub subprocess_depth: Option<u64>,
+    #[doc(hidden)]
     pub sampling_rate: u64,
     #[doc(hidden)]
+    pub auto_rate: bool,
+    #[doc(hidden)]
+    pub max_overhead: f64,
+    #[doc(hidden)]
+    pub jitter: Option<f64>,
+    #[doc(hidden)]
+    pub seed: Option<u64>,
+    #[doc(hidden)]
     pub filename: Option<String>,
     #[doc(hidden)]
     pub format: Option<FileFormat>,
     #[doc(hidden)]
     pub show_line_numbers: bool,
     #[doc(hidden)]
+    pub collapse_recursion: bool,
+    #[doc(hidden)]
+    pub bytecode_offsets: bool,
+    #[doc(hidden)]
+    pub focus: Option<String>,
+    #[doc(hidden)]
+    pub min_samples: Option<usize>,
+    #[doc(hidden)]
+    pub gray_libraries: bool,
+    #[doc(hidden)]
     pub duration: RecordDuration,
     #[doc(hidden)]
+    pub sample_limit: Option<u64>,
+    #[doc(hidden)]
+    pub checkpoint_interval: Option<u64>,
+    #[doc(hidden)]
+    pub stall_threshold: Option<u64>,
+    #[doc(hidden)]
     pub include_idle: bool,
     #[doc(hidden)]
     pub include_thread_ids: bool,
     #[doc(hidden)]
-    pub gil_only: bool,
-    #[doc(hidden)]
     pub hide_progess: bool,
+    #[doc(hidden)]
+    pub assertions: Vec<FunctionAssertion>,
+    #[doc(hidden)]
+    pub summary: bool,
+    #[doc(hidden)]
+    pub sample_stats: bool,
+    #[doc(hidden)]
+    pub compress: bool,
+    #[doc(hidden)]
+    pub aggregate: Aggregate,
+    #[doc(hidden)]
+    pub color: Color,
+    #[doc(hidden)]
+    pub log_format: LogFormat,
+    #[doc(hidden)]
+    pub check: bool,
+    #[doc(hidden)]
+    pub dump_offsets: bool,
+    #[doc(hidden)]
+    pub prometheus: Option<String>,
+    #[doc(hidden)]
+    pub dump_json: bool,
+    #[doc(hidden)]
+    pub gil_stats: Option<u64>,
+    #[doc(hidden)]
+    pub watch: Option<u64>,
+    #[doc(hidden)]
+    pub dump_signal: Option<i32>,
+    #[doc(hidden)]
+    pub core_filename: Option<String>,
+    #[doc(hidden)]
+    pub merge_inputs: Vec<String>,
+    #[doc(hidden)]
+    pub merge_rates: Vec<u64>,
+    #[doc(hidden)]
+    pub merge_labels: bool,
+    #[doc(hidden)]
+    pub raw_addresses: bool,
+    #[doc(hidden)]
+    pub symbol_root: Option<String>,
+}
+
+// Not an arg_enum, since the 'pstats-text' format name isn't a valid identifier
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum FileFormat {
+    flamegraph,
+    raw,
+    speedscope,
+    pstats_text,
+    pprof,
+    ndjson,
+    chrometrace
+}
+
+impl FileFormat {
+    const FORMATS: [(&'static str, FileFormat); 7] = [("flamegraph", FileFormat::flamegraph), ("raw", FileFormat::raw),
+                                                      ("speedscope", FileFormat::speedscope), ("pstats-text", FileFormat::pstats_text),
+                                                      ("pprof", FileFormat::pprof), ("ndjson", FileFormat::ndjson),
+                                                      ("chrometrace", FileFormat::chrometrace)];
+
+    pub fn variants() -> Vec<&'static str> {
+        FileFormat::FORMATS.iter().map(|&(name, _)| name).collect()
+    }
+}
+
+impl std::str::FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FileFormat, String> {
+        FileFormat::FORMATS.iter()
+            .find(|&&(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|&(_, format)| format)
+            .ok_or_else(|| format!("valid values: {}", FileFormat::variants().join(", ")))
+    }
+}
+
+impl std::fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = FileFormat::FORMATS.iter().find(|&&(_, format)| format == *self).map(|&(name, _)| name);
+        write!(f, "{}", name.unwrap_or("?"))
+    }
+}
+
+arg_enum!{
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    #[allow(non_camel_case_types)]
+    pub enum LogFormat {
+        text,
+        json
+    }
+}
+
+arg_enum!{
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    #[allow(non_camel_case_types)]
+    pub enum Color {
+        auto,
+        always,
+        never
+    }
+}
+
+impl Color {
+    /// Whether to force colors on or off, with None meaning to only use them when writing to a terminal
+    pub fn force(self) -> Option<bool> {
+        match self {
+            Color::auto => None,
+            Color::always => Some(true),
+            Color::never => Some(false),
+        }
+    }
+}
+
+arg_enum!{
+    /// How the native frames below the outermost python frame of a thread are shown: 'libc' hides
+    /// just the libc and pthread entry points, 'always' shows everything (including the startup
+    /// code in python itself), and 'never' hides all of them
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    #[allow(non_camel_case_types)]
+    pub enum ShowEntry {
+        libc,
+        always,
+        never
+    }
 }
 
 arg_enum!{
-    #[derive(Debug, Clone, Eq, PartialEq)]
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
     #[allow(non_camel_case_types)]
-    pub enum FileFormat {
-        flamegraph,
-        raw,
-        speedscope
+    pub enum Aggregate {
+        function,
+        module
     }
 }
 
@@ -58,16 +316,93 @@ pub enum RecordDuration {
     Seconds(u64)
 }
 
+impl std::str::FromStr for RecordDuration {
+    type Err = String;
+
+    /// Parses 'unlimited', a number of seconds, or a duration made up of hours, minutes
+    /// and seconds like '30s', '5m' or '1h30m'
+    fn from_str(s: &str) -> Result<RecordDuration, String> {
+        if s == "unlimited" {
+            return Ok(RecordDuration::Unlimited);
+        }
+        if let Ok(seconds) = s.parse::<u64>() {
+            return Ok(RecordDuration::Seconds(seconds));
+        }
+
+        let invalid = || format!("Invalid duration '{}': expected 'unlimited', a number of seconds, \
+                                  or a duration like '30s', '5m' or '1h30m'", s);
+        let mut seconds: u64 = 0;
+        let mut value = String::new();
+        for c in s.chars() {
+            if c.is_ascii_digit() {
+                value.push(c);
+                continue;
+            }
+            let multiplier = match c {
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(invalid())
+            };
+            let count: u64 = value.parse().map_err(|_| invalid())?;
+            seconds = count.checked_mul(multiplier).and_then(|x| x.checked_add(seconds)).ok_or_else(invalid)?;
+            value.clear();
+        }
+        // every number needs a unit after it (other than a bare number of seconds, handled above)
+        if !value.is_empty() || s.is_empty() {
+            return Err(invalid());
+        }
+        Ok(RecordDuration::Seconds(seconds))
+    }
+}
+
+/// An upper bound on the percentage of samples a function can show up in,
+/// used to fail a recording on a performance regression (ie 'myfunc<5%')
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionAssertion {
+    pub function: String,
+    pub max_percent: f64,
+}
+
+impl std::str::FromStr for FunctionAssertion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FunctionAssertion, String> {
+        // function names can contain '<' themselves (like '<module>'), so split on the last one
+        let mut tokens = s.rsplitn(2, '<');
+        let percent = tokens.next().unwrap_or("");
+        let function = match tokens.next() {
+            Some(function) if !function.is_empty() => function,
+            _ => return Err(format!("Invalid assertion '{}': expected the form 'function<percent%'", s))
+        };
+
+        let percent = percent.trim().trim_end_matches('%');
+        match percent.parse::<f64>() {
+            Ok(max_percent) if (0.0..=100.0).contains(&max_percent) => {
+                Ok(FunctionAssertion{function: function.trim().to_owned(), max_percent})
+            },
+            _ => Err(format!("Invalid assertion '{}': '{}' isn't a percentage between 0 and 100", s, percent))
+        }
+    }
+}
+
+/// The names that python's eval loop functions have had across versions and platforms
+const EVAL_FRAME_FUNCTIONS: &[&str] = &["PyEval_EvalFrameDefault", "_PyEval_EvalFrameDefault", "__PyEval_EvalFrameDefault",
+                                        "PyEval_EvalFrameEx", "_PyEval_EvalFrame"];
+
 impl Default for Config {
     /// Initializes a new Config object with default parameters
     #[allow(dead_code)]
     fn default() -> Config {
         Config{pid: None, python_program: None, filename: None, format: None,
                command: String::from("top"),
-               non_blocking: false, show_line_numbers: false, sampling_rate: 100,
-               duration: RecordDuration::Unlimited, native: false,
-               gil_only: false, include_idle: false, include_thread_ids: false,
-               hide_progess: false}
+               pidfd: None, process_name: None, container_pid: None, subprocesses: false, subprocess_depth: None, non_blocking: false, show_line_numbers: false, collapse_recursion: false, bytecode_offsets: false, focus: None, min_samples: None, gray_libraries: false, sampling_rate: 100, auto_rate: false, max_overhead: 10.0, jitter: None, seed: None,
+               duration: RecordDuration::Unlimited, sample_limit: None, checkpoint_interval: None, stall_threshold: None, native: false, only_native: false,
+               gil_only: false, thread_ids: Vec::new(), cpu_time: false, max_depth: None, context_attr: None, include_idle: false, include_thread_ids: false,
+               hide_progess: false, assertions: Vec::new(), summary: false, sample_stats: false, compress: false, aggregate: Aggregate::function, color: Color::auto, log_format: LogFormat::text, check: false, dump_offsets: false, prometheus: None, no_symbolicate_modules: Vec::new(), native_modules: Vec::new(), kernel_stacks: false, ignored_native_frames: Vec::new(), blocking_native_frames: Vec::new(),
+               eval_frame_functions: EVAL_FRAME_FUNCTIONS.iter().map(|f| f.to_string()).collect(), keep_duplicate_native_frames: false, native_build_ids: false, emit_modules: None, raw_symbols: false, show_entry: ShowEntry::libc, require_cython_maps: false, path_remaps: Vec::new(), app_roots: Vec::new(), python_binary: None, libpython: None,
+               dump_json: false, dump_locals: false, suspended_tasks: false, gil_stats: None, watch: None, dump_signal: None, stop_process: false, core_filename: None, merge_inputs: Vec::new(), merge_rates: Vec::new(), merge_labels: false,
+               raw_addresses: false, symbol_root: None}
     }
 }
 
@@ -87,16 +422,264 @@ impl Config {
                     .value_name("pid")
                     .help("PID of a running python program to spy on")
                     .takes_value(true)
-                    .required_unless("python_program");
+                    .required_unless_one(&["python_program", "pidfd", "process_name", "core"]);
+        #[cfg(target_os="linux")]
+        let pidfd = Arg::with_name("pidfd")
+                    .long("pidfd")
+                    .value_name("fd")
+                    .help("A pidfd referring to a running python program to spy on. Unlike --pid, this \
+                          can't end up targeting a different process if the pid is reused")
+                    .takes_value(true)
+                    .conflicts_with_all(&["pid", "python_program"])
+                    .validator(|s| s.parse::<i32>().map(|_| ()).map_err(|e| e.to_string()));
+        #[cfg(target_os="linux")]
+        let process_name = Arg::with_name("process_name")
+                    .long("process-name")
+                    .value_name("regex")
+                    .help("Spy on the running process whose executable name or commandline matches this regex. \
+                          Fails if there isn't exactly one matching process")
+                    .takes_value(true)
+                    .conflicts_with_all(&["pid", "pidfd", "python_program"])
+                    .validator(|s| Regex::new(&s).map(|_| ()).map_err(|e| e.to_string()));
+        #[cfg(target_os="linux")]
+        let container_pid = Arg::with_name("container_pid")
+                    .long("container-pid")
+                    .value_name("pid")
+                    .help("PID of the python program as seen from inside its container, with --pid being the host \
+                          PID of any process in the same container (like the one from 'docker inspect')")
+                    .takes_value(true)
+                    .requires("pid")
+                    .validator(|s| s.parse::<Pid>().map(|_| ()).map_err(|e| e.to_string()));
+        #[cfg(target_os="linux")]
+        let kernel = Arg::with_name("kernel")
+                    .long("kernel")
+                    .help("Include the kernel stacks of threads that are blocked (like in a system call), read from \
+                          /proc/<pid>/task/<tid>/stack. This usually needs root, and is skipped when unavailable");
+        #[cfg(target_os="linux")]
+        let subprocesses = Arg::with_name("subprocesses")
+                    .short("s")
+                    .long("subprocesses")
+                    .help("Profile subprocesses of the original process");
+        #[cfg(target_os="linux")]
+        let subprocess_depth = Arg::with_name("subprocess_depth")
+                    .long("subprocess-depth")
+                    .value_name("depth")
+                    .help("Only profile subprocesses this many levels below the original process (with 1 meaning \
+                          only direct children). Defaults to following all subprocesses")
+                    .takes_value(true)
+                    .requires("subprocesses")
+                    .validator(|s| match s.parse::<u64>() {
+                        Ok(depth) if depth > 0 => Ok(()),
+                        Ok(_) => Err("depth must be at least 1".to_owned()),
+                        Err(e) => Err(e.to_string())
+                    });
         #[cfg(unwind)]
         let native = Arg::with_name("native")
                     .short("n")
                     .long("native")
                     .help("Collect stack traces from native extensions written in Cython, C or C++");
+        #[cfg(unwind)]
+        let only_native = Arg::with_name("only_native")
+                    .long("only-native")
+                    .help("Only collect native stack traces, without reading the python stacks. This is lighter weight \
+                          than --native, with the python interpreter's own functions shown instead of python frames")
+                    .conflicts_with_all(&["nonblocking", "gil"]);
+        #[cfg(unwind)]
+        let no_symbolicate_module = Arg::with_name("no_symbolicate_module")
+                    .long("no-symbolicate-module")
+                    .value_name("module")
+                    .help("Don't resolve function names for native frames in modules matching this string, and \
+                          show them as module+offset instead. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1);
+        #[cfg(unwind)]
+        let native_module = Arg::with_name("native_module")
+                    .long("native-module")
+                    .value_name("module")
+                    .help("Only resolve native frames in modules matching this string (and in python itself), \
+                          collapsing the frames from other modules into a single '<external>' frame. Can be \
+                          passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1);
+        #[cfg(unwind)]
+        let ignore_native_frame = Arg::with_name("ignore_native_frame")
+                    .long("ignore-native-frame")
+                    .value_name("module:function")
+                    .help("Remove native frames for this function from stack traces (ie 'libfoo.so:thread_start'). \
+                          Leave the module empty to match any module. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|s| parse_ignored_frame(&s).map(|_| ()));
+        #[cfg(unwind)]
+        let blocking_native_frame = Arg::with_name("blocking_native_frame")
+                    .long("blocking-native-frame")
+                    .value_name("module:function")
+                    .help("Treat threads waiting in this native function as idle (ie 'libfoo.so:wait_for_work'), on top \
+                          of the builtin libc wait functions like pthread_cond_wait and epoll_wait. Leave the module \
+                          empty to match any module. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|s| parse_ignored_frame(&s).map(|_| ()));
+        #[cfg(unwind)]
+        let eval_frame_function = Arg::with_name("eval_frame_function")
+                    .long("eval-frame-function")
+                    .value_name("function")
+                    .help("Treat this native function as python's eval loop when merging python frames into native \
+                          stacks, on top of the builtin ones like _PyEval_EvalFrameDefault. For interpreters with \
+                          patched or renamed eval loops. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1);
+        #[cfg(unwind)]
+        let keep_duplicate_native_frames = Arg::with_name("keep_duplicate_native_frames")
+                    .long("keep-duplicate-native-frames")
+                    .help("Don't collapse consecutive native frames for the same function and line into a single \
+                          frame. These are usually caused by tail calls and thunks in optimized builds");
+        #[cfg(unwind)]
+        let require_cython_maps = Arg::with_name("require_cython_maps")
+                    .long("require-cython-maps")
+                    .help("Exit with an error when the source map for a cython module can't be found, instead of \
+                          showing its frames as the generated c/c++ code")
+                    .requires("native");
+        #[cfg(unwind)]
+        let build_ids = Arg::with_name("build_ids")
+                    .long("build-ids")
+                    .help("Label the shared library of each native frame with its build-id (or soname), to show \
+                          exactly which build of the library was loaded")
+                    .requires("native");
+        #[cfg(unwind)]
+        let raw_symbols = Arg::with_name("raw_symbols")
+                    .long("raw-symbols")
+                    .help("Show native function names as their mangled symbols, without demangling them or \
+                          translating cython frames. This lowers the overhead of native profiling at high \
+                          sampling rates, for when the names will be demangled afterwards")
+                    .requires("native")
+                    .conflicts_with("require_cython_maps");
+        #[cfg(unwind)]
+        let show_entry = Arg::with_name("show_entry")
+                    .long("show-entry")
+                    .value_name("frames")
+                    .help("Which of the native frames that start the process and its threads (like main, Py_RunMain \
+                          and start_thread) to show below the outermost python frame: 'libc' hides just the libc and \
+                          pthread entry points, 'always' shows all of them and 'never' hides all of them")
+                    .takes_value(true)
+                    .possible_values(&ShowEntry::variants())
+                    .case_insensitive(true)
+                    .default_value("libc");
+        #[cfg(all(unwind, target_os="linux"))]
+        let emit_modules = Arg::with_name("emit_modules")
+                    .long("emit-modules")
+                    .value_name("filename")
+                    .help("Write the native modules loaded by the process (with their address ranges and build-ids) \
+                          to this file as json, to be able to symbolicate the native stacks or check which versions \
+                          of libraries were used later on. The file is rewritten when new modules are loaded")
+                    .takes_value(true)
+                    .requires("native")
+                    .conflicts_with("subprocesses");
+        let path_remap = Arg::with_name("path_remap")
+                    .long("path-remap")
+                    .value_name("from=to")
+                    .help("Rewrite filenames starting with this path prefix to start with another one instead (ie \
+                          '/build=/home/me/src'), using the longest matching prefix. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|s| parse_path_remap(&s).map(|_| ()));
+        let app_root = Arg::with_name("app_root")
+                    .long("app-root")
+                    .value_name("path")
+                    .help("Treat python files under this directory as application code, and everything else (like the \
+                          standard library and site-packages) as library code. Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1);
+        let log_format = Arg::with_name("log_format")
+                    .long("log-format")
+                    .value_name("format")
+                    .help("Format of py-spy's own warnings and errors on stderr: 'json' writes a JSON object \
+                          per line, with the level, message and any context like the pid")
+                    .possible_values(&LogFormat::variants())
+                    .default_value("text")
+                    .takes_value(true);
+        let focus = Arg::with_name("focus")
+                    .long("focus")
+                    .value_name("regex")
+                    .help("Only show what happens inside functions matching this regex: each stack starts at the \
+                          deepest matching frame, and samples without a matching frame are dropped")
+                    .takes_value(true)
+                    .validator(|s| Regex::new(&s).map(|_| ()).map_err(|e| e.to_string()));
+        let check = Arg::with_name("check")
+                    .long("check")
+                    .alias("dry-run")
+                    .help("Check that the process can be profiled by attaching to it and taking a single sample, \
+                          then exit without profiling it. Pass --native to check native unwinding as well");
+        let dump_offsets = Arg::with_name("dump_offsets")
+                    .long("dump-offsets")
+                    .help("Print the addresses py-spy found in the process, and the offsets of the members of the \
+                          interpreter, thread state, frame and code structs it reads, to stderr before sampling. \
+                          For debugging custom or unsupported builds of python");
+        let python_binary = Arg::with_name("python_binary")
+                    .long("python-binary")
+                    .value_name("path")
+                    .help("Path of the python executable loaded by the process, for when this can't be detected \
+                          (like with programs that embed python)")
+                    .takes_value(true);
+        let libpython = Arg::with_name("libpython")
+                    .long("libpython")
+                    .value_name("path")
+                    .help("Path of the libpython shared library loaded by the process, for when this can't be \
+                          detected (like with programs that dlopen libpython)")
+                    .takes_value(true);
+        let thread_id = Arg::with_name("thread_id")
+                    .long("thread-id")
+                    .value_name("id")
+                    .help("Only sample the thread with this python or OS thread id (in decimal, or hex with a \
+                          leading 0x). Can be passed multiple times")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|s| parse_thread_id(&s).map(|_| ()));
         let nonblocking = Arg::with_name("nonblocking")
                     .long("nonblocking")
                     .help("Don't pause the python process when collecting samples. Setting this option will reduce \
-                          the perfomance impact of sampling, but may lead to inaccurate results");
+                          the perfomance impact of sampling, but may lead to inaccurate results. Samples where a stack \
+                          changed while being read are dropped, so expect a higher error count. Can't be used with --native");
+        let aggregate = Arg::with_name("aggregate")
+                    .long("aggregate")
+                    .value_name("level")
+                    .help("Whether to show time spent in each function, or grouped by top level python package \
+                          and native shared library")
+                    .takes_value(true)
+                    .possible_values(&Aggregate::variants())
+                    .case_insensitive(true)
+                    .default_value("function");
+        let cpu = Arg::with_name("cpu")
+                    .long("cpu")
+                    .help("Only count threads as active if they have used cpu time since the previous sample. \
+                          Note that cpu time is only measured with the resolution of the OS scheduler");
+        let max_depth = Arg::with_name("max_depth")
+                    .long("max-depth")
+                    .value_name("frames")
+                    .help("Only get this many frames of each thread's stack, starting from the innermost call. \
+                          Useful for programs with deeply recursive stacks")
+                    .takes_value(true)
+                    .validator(|s| match s.parse::<usize>() {
+                        Ok(depth) if depth > 0 => Ok(()),
+                        Ok(_) => Err("depth must be at least 1".to_owned()),
+                        Err(e) => Err(e.to_string())
+                    });
+        let context_attr = Arg::with_name("context_attr")
+                    .long("context-attr")
+                    .value_name("module:attr")
+                    .help("Add the value of a module global to the root of each thread's stack, like the current \
+                          request of a web framework (ie 'myapp.context:request.path'). One attribute of the global \
+                          can be read, and threading.local globals are read for each thread")
+                    .takes_value(true)
+                    .validator(|s| parse_context_attr(&s).map(|_| ()));
         let rate = Arg::with_name("rate")
                     .short("r")
                     .long("rate")
@@ -109,21 +692,32 @@ impl Config {
                     .multiple(true);
 
         let record = clap::SubCommand::with_name("record")
-            .about("Records stack trace information to a flamegraph, speedscope or raw file")
+            .about("Records stack trace information to a flamegraph, speedscope, pstats-text, pprof, ndjson, chrometrace or raw file")
             .arg(program.clone())
             .arg(pid.clone())
             .arg(Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .value_name("filename")
-                .help("Output filename")
+                .help("Output filename, or - to write to stdout")
                 .takes_value(true)
-                .required(true))
+                .required_unless_one(&["prometheus", "check"]))
+            .arg(Arg::with_name("prometheus")
+                .long("prometheus")
+                .value_name("addr:port")
+                .help("Continuously profile the process, serving sample counts for prometheus at \
+                       http://<addr:port>/metrics instead of writing an output file")
+                .takes_value(true)
+                .conflicts_with("output")
+                .validator(|s| s.parse::<std::net::SocketAddr>().map(|_| ()).map_err(|e| e.to_string())))
             .arg(Arg::with_name("format")
                 .short("f")
                 .long("format")
                 .value_name("format")
-                .help("Output file format")
+                .help("Output file format. The raw format writes folded stacks (one 'frame1;frame2 count' line \
+                       per unique stack) that can be used with flamegraph.pl or inferno. The ndjson format \
+                       streams out each sample as a line of json as it's taken. \
+                       The chrometrace format shows a timeline of each thread in Perfetto or chrome://tracing")
                 .takes_value(true)
                 .possible_values(&FileFormat::variants())
                 .case_insensitive(true)
@@ -132,14 +726,107 @@ impl Config {
                 .short("d")
                 .long("duration")
                 .value_name("duration")
-                .help("The number of seconds to sample for")
-                .default_value("unlimited")
-                .takes_value(true))
+                .help("How long to sample for, either as a number of seconds or with units like '5m' or '1h30m' \
+                       [default: unlimited]")
+                .takes_value(true)
+                .validator(|s| s.parse::<RecordDuration>().map(|_| ())))
+            .arg(Arg::with_name("samples")
+                .long("samples")
+                .value_name("count")
+                .help("Stop after collecting this many samples, however long that takes. Samples that are dropped \
+                       because the process couldn't be read don't count towards this")
+                .takes_value(true)
+                .conflicts_with("duration")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(samples) if samples > 0 => Ok(()),
+                    Ok(_) => Err("count must be at least 1".to_owned()),
+                    Err(e) => Err(e.to_string())
+                }))
+            .arg(Arg::with_name("checkpoint_interval")
+                .long("checkpoint-interval")
+                .value_name("seconds")
+                .help("Rewrite the output file with the samples collected so far every this many seconds, so that \
+                       a partial profile is left behind if py-spy is killed. Each checkpoint is written to a \
+                       temporary file that's renamed over the output, so the output is never left half written")
+                .takes_value(true)
+                .conflicts_with("prometheus")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Ok(()),
+                    Ok(_) => Err("interval must be at least 1 second".to_owned()),
+                    Err(e) => Err(e.to_string())
+                }))
+            .arg(Arg::with_name("stalls")
+                .long("stalls")
+                .value_name("ms")
+                .help("Report gaps of more than this many milliseconds between samples, where the process couldn't \
+                       be sampled (like during long GC pauses). Samples taken right after a stall get an \
+                       '<after stall>' root frame, and the longest stalls are listed at the end along with what \
+                       was running just before and after them")
+                .takes_value(true)
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(ms) if ms > 0 => Ok(()),
+                    Ok(_) => Err("threshold must be at least 1ms".to_owned()),
+                    Err(e) => Err(e.to_string())
+                }))
             .arg(rate.clone())
+            .arg(Arg::with_name("auto_rate")
+                .long("auto-rate")
+                .help("Lower the sampling rate when taking samples is using too much time (see --max-overhead), \
+                       and raise it back up to --rate when there's time to spare"))
+            .arg(Arg::with_name("max_overhead")
+                .long("max-overhead")
+                .value_name("percent")
+                .help("With --auto-rate, the maximum percentage of the time to spend taking samples [default: 10]")
+                .takes_value(true)
+                .requires("auto_rate")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(()),
+                    _ => Err(format!("Invalid overhead '{}': expected a percentage between 0 and 100", s))
+                }))
+            .arg(Arg::with_name("jitter")
+                .long("jitter")
+                .value_name("percent")
+                .help("Randomize the time between samples by up to this percentage of the interval, instead of \
+                       using exponentially distributed intervals. The average rate stays at --rate")
+                .takes_value(true)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(percent) if percent >= 0.0 && percent < 100.0 => Ok(()),
+                    _ => Err(format!("Invalid jitter '{}': expected a percentage between 0 and 100", s))
+                }))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .value_name("seed")
+                .help("Seed for the random intervals between samples with --jitter, so that they're the same on every run")
+                .takes_value(true)
+                .requires("jitter")
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())))
             .arg(Arg::with_name("function")
                 .short("F")
                 .long("function")
                 .help("Aggregate samples by function name instead of by line number"))
+            .arg(Arg::with_name("collapse_recursion")
+                .long("collapse-recursion")
+                .help("Collapse recursive calls to the same function into a single frame in flamegraphs, \
+                       labelled with the deepest recursion seen"))
+            .arg(Arg::with_name("bytecode")
+                .long("bytecode")
+                .help("Split up samples for the same line by the bytecode offset being executed, to show which \
+                       part of a line with several expressions is hot")
+                .conflicts_with("function"))
+            .arg(Arg::with_name("min_samples")
+                .long("min-samples")
+                .value_name("samples")
+                .help("Merge flamegraph frames with fewer samples than this into an '<other>' frame, to keep \
+                       long recordings readable")
+                .takes_value(true)
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(samples) if samples > 0 => Ok(()),
+                    _ => Err(format!("Invalid number of samples '{}'", s))
+                }))
+            .arg(Arg::with_name("gray_libraries")
+                .long("gray-libraries")
+                .help("Show library frames in gray in flamegraphs, so that application code stands out")
+                .requires("app_root"))
             .arg(Arg::with_name("gil")
                 .short("g")
                 .long("gil")
@@ -151,7 +838,28 @@ impl Config {
             .arg(Arg::with_name("idle")
                 .short("i")
                 .long("idle")
-                .help("Include stack traces for idle threads"))
+                .help("Include stack traces for idle threads. Speedscope files always include idle samples, \
+                       and have separate on-cpu and wall clock profiles for each thread"))
+            .arg(Arg::with_name("assert")
+                .long("assert")
+                .value_name("assertion")
+                .help("Fail with a nonzero exit code if a function shows up in more than this percentage of samples \
+                       (ie 'myfunc<5%'). Can be passed multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<FunctionAssertion>().map(|_| ())))
+            .arg(Arg::with_name("summary")
+                .long("summary")
+                .help("Print the functions with the most samples, both as the leaf frame and anywhere on the \
+                       stack, to stderr when the recording finishes"))
+            .arg(Arg::with_name("sample_stats")
+                .long("sample-stats")
+                .help("Show how many samples were collected and dropped, the sampling rate and the duration in \
+                       the title of the flamegraph, and print how much memory was read from the process per sample"))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .help("Gzip the output, which is done automatically when the output filename ends in .gz"))
             .arg(Arg::with_name("hideprogress")
                 .long("hideprogress")
                 .hidden(true)
@@ -161,11 +869,194 @@ impl Config {
             .about("Displays a top like view of functions consuming CPU")
             .arg(program.clone())
             .arg(pid.clone())
-            .arg(rate.clone());
+            .arg(rate.clone())
+            .arg(Arg::with_name("color")
+                .long("color")
+                .value_name("when")
+                .help("When to use colors: 'auto' only uses them when writing to a terminal")
+                .takes_value(true)
+                .possible_values(&Color::variants())
+                .case_insensitive(true)
+                .default_value("auto"));
 
         let dump = clap::SubCommand::with_name("dump")
             .about("Dumps stack traces for a target program to stdout")
-            .arg(pid.clone().required(true));
+            .arg(pid.clone().required(true))
+            .arg(Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("Format output as JSON"))
+            .arg(Arg::with_name("locals")
+                .short("l")
+                .long("locals")
+                .help("Show local variables for each frame. Only strings, numbers and None have their \
+                       values shown, other objects are displayed by type"))
+            .arg(Arg::with_name("suspended")
+                .long("suspended")
+                .help("Also dump the stacks of asyncio tasks that are suspended waiting on something, which \
+                       aren't on any thread's stack (python 3.7 to 3.9)"))
+            .arg(Arg::with_name("gil_stats")
+                .long("gil-stats")
+                .value_name("seconds")
+                .help("Instead of dumping stack traces, sample the program for this many seconds and show \
+                       how often each thread held the GIL, or was waiting on another thread to release it")
+                .takes_value(true)
+                .conflicts_with_all(&["json", "locals", "suspended"])
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Ok(()),
+                    Ok(_) => Err("seconds must be at least 1".to_owned()),
+                    Err(e) => Err(e.to_string())
+                }))
+            .arg(Arg::with_name("watch")
+                .long("watch")
+                .value_name("seconds")
+                .help("Instead of dumping stack traces, sample the program for this many seconds and redraw a \
+                       timeline of whether each thread was running, idle or holding the GIL")
+                .takes_value(true)
+                .conflicts_with_all(&["json", "locals", "suspended", "gil_stats"])
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Ok(()),
+                    Ok(_) => Err("seconds must be at least 1".to_owned()),
+                    Err(e) => Err(e.to_string())
+                }));
+
+        let merge = clap::SubCommand::with_name("merge")
+            .about("Merges multiple speedscope or raw recordings into a single flamegraph, raw or speedscope file")
+            .arg(Arg::with_name("inputs")
+                .value_name("recording")
+                .help("Recordings to merge")
+                .multiple(true)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("filename")
+                .help("Output filename, or - to write to stdout")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("format")
+                .help("Output file format")
+                .takes_value(true)
+                .possible_values(&["flamegraph", "raw", "speedscope"])
+                .case_insensitive(true)
+                .default_value("flamegraph"))
+            .arg(Arg::with_name("rate")
+                .short("r")
+                .long("rate")
+                .value_name("rate")
+                .help("The sampling rate each recording was taken at, in the same order as the recordings. \
+                       Used to weight recordings taken at different rates")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())))
+            .arg(Arg::with_name("label")
+                .long("label")
+                .help("Prefix stacks with the name of the recording they came from"))
+            .arg(Arg::with_name("function")
+                .short("F")
+                .long("function")
+                .help("Aggregate samples by function name instead of by line number"))
+            .arg(Arg::with_name("idle")
+                .short("i")
+                .long("idle")
+                .help("Use the wall clock profiles from speedscope recordings, instead of the on-cpu profiles"));
+
+        let compare = clap::SubCommand::with_name("compare")
+            .about("Compares two speedscope or raw recordings, writing a flamegraph of the second recording \
+                    coloured by how much each frame changed (red got hotter, blue got colder)")
+            .arg(Arg::with_name("before")
+                .value_name("before")
+                .help("The recording to compare against")
+                .required(true))
+            .arg(Arg::with_name("after")
+                .value_name("after")
+                .help("The new recording")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("filename")
+                .help("Output filename, or - to write to stdout")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("function")
+                .short("F")
+                .long("function")
+                .help("Aggregate samples by function name instead of by line number"))
+            .arg(Arg::with_name("idle")
+                .short("i")
+                .long("idle")
+                .help("Use the wall clock profiles from speedscope recordings, instead of the on-cpu profiles"));
+
+        #[cfg(all(unwind, target_os="linux"))]
+        let symbolicate = clap::SubCommand::with_name("symbolicate")
+            .about("Symbolicates the native return addresses recorded by 'record --raw-addresses', using the matching \
+                    binaries on this machine, and writes out a flamegraph, raw or speedscope file")
+            .arg(Arg::with_name("input")
+                .value_name("addresses")
+                .help("The file of recorded addresses")
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("filename")
+                .help("Output filename, or - to write to stdout")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("format")
+                .help("Output file format")
+                .takes_value(true)
+                .possible_values(&["flamegraph", "raw", "speedscope"])
+                .case_insensitive(true)
+                .default_value("flamegraph"))
+            .arg(Arg::with_name("root")
+                .long("root")
+                .value_name("dir")
+                .help("Look for the binaries under this directory (ie 'dir/usr/lib/libc.so.6'), for when they've \
+                       been copied from the machine the addresses were recorded on. Binaries that don't match the \
+                       build that was recorded are left unsymbolicated")
+                .takes_value(true))
+            .arg(Arg::with_name("function")
+                .short("F")
+                .long("function")
+                .help("Aggregate samples by function name instead of by line number"));
+
+        let record = record.arg(cpu.clone());
+        let top = top.arg(cpu.clone());
+
+        let record = record.arg(aggregate.clone());
+        let top = top.arg(aggregate.clone());
+
+        let record = record.arg(max_depth.clone());
+        let top = top.arg(max_depth.clone());
+        let dump = dump.arg(max_depth.clone());
+
+        let record = record.arg(context_attr.clone());
+        let top = top.arg(context_attr.clone());
+        let dump = dump.arg(context_attr.clone());
+
+        let record = record.arg(path_remap.clone());
+        let top = top.arg(path_remap.clone());
+        let dump = dump.arg(path_remap.clone());
+
+        let record = record.arg(app_root.clone());
+        let top = top.arg(app_root.clone());
+        let dump = dump.arg(app_root.clone());
+
+        let record = record.arg(thread_id.clone());
+        let top = top.arg(thread_id.clone());
+        let dump = dump.arg(thread_id.clone());
+
+        let record = record.arg(python_binary.clone()).arg(libpython.clone());
+        let top = top.arg(python_binary.clone()).arg(libpython.clone());
+        let dump = dump.arg(python_binary.clone()).arg(libpython.clone());
 
         // add native unwinding if appropiate
         #[cfg(unwind)]
@@ -174,6 +1065,143 @@ impl Config {
         let top = top.arg(native.clone());
         #[cfg(unwind)]
         let dump = dump.arg(native.clone());
+        #[cfg(unwind)]
+        let record = record.arg(only_native.clone());
+        #[cfg(unwind)]
+        let top = top.arg(only_native.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(only_native.clone());
+        #[cfg(unwind)]
+        let record = record.arg(no_symbolicate_module.clone());
+        #[cfg(unwind)]
+        let top = top.arg(no_symbolicate_module.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(no_symbolicate_module.clone());
+        #[cfg(unwind)]
+        let record = record.arg(native_module.clone());
+        #[cfg(unwind)]
+        let top = top.arg(native_module.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(native_module.clone());
+        #[cfg(unwind)]
+        let record = record.arg(ignore_native_frame.clone());
+        #[cfg(unwind)]
+        let top = top.arg(ignore_native_frame.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(ignore_native_frame.clone());
+        #[cfg(unwind)]
+        let record = record.arg(blocking_native_frame.clone());
+        #[cfg(unwind)]
+        let top = top.arg(blocking_native_frame.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(blocking_native_frame.clone());
+        #[cfg(unwind)]
+        let record = record.arg(eval_frame_function.clone());
+        #[cfg(unwind)]
+        let top = top.arg(eval_frame_function.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(eval_frame_function.clone());
+        #[cfg(unwind)]
+        let record = record.arg(keep_duplicate_native_frames.clone());
+        #[cfg(unwind)]
+        let top = top.arg(keep_duplicate_native_frames.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(keep_duplicate_native_frames.clone());
+        #[cfg(unwind)]
+        let record = record.arg(build_ids.clone());
+        #[cfg(unwind)]
+        let top = top.arg(build_ids.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(build_ids.clone());
+        #[cfg(unwind)]
+        let record = record.arg(show_entry.clone());
+        #[cfg(unwind)]
+        let top = top.arg(show_entry.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(show_entry.clone());
+        #[cfg(unwind)]
+        let record = record.arg(raw_symbols.clone());
+        #[cfg(unwind)]
+        let top = top.arg(raw_symbols.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(raw_symbols.clone());
+        #[cfg(all(unwind, target_os="linux"))]
+        let record = record.arg(emit_modules.clone());
+        #[cfg(all(unwind, target_os="linux"))]
+        let top = top.arg(emit_modules.clone());
+        #[cfg(all(unwind, target_os="linux"))]
+        let dump = dump.arg(emit_modules.clone());
+        #[cfg(unwind)]
+        let record = record.arg(require_cython_maps.clone());
+        #[cfg(unwind)]
+        let top = top.arg(require_cython_maps.clone());
+        #[cfg(unwind)]
+        let dump = dump.arg(require_cython_maps.clone());
+
+        #[cfg(target_os="linux")]
+        let record = record.arg(pidfd.clone());
+        #[cfg(target_os="linux")]
+        let top = top.arg(pidfd.clone());
+        #[cfg(target_os="linux")]
+        let dump = dump.arg(pidfd.clone());
+
+        #[cfg(target_os="linux")]
+        let record = record.arg(process_name.clone());
+        #[cfg(target_os="linux")]
+        let top = top.arg(process_name.clone());
+        #[cfg(target_os="linux")]
+        let dump = dump.arg(process_name.clone());
+
+        #[cfg(target_os="linux")]
+        let record = record.arg(container_pid.clone());
+        #[cfg(target_os="linux")]
+        let top = top.arg(container_pid.clone());
+        #[cfg(target_os="linux")]
+        let dump = dump.arg(container_pid.clone());
+
+        #[cfg(target_os="linux")]
+        let record = record.arg(kernel.clone());
+        #[cfg(target_os="linux")]
+        let top = top.arg(kernel.clone());
+        #[cfg(target_os="linux")]
+        let dump = dump.arg(kernel.clone());
+
+        #[cfg(target_os="linux")]
+        let record = record.arg(subprocesses.clone()).arg(subprocess_depth.clone());
+
+        #[cfg(all(unwind, target_os="linux"))]
+        let record = record.arg(Arg::with_name("raw_addresses")
+                    .long("raw-addresses")
+                    .help("Only record the raw return addresses of each thread's native stack, along with the \
+                          modules they're in, without getting python stacks or symbolicating anything. This is \
+                          much cheaper, and the output can be symbolicated later on another machine with \
+                          'py-spy symbolicate'")
+                    .conflicts_with_all(&["native", "only_native", "prometheus", "subprocesses", "nonblocking", "checkpoint_interval"]));
+
+        #[cfg(unix)]
+        let dump = dump.arg(Arg::with_name("signal")
+                    .long("signal")
+                    .value_name("signal")
+                    .help("Keep running, and dump stack traces each time py-spy receives this signal (USR1, USR2 \
+                          or HUP) until Control-C is pressed")
+                    .takes_value(true)
+                    .conflicts_with_all(&["gil_stats", "watch"])
+                    .validator(|s| parse_signal(&s).map(|_| ())));
+
+        #[cfg(unix)]
+        let dump = dump.arg(Arg::with_name("stop")
+                    .long("stop")
+                    .help("Leave the process stopped after dumping its stack traces, for attaching a debugger to. \
+                          Run 'kill -CONT <pid>' to resume it")
+                    .conflicts_with_all(&["gil_stats", "watch", "signal"]));
+
+        #[cfg(target_os="linux")]
+        let dump = dump.arg(Arg::with_name("core")
+                    .long("core")
+                    .value_name("filename")
+                    .help("Dump the stack traces of the python program in this core file, instead of a running process")
+                    .takes_value(true)
+                    .conflicts_with_all(&["pid", "pidfd", "process_name", "native", "only_native", "kernel", "suspended", "gil_stats", "watch", "signal", "stop", "check"]));
 
         // Nonblocking isn't an option for freebsd, remove
         #[cfg(not(target_os="freebsd"))]
@@ -183,7 +1211,25 @@ impl Config {
         #[cfg(not(target_os="freebsd"))]
         let dump = dump.arg(nonblocking.clone());
 
-        let matches = App::new(crate_name!())
+        let record = record.arg(focus.clone());
+        let top = top.arg(focus.clone());
+
+        let record = record.arg(check.clone());
+        let top = top.arg(check.clone());
+        let dump = dump.arg(check.clone());
+
+        let record = record.arg(dump_offsets.clone());
+        let dump = dump.arg(dump_offsets.clone());
+
+        let record = record.arg(log_format.clone());
+        let top = top.arg(log_format.clone());
+        let dump = dump.arg(log_format.clone());
+        let merge = merge.arg(log_format.clone());
+        let compare = compare.arg(log_format.clone());
+        #[cfg(all(unwind, target_os="linux"))]
+        let symbolicate = symbolicate.arg(log_format.clone());
+
+        let app = App::new(crate_name!())
             .version(crate_version!())
             .about(crate_description!())
             .setting(clap::AppSettings::InferSubcommands)
@@ -193,7 +1239,11 @@ impl Config {
             .subcommand(record)
             .subcommand(top)
             .subcommand(dump)
-            .get_matches_from_safe(args)?;
+            .subcommand(merge)
+            .subcommand(compare);
+        #[cfg(all(unwind, target_os="linux"))]
+        let app = app.subcommand(symbolicate);
+        let matches = app.get_matches_from_safe(args)?;
         info!("Command line args: {:?}", matches);
 
         let mut config = Config::default();
@@ -204,15 +1254,74 @@ impl Config {
         match subcommand {
             "record" => {
                 config.sampling_rate = value_t!(matches, "rate", u64)?;
+                config.auto_rate = matches.occurrences_of("auto_rate") > 0;
+                config.max_overhead = matches.value_of("max_overhead").map_or(10.0, |p| p.parse().expect("invalid overhead"));
+                config.jitter = matches.value_of("jitter").map(|p| p.parse().expect("invalid jitter"));
+                config.seed = if matches.is_present("seed") { Some(value_t!(matches, "seed", u64)?) } else { None };
+                config.collapse_recursion = matches.occurrences_of("collapse_recursion") > 0;
+                config.bytecode_offsets = matches.occurrences_of("bytecode") > 0;
+                config.min_samples = matches.value_of("min_samples").map(|n| n.parse().expect("invalid samples"));
+                config.gray_libraries = matches.occurrences_of("gray_libraries") > 0;
                 config.duration = match matches.value_of("duration") {
-                    Some("unlimited") | None => RecordDuration::Unlimited,
-                    Some(seconds) => RecordDuration::Seconds(seconds.parse().expect("invalid duration"))
+                    Some(duration) => duration.parse().expect("invalid duration"),
+                    None => RecordDuration::Unlimited
                 };
+                config.sample_limit = matches.value_of("samples").map(|n| n.parse().expect("invalid samples"));
+                config.checkpoint_interval = matches.value_of("checkpoint_interval").map(|s| s.parse().expect("invalid interval"));
+                config.stall_threshold = matches.value_of("stalls").map(|s| s.parse().expect("invalid threshold"));
                 config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
                 config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.prometheus = matches.value_of("prometheus").map(|addr| addr.to_owned());
+                config.subprocesses = matches.occurrences_of("subprocesses") > 0;
+                config.subprocess_depth = matches.value_of("subprocess_depth").map(|d| d.parse().expect("invalid depth"));
+                config.assertions = if matches.is_present("assert") {
+                    values_t!(matches, "assert", FunctionAssertion)?
+                } else {
+                    Vec::new()
+                };
+                config.summary = matches.occurrences_of("summary") > 0;
+                config.sample_stats = matches.occurrences_of("sample_stats") > 0;
+                config.compress = matches.occurrences_of("compress") > 0 ||
+                    config.filename.as_ref().map_or(false, |filename| filename.ends_with(".gz"));
+                config.raw_addresses = matches.occurrences_of("raw_addresses") > 0;
             },
             "top" => {
                 config.sampling_rate = value_t!(matches, "rate", u64)?;
+                config.color = value_t!(matches.value_of("color"), Color).unwrap_or(Color::auto);
+            },
+            "dump" => {
+                config.dump_json = matches.occurrences_of("json") > 0;
+                config.dump_locals = matches.occurrences_of("locals") > 0;
+                config.suspended_tasks = matches.occurrences_of("suspended") > 0;
+                config.gil_stats = matches.value_of("gil_stats").map(|s| s.parse().expect("invalid seconds"));
+                config.watch = matches.value_of("watch").map(|s| s.parse().expect("invalid seconds"));
+                #[cfg(unix)]
+                {
+                    config.dump_signal = matches.value_of("signal").map(|s| parse_signal(s).expect("invalid signal"));
+                }
+                config.stop_process = matches.occurrences_of("stop") > 0;
+                config.core_filename = matches.value_of("core").map(|f| f.to_owned());
+            },
+            "merge" => {
+                config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.merge_inputs = matches.values_of("inputs").unwrap().map(|v| v.to_owned()).collect();
+                config.merge_rates = match matches.values_of("rate") {
+                    Some(values) => values.map(|v| v.parse().expect("invalid rate")).collect(),
+                    None => Vec::new()
+                };
+                config.merge_labels = matches.occurrences_of("label") > 0;
+            },
+            "compare" => {
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.merge_inputs = vec![matches.value_of("before").unwrap().to_owned(),
+                                           matches.value_of("after").unwrap().to_owned()];
+            },
+            "symbolicate" => {
+                config.format = Some(value_t!(matches.value_of("format"), FileFormat).unwrap_or_else(|e| e.exit()));
+                config.filename = matches.value_of("output").map(|f| f.to_owned());
+                config.merge_inputs = vec![matches.value_of("input").unwrap().to_owned()];
+                config.symbol_root = matches.value_of("root").map(|f| f.to_owned());
             }
             _ => {}
         }
@@ -220,17 +1329,69 @@ impl Config {
 
         // options that can be shared between subcommands
         config.pid = matches.value_of("pid").map(|p| p.parse().expect("invalid pid"));
+        config.pidfd = matches.value_of("pidfd").map(|fd| fd.parse().expect("invalid pidfd"));
+        config.process_name = matches.value_of("process_name").map(|name| name.to_owned());
+        config.container_pid = matches.value_of("container_pid").map(|p| p.parse().expect("invalid pid"));
+        config.kernel_stacks = matches.occurrences_of("kernel") > 0;
         config.python_program = matches.values_of("python_program").map(|vals| {
             vals.map(|v| v.to_owned()).collect()
         });
         config.show_line_numbers = matches.occurrences_of("function") == 0;
         config.include_idle = matches.occurrences_of("idle") > 0;
         config.gil_only = matches.occurrences_of("gil") > 0;
+        config.python_binary = matches.value_of("python_binary").map(|f| f.to_owned());
+        config.libpython = matches.value_of("libpython").map(|f| f.to_owned());
+        config.thread_ids = match matches.values_of("thread_id") {
+            Some(values) => values.map(|v| parse_thread_id(v).expect("invalid thread id")).collect(),
+            None => Vec::new()
+        };
+        config.cpu_time = matches.occurrences_of("cpu") > 0;
+        config.max_depth = matches.value_of("max_depth").map(|d| d.parse().expect("invalid depth"));
+        config.context_attr = matches.value_of("context_attr").map(|v| parse_context_attr(v).expect("invalid context attr"));
+        config.aggregate = value_t!(matches.value_of("aggregate"), Aggregate).unwrap_or(Aggregate::function);
         config.include_thread_ids = matches.occurrences_of("threads") > 0;
 
         config.non_blocking = matches.occurrences_of("nonblocking") > 0;
-        config.native = matches.occurrences_of("native") > 0;
+        config.only_native = matches.occurrences_of("only_native") > 0;
+        config.native = matches.occurrences_of("native") > 0 || config.only_native;
+        config.no_symbolicate_modules = match matches.values_of("no_symbolicate_module") {
+            Some(values) => values.map(|v| v.to_owned()).collect(),
+            None => Vec::new()
+        };
+        config.native_modules = match matches.values_of("native_module") {
+            Some(values) => values.map(|v| v.to_owned()).collect(),
+            None => Vec::new()
+        };
+        config.ignored_native_frames = match matches.values_of("ignore_native_frame") {
+            Some(values) => values.map(|v| parse_ignored_frame(v).expect("invalid native frame")).collect(),
+            None => Vec::new()
+        };
+        config.blocking_native_frames = match matches.values_of("blocking_native_frame") {
+            Some(values) => values.map(|v| parse_ignored_frame(v).expect("invalid native frame")).collect(),
+            None => Vec::new()
+        };
+        if let Some(values) = matches.values_of("eval_frame_function") {
+            config.eval_frame_functions.extend(values.map(|v| v.to_owned()));
+        }
+        config.path_remaps = match matches.values_of("path_remap") {
+            Some(values) => values.map(|v| parse_path_remap(v).expect("invalid path remap")).collect(),
+            None => Vec::new()
+        };
+        config.app_roots = match matches.values_of("app_root") {
+            Some(values) => values.map(|v| v.to_owned()).collect(),
+            None => Vec::new()
+        };
+        config.keep_duplicate_native_frames = matches.occurrences_of("keep_duplicate_native_frames") > 0;
+        config.native_build_ids = matches.occurrences_of("build_ids") > 0;
+        config.emit_modules = matches.value_of("emit_modules").map(|f| f.to_owned());
+        config.raw_symbols = matches.occurrences_of("raw_symbols") > 0;
+        config.show_entry = value_t!(matches.value_of("show_entry"), ShowEntry).unwrap_or(ShowEntry::libc);
+        config.require_cython_maps = matches.occurrences_of("require_cython_maps") > 0;
         config.hide_progess  = matches.occurrences_of("hideprogress") > 0;
+        config.check = matches.occurrences_of("check") > 0;
+        config.dump_offsets = matches.occurrences_of("dump_offsets") > 0;
+        config.focus = matches.value_of("focus").map(|f| f.to_owned());
+        config.log_format = value_t!(matches.value_of("log_format"), LogFormat).unwrap_or(LogFormat::text);
 
         // disable native profiling if invalidly asked for
         if config.native && config.non_blocking {
@@ -241,6 +1402,57 @@ impl Config {
     }
 }
 
+/// Parses a 'module:function' pair. Split on the first ':' since c++ function names can contain them
+fn parse_ignored_frame(s: &str) -> Result<(String, String), String> {
+    let mut tokens = s.splitn(2, ':');
+    match (tokens.next(), tokens.next()) {
+        (Some(module), Some(function)) if !function.is_empty() => Ok((module.to_owned(), function.to_owned())),
+        _ => Err(format!("Invalid native frame '{}' (expected module:function)", s))
+    }
+}
+
+/// Parses a thread id, which can be in hex (like the ids shown by dump) with a leading '0x'
+fn parse_thread_id(s: &str) -> Result<u64, String> {
+    let parsed = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u64>()
+    };
+    parsed.map_err(|e| format!("Invalid thread id '{}': {}", s, e))
+}
+
+/// Parses a 'from=to' pair of path prefixes
+fn parse_path_remap(s: &str) -> Result<(String, String), String> {
+    let mut tokens = s.splitn(2, '=');
+    match (tokens.next(), tokens.next()) {
+        (Some(from), Some(to)) if !from.is_empty() => Ok((from.to_owned(), to.to_owned())),
+        _ => Err(format!("Invalid path remap '{}' (expected from=to)", s))
+    }
+}
+
+/// Parses a 'module:name' or 'module:name.attribute' context global
+fn parse_context_attr(s: &str) -> Result<(String, String), String> {
+    let mut tokens = s.splitn(2, ':');
+    match (tokens.next(), tokens.next()) {
+        (Some(module), Some(path)) if !module.is_empty() && !path.split('.').any(|name| name.is_empty()) &&
+                                      path.split('.').count() <= 2
+            => Ok((module.to_owned(), path.to_owned())),
+        _ => Err(format!("Invalid context attr '{}' (expected module:name or module:name.attribute)", s))
+    }
+}
+
+/// Parses the name of a signal to dump stack traces on, with or without the 'SIG' prefix
+#[cfg(unix)]
+fn parse_signal(s: &str) -> Result<i32, String> {
+    let name = s.to_uppercase();
+    match name.trim_start_matches("SIG") {
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "HUP" => Ok(libc::SIGHUP),
+        _ => Err(format!("Unsupported signal '{}' (expected USR1, USR2 or HUP)", s))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -261,6 +1473,9 @@ mod tests {
         let short_config = Config::from_args(&split("py-spy r -p 1234 -o foo")).unwrap();
         assert_eq!(config, short_config);
 
+        let pstats_config = Config::from_args(&split("py-spy r -p 1234 -o foo --format pstats-text")).unwrap();
+        assert_eq!(pstats_config.format, Some(FileFormat::pstats_text));
+
         // missing the --pid argument should fail
         assert_eq!(Config::from_args(&split("py-spy record -o foo")).unwrap_err().kind,
                    clap::ErrorKind::MissingRequiredArgument);
@@ -278,11 +1493,141 @@ mod tests {
         assert_eq!(config.include_idle, false);
         assert_eq!(config.gil_only, false);
         assert_eq!(config.include_thread_ids, false);
+        assert_eq!(config.cpu_time, false);
 
-        let config_flags = Config::from_args(&split("py-spy r -p 1234 -o foo --idle --gil --threads")).unwrap();
+        let config_flags = Config::from_args(&split("py-spy r -p 1234 -o foo --idle --gil --threads --cpu")).unwrap();
         assert_eq!(config_flags.include_idle, true);
         assert_eq!(config_flags.gil_only, true);
         assert_eq!(config_flags.include_thread_ids, true);
+        assert_eq!(config_flags.cpu_time, true);
+
+        assert_eq!(config.summary, false);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --summary")).unwrap().summary, true);
+        assert_eq!(config.sample_stats, false);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --sample-stats")).unwrap().sample_stats, true);
+
+        assert_eq!(config.compress, false);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo.svg.gz")).unwrap().compress, true);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o - --compress")).unwrap().compress, true);
+
+        assert_eq!(config.max_depth, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --max-depth 100")).unwrap().max_depth, Some(100));
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --max-depth 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // performance assertions
+        let assert_config = Config::from_args(&split("py-spy r -p 1234 -o foo --assert myfunc<5% --assert <module><50.5")).unwrap();
+        assert_eq!(assert_config.assertions,
+                   vec![FunctionAssertion{function: String::from("myfunc"), max_percent: 5.0},
+                        FunctionAssertion{function: String::from("<module>"), max_percent: 50.5}]);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --assert myfunc")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // continuous profiling doesn't need an output file
+        let prometheus_config = Config::from_args(&split("py-spy r -p 1234 --prometheus 127.0.0.1:9090")).unwrap();
+        assert_eq!(prometheus_config.prometheus, Some(String::from("127.0.0.1:9090")));
+        assert_eq!(prometheus_config.filename, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 --prometheus 127.0.0.1:9090 -o foo")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 --prometheus localhost")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // durations can either be in seconds, or use units
+        assert_eq!(config.duration, RecordDuration::Unlimited);
+        let duration_config = Config::from_args(&split("py-spy r -p 1234 -o foo -d 90")).unwrap();
+        assert_eq!(duration_config.duration, RecordDuration::Seconds(90));
+        let duration_config = Config::from_args(&split("py-spy r -p 1234 -o foo --duration 1h30m")).unwrap();
+        assert_eq!(duration_config.duration, RecordDuration::Seconds(5400));
+
+        // or sampling can stop after a number of samples instead
+        assert_eq!(config.sample_limit, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --samples 500")).unwrap().sample_limit, Some(500));
+        assert!(Config::from_args(&split("py-spy r -p 1234 -o foo --samples 0")).is_err());
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --samples 500 -d 10")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+
+        assert_eq!(config.stall_threshold, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --stalls 250")).unwrap().stall_threshold, Some(250));
+        assert!(Config::from_args(&split("py-spy r -p 1234 -o foo --stalls 0")).is_err());
+
+        assert_eq!(config.checkpoint_interval, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --checkpoint-interval 60")).unwrap().checkpoint_interval, Some(60));
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --checkpoint-interval 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // the overhead budget only makes sense along with --auto-rate
+        assert_eq!(config.auto_rate, false);
+        let rate_config = Config::from_args(&split("py-spy r -p 1234 -o foo --auto-rate")).unwrap();
+        assert_eq!((rate_config.auto_rate, rate_config.max_overhead), (true, 10.0));
+        let rate_config = Config::from_args(&split("py-spy r -p 1234 -o foo --auto-rate --max-overhead 2.5")).unwrap();
+        assert_eq!(rate_config.max_overhead, 2.5);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --max-overhead 5")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --auto-rate --max-overhead 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        assert_eq!(config.collapse_recursion, false);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --collapse-recursion")).unwrap().collapse_recursion, true);
+        assert_eq!(config.bytecode_offsets, false);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --bytecode")).unwrap().bytecode_offsets, true);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --bytecode --function")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+        assert_eq!(config.focus, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --focus ^handle_")).unwrap().focus, Some(String::from("^handle_")));
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --focus (")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(config.min_samples, None);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --min-samples 10")).unwrap().min_samples, Some(10));
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --min-samples 0")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        assert_eq!((config.app_roots.len(), config.gray_libraries), (0, false));
+        let app_config = Config::from_args(&split("py-spy r -p 1234 -o foo --app-root /srv/app --app-root /opt/lib --gray-libraries")).unwrap();
+        assert_eq!(app_config.app_roots, vec![String::from("/srv/app"), String::from("/opt/lib")]);
+        assert!(app_config.gray_libraries);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --gray-libraries")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+
+        assert_eq!((config.jitter, config.seed), (None, None));
+        let jitter_config = Config::from_args(&split("py-spy r -p 1234 -o foo --jitter 20 --seed 42")).unwrap();
+        assert_eq!((jitter_config.jitter, jitter_config.seed), (Some(20.0), Some(42)));
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --seed 42")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --jitter 20 --seed abc")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --jitter 100")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo -d 5x")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // following subprocesses, optionally limiting how deep to go
+        #[cfg(target_os="linux")]
+        {
+            let config = Config::from_args(&split("py-spy r -p 1234 -o foo --subprocesses")).unwrap();
+            assert_eq!(config.subprocesses, true);
+            assert_eq!(config.subprocess_depth, None);
+            let config = Config::from_args(&split("py-spy r -p 1234 -o foo -s --subprocess-depth 1")).unwrap();
+            assert_eq!(config.subprocess_depth, Some(1));
+            assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo --subprocess-depth 1")).unwrap_err().kind,
+                       clap::ErrorKind::MissingRequiredArgument);
+            assert_eq!(Config::from_args(&split("py-spy r -p 1234 -o foo -s --subprocess-depth 0")).unwrap_err().kind,
+                       clap::ErrorKind::ValueValidation);
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!("unlimited".parse(), Ok(RecordDuration::Unlimited));
+        assert_eq!("30".parse(), Ok(RecordDuration::Seconds(30)));
+        assert_eq!("30s".parse(), Ok(RecordDuration::Seconds(30)));
+        assert_eq!("5m".parse(), Ok(RecordDuration::Seconds(300)));
+        assert_eq!("1h30m".parse(), Ok(RecordDuration::Seconds(5400)));
+        assert_eq!("1m30s".parse(), Ok(RecordDuration::Seconds(90)));
+        assert!("".parse::<RecordDuration>().is_err());
+        assert!("5".repeat(30).parse::<RecordDuration>().is_err());
+        assert!("1h30".parse::<RecordDuration>().is_err());
+        assert!("m".parse::<RecordDuration>().is_err());
+        assert!("5 minutes".parse::<RecordDuration>().is_err());
     }
 
     #[test]
@@ -299,6 +1644,173 @@ mod tests {
         // missing the --pid argument should fail
         assert_eq!(Config::from_args(&split("py-spy dump")).unwrap_err().kind,
                    clap::ErrorKind::MissingRequiredArgument);
+
+        assert_eq!(config.log_format, LogFormat::text);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --log-format json")).unwrap().log_format, LogFormat::json);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --log-format xml")).unwrap_err().kind,
+                   clap::ErrorKind::InvalidValue);
+
+        assert_eq!(config.dump_json, false);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --json")).unwrap().dump_json, true);
+
+        assert_eq!(config.dump_locals, false);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --locals")).unwrap().dump_locals, true);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 -l")).unwrap().dump_locals, true);
+
+        assert_eq!(config.suspended_tasks, false);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --suspended")).unwrap().suspended_tasks, true);
+        assert!(Config::from_args(&split("py-spy dump -p 1234 --suspended --gil-stats 5")).is_err());
+
+        assert_eq!(config.gil_stats, None);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --gil-stats 5")).unwrap().gil_stats, Some(5));
+        assert!(Config::from_args(&split("py-spy dump -p 1234 --gil-stats 0")).is_err());
+        assert!(Config::from_args(&split("py-spy dump -p 1234 --gil-stats 5 --json")).is_err());
+
+        assert_eq!(config.watch, None);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --watch 5")).unwrap().watch, Some(5));
+        assert!(Config::from_args(&split("py-spy dump -p 1234 --watch 0")).is_err());
+        assert!(Config::from_args(&split("py-spy dump -p 1234 --watch 5 --gil-stats 5")).is_err());
+
+        #[cfg(unix)]
+        {
+            assert_eq!(config.dump_signal, None);
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --signal USR1")).unwrap().dump_signal, Some(libc::SIGUSR1));
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --signal sigusr2")).unwrap().dump_signal, Some(libc::SIGUSR2));
+            assert!(Config::from_args(&split("py-spy dump -p 1234 --signal KILL")).is_err());
+            assert!(Config::from_args(&split("py-spy dump -p 1234 --signal HUP --gil-stats 5")).is_err());
+
+            assert_eq!(config.stop_process, false);
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --stop")).unwrap().stop_process, true);
+            assert!(Config::from_args(&split("py-spy dump -p 1234 --stop --signal USR1")).is_err());
+        }
+
+        assert_eq!(config.python_binary, None);
+        assert_eq!(config.libpython, None);
+        let config = Config::from_args(&split("py-spy dump -p 1234 --python-binary /opt/host/bin/app --libpython /opt/host/lib/libpython3.8.so")).unwrap();
+        assert_eq!(config.python_binary, Some(String::from("/opt/host/bin/app")));
+        assert_eq!(config.libpython, Some(String::from("/opt/host/lib/libpython3.8.so")));
+
+        assert_eq!(config.thread_ids, Vec::<u64>::new());
+        let config = Config::from_args(&split("py-spy dump -p 1234 --thread-id 5678 --thread-id 0x7F1A")).unwrap();
+        assert_eq!(config.thread_ids, vec![5678, 0x7f1a]);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --thread-id main")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        assert_eq!(config.path_remaps, Vec::new());
+        let config = Config::from_args(&split("py-spy dump -p 1234 --path-remap /build=/home/me/src --path-remap /tmp=")).unwrap();
+        assert_eq!(config.path_remaps, vec![(String::from("/build"), String::from("/home/me/src")),
+                                            (String::from("/tmp"), String::from(""))]);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --path-remap /build")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        assert_eq!(config.context_attr, None);
+        let config = Config::from_args(&split("py-spy dump -p 1234 --context-attr myapp.context:request.path")).unwrap();
+        assert_eq!(config.context_attr, Some((String::from("myapp.context"), String::from("request.path"))));
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --context-attr myapp:request.url.path")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+        assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --context-attr request")).unwrap_err().kind,
+                   clap::ErrorKind::ValueValidation);
+
+        // but passing a pidfd instead works
+        #[cfg(target_os="linux")]
+        {
+            let config = Config::from_args(&split("py-spy dump --pidfd 3")).unwrap();
+            assert_eq!(config.pidfd, Some(3));
+            assert_eq!(config.pid, None);
+            assert_eq!(Config::from_args(&split("py-spy dump --pidfd 3 --pid 1234")).unwrap_err().kind,
+                       clap::ErrorKind::ArgumentConflict);
+
+            // as does passing a process name
+            let config = Config::from_args(&split("py-spy dump --process-name gunicorn.*worker")).unwrap();
+            assert_eq!(config.process_name, Some(String::from("gunicorn.*worker")));
+            assert_eq!(Config::from_args(&split("py-spy dump --process-name gunicorn --pid 1234")).unwrap_err().kind,
+                       clap::ErrorKind::ArgumentConflict);
+            assert_eq!(Config::from_args(&split("py-spy dump --process-name gunicorn(")).unwrap_err().kind,
+                       clap::ErrorKind::ValueValidation);
+
+            // processes in containers can be picked by their pid inside of the container
+            let config = Config::from_args(&split("py-spy dump --pid 1234 --container-pid 7")).unwrap();
+            assert_eq!((config.pid, config.container_pid), (Some(1234), Some(7)));
+            assert_eq!(Config::from_args(&split("py-spy dump --container-pid 7 --process-name gunicorn")).unwrap_err().kind,
+                       clap::ErrorKind::MissingRequiredArgument);
+
+            assert_eq!(Config::from_args(&split("py-spy dump --pid 1234")).unwrap().kernel_stacks, false);
+            assert_eq!(Config::from_args(&split("py-spy dump --pid 1234 --kernel")).unwrap().kernel_stacks, true);
+
+            // or a core file
+            let config = Config::from_args(&split("py-spy dump --core core.1234")).unwrap();
+            assert_eq!(config.core_filename, Some(String::from("core.1234")));
+            assert_eq!(Config::from_args(&split("py-spy dump --core core.1234 --pid 1234")).unwrap_err().kind,
+                       clap::ErrorKind::ArgumentConflict);
+        }
+
+        #[cfg(unwind)]
+        {
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --no-symbolicate-module libfoo.so --no-symbolicate-module libbar")).unwrap();
+            assert_eq!(config.no_symbolicate_modules, vec![String::from("libfoo.so"), String::from("libbar")]);
+
+            assert_eq!(config.native_modules, Vec::<String>::new());
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --native-module _myext --native-module libfoo")).unwrap();
+            assert_eq!(config.native_modules, vec![String::from("_myext"), String::from("libfoo")]);
+
+            let config = Config::from_args(&split("py-spy dump -p 1234 --ignore-native-frame libfoo.so:start --ignore-native-frame :ns::run")).unwrap();
+            assert_eq!(config.ignored_native_frames, vec![(String::from("libfoo.so"), String::from("start")),
+                                                          (String::from(""), String::from("ns::run"))]);
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --ignore-native-frame libfoo.so")).unwrap_err().kind,
+                       clap::ErrorKind::ValueValidation);
+
+            let config = Config::from_args(&split("py-spy record -p 1234 -o foo --native --blocking-native-frame libfoo.so:wait_for_work")).unwrap();
+            assert_eq!(config.blocking_native_frames, vec![(String::from("libfoo.so"), String::from("wait_for_work"))]);
+            assert_eq!(Config::from_args(&split("py-spy top -p 1234 --blocking-native-frame wait_for_work")).unwrap_err().kind,
+                       clap::ErrorKind::ValueValidation);
+
+            assert!(config.eval_frame_functions.contains(&String::from("_PyEval_EvalFrameDefault")));
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --eval-frame-function MyEval_EvalFrame")).unwrap();
+            assert_eq!(config.eval_frame_functions.len(), EVAL_FRAME_FUNCTIONS.len() + 1);
+            assert_eq!(config.eval_frame_functions.last(), Some(&String::from("MyEval_EvalFrame")));
+
+            assert_eq!(config.keep_duplicate_native_frames, false);
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --keep-duplicate-native-frames")).unwrap();
+            assert_eq!(config.keep_duplicate_native_frames, true);
+
+            assert_eq!(config.native_build_ids, false);
+            let config = Config::from_args(&split("py-spy record -p 1234 -o foo --native --build-ids")).unwrap();
+            assert_eq!(config.native_build_ids, true);
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --build-ids")).unwrap_err().kind,
+                       clap::ErrorKind::MissingRequiredArgument);
+
+            assert_eq!(config.show_entry, ShowEntry::libc);
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --show-entry never")).unwrap();
+            assert_eq!(config.show_entry, ShowEntry::never);
+            assert_eq!(Config::from_args(&split("py-spy dump -p 1234 --native --show-entry sometimes")).unwrap_err().kind,
+                       clap::ErrorKind::InvalidValue);
+
+            assert_eq!(config.raw_symbols, false);
+            let config = Config::from_args(&split("py-spy record -p 1234 -o foo --native --raw-symbols")).unwrap();
+            assert_eq!(config.raw_symbols, true);
+            assert_eq!(Config::from_args(&split("py-spy record -p 1234 -o foo --native --raw-symbols --require-cython-maps")).unwrap_err().kind,
+                       clap::ErrorKind::ArgumentConflict);
+
+            #[cfg(target_os="linux")]
+            {
+                assert_eq!(config.emit_modules, None);
+                let config = Config::from_args(&split("py-spy record -p 1234 -o foo --native --emit-modules modules.json")).unwrap();
+                assert_eq!(config.emit_modules, Some(String::from("modules.json")));
+                assert_eq!(Config::from_args(&split("py-spy record -p 1234 -o foo --emit-modules modules.json")).unwrap_err().kind,
+                           clap::ErrorKind::MissingRequiredArgument);
+            }
+
+            assert_eq!(config.require_cython_maps, false);
+            let config = Config::from_args(&split("py-spy dump -p 1234 --native --require-cython-maps")).unwrap();
+            assert_eq!(config.require_cython_maps, true);
+
+            assert_eq!(config.only_native, false);
+            let config = Config::from_args(&split("py-spy record -p 1234 -o foo --only-native")).unwrap();
+            assert_eq!(config.only_native, true);
+            assert_eq!(config.native, true);
+            assert_eq!(Config::from_args(&split("py-spy record -p 1234 -o foo --only-native --gil")).unwrap_err().kind,
+                       clap::ErrorKind::ArgumentConflict);
+        }
     }
 
     #[test]
@@ -311,6 +1823,84 @@ mod tests {
         // short version
         let short_config = Config::from_args(&split("py-spy t -p 1234")).unwrap();
         assert_eq!(config, short_config);
+        assert_eq!(config.aggregate, Aggregate::function);
+
+        let config = Config::from_args(&split("py-spy top --pid 1234 --aggregate module")).unwrap();
+        assert_eq!(config.aggregate, Aggregate::module);
+        assert_eq!(Config::from_args(&split("py-spy top --pid 1234 --aggregate line")).unwrap_err().kind,
+                   clap::ErrorKind::InvalidValue);
+
+        assert_eq!(short_config.color, Color::auto);
+        assert_eq!(short_config.color.force(), None);
+        let config = Config::from_args(&split("py-spy top --pid 1234 --color never")).unwrap();
+        assert_eq!(config.color.force(), Some(false));
+        assert_eq!(Config::from_args(&split("py-spy top --pid 1234 --color ALWAYS")).unwrap().color.force(), Some(true));
+        assert_eq!(Config::from_args(&split("py-spy top --pid 1234 --color sometimes")).unwrap_err().kind,
+                   clap::ErrorKind::InvalidValue);
+
+        assert_eq!(short_config.check, false);
+        assert_eq!(short_config.dump_offsets, false);
+        assert_eq!(Config::from_args(&split("py-spy dump --pid 1234 --dump-offsets")).unwrap().dump_offsets, true);
+        assert_eq!(Config::from_args(&split("py-spy top --pid 1234 --check")).unwrap().check, true);
+        // record doesn't need an output file to check the process
+        assert_eq!(Config::from_args(&split("py-spy record --pid 1234 --dry-run")).unwrap().check, true);
+    }
+
+    #[test]
+    fn test_parse_merge_args() {
+        let config = Config::from_args(&split("py-spy merge a.txt b.json -o out.svg")).unwrap();
+        assert_eq!(config.command, String::from("merge"));
+        assert_eq!(config.merge_inputs, vec![String::from("a.txt"), String::from("b.json")]);
+        assert_eq!(config.filename, Some(String::from("out.svg")));
+        assert_eq!(config.format, Some(FileFormat::flamegraph));
+        assert_eq!(config.merge_labels, false);
+
+        let config = Config::from_args(&split("py-spy merge a.txt b.txt -o out.txt -f raw -r 100 -r 200 --label")).unwrap();
+        assert_eq!(config.format, Some(FileFormat::raw));
+        assert_eq!(config.merge_rates, vec![100, 200]);
+        assert_eq!(config.merge_labels, true);
+
+        let config = Config::from_args(&split("py-spy merge host1.txt host2.txt -o out.json -f speedscope")).unwrap();
+        assert_eq!(config.format, Some(FileFormat::speedscope));
+
+        // but not the formats that need the individual samples
+        assert_eq!(Config::from_args(&split("py-spy merge a.txt -o out.json -f chrometrace")).unwrap_err().kind,
+                   clap::ErrorKind::InvalidValue);
+        assert_eq!(Config::from_args(&split("py-spy merge -o out.svg")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    #[cfg(all(unwind, target_os="linux"))]
+    fn test_parse_symbolicate_args() {
+        let config = Config::from_args(&split("py-spy record --pid 1234 -o addresses.json --raw-addresses")).unwrap();
+        assert_eq!(config.raw_addresses, true);
+        assert_eq!(Config::from_args(&split("py-spy record --pid 1234 -o addresses.json --raw-addresses --native")).unwrap_err().kind,
+                   clap::ErrorKind::ArgumentConflict);
+
+        let config = Config::from_args(&split("py-spy symbolicate addresses.json -o out.txt -f raw --root /tmp/sysroot")).unwrap();
+        assert_eq!(config.command, String::from("symbolicate"));
+        assert_eq!(config.merge_inputs, vec![String::from("addresses.json")]);
+        assert_eq!(config.filename, Some(String::from("out.txt")));
+        assert_eq!(config.format, Some(FileFormat::raw));
+        assert_eq!(config.symbol_root, Some(String::from("/tmp/sysroot")));
+        assert_eq!(Config::from_args(&split("py-spy symbolicate -o out.svg")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn test_parse_compare_args() {
+        let config = Config::from_args(&split("py-spy compare before.txt after.json -o diff.svg -F")).unwrap();
+        assert_eq!(config.command, String::from("compare"));
+        assert_eq!(config.merge_inputs, vec![String::from("before.txt"), String::from("after.json")]);
+        assert_eq!(config.filename, Some(String::from("diff.svg")));
+        assert_eq!(config.show_line_numbers, false);
+
+        // needs exactly two recordings
+        assert_eq!(Config::from_args(&split("py-spy compare before.txt -o diff.svg")).unwrap_err().kind,
+                   clap::ErrorKind::MissingRequiredArgument);
+        assert_eq!(Config::from_args(&split("py-spy compare a.txt b.txt c.txt -o diff.svg")).unwrap_err().kind,
+                   clap::ErrorKind::UnknownArgument);
     }
 
     #[test]
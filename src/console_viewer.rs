@@ -1,13 +1,14 @@
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::vec::Vec;
 use std::io;
 use std::io::{Read, Write};
 use std::sync::{Mutex, Arc, atomic};
 use std::thread;
 
-use console::{Term, style};
+use console::{Term, StyledObject, style};
 use failure::Error;
+use regex::Regex;
 
 use crate::stack_trace::{StackTrace, Frame};
 
@@ -18,6 +19,7 @@ pub struct ConsoleViewer {
     version: String,
     command: String,
     sampling_rate: f64,
+    color: Option<bool>,
     running: Arc<atomic::AtomicBool>,
     options: Arc<Mutex<Options>>,
     stats: Stats
@@ -27,7 +29,8 @@ impl ConsoleViewer {
     pub fn new(show_linenumbers: bool,
                python_command: &str,
                version: &str,
-               sampling_rate: f64) -> io::Result<ConsoleViewer> {
+               sampling_rate: f64,
+               color: Option<bool>) -> io::Result<ConsoleViewer> {
         let running = Arc::new(atomic::AtomicBool::new(true));
         let options = Arc::new(Mutex::new(Options::new(show_linenumbers)));
 
@@ -41,6 +44,10 @@ impl ConsoleViewer {
                 if let Some(Ok(key)) = std::io::stdin().bytes().next() {
                     let mut options = input_options.lock().unwrap();
                     options.dirty = true;
+                    if options.filter_input.is_some() {
+                        options.edit_filter(key);
+                        continue;
+                    }
                     match key as char {
                         'R' | 'r' => options.reset = true,
                         'L' | 'l' => options.show_linenumbers = !options.show_linenumbers,
@@ -50,6 +57,22 @@ impl ConsoleViewer {
                         '2' => options.sort_column = 2,
                         '3' => options.sort_column = 3,
                         '4' => options.sort_column = 4,
+                        // switch between sorting by own and total (cumulative) time
+                        'C' | 'c' => options.sort_column = match options.sort_column { 1 => 2, 2 => 1, 3 => 4, _ => 3 },
+                        '/' => {
+                            options.filter_input = Some(String::new());
+                            options.filter_target = FilterTarget::Function;
+                        },
+                        'N' | 'n' => {
+                            options.filter_input = Some(String::new());
+                            options.filter_target = FilterTarget::Thread;
+                        },
+                        // cycle through showing each thread on its own, and then all of them again
+                        'T' | 't' => {
+                            options.thread_index = Some(options.thread_index.map_or(0, |index| index + 1));
+                            options.thread_filter = None;
+                        },
+                        'A' | 'a' => options.app_only = !options.app_only,
                         _ => {},
                     }
                 }
@@ -59,7 +82,7 @@ impl ConsoleViewer {
         Ok(ConsoleViewer{console_config: os_impl::ConsoleConfig::new()?,
                          version:version.to_owned(),
                          command: python_command.to_owned(),
-                         show_idle: false, running, options, sampling_rate,
+                         show_idle: false, running, options, sampling_rate, color,
                          stats: Stats::new()})
     }
 
@@ -73,6 +96,10 @@ impl ConsoleViewer {
                 continue;
             }
 
+            // also keep the statistics of each thread separately, so that they can be filtered by thread
+            let thread = trace.thread_name.clone().unwrap_or_else(|| format!("{:#X}", trace.thread_id));
+            let thread_counts = self.stats.thread_counts.entry(thread).or_insert_with(ThreadCounts::default);
+
             if trace.owns_gil {
                 self.stats.gil += 1
             }
@@ -81,19 +108,29 @@ impl ConsoleViewer {
                 self.stats.active += 1
             }
 
-            update_function_statistics(&mut self.stats.line_counts, trace, |frame| {
+            let line_label = |frame: &Frame| {
                 let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
                 if frame.line != 0 {
                     format!("{} ({}:{})", frame.name, filename, frame.line)
                 } else {
                     format!("{} ({})", frame.name, filename)
                 }
-            });
-
-            update_function_statistics(&mut self.stats.function_counts, trace, |frame| {
+            };
+            let function_label = |frame: &Frame| {
                 let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
                 format!("{} ({})", frame.name, filename)
-            });
+            };
+            update_function_statistics(&mut self.stats.line_counts, trace, line_label);
+            update_function_statistics(&mut self.stats.function_counts, trace, function_label);
+            update_function_statistics(&mut thread_counts.line_counts, trace, line_label);
+            update_function_statistics(&mut thread_counts.function_counts, trace, function_label);
+
+            // remember which rows are application code, for the 'app only' filter
+            for frame in trace.frames.iter().filter(|frame| frame.application) {
+                let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
+                self.stats.application.insert(format!("{} ({}:{})", frame.name, filename, frame.line));
+                self.stats.application.insert(format!("{} ({})", frame.name, filename));
+            }
         }
         self.increment_common()?;
         Ok(())
@@ -103,8 +140,34 @@ impl ConsoleViewer {
         // Get the top aggregate function calls (either by line or by function as )
         let mut options = self.options.lock().unwrap();
         options.dirty = false;
-        let counts = if options.show_linenumbers { &self.stats.line_counts } else { &self.stats.function_counts };
-        let mut counts:Vec<(&FunctionStatistics, &str)> = counts.iter().map(|(x,y)| (y, x.as_ref())).collect();
+
+        // when filtering by thread, add up the statistics of just the matching threads
+        let mut threads: Vec<&String> = self.stats.thread_counts.keys().collect();
+        threads.sort();
+        if options.thread_index.map_or(false, |index| index >= threads.len()) {
+            options.thread_index = None;
+        }
+        let thread_selected = |thread: &str| match (options.thread_index, options.thread_filter.as_ref()) {
+            (Some(index), _) => threads[index] == thread,
+            (None, Some(filter)) => filter.is_match(thread),
+            (None, None) => true
+        };
+        let merged;
+        let counts = if options.thread_index.is_some() || options.thread_filter.is_some() {
+            merged = merge_function_statistics(self.stats.thread_counts.iter()
+                .filter(|(thread, _)| thread_selected(thread.as_str()))
+                .map(|(_, counts)| if options.show_linenumbers { &counts.line_counts } else { &counts.function_counts }));
+            &merged
+        } else if options.show_linenumbers {
+            &self.stats.line_counts
+        } else {
+            &self.stats.function_counts
+        };
+        let mut counts:Vec<(&FunctionStatistics, &str)> = counts.iter()
+            .filter(|(label, _)| options.filter.as_ref().map_or(true, |filter| filter.is_match(label)))
+            .filter(|(label, _)| !options.app_only || self.stats.application.contains(label.as_str()))
+            .map(|(x,y)| (y, x.as_ref()))
+            .collect();
 
         // TODO: subsort ?
         match options.sort_column {
@@ -126,43 +189,64 @@ impl ConsoleViewer {
             ($($arg:tt)*) => { term.clear_line()?; term.write_line(&format!($($arg)*))?; }
         }
         self.console_config.reset_cursor()?;
-        let mut header_lines = if options.usage { 18 } else { 8 };
+        let mut header_lines = if options.usage { 23 } else { 8 };
 
         if let Some(delay) = self.stats.last_delay {
             let late_rate = self.stats.late_samples as f64 / self.stats.overall_samples as f64;
             if late_rate > 0.10 && delay > std::time::Duration::from_secs(1) {
                 let msg = format!("{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate.", delay);
-                out!("{}", style(msg).red());
+                out!("{}", self.style(msg).red());
                 header_lines += 1;
             }
         }
 
         // Display aggregate stats about the process
-        out!("Collecting samples from '{}' (python v{})", style(&self.command).green(), &self.version);
+        out!("Collecting samples from '{}' (python v{})", self.style(&self.command).green(), &self.version);
 
         let error_rate = self.stats.errors as f64 / self.stats.overall_samples as f64;
         if error_rate >= 0.01 && self.stats.overall_samples > 100 {
             let error_string = self.stats.last_error.as_ref().unwrap();
             out!("Total Samples {}, Error Rate {:.2}% ({})",
-                 style(self.stats.overall_samples).bold(),
-                 style(error_rate * 100.0).bold().red(),
-                 style(error_string).bold());
+                 self.style(self.stats.overall_samples).bold(),
+                 self.style(error_rate * 100.0).bold().red(),
+                 self.style(error_string).bold());
         } else {
-             out!("Total Samples {}", style(self.stats.overall_samples).bold());
+             out!("Total Samples {}", self.style(self.stats.overall_samples).bold());
         }
 
         out!("GIL: {:.2}%, Active: {:>.2}%, Threads: {}",
-            style(100.0 * self.stats.gil as f64 / self.stats.current_samples as f64).bold(),
-            style(100.0 * self.stats.active as f64 / self.stats.current_samples as f64).bold(),
-            style(self.stats.threads).bold());
+            self.style(100.0 * self.stats.gil as f64 / self.stats.current_samples as f64).bold(),
+            self.style(100.0 * self.stats.active as f64 / self.stats.current_samples as f64).bold(),
+            self.style(self.stats.threads).bold());
+
+        if let Some(filter) = options.filter.as_ref() {
+            out!("Showing functions matching '{}'", self.style(filter.as_str()).bold());
+            header_lines += 1;
+        }
+        if options.app_only {
+            out!("Showing only application code (from --app-root)");
+            header_lines += 1;
+        }
+        if let Some(index) = options.thread_index {
+            out!("Showing thread '{}'", self.style(threads[index]).bold());
+            header_lines += 1;
+        } else if let Some(filter) = options.thread_filter.as_ref() {
+            out!("Showing threads matching '{}'", self.style(filter.as_str()).bold());
+            header_lines += 1;
+        }
 
         out!();
 
-        // Build up the header for the table
-        let mut percent_own_header = style("%Own ").reverse();
-        let mut percent_total_header = style("%Total").reverse();
-        let mut time_own_header = style("OwnTime").reverse();
-        let mut time_total_header = style("TotalTime").reverse();
+        // Build up the header for the table. Without colors the bold header for the column we're
+        // sorting by doesn't show up, so mark it with a '*' too
+        let marked = !self.color.unwrap_or_else(console::colors_enabled);
+        let label = |column: i32, name: &str| {
+            if marked && options.sort_column == column { format!("*{}", name) } else { name.to_owned() }
+        };
+        let mut percent_own_header = self.style(label(1, "%Own ")).reverse();
+        let mut percent_total_header = self.style(label(2, "%Total")).reverse();
+        let mut time_own_header = self.style(label(3, "OwnTime")).reverse();
+        let mut time_total_header = self.style(label(4, "TotalTime")).reverse();
         match options.sort_column {
             1 => percent_own_header = percent_own_header.bold(),
             2 => percent_total_header = percent_total_header.bold(),
@@ -172,9 +256,9 @@ impl ConsoleViewer {
         }
 
         let function_header = if options.show_linenumbers {
-            style("  Function (filename:line)").reverse()
+            self.style("  Function (filename:line)").reverse()
         } else {
-            style("  Function (filename)").reverse()
+            self.style("  Function (filename)").reverse()
         };
 
         // If we aren't at least 50 characters wide, lets use two lines per entry
@@ -201,28 +285,49 @@ impl ConsoleViewer {
 
         out!();
         if options.usage {
-            out!("{:width$}", style(" Keyboard Shortcuts ").reverse(), width=width as usize);
+            out!("{:width$}", self.style(" Keyboard Shortcuts ").reverse(), width=width as usize);
             out!();
-            out!("{:^12}{:<}", style("key").green(), style("action").green());
+            out!("{:^12}{:<}", self.style("key").green(), self.style("action").green());
             out!("{:^12}{:<}", "1", "Sort by %Own (% of time currently spent in the function)");
             out!("{:^12}{:<}", "2", "Sort by %Total (% of time currently in the function and its children)");
             out!("{:^12}{:<}", "3", "Sort by OwnTime (Overall time spent in the function)");
             out!("{:^12}{:<}", "4", "Sort by TotalTime (Overall time spent in the function and its children)");
+            out!("{:^12}{:<}", "C,c", "Toggle between sorting by own and total time");
+            out!("{:^12}{:<}", "/", "Only show functions matching a regex (an empty regex shows everything)");
             out!("{:^12}{:<}", "L,l", "Toggle between aggregating by line number or by function");
+            out!("{:^12}{:<}", "A,a", "Toggle only showing application code (functions under an --app-root)");
+            out!("{:^12}{:<}", "T,t", "Cycle through showing the functions of each thread by itself");
+            out!("{:^12}{:<}", "N,n", "Only show threads with names matching a regex (an empty regex shows every thread)");
             out!("{:^12}{:<}", "R,r", "Reset statistics");
             out!("{:^12}{:<}", "X,x", "Exit this help screen");
             out!();
             //println!("{:^12}{:<}", "Control-C", "Quit py-spy");
+        } else if let Some(input) = options.filter_input.as_ref() {
+            match options.filter_target {
+                FilterTarget::Function => { out!("Filter: /{}", input); },
+                FilterTarget::Thread => { out!("Thread filter: {}", input); }
+            }
+        } else if let Some(error) = options.filter_error.as_ref() {
+            out!("{}", self.style(error).red());
         } else {
             out!("Press {} to quit, or {} for help.",
-                 style("Control-C").bold().reverse(),
-                 style("?").bold().reverse());
+                 self.style("Control-C").bold().reverse(),
+                 self.style("?").bold().reverse());
         }
         std::io::stdout().flush()?;
 
         Ok(())
     }
 
+    /// Styles text for the terminal, unless --color says otherwise (with None leaving it
+    /// up to whether stdout is a terminal)
+    fn style<D>(&self, val: D) -> StyledObject<D> {
+        match self.color {
+            Some(color) => style(val).force_styling(color),
+            None => style(val)
+        }
+    }
+
     pub fn increment_error(&mut self, err: &Error) ->  Result<(), Error> {
         self.maybe_reset();
         self.stats.errors += 1;
@@ -272,7 +377,7 @@ impl Drop for ConsoleViewer {
     }
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Default)]
 struct FunctionStatistics {
     current_own: u64,
     current_total: u64,
@@ -302,12 +407,53 @@ fn update_function_statistics<K>(counts: &mut HashMap<String, FunctionStatistics
     }
 }
 
+/// Adds up the statistics for each function across several threads
+fn merge_function_statistics<'a, I>(counts: I) -> HashMap<String, FunctionStatistics>
+    where I: Iterator<Item=&'a HashMap<String, FunctionStatistics>> {
+    let mut merged: HashMap<String, FunctionStatistics> = HashMap::new();
+    for counts in counts {
+        for (key, stats) in counts {
+            let entry = merged.entry(key.clone()).or_insert_with(FunctionStatistics::default);
+            entry.current_own += stats.current_own;
+            entry.current_total += stats.current_total;
+            entry.overall_own += stats.overall_own;
+            entry.overall_total += stats.overall_total;
+        }
+    }
+    merged
+}
+
+/// What the regex being typed in will filter on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterTarget {
+    Function,
+    Thread,
+}
+
+/// The statistics for a single thread
+#[derive(Default)]
+struct ThreadCounts {
+    function_counts: HashMap<String, FunctionStatistics>,
+    line_counts: HashMap<String, FunctionStatistics>,
+}
+
 struct Options {
     dirty: bool,
     usage: bool,
     sort_column: i32,
     show_linenumbers: bool,
+    /// Only show functions from application code, hiding library code
+    app_only: bool,
     reset: bool,
+    filter: Option<Regex>,
+    // the filter that is currently being typed in, after pressing '/' (or 'n' for threads)
+    filter_input: Option<String>,
+    filter_target: FilterTarget,
+    filter_error: Option<String>,
+    // either the index of the single thread being shown (in the sorted thread names), or a regex
+    // that thread names have to match
+    thread_index: Option<usize>,
+    thread_filter: Option<Regex>,
 }
 
 struct Stats {
@@ -321,13 +467,60 @@ struct Stats {
     gil: u64,
     function_counts: HashMap<String, FunctionStatistics>,
     line_counts: HashMap<String, FunctionStatistics>,
+    thread_counts: HashMap<String, ThreadCounts>,
+    /// The function and line labels that are for application code
+    application: HashSet<String>,
     last_error: Option<String>,
     last_delay: Option<std::time::Duration>,
 }
 
 impl Options {
     fn new(show_linenumbers: bool) -> Options {
-        Options{dirty: false, usage: false, reset: false, sort_column: 1, show_linenumbers}
+        Options{dirty: false, usage: false, reset: false, sort_column: 1, show_linenumbers, app_only: false,
+                filter: None, filter_input: None, filter_target: FilterTarget::Function, filter_error: None,
+                thread_index: None, thread_filter: None}
+    }
+
+    /// Handles a keypress while typing in a filter
+    fn edit_filter(&mut self, key: u8) {
+        match key {
+            b'\n' | b'\r' => {
+                let input = self.filter_input.take().unwrap_or_default();
+                self.filter_error = None;
+                let filter = if input.is_empty() {
+                    None
+                } else {
+                    match Regex::new(&input) {
+                        Ok(filter) => Some(filter),
+                        Err(e) => {
+                            self.filter_error = Some(format!("Invalid filter '{}': {}", input, e));
+                            return;
+                        }
+                    }
+                };
+                match self.filter_target {
+                    FilterTarget::Function => self.filter = filter,
+                    FilterTarget::Thread => {
+                        self.thread_filter = filter;
+                        self.thread_index = None;
+                    }
+                }
+            },
+            // escape cancels editing, and leaves the previous filter in place
+            0x1b => self.filter_input = None,
+            // backspace/delete
+            0x08 | 0x7f => {
+                if let Some(input) = self.filter_input.as_mut() {
+                    input.pop();
+                }
+            },
+            b' ' ..= b'~' => {
+                if let Some(input) = self.filter_input.as_mut() {
+                    input.push(key as char);
+                }
+            },
+            _ => {}
+        }
     }
 }
 
@@ -335,8 +528,8 @@ impl Stats {
     fn new() -> Stats {
         Stats{current_samples: 0, overall_samples: 0, elapsed: 0.,
               errors: 0, late_samples: 0, threads: 0, gil: 0, active: 0,
-              line_counts: HashMap::new(), function_counts: HashMap::new(),
-              last_error: None, last_delay: None}
+              line_counts: HashMap::new(), function_counts: HashMap::new(), application: HashSet::new(),
+              thread_counts: HashMap::new(), last_error: None, last_delay: None}
     }
 
     pub fn reset_current(&mut self) {
@@ -350,6 +543,13 @@ impl Stats {
             val.current_total = 0;
             val.current_own = 0;
         }
+
+        for thread in self.thread_counts.values_mut() {
+            for val in thread.line_counts.values_mut().chain(thread.function_counts.values_mut()) {
+                val.current_total = 0;
+                val.current_own = 0;
+            }
+        }
         self.gil = 0;
         self.active = 0;
         self.current_samples = 0;
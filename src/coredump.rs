@@ -0,0 +1,380 @@
+/* Reads python stack traces out of ELF core dumps.
+
+The memory of the process is reconstructed from the PT_LOAD segments in the core file
+(falling back to the mapped files on disk for segments that weren't included in the dump,
+like read only sections of shared libraries). Notes in the core are used to figure out which
+files were mapped where, so that the python binary and libpython can be located.
+*/
+use std::collections::HashMap;
+use std::fs::File;
+
+use failure::{Error, ResultExt};
+use goblin::elf::{header, program_header, Elf};
+use memmap::Mmap;
+use remoteprocess::{Pid, ProcessMemory};
+
+use crate::binary_parser::parse_binary;
+use crate::python_bindings::{v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0};
+use crate::python_interpreters::{InterpreterState, ThreadState};
+use crate::python_spy::{get_gil_threadid, get_interpreter_address, get_python_version, get_short_filename,
+                        get_threadstate_address, heuristic_is_thread_idle, is_python_lib, PythonProcessInfo};
+use crate::python_threading::thread_names;
+use crate::stack_trace::{get_stack_trace, StackTrace};
+use crate::version::Version;
+
+const NT_PRPSINFO: u32 = 3;
+const NT_FILE: u32 = 0x4649_4c45;
+
+// offsets of pr_pid and pr_psargs in the elf_prpsinfo struct
+#[cfg(target_pointer_width="64")]
+const PRPSINFO_PID_OFFSET: usize = 24;
+#[cfg(target_pointer_width="64")]
+const PRPSINFO_PSARGS_OFFSET: usize = 56;
+#[cfg(not(target_pointer_width="64"))]
+const PRPSINFO_PID_OFFSET: usize = 12;
+#[cfg(not(target_pointer_width="64"))]
+const PRPSINFO_PSARGS_OFFSET: usize = 44;
+
+/// A region of memory that was included in the core dump
+struct Segment {
+    addr: usize,
+    size: usize,
+    // the part of the segment that is stored in the core file, which can be smaller than the size
+    offset: usize,
+    file_size: usize,
+    executable: bool,
+}
+
+/// A file that was mapped into the memory of the process
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedFile {
+    pub start: usize,
+    pub end: usize,
+    pub offset: usize,
+    pub filename: String,
+}
+
+/// The memory image of a process, as stored in an ELF core file
+pub struct CoreDump {
+    pub pid: Pid,
+    pub cmdline: String,
+    pub files: Vec<MappedFile>,
+    contents: Mmap,
+    segments: Vec<Segment>,
+    mapped_contents: HashMap<String, Mmap>,
+}
+
+impl CoreDump {
+    pub fn new(filename: &str) -> Result<CoreDump, Error> {
+        let file = File::open(filename).context(format!("Failed to open core file '{}'", filename))?;
+        let contents = unsafe { Mmap::map(&file)? };
+
+        let mut segments = Vec::new();
+        let mut files = Vec::new();
+        let mut pid = 0;
+        let mut cmdline = String::new();
+        {
+            let elf = Elf::parse(&contents)?;
+            if elf.header.e_type != header::ET_CORE {
+                return Err(format_err!("'{}' isn't a core file", filename));
+            }
+
+            for ph in &elf.program_headers {
+                match ph.p_type {
+                    program_header::PT_LOAD => {
+                        segments.push(Segment{addr: ph.p_vaddr as usize, size: ph.p_memsz as usize,
+                                              offset: ph.p_offset as usize, file_size: ph.p_filesz as usize,
+                                              executable: ph.p_flags & program_header::PF_X != 0});
+                    },
+                    program_header::PT_NOTE => {
+                        let start = ph.p_offset as usize;
+                        let end = start + ph.p_filesz as usize;
+                        if end > contents.len() {
+                            return Err(format_err!("Note segment extends past the end of '{}'", filename));
+                        }
+                        for (note_type, desc) in parse_notes(&contents[start..end]) {
+                            match note_type {
+                                NT_FILE => files = parse_file_note(desc)
+                                    .ok_or_else(|| format_err!("Failed to parse NT_FILE note in '{}'", filename))?,
+                                NT_PRPSINFO if desc.len() >= PRPSINFO_PSARGS_OFFSET + 80 => {
+                                    pid = read_u32(desc, PRPSINFO_PID_OFFSET) as Pid;
+                                    let psargs = &desc[PRPSINFO_PSARGS_OFFSET..PRPSINFO_PSARGS_OFFSET + 80];
+                                    let len = psargs.iter().position(|&b| b == 0).unwrap_or(psargs.len());
+                                    cmdline = String::from_utf8_lossy(&psargs[..len]).trim().to_owned();
+                                },
+                                _ => {}
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        if files.is_empty() {
+            return Err(format_err!("Core file '{}' doesn't list any mapped files", filename));
+        }
+
+        // memory from the mapped files might not be included in the core, so load the files
+        // themselves to read from (skipping any that no longer exist)
+        let mut mapped_contents = HashMap::new();
+        for mapped in &files {
+            if mapped_contents.contains_key(&mapped.filename) {
+                continue;
+            }
+            match File::open(&mapped.filename).and_then(|f| unsafe { Mmap::map(&f) }) {
+                Ok(contents) => { mapped_contents.insert(mapped.filename.clone(), contents); },
+                Err(e) => info!("Failed to load mapped file '{}': {}", mapped.filename, e)
+            }
+        }
+
+        Ok(CoreDump{pid, cmdline, files, contents, segments, mapped_contents})
+    }
+
+    /// Whether the memory at an address was executable in the process
+    pub fn is_executable(&self, addr: usize) -> bool {
+        self.segments.iter().any(|s| s.executable && addr >= s.addr && addr < s.addr + s.size)
+    }
+
+    // reads memory from a single segment
+    fn read_segment(&self, addr: usize, buf: &mut [u8]) -> Option<()> {
+        let segment = self.segments.iter().find(|s| addr >= s.addr && addr < s.addr + s.size)?;
+        let start = addr - segment.addr;
+        if start + buf.len() <= segment.file_size {
+            let offset = segment.offset + start;
+            buf.copy_from_slice(self.contents.get(offset..offset + buf.len())?);
+            return Some(());
+        }
+
+        // otherwise this wasn't included in the core, try reading from the mapped file instead
+        let mapped = self.files.iter().find(|f| addr >= f.start && addr + buf.len() <= f.end)?;
+        let contents = self.mapped_contents.get(&mapped.filename)?;
+        let offset = mapped.offset + addr - mapped.start;
+        buf.copy_from_slice(contents.get(offset..offset + buf.len())?);
+        Some(())
+    }
+}
+
+impl ProcessMemory for CoreDump {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        // reads can span multiple segments, so split them up at segment boundaries
+        let mut pos = 0;
+        while pos < buf.len() {
+            let current = addr + pos;
+            let segment_end = self.segments.iter()
+                .find(|s| current >= s.addr && current < s.addr + s.size)
+                .map(|s| s.addr + s.size)
+                .ok_or_else(|| remoteprocess::Error::Other(format!("Address 0x{:x} isn't in core file", current)))?;
+            let end = std::cmp::min(buf.len(), pos + segment_end - current);
+            self.read_segment(current, &mut buf[pos..end])
+                .ok_or_else(|| remoteprocess::Error::Other(format!("Failed to read 0x{:x} from core file", current)))?;
+            pos = end;
+        }
+        Ok(())
+    }
+}
+
+/// Lets you retrieve the stack traces of a python program from a core dump
+pub struct PythonCoreDump {
+    pub core: CoreDump,
+    pub version: Version,
+    pub interpreter_address: usize,
+    pub threadstate_address: usize,
+}
+
+impl PythonCoreDump {
+    pub fn new(filename: &str) -> Result<PythonCoreDump, Error> {
+        let core = CoreDump::new(filename)?;
+        let python_info = get_python_info(&core)?;
+
+        let version = get_python_version(&python_info, &core)?;
+        info!("python version {} detected", version);
+
+        let interpreter_address = get_interpreter_address(&python_info, &core, &version)?;
+        info!("Found interpreter at 0x{:016x}", interpreter_address);
+
+        let threadstate_address = get_threadstate_address(&python_info, &version);
+        Ok(PythonCoreDump{core, version, interpreter_address, threadstate_address})
+    }
+
+    /// Gets a StackTrace for each python thread in the core dump
+    pub fn get_stack_traces(&self, copy_locals: bool) -> Result<Vec<StackTrace>, Error> {
+        match self.version {
+            Version{major: 2, minor: 3..=7, ..} => self._get_stack_traces::<v2_7_15::_is>(copy_locals),
+            Version{major: 3, minor: 3, ..} => self._get_stack_traces::<v3_3_7::_is>(copy_locals),
+            Version{major: 3, minor: 4..=5, ..} => self._get_stack_traces::<v3_5_5::_is>(copy_locals),
+            Version{major: 3, minor: 6, ..} => self._get_stack_traces::<v3_6_6::_is>(copy_locals),
+            Version{major: 3, minor: 7, ..} => self._get_stack_traces::<v3_7_0::_is>(copy_locals),
+            Version{major: 3, minor: 8, patch: 0, ..} => {
+                match self.version.release_flags.as_ref() {
+                    "a1" | "a2" | "a3" => self._get_stack_traces::<v3_7_0::_is>(copy_locals),
+                    _ => self._get_stack_traces::<v3_8_0::_is>(copy_locals)
+                }
+            },
+            Version{major: 3, minor: 8..=9, ..} => self._get_stack_traces::<v3_8_0::_is>(copy_locals),
+            _ => Err(format_err!("Unsupported version of Python: {}", self.version)),
+        }
+    }
+
+    fn _get_stack_traces<I: InterpreterState>(&self, copy_locals: bool) -> Result<Vec<StackTrace>, Error> {
+        let interp: I = self.core.copy_struct(self.interpreter_address)
+            .context("Failed to copy PyInterpreterState from core")?;
+        let gil_thread_id = get_gil_threadid::<I, CoreDump>(self.threadstate_address, &self.core)?;
+
+        let names = thread_names(&interp, &self.core, &self.version).unwrap_or_else(|e| {
+            info!("Failed to get python thread names: {}", e);
+            HashMap::new()
+        });
+
+        let mut traces = Vec::new();
+        let mut threads = interp.head();
+        while !threads.is_null() {
+            let thread = self.core.copy_pointer(threads).context("Failed to copy PyThreadState")?;
+            let mut trace = get_stack_trace(&thread, &self.core, copy_locals, None, &self.version)?;
+
+            // we can't ask the OS if the threads are running, so guess from the stacks
+            trace.owns_gil = trace.thread_id == gil_thread_id;
+            trace.active = !heuristic_is_thread_idle(&trace);
+            trace.thread_name = names.get(&trace.thread_id).cloned();
+            for frame in &mut trace.frames {
+                frame.short_filename = get_short_filename(&frame.filename);
+            }
+
+            traces.push(trace);
+            if traces.len() > 4096 {
+                return Err(format_err!("Max thread recursion depth reached"));
+            }
+            threads = thread.next();
+        }
+        Ok(traces)
+    }
+}
+
+/// Finds and parses the python binary and libpython from the files mapped in the core
+fn get_python_info(core: &CoreDump) -> Result<PythonProcessInfo, Error> {
+    // the executable is the first file mapped into memory
+    let python_filename = core.files[0].filename.clone();
+
+    let executable_map = |pred: &dyn Fn(&str) -> bool| {
+        core.files.iter().find(|f| pred(&f.filename) && core.is_executable(f.start))
+    };
+
+    let map = executable_map(&|filename| filename == python_filename)
+        .ok_or_else(|| format_err!("Failed to find executable mapping for '{}' in core", python_filename))?;
+    let python_binary = parse_binary(&python_filename, map.start as u64, (map.end - map.start) as u64)?;
+
+    let libpython_binary = match executable_map(&|filename| is_python_lib(filename)) {
+        Some(map) => {
+            info!("Found libpython binary @ {}", map.filename);
+            Some(parse_binary(&map.filename, map.start as u64, (map.end - map.start) as u64)?)
+        },
+        None => None
+    };
+
+    let regions = core.segments.iter().map(|s| (s.addr, s.size)).collect();
+    let binary_regions = core.files.iter()
+        .filter(|f| f.filename == python_filename || is_python_lib(&f.filename))
+        .map(|f| (f.start, f.end - f.start))
+        .collect();
+    Ok(PythonProcessInfo{python_binary, libpython_binary, regions, binary_regions, python_filename})
+}
+
+/// Returns the (type, description) for each note in a PT_NOTE segment
+fn parse_notes(data: &[u8]) -> Vec<(u32, &[u8])> {
+    let align = |size: usize| (size + 3) & !3;
+    let mut notes = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        let namesz = read_u32(data, pos) as usize;
+        let descsz = read_u32(data, pos + 4) as usize;
+        let note_type = read_u32(data, pos + 8);
+        let desc_start = pos + 12 + align(namesz);
+        if desc_start + descsz > data.len() {
+            break;
+        }
+        notes.push((note_type, &data[desc_start..desc_start + descsz]));
+        pos = desc_start + align(descsz);
+    }
+    notes
+}
+
+/// Parses the NT_FILE note, which lists the files mapped into memory
+fn parse_file_note(desc: &[u8]) -> Option<Vec<MappedFile>> {
+    let word = std::mem::size_of::<usize>();
+    let read_word = |index: usize| -> Option<usize> {
+        let bytes = desc.get(index * word..(index + 1) * word)?;
+        let mut value = 0usize;
+        for (i, &b) in bytes.iter().enumerate() {
+            value |= (b as usize) << (8 * i);
+        }
+        Some(value)
+    };
+
+    // the note starts with the number of files and the page size, followed by a
+    // (start, end, page offset) entry for each file and then the null terminated filenames
+    let count = read_word(0)?;
+    let page_size = read_word(1)?;
+    let names_start = (2 + count.checked_mul(3)?).checked_mul(word)?;
+    let mut names = desc.get(names_start..)?.split(|&b| b == 0);
+
+    let mut files = Vec::new();
+    for i in 0..count {
+        let entry = 2 + i * 3;
+        let filename = String::from_utf8_lossy(names.next()?).to_string();
+        files.push(MappedFile{start: read_word(entry)?, end: read_word(entry + 1)?,
+                              offset: read_word(entry + 2)?.checked_mul(page_size)?, filename});
+    }
+    Some(files)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut value = 0;
+    for (i, &b) in data[offset..offset + 4].iter().enumerate() {
+        value |= u32::from(b) << (8 * i);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(values: &[usize]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for value in values {
+            for i in 0..std::mem::size_of::<usize>() {
+                ret.push((value >> (8 * i)) as u8);
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn test_parse_notes() {
+        let mut data = Vec::new();
+        // a note with a 5 byte name (padded to 8) and a 3 byte description (padded to 4)
+        data.extend_from_slice(&[5, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0]);
+        data.extend_from_slice(b"CORE\0\0\0\0");
+        data.extend_from_slice(&[1, 2, 3, 0]);
+        // followed by a second note with an empty description
+        data.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0]);
+        data.extend_from_slice(b"GNU\0");
+
+        let notes = parse_notes(&data);
+        assert_eq!(notes, vec![(1, &[1u8, 2, 3][..]), (3, &[][..])]);
+    }
+
+    #[test]
+    fn test_parse_file_note() {
+        let mut desc = words(&[2, 4096, 0x1000, 0x2000, 0, 0x5000, 0x8000, 2]);
+        desc.extend_from_slice(b"/usr/bin/python3.7\0/usr/lib/libpython3.7m.so\0");
+
+        let files = parse_file_note(&desc).unwrap();
+        assert_eq!(files, vec![
+            MappedFile{start: 0x1000, end: 0x2000, offset: 0, filename: String::from("/usr/bin/python3.7")},
+            MappedFile{start: 0x5000, end: 0x8000, offset: 8192, filename: String::from("/usr/lib/libpython3.7m.so")}]);
+
+        // truncated notes should fail to parse
+        assert_eq!(parse_file_note(&desc[..40]), None);
+    }
+}
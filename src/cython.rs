@@ -1,28 +1,52 @@
 
 use std;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use regex::Regex;
 
 use failure::Error;
 
 use crate::utils::resolve_filename;
-use crate::stack_trace::Frame;
+use crate::stack_trace::{Frame, FrameOrigin};
 
 pub struct SourceMaps {
     maps: HashMap<String, Option<SourceMap>>,
+    // the root directory of the process, when it's in a different mount namespace
+    root: Option<PathBuf>,
+    /// The generated c/c++ files we couldn't load source maps for, by the module they're in
+    pub missing: BTreeMap<String, BTreeSet<String>>,
+    /// Fail to translate frames that need a source map that can't be loaded, instead of leaving
+    /// them as frames for the generated code
+    pub require_maps: bool,
 }
 
+/// The error from translating a frame with require_maps set, when its source map couldn't be loaded
+#[derive(Debug)]
+pub struct MissingSourceMap {
+    pub filename: String,
+    pub module: String,
+}
+
+impl std::fmt::Display for MissingSourceMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Failed to load the cython source map for '{}' in {}", self.filename, self.module)
+    }
+}
+
+impl std::error::Error for MissingSourceMap {}
+
 impl SourceMaps {
-    pub fn new() -> SourceMaps {
+    pub fn new(root: Option<PathBuf>) -> SourceMaps {
         let maps = HashMap::new();
-        SourceMaps{maps}
+        SourceMaps{maps, root, missing: BTreeMap::new(), require_maps: false}
     }
 
-    pub fn translate(&mut self, frame: &mut Frame) {
+    pub fn translate(&mut self, frame: &mut Frame) -> Result<(), Error> {
         if self.translate_frame(frame) {
-            self.load_map(frame);
+            self.load_map(frame)?;
             self.translate_frame(frame);
         }
+        Ok(())
     }
 
     // tries to replace the frame using a cython sourcemap if possible
@@ -37,6 +61,11 @@ impl SourceMaps {
                 if let Some((file, line)) = map.lookup(line) {
                     frame.filename = file.clone();
                     frame.line = *line as i32;
+                    // the start of the generated c function maps back to the cython def
+                    frame.first_line = frame.first_line
+                        .and_then(|first_line| map.lookup(first_line as u32))
+                        .map(|(_, first_line)| *first_line as i32);
+                    frame.origin = FrameOrigin::Cython;
                 }
             }
             return false;
@@ -46,22 +75,35 @@ impl SourceMaps {
     }
 
     // loads the corresponding cython source map for the frame
-    fn load_map(&mut self, frame: &Frame) {
+    fn load_map(&mut self, frame: &Frame) -> Result<(), Error> {
         if !(frame.filename.ends_with(".cpp") || frame.filename.ends_with(".c")) {
             self.maps.insert(frame.filename.clone(), None);
-            return;
+            return Ok(());
         }
 
-        let map = match SourceMap::new(&frame.filename, &frame.module) {
+        let map = match SourceMap::new(&frame.filename, &frame.module, self.root.as_ref().map(PathBuf::as_path)) {
             Ok(map) => map,
             Err(e) => {
                 info!("Failed to load cython file {}: {:?}", &frame.filename, e);
                 self.maps.insert(frame.filename.clone(), None);
-                return;
+
+                // warn about each module once, rather than for every file in it
+                let module = frame.module.clone().unwrap_or_default();
+                let missing = self.missing.entry(module.clone()).or_insert_with(BTreeSet::new);
+                if missing.is_empty() {
+                    warn!("Failed to load cython source maps for {}, its frames will show the generated code", module);
+                }
+                missing.insert(frame.filename.clone());
+
+                if self.require_maps {
+                    return Err(MissingSourceMap{filename: frame.filename.clone(), module}.into());
+                }
+                return Ok(());
             }
         };
 
         self.maps.insert(frame.filename.clone(), Some(map));
+        Ok(())
     }
 }
 
@@ -70,12 +112,15 @@ struct SourceMap {
 }
 
 impl SourceMap {
-    pub fn new(filename: &str, module: &Option<String>) -> Result<SourceMap, Error> {
-        let contents = std::fs::read_to_string(filename)?;
-        SourceMap::from_contents(&contents, module)
+    pub fn new(filename: &str, module: &Option<String>, root: Option<&Path>) -> Result<SourceMap, Error> {
+        let contents = match root {
+            Some(root) => std::fs::read_to_string(root.join(filename.trim_start_matches('/')))?,
+            None => std::fs::read_to_string(filename)?
+        };
+        SourceMap::from_contents(&contents, module, root)
     }
 
-    pub fn from_contents(contents: &str, module: &Option<String>) -> Result<SourceMap, Error> {
+    pub fn from_contents(contents: &str, module: &Option<String>, root: Option<&Path>) -> Result<SourceMap, Error> {
         lazy_static! {
             static ref RE: Regex = Regex::new(r#"^\s*/\* "(.+\..+)":([0-9]+)"#).unwrap();
         }
@@ -92,7 +137,7 @@ impl SourceMap {
                     // try resolving the cython filename (TODO: could cache this?)
                     let filename = match module {
                         Some(module) => {
-                            resolve_filename(cython_file, module)
+                            resolve_filename(cython_file, module, root)
                                 .unwrap_or_else(|| cython_file.to_owned())
                         },
                         None => cython_file.to_owned()
@@ -117,11 +162,24 @@ impl SourceMap {
     }
 }
 
+/// Whether a native frame is part of the cython runtime (like the '__Pyx_PyObject_Call' helpers
+/// used to call functions), rather than code from a .pyx file
 pub fn ignore_frame(name: &str) -> bool {
-    let ignorable = ["__Pyx_PyFunction_FastCallDict", "__Pyx_PyObject_CallOneArg",
-        "__Pyx_PyObject_Call", "__Pyx_PyObject_Call", "__pyx_FusedFunction_call"];
+    name.starts_with("__Pyx_") || name == "__pyx_FusedFunction_call"
+}
+
+/// Whether a mangled function name is the python wrapper ('__pyx_pw_') that cython generates
+/// for each def, which converts the arguments and then calls the implementation ('__pyx_pf_')
+pub fn is_wrapper(name: &str) -> bool {
+    name.starts_with("__pyx_pw") || name.starts_with("___pyx_pw") ||
+        (name.starts_with("__pyx_fuse_") && name.contains("__pyx_pw"))
+}
 
-    ignorable.iter().any(|&f| f == name)
+/// Whether a wrapper frame is for the same function as the frame it called, in which case the
+/// two can be shown as a single frame. The demangled names can differ by leading underscores
+pub fn is_same_function(wrapper: &Frame, callee: &Frame) -> bool {
+    wrapper.module == callee.module &&
+        wrapper.name.trim_start_matches('_') == callee.name.trim_start_matches('_')
 }
 
 pub fn demangle(name: &str) -> &str {
@@ -184,9 +242,32 @@ mod tests {
         assert_eq!(demangle("use_1__pyx_f_8implicit_3bpr_has_non_zero"), "bpr_has_non_zero");
     }
 
+    #[test]
+    fn test_runtime_frames() {
+        assert!(ignore_frame("__Pyx_PyObject_Call"));
+        assert!(ignore_frame("__Pyx_PyFunction_FastCallDict"));
+        assert!(ignore_frame("__Pyx__PyObject_CallOneArg"));
+        assert!(ignore_frame("__Pyx_Coroutine_SendEx"));
+        assert!(ignore_frame("__pyx_FusedFunction_call"));
+        assert!(!ignore_frame("__pyx_pw_8implicit_4_als_5least_squares_cg"));
+        assert!(!ignore_frame("PyObject_Call"));
+
+        assert!(is_wrapper("__pyx_pw_8implicit_4_als_5least_squares_cg"));
+        assert!(is_wrapper("__pyx_fuse_1_0__pyx_pw_8implicit_4_als_31_least_squares_cg"));
+        assert!(!is_wrapper("__pyx_pf_8implicit_4_als_30_least_squares_cg"));
+        assert!(!is_wrapper("__pyx_f_6mtrand_cont0_array"));
+
+        let frame = |mangled: &str| Frame{name: demangle(mangled).to_owned(), filename: "_als.cpp".to_owned(),
+                                          module: Some("_als.so".to_owned()), short_filename: None, line: 0,
+                                          locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false};
+        let wrapper = frame("__pyx_pw_8implicit_4_als_5least_squares_cg");
+        assert!(is_same_function(&wrapper, &frame("__pyx_pf_8implicit_4_als_30_least_squares_cg")));
+        assert!(!is_same_function(&wrapper, &frame("__pyx_pf_8implicit_4_als_2calculate_loss")));
+    }
+
     #[test]
     fn test_source_map() {
-        let map = SourceMap::from_contents(include_str!("../ci/testdata/cython_test.c"), &None).unwrap();
+        let map = SourceMap::from_contents(include_str!("../ci/testdata/cython_test.c"), &None, None).unwrap();
 
         // we don't have info on cython line numbers until line 1261
         assert_eq!(map.lookup(1000), None);
@@ -27,34 +27,130 @@ SOFTWARE.
 */
 
 use std;
-use std::collections::HashMap;
-use std::fs::File;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
 
+use std::io::Write;
+
 use failure::Error;
+use regex::{Captures, Regex};
 use inferno::flamegraph::{Direction, Options};
+use inferno::flamegraph::color::{Color, PaletteMap};
 
-use crate::stack_trace::StackTrace;
+use crate::stack_trace::{FrameOrigin, StackTrace};
 
 pub struct Flamegraph {
     pub counts: HashMap<String, usize>,
     pub show_linenumbers: bool,
+    /// The colours to use for frames from python, native and cython code
+    pub palette: FramePalette,
+    origins: HashMap<String, FrameOrigin>,
+    /// The frame names that came from library rather than application code, when graying them out
+    libraries: HashSet<String>,
+    /// The full filename and function starting line for each frame name, where known
+    sources: HashMap<String, (String, i32)>,
+    /// Extra information to show after the title, like how many samples were collected
+    pub subtitle: Option<String>,
+    /// Collapse runs of recursive calls to the same function into a single frame
+    pub collapse_recursion: bool,
+    /// Prune frames with fewer samples than this, merging them into an '<other>' frame
+    pub min_samples: Option<usize>,
+    /// Split up python lines by the bytecode offset being executed, like 'func (file.py:10@24)'
+    pub show_bytecode_offsets: bool,
+    /// Colour frames that aren't application code (see --app-root) gray
+    pub gray_libraries: bool,
+}
+
+/// Base colours for each frame origin. Each frame gets a slightly lighter or darker shade
+/// of this depending on its name, so that neighbouring frames can still be told apart
+#[derive(Debug, Clone)]
+pub struct FramePalette {
+    pub python: (u8, u8, u8),
+    pub native: (u8, u8, u8),
+    pub cython: (u8, u8, u8),
+    pub library: (u8, u8, u8),
+}
+
+impl Default for FramePalette {
+    fn default() -> FramePalette {
+        // blue for python, green for native and orange for cython, with library code in gray
+        // when it's being separated out
+        FramePalette{python: (90, 140, 220), native: (90, 190, 100), cython: (235, 150, 60), library: (170, 170, 170)}
+    }
+}
+
+impl FramePalette {
+    fn color(&self, origin: FrameOrigin, name: &str) -> Color {
+        let base = match origin {
+            FrameOrigin::Python => self.python,
+            FrameOrigin::Native => self.native,
+            FrameOrigin::Cython => self.cython,
+        };
+        shade(base, name)
+    }
+
+    fn library_color(&self, name: &str) -> Color {
+        shade(self.library, name)
+    }
+}
+
+/// A slightly lighter or darker version of a colour, picked by a cheap hash of the frame name
+/// so that each function always gets the same shade
+fn shade((r, g, b): (u8, u8, u8), name: &str) -> Color {
+    let hash = name.bytes().fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(u32::from(b)));
+    let shade = (hash % 48) as i32 - 24;
+    let adjust = |c: u8| (i32::from(c) + shade).max(0).min(255) as u8;
+    Color{r: adjust(r), g: adjust(g), b: adjust(b)}
 }
 
 impl Flamegraph {
     pub fn new(show_linenumbers: bool) -> Flamegraph {
-        Flamegraph { counts: HashMap::new(), show_linenumbers }
+        Flamegraph { counts: HashMap::new(), show_linenumbers, palette: FramePalette::default(), origins: HashMap::new(),
+                     libraries: HashSet::new(), sources: HashMap::new(), subtitle: None, collapse_recursion: false,
+                     min_samples: None, show_bytecode_offsets: false, gray_libraries: false }
     }
 
     pub fn increment(&mut self, trace: &StackTrace) -> std::io::Result<()> {
         // convert the frame into a single ';' delimited String
+        let origins = &mut self.origins;
+        let libraries = &mut self.libraries;
+        let gray_libraries = self.gray_libraries;
+        let sources = &mut self.sources;
+        let show_linenumbers = self.show_linenumbers;
+        let collapse_recursion = self.collapse_recursion;
+        let show_bytecode_offsets = self.show_bytecode_offsets;
         let frame = trace.frames.iter().rev().map(|frame| {
             let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
-            if self.show_linenumbers && frame.line != 0 {
-                format!("{} ({}:{})", frame.name, filename, frame.line)
+            let name = if show_linenumbers && frame.line != 0 {
+                match frame.lasti.filter(|_| show_bytecode_offsets) {
+                    Some(lasti) => format!("{} ({}:{}@{})", frame.name, filename, frame.line, lasti),
+                    None => format!("{} ({}:{})", frame.name, filename, frame.line)
+                }
             } else {
                 format!("{} ({})", frame.name, filename)
+            };
+            // ';' separates frames and newlines separate stacks in the folded format, so
+            // make sure that neither shows up inside of a single frame
+            let name = name.replace(';', ":").replace('\n', " ");
+            // collapsed recursive frames are named after the function rather than the line, so
+            // also need to be able to look up where they came from by the function
+            let mut keys = vec![name.clone()];
+            if collapse_recursion && function_name(&name) != name {
+                keys.push(function_name(&name));
+            }
+            for key in keys {
+                if frame.origin != FrameOrigin::Python && !origins.contains_key(&key) {
+                    origins.insert(key.clone(), frame.origin);
+                }
+                if gray_libraries && !frame.application && !libraries.contains(&key) {
+                    libraries.insert(key.clone());
+                }
+                if let Some(first_line) = frame.first_line.filter(|_| !sources.contains_key(&key)) {
+                    sources.insert(key, (frame.filename.clone(), first_line));
+                }
             }
+            name
         }).collect::<Vec<String>>().join(";");
 
         // update counts for that frame
@@ -63,25 +159,96 @@ impl Flamegraph {
     }
 
     fn get_lines(&self) -> Vec<String> {
-        self.counts.iter().map(|(k, v)| format!("{} {}", k, v)).collect()
+        // sort the stacks so that the output is deterministic, and can be easily diffed
+        let mut lines: Vec<String> = self.stacks().iter().map(|(k, v)| format!("{} {}", k, v)).collect();
+        lines.sort();
+        lines
     }
 
-    pub fn write(&self, w: &File) -> Result<(), Error> {
+    /// The stacks to write out, after collapsing recursion and pruning small frames if asked to
+    fn stacks(&self) -> Cow<'_, HashMap<String, usize>> {
+        let mut stacks = Cow::Borrowed(&self.counts);
+        if self.collapse_recursion {
+            stacks = Cow::Owned(collapse_recursive_frames(&stacks));
+        }
+        if let Some(min_samples) = self.min_samples {
+            stacks = Cow::Owned(prune_stacks(&stacks, min_samples));
+        }
+        stacks
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut palette_map = self.get_palette_map();
+        // this version of inferno doesn't support subtitles, so show them as part of the title
+        let title = match self.subtitle.as_ref() {
+            Some(subtitle) => format!("py-spy: {}", subtitle),
+            None => "py-spy".to_owned()
+        };
         let mut opts =  Options {
             direction: Direction::Inverted,
             min_width: 1.0,
-            title: "py-spy".to_owned(),
+            title,
+            // pick the default colours from the frame names rather than at random, so that
+            // regenerating a flamegraph from the same samples gives exactly the same svg
+            hash: true,
             ..Default::default()
         };
 
+        // colour frames by where they came from, but only if we have native or library frames. Pure
+        // python profiles are left with the default colours
+        if !self.origins.is_empty() || !self.libraries.is_empty() {
+            opts.palette_map = Some(&mut palette_map);
+        }
+
         let lines = self.get_lines();
-        inferno::flamegraph::from_lines(&mut opts, lines.iter().map(|x| x.as_str()), w)
+        let mut svg = Vec::new();
+        inferno::flamegraph::from_lines(&mut opts, lines.iter().map(|x| x.as_str()), &mut svg)
             .map_err(|e| format_err!("Failed to write flamegraph: {}", e))?;
+
+        let svg = String::from_utf8(svg)?;
+        let svg = self.add_source_attributes(&svg);
+        w.write_all(svg.as_bytes())?;
         Ok(())
     }
 
-    pub fn write_raw(&self, w: &mut File) -> Result<(), Error> {
-        use std::io::Write;
+    /// Adds 'data-filename' and 'data-first-line' attributes to the group for each frame in the
+    /// svg, so that viewers can link to where the function is defined rather than just the sampled line
+    fn add_source_attributes(&self, svg: &str) -> String {
+        lazy_static! {
+            // inferno titles each frame like 'name (123 samples, 4.56%)'
+            static ref FRAME: Regex = Regex::new(r"<g>(\s*<title>([^<]*) \([\d,]+ samples?, [^)<]*\)</title>)").unwrap();
+        }
+        if self.sources.is_empty() {
+            return svg.to_owned();
+        }
+        FRAME.replace_all(svg, |caps: &Captures| {
+            let name = unescape_xml(&caps[2]);
+            match self.sources.get(uncollapsed_name(&name)) {
+                Some((filename, first_line)) => format!("<g data-filename=\"{}\" data-first-line=\"{}\">{}",
+                                                        escape_xml(filename), first_line, &caps[1]),
+                None => caps[0].to_owned()
+            }
+        }).into_owned()
+    }
+
+    /// Maps each frame name to the colour for its origin, or to gray for library frames
+    fn get_palette_map(&self) -> PaletteMap {
+        let mut palette_map = PaletteMap::default();
+        for stack in self.stacks().keys() {
+            for name in stack.split(';') {
+                let key = uncollapsed_name(name);
+                if self.libraries.contains(key) {
+                    palette_map.insert(name, self.palette.library_color(name));
+                    continue;
+                }
+                let origin = self.origins.get(key).cloned().unwrap_or(FrameOrigin::Python);
+                palette_map.insert(name, self.palette.color(origin, name));
+            }
+        }
+        palette_map
+    }
+
+    pub fn write_raw(&self, w: &mut dyn Write) -> Result<(), Error> {
         for line in self.get_lines() {
             w.write_all(line.as_bytes())?;
             w.write_all(b"\n")?;
@@ -89,3 +256,289 @@ impl Flamegraph {
         Ok(())
     }
 }
+
+/// Collapses each run of consecutive frames for the same function (ignoring line numbers) into a
+/// single frame labelled with the deepest run seen at that point in the tree, like
+/// 'parse (parser.py) [depth 12]'. Stacks that end up the same have their counts summed, so that
+/// the totals (and percentages) don't change
+fn collapse_recursive_frames(counts: &HashMap<String, usize>) -> HashMap<String, usize> {
+    // split each stack into runs of (frame, function, depth)
+    let stacks: Vec<(Vec<(&str, String, usize)>, usize)> = counts.iter().map(|(stack, count)| {
+        let mut runs: Vec<(&str, String, usize)> = Vec::new();
+        for name in stack.split(';') {
+            let function = function_name(name);
+            match runs.last_mut() {
+                Some(run) if run.1 == function => run.2 += 1,
+                _ => runs.push((name, function, 1))
+            }
+        }
+        (runs, *count)
+    }).collect();
+
+    // a collapsed frame is identified by the frames above it, so find the deepest run for each
+    let node_key = |prefix: &mut String, (name, function, depth): &(&str, String, usize)| {
+        prefix.push(';');
+        prefix.push_str(if *depth > 1 { function.as_str() } else { *name });
+    };
+    let mut max_depths: HashMap<String, usize> = HashMap::new();
+    for (runs, _) in &stacks {
+        let mut prefix = String::new();
+        for run in runs {
+            node_key(&mut prefix, run);
+            if run.2 > 1 {
+                let max_depth = max_depths.entry(prefix.clone()).or_insert(0);
+                *max_depth = std::cmp::max(*max_depth, run.2);
+            }
+        }
+    }
+
+    let mut collapsed = HashMap::new();
+    for (runs, count) in &stacks {
+        let mut prefix = String::new();
+        let frames: Vec<String> = runs.iter().map(|run| {
+            node_key(&mut prefix, run);
+            match max_depths.get(&prefix) {
+                Some(depth) => format!("{} [depth {}]", run.1, depth),
+                _ => run.0.to_owned()
+            }
+        }).collect();
+        *collapsed.entry(frames.join(";")).or_insert(0) += count;
+    }
+    collapsed
+}
+
+/// Cuts stacks off at the first frame with fewer than min_samples samples in total, replacing it
+/// (and everything it called) with an '<other>' frame. The small frames under the same parent all
+/// end up in the same '<other>' frame, so counts are kept the same
+fn prune_stacks(counts: &HashMap<String, usize>, min_samples: usize) -> HashMap<String, usize> {
+    // the total samples for each frame in the tree, keyed by the stack leading up to it
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for (stack, count) in counts {
+        for (end, _) in stack.match_indices(';').chain(std::iter::once((stack.len(), ""))) {
+            *totals.entry(&stack[..end]).or_insert(0) += count;
+        }
+    }
+
+    let mut pruned = HashMap::new();
+    for (stack, count) in counts {
+        let mut start = 0;
+        let mut kept = stack.as_str();
+        for (end, _) in stack.match_indices(';').chain(std::iter::once((stack.len(), ""))) {
+            if totals[&stack[..end]] < min_samples {
+                kept = if start == 0 { "<other>" } else { &stack[..start] };
+                break;
+            }
+            start = end + 1;
+        }
+        let kept = if kept == stack.as_str() || kept == "<other>" { kept.to_owned() } else { format!("{}<other>", kept) };
+        *pruned.entry(kept).or_insert(0) += count;
+    }
+    pruned
+}
+
+/// Describes how a profile was collected, so that it's obvious when a flamegraph is missing a lot of
+/// samples: like '1000 samples, 12 dropped (1.19%), 100 Hz, 10.12s'
+pub fn sample_summary(samples: u64, errors: u64, rate: f64, duration: std::time::Duration) -> String {
+    let dropped = 100.0 * errors as f64 / std::cmp::max(samples + errors, 1) as f64;
+    format!("{} samples, {} dropped ({:.2}%), {:.0} Hz, {:.2}s", samples, errors, dropped, rate, duration.as_secs_f64())
+}
+
+/// Strips the line number from a frame name, so 'parse (parser.py:10)' becomes 'parse (parser.py)'
+fn function_name(name: &str) -> String {
+    if let Some(colon) = name.rfind(':') {
+        let line = &name[colon + 1..];
+        // (the line can also have a bytecode offset, like ':10@24')
+        if line.len() > 1 && line.ends_with(')') && line[..line.len() - 1].bytes().all(|b| b.is_ascii_digit() || b == b'@') {
+            return format!("{})", &name[..colon]);
+        }
+    }
+    name.to_owned()
+}
+
+/// Strips the ' [depth N]' suffix from a collapsed recursive frame, returning its function name
+fn uncollapsed_name(name: &str) -> &str {
+    match name.rfind(" [depth ") {
+        Some(index) if name.ends_with(']') => &name[..index],
+        _ => name
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::{Frame, FrameOrigin};
+
+    fn trace(frames: &[(&str, i32)]) -> StackTrace {
+        let frames = frames.iter().map(|&(name, line)| {
+            Frame{name: name.to_owned(), filename: "/tmp/test.py".to_owned(), module: None,
+                  short_filename: Some("test.py".to_owned()), line, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+        }).collect();
+        StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    #[test]
+    fn test_folded_lines() {
+        let mut flamegraph = Flamegraph::new(true);
+        flamegraph.increment(&trace(&[("leaf", 4), ("<module>", 10)])).unwrap();
+        flamegraph.increment(&trace(&[("leaf", 4), ("<module>", 10)])).unwrap();
+        flamegraph.increment(&trace(&[("semi;colon", 0), ("<module>", 12)])).unwrap();
+
+        assert_eq!(flamegraph.get_lines(),
+                   vec!["<module> (test.py:10);leaf (test.py:4) 2".to_owned(),
+                        "<module> (test.py:12);semi:colon (test.py) 1".to_owned()]);
+        assert!(flamegraph.origins.is_empty());
+    }
+
+    #[test]
+    fn test_source_attributes() {
+        let mut flamegraph = Flamegraph::new(true);
+        let mut stack = trace(&[("leaf", 4), ("<module>", 10)]);
+        stack.frames[0].first_line = Some(2);
+        flamegraph.increment(&stack).unwrap();
+
+        let svg = "<g><title>all (1 samples, 100%)</title></g>\
+                   <g><title>&lt;module&gt; (test.py:10) (1 samples, 100%)</title></g>\
+                   <g><title>leaf (test.py:4) (1 samples, 100%)</title></g>";
+        assert_eq!(flamegraph.add_source_attributes(svg),
+                   "<g><title>all (1 samples, 100%)</title></g>\
+                    <g><title>&lt;module&gt; (test.py:10) (1 samples, 100%)</title></g>\
+                    <g data-filename=\"/tmp/test.py\" data-first-line=\"2\"><title>leaf (test.py:4) (1 samples, 100%)</title></g>");
+    }
+
+    #[test]
+    fn test_collapse_recursion() {
+        let mut flamegraph = Flamegraph::new(true);
+        flamegraph.collapse_recursion = true;
+        flamegraph.increment(&trace(&[("leaf", 4), ("walk", 12), ("walk", 12), ("walk", 10), ("<module>", 10)])).unwrap();
+        flamegraph.increment(&trace(&[("walk", 10), ("walk", 12), ("<module>", 10)])).unwrap();
+        flamegraph.increment(&trace(&[("walk", 10), ("<module>", 12)])).unwrap();
+
+        // the runs of 'walk' are labelled with the deepest run, and the counts are kept
+        assert_eq!(flamegraph.get_lines(),
+                   vec!["<module> (test.py:10);walk (test.py) [depth 3] 1".to_owned(),
+                        "<module> (test.py:10);walk (test.py) [depth 3];leaf (test.py:4) 1".to_owned(),
+                        "<module> (test.py:12);walk (test.py:10) 1".to_owned()]);
+
+        assert_eq!(function_name("walk (test.py:12)"), "walk (test.py)");
+        assert_eq!(function_name("walk (test.py)"), "walk (test.py)");
+        assert_eq!(function_name("walk (test.py:12@24)"), "walk (test.py)");
+        assert_eq!(uncollapsed_name("walk (test.py) [depth 3]"), "walk (test.py)");
+    }
+
+    #[test]
+    fn test_bytecode_offsets() {
+        let mut flamegraph = Flamegraph::new(true);
+        flamegraph.show_bytecode_offsets = true;
+        for lasti in &[8, 8, 14] {
+            let mut stack = trace(&[("leaf", 4), ("<module>", 10)]);
+            stack.frames[0].lasti = Some(*lasti);
+            flamegraph.increment(&stack).unwrap();
+        }
+
+        // samples on the same line are split up by the instruction being executed
+        assert_eq!(flamegraph.get_lines(),
+                   vec!["<module> (test.py:10);leaf (test.py:4@14) 1".to_owned(),
+                        "<module> (test.py:10);leaf (test.py:4@8) 2".to_owned()]);
+    }
+
+    #[test]
+    fn test_min_samples() {
+        let mut flamegraph = Flamegraph::new(false);
+        flamegraph.min_samples = Some(2);
+        for _ in 0..3 {
+            flamegraph.increment(&trace(&[("big", 0), ("<module>", 0)])).unwrap();
+        }
+        flamegraph.increment(&trace(&[("tiny", 0), ("<module>", 0)])).unwrap();
+        flamegraph.increment(&trace(&[("small", 0), ("<module>", 0)])).unwrap();
+        flamegraph.increment(&trace(&[("main", 0)])).unwrap();
+
+        // the small frames are merged into a single '<other>' frame with the same total
+        assert_eq!(flamegraph.get_lines(),
+                   vec!["<module> (test.py);<other> 2".to_owned(),
+                        "<module> (test.py);big (test.py) 3".to_owned(),
+                        "<other> 1".to_owned()]);
+    }
+
+    #[test]
+    fn test_sample_summary() {
+        assert_eq!(sample_summary(990, 10, 100.0, std::time::Duration::from_millis(10_500)),
+                   "990 samples, 10 dropped (1.00%), 100 Hz, 10.50s");
+        assert_eq!(sample_summary(0, 0, 100.0, std::time::Duration::from_secs(0)),
+                   "0 samples, 0 dropped (0.00%), 100 Hz, 0.00s");
+    }
+
+    #[test]
+    fn test_frame_colors() {
+        let mut flamegraph = Flamegraph::new(true);
+        let mut native = trace(&[("malloc", 0), ("<module>", 10)]);
+        native.frames[0].origin = FrameOrigin::Native;
+        flamegraph.increment(&native).unwrap();
+        assert_eq!(flamegraph.origins.get("malloc (test.py)"), Some(&FrameOrigin::Native));
+
+        // each origin gets a shade of its base colour, and the same name always gets the same shade
+        let palette = FramePalette::default();
+        let python = palette.color(FrameOrigin::Python, "<module> (test.py:10)");
+        assert!(python.b > python.r && python.b > python.g);
+        let native = palette.color(FrameOrigin::Native, "malloc (test.py)");
+        assert!(native.g > native.r && native.g > native.b);
+        let cython = palette.color(FrameOrigin::Cython, "f (test.pyx:10)");
+        assert!(cython.r > cython.g && cython.g > cython.b);
+        assert_eq!(palette.color(FrameOrigin::Native, "malloc (test.py)"), native);
+    }
+
+    #[test]
+    fn test_gray_libraries() {
+        let mut flamegraph = Flamegraph::new(false);
+        let mut stack = trace(&[("json_dumps", 0), ("handler", 0)]);
+        stack.frames[1].application = true;
+        flamegraph.increment(&stack).unwrap();
+        // library frames are only tracked when asked to gray them out
+        assert!(flamegraph.libraries.is_empty());
+
+        flamegraph.gray_libraries = true;
+        flamegraph.increment(&stack).unwrap();
+        assert!(flamegraph.libraries.contains("json_dumps (test.py)"));
+        assert!(!flamegraph.libraries.contains("handler (test.py)"));
+
+        let palette_map = flamegraph.get_palette_map();
+        let library = palette_map.get("json_dumps (test.py)").unwrap();
+        assert!(library.r == library.g && library.g == library.b);
+        assert_eq!(palette_map.get("handler (test.py)"), Some(FramePalette::default().color(FrameOrigin::Python, "handler (test.py)")));
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let stacks = [trace(&[("leaf", 4), ("<module>", 10)]), trace(&[("run", 0), ("<module>", 10)]),
+                      trace(&[("leaf", 0), ("a", 0), ("<module>", 10)]), trace(&[("a", 0), ("<module>", 10)])];
+        let mut forward = Flamegraph::new(true);
+        let mut backward = Flamegraph::new(true);
+        for stack in stacks.iter() {
+            forward.increment(stack).unwrap();
+        }
+        for stack in stacks.iter().rev() {
+            backward.increment(stack).unwrap();
+        }
+
+        // children are ordered by name, regardless of the order they were sampled in
+        let lines = forward.get_lines();
+        assert_eq!(lines, vec!["<module> (test.py:10);a (test.py) 1".to_owned(),
+                               "<module> (test.py:10);a (test.py);leaf (test.py) 1".to_owned(),
+                               "<module> (test.py:10);leaf (test.py:4) 1".to_owned(),
+                               "<module> (test.py:10);run (test.py) 1".to_owned()]);
+        assert_eq!(backward.get_lines(), lines);
+
+        let mut forward_svg = Vec::new();
+        forward.write(&mut forward_svg).unwrap();
+        let mut backward_svg = Vec::new();
+        backward.write(&mut backward_svg).unwrap();
+        assert!(forward_svg == backward_svg);
+    }
+}
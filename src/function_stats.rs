@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::stack_trace::{StackTrace, Frame};
+
+/// Identifies a function across samples (function name + short filename + first line)
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+pub struct FunctionKey {
+    pub name: String,
+    pub filename: String,
+    /// The line the function starts on, or 0 if this isn't known (like for native frames)
+    pub line: i32,
+}
+
+/// Own/total sample counts for a single function
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct FunctionCount {
+    /// Number of samples where the function was the leaf frame
+    pub own: u64,
+    /// Number of samples where the function was anywhere on the stack
+    pub total: u64,
+}
+
+/// Flat per-function statistics aggregated over a recording
+pub struct FunctionStats {
+    pub samples: u64,
+    pub counts: HashMap<FunctionKey, FunctionCount>,
+    /// Number of samples with a function of each name on the stack, across all files
+    name_totals: HashMap<String, u64>,
+}
+
+impl FunctionStats {
+    pub fn new() -> FunctionStats {
+        FunctionStats{samples: 0, counts: HashMap::new(), name_totals: HashMap::new()}
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        self.samples += 1;
+
+        // deduplicate so that recursive calls don't overcount the total
+        let mut seen = HashSet::new();
+        let mut seen_names = HashSet::new();
+        for (i, frame) in trace.frames.iter().enumerate() {
+            if seen_names.insert(&frame.name) {
+                *self.name_totals.entry(frame.name.clone()).or_insert(0) += 1;
+            }
+            let key = FunctionKey::new(frame);
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let entry = self.counts.entry(key).or_insert_with(FunctionCount::default);
+            entry.total += 1;
+            if i == 0 {
+                entry.own += 1;
+            }
+        }
+    }
+
+    /// Returns the percentage of samples that had a function with this name on the stack
+    pub fn percent_total(&self, name: &str) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        // the same function name can show up in multiple files, which are all counted here -
+        // but only once per sample, even if the sample has the function from several files
+        let total = self.name_totals.get(name).cloned().unwrap_or(0);
+        100.0 * total as f64 / self.samples as f64
+    }
+
+    /// Returns the n functions with the most samples, either as the leaf frame (own) or
+    /// anywhere on the stack (total)
+    pub fn top(&self, n: usize, by_total: bool) -> Vec<(&FunctionKey, &FunctionCount)> {
+        let mut counts: Vec<(&FunctionKey, &FunctionCount)> = self.counts.iter()
+            .filter(|(_, count)| if by_total { count.total > 0 } else { count.own > 0 })
+            .collect();
+        counts.sort_unstable_by(|a, b| {
+            let (first, second) = if by_total { (b.1.total.cmp(&a.1.total), b.1.own.cmp(&a.1.own)) }
+                                  else { (b.1.own.cmp(&a.1.own), b.1.total.cmp(&a.1.total)) };
+            first.then(second).then(a.0.cmp(&b.0))
+        });
+        counts.truncate(n);
+        counts
+    }
+
+    /// Returns a summary table of the top functions by own and total samples
+    pub fn summary(&self, n: usize) -> Vec<String> {
+        let percent = |count: u64| 100.0 * count as f64 / std::cmp::max(self.samples, 1) as f64;
+        let mut lines = Vec::new();
+        for &(by_total, title) in &[(false, "Top functions by self time"), (true, "Top functions by total time")] {
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            lines.push(format!("{} ({} samples):", title, self.samples));
+            lines.push(format!("{:>8} {:>8}  {}", "%Own", "%Total", "Function (filename)"));
+            for (key, count) in self.top(n, by_total) {
+                lines.push(format!("{:>7.2}% {:>7.2}%  {} ({})", percent(count.own), percent(count.total), key.name, key.filename));
+            }
+        }
+        lines
+    }
+}
+
+impl FunctionKey {
+    pub fn new(frame: &Frame) -> FunctionKey {
+        let filename = match &frame.short_filename { Some(f) => f, None => &frame.filename };
+        FunctionKey{name: frame.name.clone(), filename: filename.clone(), line: frame.first_line.unwrap_or(0)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::FrameOrigin;
+
+    fn frame(name: &str) -> Frame {
+        Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+    }
+
+    fn trace(names: &[&str]) -> StackTrace {
+        StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false,
+                   frames: names.iter().map(|name| frame(name)).collect()}
+    }
+
+    #[test]
+    fn test_function_stats() {
+        let mut stats = FunctionStats::new();
+        stats.increment(&trace(&["leaf", "recurse", "recurse", "<module>"]));
+        stats.increment(&trace(&["recurse", "<module>"]));
+        stats.increment(&trace(&["other", "<module>"]));
+        stats.increment(&trace(&["other", "<module>"]));
+
+        let recurse = stats.counts[&FunctionKey{name: "recurse".to_owned(), filename: "test.py".to_owned(), line: 0}];
+        assert_eq!(recurse, FunctionCount{own: 1, total: 2});
+
+        assert_eq!(stats.percent_total("<module>"), 100.0);
+        assert_eq!(stats.percent_total("recurse"), 50.0);
+        assert_eq!(stats.percent_total("leaf"), 25.0);
+        assert_eq!(stats.percent_total("missing"), 0.0);
+
+        let names = |top: Vec<(&FunctionKey, &FunctionCount)>| -> Vec<String> { top.iter().map(|(key, _)| key.name.clone()).collect() };
+        assert_eq!(names(stats.top(2, false)), vec!["other", "recurse"]);
+        assert_eq!(names(stats.top(2, true)), vec!["<module>", "other"]);
+
+        let summary = stats.summary(1);
+        assert_eq!(summary[0], "Top functions by self time (4 samples):");
+        assert_eq!(summary[2], "  50.00%   50.00%  other (test.py)");
+        assert_eq!(summary[6], "   0.00%  100.00%  <module> (test.py)");
+
+        // functions with the same name in different files are added together, without
+        // counting samples that have both twice
+        let mut stats = FunctionStats::new();
+        let mut helper = |filenames: &[&str]| {
+            let mut trace = trace(&[]);
+            trace.frames = filenames.iter().map(|f| Frame{filename: f.to_string(), ..frame("helper")}).collect();
+            stats.increment(&trace);
+        };
+        helper(&["a.py"]);
+        helper(&["b.py"]);
+        helper(&["a.py", "b.py"]);
+        helper(&[]);
+        assert_eq!(stats.percent_total("helper"), 75.0);
+    }
+}
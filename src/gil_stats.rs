@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use crate::stack_trace::StackTrace;
+
+/// Counts how often each thread held the GIL over a series of samples, versus how often it
+/// wanted to run (was active) but was waiting for another thread to release it
+pub struct GilStats {
+    pub samples: u64,
+    pub threads: HashMap<u64, ThreadGilCount>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ThreadGilCount {
+    pub thread_name: Option<String>,
+    /// Samples where the thread held the GIL
+    pub holding: u64,
+    /// Samples where the thread was active, but another thread held the GIL
+    pub waiting: u64,
+    /// Samples where the thread was idle
+    pub idle: u64,
+}
+
+impl ThreadGilCount {
+    fn total(&self) -> u64 {
+        self.holding + self.waiting + self.idle
+    }
+}
+
+impl GilStats {
+    pub fn new() -> GilStats {
+        GilStats{samples: 0, threads: HashMap::new()}
+    }
+
+    /// Adds the stack traces from a single sample
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        self.samples += 1;
+        for trace in traces.iter().filter(|trace| !trace.pure_native) {
+            let count = self.threads.entry(trace.thread_id).or_insert_with(ThreadGilCount::default);
+            if trace.thread_name.is_some() {
+                count.thread_name = trace.thread_name.clone();
+            }
+            match (trace.owns_gil, trace.active) {
+                (true, _) => count.holding += 1,
+                (false, true) => count.waiting += 1,
+                (false, false) => count.idle += 1,
+            }
+        }
+    }
+
+    /// Returns a line for each thread, ordered by how often it held the GIL
+    pub fn table(&self) -> Vec<String> {
+        let mut threads: Vec<(&u64, &ThreadGilCount)> = self.threads.iter().collect();
+        threads.sort_by(|a, b| b.1.holding.cmp(&a.1.holding).then(a.0.cmp(b.0)));
+
+        let percent = |count: u64, total: u64| 100.0 * count as f64 / std::cmp::max(total, 1) as f64;
+        let mut lines = vec![format!("{:>18} {:>9} {:>9} {:>9} {:>8}  Name", "Thread", "%GIL", "%Waiting", "%Idle", "Samples")];
+        for (thread_id, count) in threads {
+            let total = count.total();
+            lines.push(format!("{:>#18x} {:>8.2}% {:>8.2}% {:>8.2}% {:>8}  {}", thread_id,
+                               percent(count.holding, total), percent(count.waiting, total), percent(count.idle, total),
+                               total, count.thread_name.as_ref().map_or("", |name| name.as_str())));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(thread_id: u64, owns_gil: bool, active: bool) -> StackTrace {
+        StackTrace{thread_id, os_thread_id: None, active, owns_gil, thread_name: None, interpreter_id: None, pure_native: false, frames: Vec::new()}
+    }
+
+    #[test]
+    fn test_gil_stats() {
+        let mut stats = GilStats::new();
+        stats.increment(&[trace(1, true, true), trace(2, false, true)]);
+        stats.increment(&[trace(1, true, true), trace(2, false, false)]);
+        stats.increment(&[trace(1, false, true), trace(2, true, true)]);
+
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.threads[&1], ThreadGilCount{thread_name: None, holding: 2, waiting: 1, idle: 0});
+        assert_eq!(stats.threads[&2], ThreadGilCount{thread_name: None, holding: 1, waiting: 1, idle: 1});
+
+        let table = stats.table();
+        assert_eq!(table.len(), 3);
+        assert!(table[1].trim_start().starts_with("0x1 "));
+        assert!(table[1].contains("66.67%    33.33%"));
+    }
+}
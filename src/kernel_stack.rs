@@ -0,0 +1,52 @@
+use remoteprocess::{Pid, Tid};
+
+use crate::stack_trace::{Frame, FrameOrigin};
+
+/// The module that kernel frames are labelled with
+pub const KERNEL_MODULE: &str = "[kernel]";
+
+/// Reads the kernel stack of a thread from /proc/<pid>/task/<tid>/stack, innermost frame first.
+/// This needs root (or CAP_SYS_ADMIN) on most systems, and fails otherwise
+pub fn read_kernel_stack(pid: Pid, tid: Tid) -> std::io::Result<Vec<Frame>> {
+    let contents = std::fs::read_to_string(format!("/proc/{}/task/{}/stack", pid, tid))?;
+    Ok(parse_kernel_stack(&contents))
+}
+
+/// Parses the contents of a /proc stack file, which has a line like '[<0>] do_select+0x5d1/0x790'
+/// for each frame (with the address hidden on newer kernels)
+fn parse_kernel_stack(contents: &str) -> Vec<Frame> {
+    contents.lines().filter_map(|line| {
+        let symbol = match line.find("] ") {
+            Some(index) => &line[index + 2..],
+            None => line
+        };
+        let function = symbol.split('+').next()?.trim();
+        // unresolved addresses (like the 0xffffffffffffffff terminating older stacks) aren't useful
+        if function.is_empty() || function.starts_with("0x") {
+            return None;
+        }
+        Some(Frame{name: function.to_owned(), filename: KERNEL_MODULE.to_owned(), module: Some(KERNEL_MODULE.to_owned()),
+                   short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false})
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel_stack() {
+        let stack = "[<0>] do_select+0x5d1/0x790\n\
+                     [<0>] core_sys_select+0x1d2/0x3a0\n\
+                     [<ffffffff8110e35c>] __x64_sys_select+0xb9/0x100\n\
+                     [<0>] entry_SYSCALL_64_after_hwframe+0x44/0xa9\n\
+                     [<ffffffffffffffff>] 0xffffffffffffffff\n";
+        let frames = parse_kernel_stack(stack);
+        let names: Vec<&str> = frames.iter().map(|frame| frame.name.as_str()).collect();
+        assert_eq!(names, vec!["do_select", "core_sys_select", "__x64_sys_select", "entry_SYSCALL_64_after_hwframe"]);
+        assert_eq!(frames[0].module, Some(KERNEL_MODULE.to_owned()));
+        assert_eq!(frames[0].origin, FrameOrigin::Native);
+
+        assert!(parse_kernel_stack("").is_empty());
+    }
+}
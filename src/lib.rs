@@ -24,6 +24,32 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! To also get the native stack of each thread (merged in with the python frames), set
+//! `config.native = true` before creating the PythonSpy. Frames from native code are tagged
+//! with `FrameOrigin::Native`, and statistics about how the stacks were unwound are available
+//! from `spy.native`.
+//!
+//! For a one-off snapshot of a process, [`sample`](fn.sample.html) does this in a single call.
+//!
+//! To sample a process repeatedly (like `py-spy record` does), wrap the PythonSpy in a
+//! [`Sampler`](struct.Sampler.html), which is an iterator that yields the stack traces for
+//! each sample at the requested rate, and stops once the process exits:
+//!
+//! ```rust,no_run
+//! fn count_samples(pid: py_spy::Pid) -> Result<(), failure::Error> {
+//!     let mut process = py_spy::PythonSpy::new(pid, &py_spy::Config::default())?;
+//!     let (mut samples, mut errors) = (0, 0);
+//!     for traces in py_spy::Sampler::new(&mut process, 100.0).take(1000) {
+//!         match traces {
+//!             Ok(_) => samples += 1,
+//!             Err(_) => errors += 1
+//!         }
+//!     }
+//!     println!("{} samples, {} errors", samples, errors);
+//!     Ok(())
+//! }
+//! ```
 
 #[macro_use]
 extern crate clap;
@@ -44,8 +70,14 @@ extern crate regex;
 #[cfg(windows)]
 extern crate winapi;
 extern crate cpp_demangle;
+extern crate rustc_demangle;
 extern crate rand;
 extern crate remoteprocess;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(unwind, target_os="linux"))]
+extern crate serde_json;
 
 mod config;
 mod binary_parser;
@@ -53,16 +85,37 @@ mod binary_parser;
 mod cython;
 #[cfg(unwind)]
 mod native_stack_trace;
+#[cfg(unwind)]
+mod perf_map;
+#[cfg(target_os="linux")]
+mod kernel_stack;
 mod python_bindings;
+mod python_data_access;
+mod python_asyncio;
+mod python_context;
 mod python_interpreters;
 mod python_spy;
+mod python_threading;
+mod sampler;
 mod stack_trace;
+mod timer;
 mod utils;
 mod version;
 
-pub use python_spy::PythonSpy;
+pub use python_spy::{AttachError, PythonSpy};
+pub use sampler::Sampler;
 pub use config::Config;
 pub use stack_trace::StackTrace;
 pub use stack_trace::Frame;
+pub use stack_trace::FrameOrigin;
 pub use remoteprocess::Pid;
+pub use version::Version;
+#[cfg(unwind)]
+pub use native_stack_trace::{NativeStack, UnwindStats};
+
+/// Gets the stack traces for each thread of a python process. This attaches to the process for
+/// just this call, so when sampling repeatedly create a PythonSpy and reuse it instead
+pub fn sample(pid: Pid, config: &Config) -> Result<Vec<StackTrace>, failure::Error> {
+    PythonSpy::new(pid, config)?.get_stack_traces()
+}
 
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{Level, Log, Metadata, Record};
+use serde_json::{Map, Value};
+
+use crate::config::LogFormat;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Sends log records to env_logger (so RUST_LOG still picks what gets logged), unless logging
+/// has been switched to JSON with --log-format json
+struct Logger {
+    text: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.text.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.text.matches(record) {
+            return;
+        }
+        if json() {
+            write_json(record.level(), &record.args().to_string(), &[("target", Value::from(record.target()))]);
+        } else {
+            self.text.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.text.flush();
+    }
+}
+
+/// Installs the logger. Logging starts out as text, since the log format isn't known until
+/// the command line has been parsed
+pub fn init() {
+    let text = env_logger::Builder::from_default_env().build();
+    let max_level = text.filter();
+    if log::set_boxed_logger(Box::new(Logger{text})).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::json, Ordering::Relaxed);
+}
+
+/// Whether warnings and errors should be written as JSON lines
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Reports a warning or error to the user on stderr. With --log-format json this is written as a
+/// JSON object with the level and message, along with the context (like the pid) as extra fields
+pub fn report(level: Level, message: &str, context: &[(&str, Value)]) {
+    if json() {
+        write_json(level, message, context);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+fn write_json(level: Level, message: &str, context: &[(&str, Value)]) {
+    let mut line = Map::new();
+    line.insert("level".to_owned(), Value::from(level.to_string().to_lowercase()));
+    line.insert("message".to_owned(), Value::from(message));
+    for (key, value) in context {
+        line.insert((*key).to_owned(), value.clone());
+    }
+    eprintln!("{}", Value::Object(line));
+}
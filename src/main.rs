@@ -5,6 +5,7 @@ extern crate ctrlc;
 extern crate env_logger;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate goblin;
 extern crate indicatif;
 extern crate inferno;
@@ -24,6 +25,7 @@ extern crate termios;
 #[cfg(windows)]
 extern crate winapi;
 extern crate cpp_demangle;
+extern crate rustc_demangle;
 extern crate rand;
 extern crate serde;
 #[macro_use]
@@ -33,37 +35,83 @@ extern crate serde_json;
 extern crate remoteprocess;
 
 mod config;
+#[cfg(all(unwind, target_os="linux"))]
+mod address_dump;
 mod binary_parser;
 #[cfg(unwind)]
 mod cython;
+#[cfg(target_os="linux")]
+mod coredump;
 #[cfg(unwind)]
 mod native_stack_trace;
+#[cfg(unwind)]
+mod perf_map;
+#[cfg(target_os="linux")]
+mod kernel_stack;
 mod python_bindings;
+mod python_data_access;
+mod python_asyncio;
+mod python_context;
 mod python_interpreters;
 mod python_spy;
+mod python_threading;
 mod stack_trace;
+#[cfg(target_os="linux")]
+mod subprocesses;
+mod aggregate;
+mod chrometrace;
 mod console_viewer;
 mod flamegraph;
+mod function_stats;
+mod gil_stats;
+mod logging;
+mod merge;
+mod ndjson;
+mod pprof;
+mod prometheus;
+mod pstats;
+mod sampler;
 mod speedscope;
+mod stalls;
+mod timeline;
 mod timer;
 mod utils;
 mod version;
 
-use std::io::Read;
+use std::io::{Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use failure::Error;
+use log::Level;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
-use python_spy::PythonSpy;
-use stack_trace::{StackTrace, Frame};
+use python_spy::{AttachError, PythonSpy};
+use sampler::{Sampler, process_exitted, sample_with_retry};
+use stack_trace::{StackTrace, Frame, FrameOrigin, sort_traces};
 use console_viewer::ConsoleViewer;
-use config::{Config, FileFormat, RecordDuration};
+use config::{Aggregate, Config, FileFormat, RecordDuration};
+use function_stats::FunctionStats;
+
+/// The json written out by 'dump --json', along with which version of the format it is
+#[derive(Serialize)]
+struct JsonDump<'a> {
+    py_spy_format_version: u32,
+    py_spy_version: &'static str,
+    traces: &'a [StackTrace],
+}
+
+impl<'a> JsonDump<'a> {
+    fn new(traces: &'a [StackTrace]) -> JsonDump<'a> {
+        JsonDump{py_spy_format_version: stack_trace::FORMAT_VERSION, py_spy_version: env!("CARGO_PKG_VERSION"), traces}
+    }
+}
 
 fn print_traces(traces: &[StackTrace], show_idle: bool) {
     use console::style;
-    for trace in traces.iter().rev() {
+    for trace in traces {
         if !show_idle && !trace.active {
             continue;
         }
@@ -79,7 +127,19 @@ fn print_traces(traces: &[StackTrace], show_idle: bool) {
             format!("0x{:#X}", trace.thread_id)
         };
 
-        println!("Thread {} ({})", style(thread_id).bold().yellow(), trace.status_str());
+        let mut status = if trace.pure_native {
+            format!("{}, native only", trace.status_str())
+        } else {
+            trace.status_str().to_owned()
+        };
+        if let Some(id) = trace.interpreter_id.filter(|&id| id != 0) {
+            status = format!("{}, sub-interpreter {}", status, id);
+        }
+
+        match trace.thread_name.as_ref() {
+            Some(name) => println!("Thread {} ({}): \"{}\"", style(thread_id).bold().yellow(), status, name),
+            None => println!("Thread {} ({})", style(thread_id).bold().yellow(), status)
+        }
         for frame in &trace.frames {
             let filename = match &frame.short_filename { Some(f) => &f, None => &frame.filename };
             if frame.line != 0 {
@@ -87,12 +147,83 @@ fn print_traces(traces: &[StackTrace], show_idle: bool) {
             } else {
                 println!("\t {} ({})", style(&frame.name).green(), style(&filename).cyan());
             }
+
+            if let Some(locals) = &frame.locals {
+                for (name, value) in locals {
+                    println!("\t\t {} = {}", style(name).bold(), value);
+                }
+            }
         }
     }
 }
 
-fn process_exitted(process: &remoteprocess::Process) -> bool {
-    process.exe().is_err()
+/// Returns the host pid of the process that has the pid container_pid inside of the pid namespace
+/// of another process (like a container's init process)
+#[cfg(target_os="linux")]
+fn find_container_process(pid: remoteprocess::Pid, container_pid: remoteprocess::Pid) -> Result<remoteprocess::Pid, Error> {
+    let namespace = std::fs::read_link(format!("/proc/{}/ns/pid", pid))
+        .map_err(|e| format_err!("Failed to get the pid namespace of process {}: {}", pid, e))?;
+
+    for candidate in remoteprocess::processes()? {
+        // processes can exit while we're looking at them, so skip any we can't read
+        if std::fs::read_link(format!("/proc/{}/ns/pid", candidate)).ok().as_ref() != Some(&namespace) {
+            continue;
+        }
+        let status = match std::fs::read_to_string(format!("/proc/{}/status", candidate)) {
+            Ok(status) => status,
+            Err(_) => continue
+        };
+        if utils::namespace_pid(&status) == Some(container_pid) {
+            info!("Process {} in the pid namespace of {} is process {} on the host", container_pid, pid, candidate);
+            return Ok(candidate);
+        }
+    }
+    Err(format_err!("No process has pid {} in the pid namespace of process {}", container_pid, pid))
+}
+
+/// Returns the pid of the only process whose executable name or commandline matches a regex
+#[cfg(target_os="linux")]
+fn find_process_by_name(pattern: &str) -> Result<remoteprocess::Pid, Error> {
+    let regex = regex::Regex::new(pattern)?;
+
+    // our own commandline (and that of sudo if we were launched through it) contains the
+    // pattern, so don't consider those as potential matches
+    let ignored = [std::process::id() as remoteprocess::Pid, unsafe { libc::getppid() }];
+
+    let mut matches = Vec::new();
+    for pid in remoteprocess::processes()? {
+        if ignored.contains(&pid) {
+            continue;
+        }
+
+        // processes can exit while we're looking at them, or we might not have permissions
+        // to read their details - so just skip over those
+        let process = match remoteprocess::Process::new(pid) {
+            Ok(process) => process,
+            Err(_) => continue
+        };
+        let exe = process.exe().ok().and_then(|exe| {
+            std::path::Path::new(&exe).file_name().map(|name| name.to_string_lossy().to_string())
+        });
+        let cmdline = process.cmdline().map(|args| args.join(" ")).unwrap_or_default();
+
+        let exe_matches = exe.map(|exe| regex.is_match(&exe)).unwrap_or(false);
+        if exe_matches || (!cmdline.is_empty() && regex.is_match(cmdline.trim_end())) {
+            matches.push((pid, cmdline));
+        }
+    }
+
+    match matches.len() {
+        0 => Err(format_err!("No processes found matching '{}'", pattern)),
+        1 => Ok(matches[0].0),
+        _ => {
+            eprintln!("Multiple processes match '{}':", pattern);
+            for (pid, cmdline) in &matches {
+                eprintln!("\t{}\t{}", pid, cmdline);
+            }
+            Err(format_err!("Found {} processes matching '{}', use --pid to pick one of them", matches.len(), pattern))
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -108,21 +239,75 @@ fn permission_denied(err: &Error) -> bool {
     })
 }
 
+// Exit codes for the failures that scripts running py-spy are most likely to want to handle
+// differently (these are documented in the README)
+const EXIT_ERROR: i32 = 1;
+const EXIT_NO_PYTHON: i32 = 2;
+const EXIT_UNSUPPORTED_VERSION: i32 = 3;
+#[cfg(unix)]
+const EXIT_PERMISSION_DENIED: i32 = 4;
+const EXIT_PROCESS_EXITED: i32 = 5;
+
+/// Returns the exit code for an error
+fn exit_code(err: &Error) -> i32 {
+    #[cfg(unix)]
+    {
+        if permission_denied(err) {
+            return EXIT_PERMISSION_DENIED;
+        }
+    }
+
+    for cause in err.iter_chain() {
+        match cause.downcast_ref::<AttachError>() {
+            Some(AttachError::NoPython(_)) => return EXIT_NO_PYTHON,
+            Some(AttachError::UnsupportedVersion(_)) => return EXIT_UNSUPPORTED_VERSION,
+            Some(AttachError::ProcessExited(_)) => return EXIT_PROCESS_EXITED,
+            None => {}
+        }
+    }
+    EXIT_ERROR
+}
+
+/// Names the kind of error for --log-format json, matching the exit codes
+fn error_kind(err: &Error) -> &'static str {
+    match exit_code(err) {
+        EXIT_NO_PYTHON => "no_python",
+        EXIT_UNSUPPORTED_VERSION => "unsupported_version",
+        EXIT_PROCESS_EXITED => "process_exited",
+        #[cfg(unix)]
+        EXIT_PERMISSION_DENIED => "permission_denied",
+        _ => "error"
+    }
+}
+
 fn sample_console(process: &mut PythonSpy,
                   display: &str,
                   config: &Config) -> Result<(), Error> {
     let rate = config.sampling_rate;
     let mut console = ConsoleViewer::new(config.show_line_numbers, display,
                                          &format!("{}", process.version),
-                                         1.0 / rate as f64)?;
+                                         1.0 / rate as f64,
+                                         config.color.force())?;
+    let focus = config.focus.as_ref().map(|focus| regex::Regex::new(focus)).transpose()?;
 
     for sleep in timer::Timer::new(rate as f64) {
         if let Err(elapsed) = sleep {
             console.increment_late_sample(elapsed);
         }
 
-        match process.get_stack_traces() {
-            Ok(traces) => {
+        match sample_with_retry(process) {
+            Ok(mut traces) => {
+                if let Some(focus) = focus.as_ref() {
+                    traces = traces.into_iter().filter_map(|mut trace| {
+                        trace.frames = aggregate::focus_frames(&trace.frames, focus)?;
+                        Some(trace)
+                    }).collect();
+                }
+                if config.aggregate == Aggregate::module {
+                    for trace in traces.iter_mut() {
+                        trace.frames = aggregate::aggregate_by_module(&trace.frames);
+                    }
+                }
                 console.increment(&traces)?;
             },
             Err(err) => {
@@ -139,27 +324,156 @@ fn sample_console(process: &mut PythonSpy,
     Ok(())
 }
 
+fn serve_metrics(process: &mut PythonSpy, addr: &str, config: &Config) -> Result<(), Error> {
+    let metrics = Arc::new(Mutex::new(prometheus::Metrics::new()));
+    prometheus::serve(addr, metrics.clone())?;
+    println!("Sampling process {} times a second. Serving metrics at http://{}/metrics", config.sampling_rate, addr);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })?;
+
+    for _ in timer::Timer::new(config.sampling_rate as f64) {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match sample_with_retry(process) {
+            Ok(traces) => {
+                // free-threaded builds of python don't have a GIL, so count all running threads instead
+                let traces: Vec<StackTrace> = traces.into_iter()
+                    .filter(|trace| config.include_idle || trace.active)
+                    .filter(|trace| !config.gil_only || trace.owns_gil || (process.version.free_threaded && trace.active))
+                    .collect();
+                metrics.lock().map_err(|_| format_err!("metrics lock poisoned"))?.increment(&traces);
+            },
+            Err(err) => {
+                if process_exitted(&process.process) {
+                    println!("process {} ended", process.pid);
+                    break;
+                }
+                warn!("Failed to get stack trace {:?}", err);
+                metrics.lock().map_err(|_| format_err!("metrics lock poisoned"))?.increment_error();
+            }
+        }
+    }
+    Ok(())
+}
+
 pub trait Recorder {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error>;
-    fn write(&self, w: &mut std::fs::File) -> Result<(), Error>;
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error>;
+
+    /// Whether idle samples should always be recorded, even without --idle. This is for
+    /// formats that tag each sample as active or idle, and can show either view from the same data
+    fn records_idle(&self) -> bool { false }
+
+    /// Whether sample should be called at each sample, for formats that need to know which
+    /// stack traces were recorded together rather than just counting each trace
+    fn records_samples(&self) -> bool { false }
+
+    /// Called before writing with the rate that samples were actually taken at, if that differs
+    /// from the requested rate (because of --auto-rate), for formats that estimate time from samples
+    fn set_sampling_rate(&mut self, _rate: f64) {}
+
+    /// Called with all the stack traces recorded from a process at each sample (along with when
+    /// the sample was taken), for formats that stream out each sample as it happens or show
+    /// how stacks change over time
+    fn sample(&mut self, _pid: remoteprocess::Pid, _time: SystemTime, _traces: &[StackTrace]) -> Result<(), Error> { Ok(()) }
+
+    /// Called before writing with a description of how the samples were collected (--sample-stats),
+    /// for formats that can show it alongside the profile
+    fn set_subtitle(&mut self, _subtitle: &str) {}
 }
 
 impl Recorder for speedscope::Stats {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.record(trace)?)
+    fn increment(&mut self, _trace: &StackTrace) -> Result<(), Error> {
+        Ok(())
     }
-    fn write(&self, w: &mut std::fs::File) -> Result<(), Error> {
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.write(w)
     }
+    fn records_idle(&self) -> bool { true }
+    fn records_samples(&self) -> bool { true }
+    fn sample(&mut self, _pid: remoteprocess::Pid, time: SystemTime, traces: &[StackTrace]) -> Result<(), Error> {
+        for trace in traces {
+            self.record(trace, time)?;
+        }
+        Ok(())
+    }
+    fn set_sampling_rate(&mut self, rate: f64) {
+        self.set_sampling_rate(rate)
+    }
 }
 
 impl Recorder for flamegraph::Flamegraph {
     fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
         Ok(self.increment(trace)?)
     }
-    fn write(&self, w: &mut std::fs::File) -> Result<(), Error> {
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn set_subtitle(&mut self, subtitle: &str) {
+        self.subtitle = Some(subtitle.to_owned());
+    }
+}
+
+impl Recorder for pstats::PStats {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace);
+        Ok(())
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn set_sampling_rate(&mut self, rate: f64) {
+        self.sampling_rate = rate;
+    }
+}
+
+impl Recorder for pprof::PProf {
+    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        self.increment(trace);
+        Ok(())
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.write(w)
     }
+    fn set_sampling_rate(&mut self, rate: f64) {
+        self.sampling_rate = rate;
+    }
+}
+
+impl Recorder for ndjson::NDJson {
+    fn increment(&mut self, _trace: &StackTrace) -> Result<(), Error> {
+        Ok(())
+    }
+    fn write(&self, _w: &mut dyn Write) -> Result<(), Error> {
+        Ok(())
+    }
+    fn records_samples(&self) -> bool { true }
+    fn sample(&mut self, pid: remoteprocess::Pid, time: SystemTime, traces: &[StackTrace]) -> Result<(), Error> {
+        self.write_sample(pid, time, traces)
+    }
+}
+
+impl Recorder for chrometrace::ChromeTrace {
+    fn increment(&mut self, _trace: &StackTrace) -> Result<(), Error> {
+        Ok(())
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+    fn records_samples(&self) -> bool { true }
+    fn sample(&mut self, pid: remoteprocess::Pid, time: SystemTime, traces: &[StackTrace]) -> Result<(), Error> {
+        self.increment(pid, time, traces);
+        Ok(())
+    }
+    fn set_sampling_rate(&mut self, rate: f64) {
+        self.set_sampling_rate(rate)
+    }
 }
 
 pub struct RawFlamegraph(flamegraph::Flamegraph);
@@ -169,31 +483,104 @@ impl Recorder for RawFlamegraph {
         Ok(self.0.increment(trace)?)
     }
 
-    fn write(&self, w: &mut std::fs::File) -> Result<(), Error> {
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
         self.0.write_raw(w)
     }
 }
 
-fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error> {
-    let mut output: Box<dyn Recorder> = match config.format {
-        Some(FileFormat::flamegraph) => Box::new(flamegraph::Flamegraph::new(config.show_line_numbers)),
-        Some(FileFormat::speedscope) =>  Box::new(speedscope::Stats::new()),
-        Some(FileFormat::raw) => Box::new(RawFlamegraph(flamegraph::Flamegraph::new(config.show_line_numbers))),
-        None => return Err(format_err!("A file format is required to record samples"))
-    };
+fn new_flamegraph(config: &Config) -> flamegraph::Flamegraph {
+    let mut flamegraph = flamegraph::Flamegraph::new(config.show_line_numbers);
+    flamegraph.collapse_recursion = config.collapse_recursion;
+    flamegraph.show_bytecode_offsets = config.bytecode_offsets;
+    flamegraph.min_samples = config.min_samples;
+    flamegraph.gray_libraries = config.gray_libraries;
+    flamegraph
+}
+
+/// Writes out the samples recorded so far. When atomic is set the output is written to a temporary
+/// file that's renamed over the output file, so that the output file is never left half written
+fn write_recording(output: &dyn Recorder, filename: &str, compress: bool, atomic: bool) -> Result<(), Error> {
+    let path = if atomic { format!("{}.tmp", filename) } else { filename.to_owned() };
+    {
+        let mut out_file = utils::create_output(&path)?;
+        if compress {
+            let mut encoder = GzEncoder::new(out_file, Compression::default());
+            output.write(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        } else {
+            output.write(&mut out_file)?;
+            out_file.flush()?;
+        }
+    }
+    if atomic {
+        std::fs::rename(&path, filename)?;
+    }
+    Ok(())
+}
 
+fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error> {
     let filename = match config.filename.as_ref() {
         Some(filename) => filename,
         None => return Err(format_err!("A filename is required to record samples"))
     };
 
+    let mut output: Box<dyn Recorder> = match config.format {
+        Some(FileFormat::flamegraph) => Box::new(new_flamegraph(config)),
+        Some(FileFormat::speedscope) =>  Box::new(speedscope::Stats::new(config.include_idle, config.sampling_rate)),
+        Some(FileFormat::raw) => Box::new(RawFlamegraph(new_flamegraph(config))),
+        Some(FileFormat::pstats_text) => Box::new(pstats::PStats::new(config.sampling_rate)),
+        Some(FileFormat::pprof) => Box::new(pprof::PProf::new(config.sampling_rate, config.show_line_numbers)),
+        Some(FileFormat::ndjson) => Box::new(ndjson::NDJson::new(filename)?),
+        Some(FileFormat::chrometrace) => Box::new(chrometrace::ChromeTrace::new(config.sampling_rate, config.show_line_numbers)),
+        None => return Err(format_err!("A file format is required to record samples"))
+    };
+    let streaming = config.format == Some(FileFormat::ndjson);
+    if streaming && config.compress {
+        return Err(format_err!("Can't compress ndjson output, since it is written out as samples are taken"));
+    }
+    // pprof profiles are always gzipped
+    let compress = config.compress && config.format != Some(FileFormat::pprof);
+    let records_samples = output.records_samples();
+
+    let checkpoint_interval = config.checkpoint_interval.map(Duration::from_secs);
+    if checkpoint_interval.is_some() {
+        if streaming {
+            return Err(format_err!("Can't checkpoint ndjson output, since it is written out as samples are taken"));
+        }
+        if filename == "-" {
+            return Err(format_err!("Can't checkpoint output written to stdout"));
+        }
+    }
+
+    // when writing to stdout, keep stdout clean by writing any status messages to stderr
+    let to_stdout = filename == "-";
+    macro_rules! status {
+        ($($arg:tt)*) => { if to_stdout { eprintln!($($arg)*) } else { println!($($arg)*) } }
+    }
+    let hide_progress = config.hide_progess || to_stdout;
+
     let mut max_samples = None;
+    // with --auto-rate the number of samples we'll take isn't known, so stop at a deadline instead
+    let mut deadline = None;
     use indicatif::ProgressBar;
 
-    let progress = match (config.hide_progess, &config.duration) {
+    if let Some(sample_limit) = config.sample_limit {
+        max_samples = Some(sample_limit);
+    } else if let RecordDuration::Seconds(sec) = config.duration {
+        max_samples = Some(sec * config.sampling_rate);
+        if config.auto_rate {
+            deadline = Some(Duration::from_secs(sec));
+        }
+    }
+
+    let progress = match (hide_progress, &config.duration) {
         (true, _) => ProgressBar::hidden(),
+        (false, _) if config.sample_limit.is_some() => {
+            println!("Sampling process {} times a second until {} samples are collected. Press Control-C to exit.",
+                config.sampling_rate, max_samples.unwrap());
+            ProgressBar::new(max_samples.unwrap())
+        }
         (false, RecordDuration::Seconds(sec)) => {
-            max_samples = Some(sec * config.sampling_rate);
             println!("Sampling process {} times a second for {} seconds. Press Control-C to exit.",
                 config.sampling_rate, sec);
             ProgressBar::new(max_samples.unwrap())
@@ -207,7 +594,9 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
 
     let mut errors = 0;
     let mut samples = 0;
-    println!();
+    // whether any thread matched the --thread-id filter
+    let mut matched_threads = false;
+    status!();
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -217,9 +606,33 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
 
     let mut exit_message = "";
 
-    for sleep in timer::Timer::new(config.sampling_rate as f64) {
-        if let Err(delay) = sleep {
-            if delay > Duration::from_secs(1) && !config.hide_progess {
+    // only aggregate per function statistics if we need them
+    let mut function_stats = if config.assertions.is_empty() && !config.summary { None } else { Some(FunctionStats::new()) };
+
+    // subprocesses are each sampled in their own thread, and merged in with the samples here
+    #[cfg(target_os="linux")]
+    let mut subprocesses = if config.subprocesses { Some(subprocesses::Subprocesses::new(process.pid, config)) } else { None };
+
+    let focus = config.focus.as_ref().map(|focus| regex::Regex::new(focus)).transpose()?;
+
+    let mut stall_detector = config.stall_threshold.map(|ms| stalls::StallDetector::new(Duration::from_millis(ms)));
+
+    let start = Instant::now();
+    let mut last_checkpoint = start;
+
+    let mut sampler = Sampler::new(process, config.sampling_rate as f64);
+    if config.auto_rate {
+        sampler = sampler.with_max_overhead(config.max_overhead);
+    }
+    if let Some(jitter) = config.jitter {
+        sampler = sampler.with_jitter(jitter, config.seed);
+    }
+    let pid = sampler.spy().pid;
+    let free_threaded = sampler.spy().version.free_threaded;
+
+    while let Some(result) = sampler.next() {
+        if let Some(delay) = sampler.behind() {
+            if delay > Duration::from_secs(1) && !hide_progress && !config.auto_rate {
                 let term = console::Term::stdout();
                 term.move_cursor_up(2)?;
                 println!("{:.2?} behind in sampling, results may be inaccurate. Try reducing the sampling rate.", delay);
@@ -232,44 +645,106 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
             break;
         }
 
-        match process.get_stack_traces() {
+        match result {
             Ok(traces) => {
-                for mut trace in traces {
-                    if !(config.include_idle || trace.active) {
+                matched_threads |= !traces.is_empty();
+                let stalled = stall_detector.as_mut().and_then(|detector| detector.increment(Instant::now(), &traces)).is_some();
+                let traces = traces.into_iter().map(|trace| (pid, trace));
+                #[cfg(target_os="linux")]
+                let traces = traces.chain(subprocesses.as_mut().map(|s| s.poll()).unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|(pid, traces)| traces.into_iter().map(move |trace| (pid, trace))));
+
+                // the traces for each process, for formats that record each sample
+                let mut sample: Vec<(remoteprocess::Pid, Vec<StackTrace>)> = vec![(pid, Vec::new())];
+
+                for (pid, mut trace) in traces {
+                    let show = config.include_idle || trace.active;
+                    if !(show || output.records_idle()) {
                         continue;
                     }
 
-                    if config.gil_only && !trace.owns_gil {
+                    // free-threaded builds of python don't have a GIL, so show all running threads instead
+                    let holds_gil = if free_threaded { trace.active } else { trace.owns_gil };
+                    if config.gil_only && !holds_gil {
                         continue;
                     }
 
+                    if let Some(focus) = focus.as_ref() {
+                        match aggregate::focus_frames(&trace.frames, focus) {
+                            Some(frames) => trace.frames = frames,
+                            None => continue
+                        }
+                    }
+
+                    if config.aggregate == Aggregate::module {
+                        trace.frames = aggregate::aggregate_by_module(&trace.frames);
+                    }
+
                     if config.include_thread_ids {
-                        trace.frames.push(Frame{name: format!("thread {}", trace.thread_id),
+                        let name = match trace.thread_name.as_ref() {
+                            Some(thread_name) => format!("thread {} ({})", trace.thread_id, thread_name),
+                            None => format!("thread {}", trace.thread_id)
+                        };
+                        trace.frames.push(Frame{name,
+                            filename: String::from(""),
+                            module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false});
+                    }
+
+                    if config.subprocesses {
+                        trace.frames.push(Frame{name: format!("process {}", pid),
                             filename: String::from(""),
-                            module: None, short_filename: None, line: 0});
+                            module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false});
+                    }
+
+                    if stalled {
+                        trace.frames.push(Frame{name: String::from("<after stall>"),
+                            filename: String::from(""),
+                            module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false});
                     }
 
                     output.increment(&trace)?;
+                    if let Some(stats) = function_stats.as_mut().filter(|_| show) {
+                        stats.increment(&trace);
+                    }
+
+                    if records_samples {
+                        // traces from the same process are always next to each other
+                        match sample.last_mut() {
+                            Some((last, traces)) if *last == pid => traces.push(trace),
+                            _ => sample.push((pid, vec![trace]))
+                        }
+                    }
+                }
+
+                if records_samples {
+                    // subprocesses are sampled on their own threads, so this is approximate for them
+                    let time = sampler.spy().sample_time.unwrap_or_else(SystemTime::now);
+                    for (pid, traces) in &sample {
+                        output.sample(*pid, time, traces)?;
+                    }
                 }
 
                 samples += 1;
-                if let Some(max_samples) = max_samples {
+                if let Some(max_samples) = max_samples.filter(|_| deadline.is_none()) {
                     if samples >= max_samples {
                         break;
                     }
                 }
             },
             Err(e) => {
-                if process_exitted(&process.process) {
-                    exit_message = "Stopped sampling because the process ended";
-                    break;
-                } else {
-                    warn!("Failed to get stack trace {:?}", e);
-                    errors += 1;
+                // with --require-cython-maps, missing source maps stop the recording rather than dropping samples
+                #[cfg(unwind)]
+                {
+                    if e.iter_chain().any(|cause| cause.downcast_ref::<cython::MissingSourceMap>().is_some()) {
+                        return Err(e);
+                    }
                 }
+                warn!("Failed to get stack trace {:?}", e);
+                errors += 1;
             }
         }
-        if config.duration == RecordDuration::Unlimited {
+        if config.duration == RecordDuration::Unlimited && config.sample_limit.is_none() {
             let msg = if errors > 0 {
                 format!("Collected {} samples ({} errors)", samples, errors)
             } else {
@@ -278,56 +753,433 @@ fn record_samples(process: &mut PythonSpy, config: &Config) -> Result<(), Error>
             progress.set_message(&msg);
         }
 
-        progress.inc(1);
+        if sampler.rate_changed() && !hide_progress {
+            let term = console::Term::stdout();
+            term.move_cursor_up(2)?;
+            println!("Sampling {:.1} times a second to stay under {}% overhead", sampler.rate(), config.max_overhead);
+            term.move_cursor_down(1)?;
+        }
+
+        if let Some(deadline) = deadline {
+            let elapsed = start.elapsed();
+            if elapsed >= deadline {
+                break;
+            }
+            progress.set_position((elapsed.as_secs_f64() * config.sampling_rate as f64) as u64);
+        } else if config.sample_limit.is_some() {
+            // dropped samples don't count towards the limit
+            progress.set_position(samples);
+        } else {
+            progress.inc(1);
+        }
+
+        if let Some(interval) = checkpoint_interval {
+            if last_checkpoint.elapsed() >= interval {
+                // a failed checkpoint shouldn't stop the recording, the next one might work
+                if let Err(e) = write_recording(output.as_ref(), filename, compress, true) {
+                    warn!("Failed to write checkpoint to '{}': {}", filename, e);
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
     }
     progress.finish();
+    let memory_reads = sampler.memory_reads();
+    if sampler.process_exited() {
+        exit_message = "Stopped sampling because the process ended";
+    } else if sampler.interpreter_finalizing() {
+        exit_message = "Stopped sampling because the interpreter is shutting down";
+    }
+    // (the sampler holds on to the process until it's dropped)
+    drop(sampler);
     // write out a message here (so as not to interfere with progress bar) if we ended earlier
     if !exit_message.is_empty() {
-        println!("{}", exit_message);
+        status!("{}", exit_message);
+    }
+    if errors > 0 {
+        status!("Dropped {} of {} samples because the process couldn't be read", errors, samples + errors);
+    }
+    if config.sample_stats {
+        status!("Memory reads: {}", memory_reads.describe_average((samples + errors) as u64));
+    }
+    if let Some(detector) = stall_detector.as_ref() {
+        if detector.stalls.is_empty() {
+            status!("No stalls of more than {}ms between samples", config.stall_threshold.unwrap());
+        } else {
+            status!("{} stalls of more than {}ms between samples, the longest were:", detector.stalls.len(), config.stall_threshold.unwrap());
+            for stall in detector.worst(5) {
+                status!("\t{:.2?} at {:.2?}", stall.duration, stall.start);
+                for line in stall.before.iter() {
+                    status!("\t\tbefore {}", line);
+                }
+                for line in stall.after.iter() {
+                    status!("\t\tafter  {}", line);
+                }
+            }
+        }
+    }
+    if !config.thread_ids.is_empty() && !matched_threads {
+        logging::report(Level::Warn, &format!("No threads matched {}, so no samples were recorded", describe_thread_ids(&config.thread_ids)),
+                        &[("pid", pid.into())]);
     }
 
-    {
-    let mut out_file = std::fs::File::create(filename)?;
-    output.write(&mut out_file)?;
+    // let formats know the rate samples were actually taken at, rather than the requested one
+    let effective_rate = samples as f64 / start.elapsed().as_secs_f64();
+    if config.auto_rate {
+        status!("Sampled an average of {:.1} times a second (requested {})", effective_rate, config.sampling_rate);
+        output.set_sampling_rate(effective_rate);
+    }
+
+    if config.sample_stats {
+        let rate = if config.auto_rate { effective_rate } else { config.sampling_rate as f64 };
+        output.set_subtitle(&flamegraph::sample_summary(samples, errors, rate, start.elapsed()));
+    }
 
+    // streaming formats have already written out everything
+    if !streaming {
+        write_recording(output.as_ref(), filename, compress, checkpoint_interval.is_some())?;
+    }
+
+    // let people know how much they can trust the native stacks
+    #[cfg(unwind)]
+    {
+        if let Some(native) = process.native.as_ref().filter(|_| config.native) {
+            status!("Native stacks: {}", native.unwind_stats);
+            if native.native_only_stats.total() > 0 {
+                status!("Native only threads: {}", native.native_only_stats);
+            }
+            #[cfg(target_os="linux")]
+            {
+                if let Some(summary) = native.fallback_summary() {
+                    info!("Threads that needed the libunwind fallback: {:?}", native.fallback_threads());
+                    status!("Native threads: {}", summary);
+                }
+            }
+            if let Some(missing) = native.missing_cython_maps() {
+                status!("Couldn't find cython source maps for (frames show the generated code instead):");
+                for line in missing {
+                    status!("\t{}", line);
+                }
+            }
+        }
     }
 
     match config.format.as_ref().unwrap() {
         FileFormat::flamegraph => {
-            println!("Wrote flamegraph data to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+            status!("Wrote flamegraph data to '{}'. Samples: {} Errors: {}", filename, samples, errors);
             // open generated flame graph in the browser on OSX (theory being that on linux
             // you might be SSH'ed into a server somewhere and this isn't desired, but on
             // that is pretty unlikely for osx) (note to self: xdg-open will open on linux)
             #[cfg(target_os = "macos")]
-            std::process::Command::new("open").arg(filename).spawn()?;
+            {
+                if !to_stdout && !compress {
+                    std::process::Command::new("open").arg(filename).spawn()?;
+                }
+            }
         },
         FileFormat::speedscope =>  {
-            println!("Wrote speedscope file to '{}'. Samples: {} Errors: {}", filename, samples, errors);
-            println!("Visit https://www.speedscope.app/ to view");
+            status!("Wrote speedscope file to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+            status!("Visit https://www.speedscope.app/ to view");
         },
         FileFormat::raw => {
-            println!("Wrote raw flamegraph data to '{}'. Samples: {} Errors: {}", filename, samples, errors);
-            println!("You can use the flamegraph.pl script from https://github.com/brendangregg/flamegraph to generate a SVG");
+            status!("Wrote raw flamegraph data to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+            status!("You can use the flamegraph.pl script from https://github.com/brendangregg/flamegraph to generate a SVG");
+        },
+        FileFormat::pstats_text => {
+            status!("Wrote pstats style profile to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+        },
+        FileFormat::pprof => {
+            status!("Wrote pprof profile to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+            status!("You can use 'go tool pprof' to view");
+        },
+        FileFormat::chrometrace => {
+            status!("Wrote chrome trace to '{}'. Samples: {} Errors: {}", filename, samples, errors);
+            status!("Visit https://ui.perfetto.dev/ or chrome://tracing to view");
+        },
+        FileFormat::ndjson => {
+            status!("Wrote samples as newline delimited json to '{}'. Samples: {} Errors: {}", filename, samples, errors);
         }
     };
 
+    if let Some(stats) = function_stats {
+        if config.summary {
+            eprintln!();
+            for line in stats.summary(10) {
+                eprintln!("{}", line);
+            }
+        }
+        check_assertions(&stats, config)?;
+    }
+
+    Ok(())
+}
+
+/// Formats the thread ids passed to --thread-id, for messages about them
+fn describe_thread_ids(thread_ids: &[u64]) -> String {
+    let ids: Vec<String> = thread_ids.iter().map(|id| format!("{:#X}", id)).collect();
+    format!("--thread-id {}", ids.join(", "))
+}
+
+fn check_assertions(stats: &FunctionStats, config: &Config) -> Result<(), Error> {
+    let mut failed = 0;
+    for assertion in &config.assertions {
+        let actual = stats.percent_total(&assertion.function);
+        if actual > assertion.max_percent {
+            eprintln!("Assertion failed: '{}' was in {:.2}% of samples (expected < {}%)",
+                      assertion.function, actual, assertion.max_percent);
+            failed += 1;
+        } else {
+            let message = format!("Assertion passed: '{}' was in {:.2}% of samples (expected < {}%)",
+                                  assertion.function, actual, assertion.max_percent);
+            // keep stdout clean if the recording is being written there
+            if config.filename.as_deref() == Some("-") {
+                eprintln!("{}", message);
+            } else {
+                println!("{}", message);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format_err!("{} of {} assertions failed", failed, config.assertions.len()));
+    }
+    Ok(())
+}
+
+fn sample_gil_stats(process: &mut PythonSpy, seconds: u64, config: &Config) -> Result<(), Error> {
+    // free-threaded builds of python don't have a GIL for threads to contend on
+    if process.version.free_threaded {
+        return Err(format_err!("Python v{} doesn't have a GIL, so there are no GIL statistics to show", process.version));
+    }
+
+    println!("Sampling process {} for {} seconds at {} samples a second", process.pid, seconds, config.sampling_rate);
+    let mut stats = gil_stats::GilStats::new();
+    let mut errors = 0;
+    let start = Instant::now();
+    let duration = Duration::from_secs(seconds);
+    for _ in timer::Timer::new(config.sampling_rate as f64) {
+        if start.elapsed() >= duration {
+            break;
+        }
+
+        match sample_with_retry(process) {
+            Ok(traces) => stats.increment(&traces),
+            Err(err) => {
+                if process_exitted(&process.process) {
+                    println!("process {} ended", process.pid);
+                    break;
+                }
+                warn!("Failed to get stack trace {:?}", err);
+                errors += 1;
+            }
+        }
+    }
+
+    println!("Samples: {} Errors: {}\n", stats.samples, errors);
+    for line in stats.table() {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Samples the process for a number of seconds, redrawing a timeline of each thread's state
+/// after every few samples
+fn watch_threads(process: &mut PythonSpy, seconds: u64, config: &Config) -> Result<(), Error> {
+    let term = console::Term::stdout();
+    let show_gil = !process.version.free_threaded;
+    let mut timeline = timeline::Timeline::new(1000);
+    let mut errors = 0;
+    let mut drawn_lines = 0;
+    let mut last_draw: Option<Instant> = None;
+    let start = Instant::now();
+    let duration = Duration::from_secs(seconds);
+    let mut sampler = sampler::Sampler::new(process, config.sampling_rate as f64);
+    for sample in sampler.by_ref() {
+        match sample {
+            Ok(traces) => timeline.increment(&traces),
+            Err(err) => {
+                warn!("Failed to get stack trace {:?}", err);
+                errors += 1;
+            }
+        }
+
+        let finished = start.elapsed() >= duration;
+        // redrawing on every sample would flicker at high sampling rates
+        if finished || last_draw.map_or(true, |drawn| drawn.elapsed() >= Duration::from_millis(100)) {
+            let lines = timeline.lines(term.size().1 as usize, show_gil);
+            term.move_cursor_up(drawn_lines)?;
+            for line in lines.iter() {
+                term.clear_line()?;
+                term.write_line(line)?;
+            }
+            drawn_lines = lines.len();
+            last_draw = Some(Instant::now());
+        }
+        if finished {
+            break;
+        }
+    }
+
+    if sampler.process_exited() {
+        println!("process {} ended", sampler.spy().pid);
+    }
+    if errors > 0 {
+        println!("Errors: {}", errors);
+    }
+    Ok(())
+}
+
+/// Set from the signal handler installed by dump_on_signal
+#[cfg(unix)]
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_dump(_signal: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Stops the process with SIGSTOP once its stack traces have been dumped, so that a debugger can
+/// be attached to it (with --stop)
+#[cfg(unix)]
+fn leave_stopped(process: &PythonSpy) -> Result<(), Error> {
+    if unsafe { libc::kill(process.pid, libc::SIGSTOP) } != 0 {
+        return Err(format_err!("Failed to stop process {}: {}", process.pid, std::io::Error::last_os_error()));
+    }
+    logging::report(Level::Warn, &format!("Process {} has been left stopped, run 'kill -CONT {}' to resume it", process.pid, process.pid),
+                    &[("pid", process.pid.into())]);
+    Ok(())
+}
+
+/// Waits for py-spy to be sent a signal, and dumps the stack traces of the process each time it
+/// is. This lets people get stacks at the exact moment something goes wrong, without polling
+#[cfg(unix)]
+fn dump_on_signal(process: &mut PythonSpy, signal: i32, config: &Config) -> Result<(), Error> {
+    if unsafe { libc::signal(signal, request_dump as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(format_err!("Failed to install signal handler: {}", std::io::Error::last_os_error()));
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })?;
+
+    eprintln!("Waiting to dump process {} each time 'kill -{} {}' is run. Press Control-C to exit.",
+              process.pid, signal, std::process::id());
+
+    let mut dumps = 0;
+    while running.load(Ordering::SeqCst) {
+        if !DUMP_REQUESTED.swap(false, Ordering::SeqCst) {
+            if process_exitted(&process.process) {
+                logging::report(Level::Info, &format!("process {} ended", process.pid), &[("pid", process.pid.into())]);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+
+        dumps += 1;
+        let mut traces = match sample_with_retry(process) {
+            Ok(traces) => traces,
+            Err(err) => {
+                if process_exitted(&process.process) {
+                    logging::report(Level::Info, &format!("process {} ended", process.pid), &[("pid", process.pid.into())]);
+                    break;
+                }
+                logging::report(Level::Error, &format!("Failed to get stack traces for dump {}: {}", dumps, err),
+                                &[("pid", process.pid.into()), ("kind", error_kind(&err).into())]);
+                continue;
+            }
+        };
+
+        sort_traces(&mut traces);
+        if config.dump_json {
+            println!("{}", serde_json::to_string(&JsonDump::new(&traces))?);
+        } else {
+            let elapsed = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            println!("Dump {} of process {} at {:.3}s since the epoch", dumps, process.pid, elapsed.as_secs_f64());
+            print_traces(&traces, true);
+        }
+        std::io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// Takes a single sample for --check, to make sure that profiling the process will work
+fn check_process(process: &mut PythonSpy) -> Result<(), Error> {
+    let traces = process.get_stack_traces()?;
+    #[cfg(unwind)]
+    let native = process.native.is_some();
+    #[cfg(not(unwind))]
+    let native = false;
+    println!("OK: Python {}, {} threads, native available: {}", process.version, traces.len(),
+             if native { "yes" } else { "no" });
     Ok(())
 }
 
 fn run_spy_command(process: &mut PythonSpy, config: &config::Config) -> Result<(), Error> {
+    // (printed to stderr, so that it doesn't get mixed up with json or stdout output)
+    if config.dump_offsets {
+        for line in process.describe_offsets()? {
+            eprintln!("{}", line);
+        }
+    }
+
     match config.command.as_ref() {
+        _ if config.check => {
+            check_process(process)?;
+        },
+        "dump" if config.gil_stats.is_some() => {
+            sample_gil_stats(process, config.gil_stats.unwrap(), config)?;
+        },
+        "dump" if config.watch.is_some() => {
+            watch_threads(process, config.watch.unwrap(), config)?;
+        },
+        #[cfg(unix)]
+        "dump" if config.dump_signal.is_some() => {
+            dump_on_signal(process, config.dump_signal.unwrap(), config)?;
+        },
+        "dump" if config.dump_json => {
+            let mut traces = process.get_stack_traces()?;
+            sort_traces(&mut traces);
+            println!("{}", serde_json::to_string_pretty(&JsonDump::new(&traces))?);
+            #[cfg(unix)]
+            {
+                if config.stop_process {
+                    leave_stopped(process)?;
+                }
+            }
+        },
         "dump" =>  {
-            #[cfg(any(target_os="linux", target_os="macos"))]
+            #[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
             let process_info = process.process.cmdline()?.join(" ");
-            #[cfg(not(any(target_os="linux", target_os="macos")))]
+            #[cfg(not(any(target_os="linux", target_os="android", target_os="macos")))]
             let process_info = process.process.exe()?;
 
             println!("Process {}: {}", console::style(process.pid).bold().yellow(), process_info);
             println!("Python v{}\n", console::style(&process.version).bold());
-            print_traces(&process.get_stack_traces()?, true);
+            let mut traces = process.get_stack_traces()?;
+            sort_traces(&mut traces);
+            if traces.is_empty() && !config.thread_ids.is_empty() {
+                println!("No threads matched {}", describe_thread_ids(&config.thread_ids));
+            }
+            print_traces(&traces, true);
+            #[cfg(unix)]
+            {
+                if config.stop_process {
+                    leave_stopped(process)?;
+                }
+            }
+        },
+        #[cfg(all(unwind, target_os="linux"))]
+        "record" if config.raw_addresses => {
+            address_dump::record_addresses(process.pid, config)?;
         },
         "record" => {
-            record_samples(process, config)?;
+            match config.prometheus.as_ref() {
+                Some(addr) => serve_metrics(process, addr, config)?,
+                None => record_samples(process, config)?
+            }
         },
         "top" => {
             let display = match config.python_program.as_ref() {
@@ -344,8 +1196,98 @@ fn run_spy_command(process: &mut PythonSpy, config: &config::Config) -> Result<(
     Ok(())
 }
 
+#[cfg(target_os="linux")]
+fn dump_core(filename: &str, config: &Config) -> Result<(), Error> {
+    let core = coredump::PythonCoreDump::new(filename)?;
+    let mut traces = core.get_stack_traces(config.dump_locals)?;
+    for frame in traces.iter_mut().flat_map(|trace| trace.frames.iter_mut()) {
+        frame.filename = utils::remap_filename(&frame.filename, &config.path_remaps);
+    }
+    sort_traces(&mut traces);
+    if config.dump_json {
+        println!("{}", serde_json::to_string_pretty(&JsonDump::new(&traces))?);
+    } else {
+        println!("Core {} from process {}: {}", console::style(filename).bold().yellow(),
+                 console::style(core.core.pid).bold().yellow(), core.core.cmdline);
+        println!("Python v{}\n", console::style(&core.version).bold());
+        print_traces(&traces, true);
+    }
+    Ok(())
+}
+
+/// Attaches to a python program that we've just started. This retries quickly so that we don't
+/// miss the start of short lived programs, until either we attach or the program exits
+fn attach_to_subprocess(command: &mut std::process::Child, config: &Config) -> Result<PythonSpy, Error> {
+    let pid = command.id() as remoteprocess::Pid;
+    let start = Instant::now();
+    loop {
+        let err = match PythonSpy::new(pid, config) {
+            Ok(mut process) => {
+                // verify that we can load a stack trace before returning success
+                match process.get_stack_traces() {
+                    Ok(_) => return Ok(process),
+                    Err(err) => err
+                }
+            },
+            Err(err) => err
+        };
+
+        if let Some(exit) = command.try_wait()? {
+            return Err(format_err!("Process {} exited ({}) before it could be sampled: {}", pid, exit, err));
+        }
+        if start.elapsed() >= Duration::from_secs(2) {
+            return Err(err);
+        }
+        info!("Failed to connect to process, retrying. Error: {}", err);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
 fn pyspy_main() -> Result<(), Error> {
     let config = config::Config::from_commandline();
+    logging::set_format(config.log_format);
+
+    // get the pid of the process that the pidfd refers to
+    #[cfg(target_os="linux")]
+    let config = match config.pidfd {
+        Some(fd) => Config{pid: Some(remoteprocess::Process::from_pidfd(fd)?.pid), ..config},
+        None => config
+    };
+
+    // find the pid of the process matching the --process-name regex
+    #[cfg(target_os="linux")]
+    let config = match config.process_name.as_ref() {
+        Some(name) => Config{pid: Some(find_process_by_name(name)?), ..config.clone()},
+        None => config
+    };
+
+    // translate a pid from inside of a container to the pid of the process on the host
+    #[cfg(target_os="linux")]
+    let config = match (config.pid, config.container_pid) {
+        (Some(pid), Some(container_pid)) => Config{pid: Some(find_container_process(pid, container_pid)?), ..config.clone()},
+        _ => config
+    };
+
+    // merging recordings is just post processing, and doesn't need a process to spy on
+    if config.command == "merge" {
+        return merge::merge_recordings(&config);
+    }
+    if config.command == "compare" {
+        return merge::diff_recordings(&config);
+    }
+    #[cfg(all(unwind, target_os="linux"))]
+    {
+        if config.command == "symbolicate" {
+            return address_dump::symbolicate_addresses(&config);
+        }
+    }
+
+    #[cfg(target_os="linux")]
+    {
+        if let Some(filename) = config.core_filename.as_ref() {
+            return dump_core(filename, &config);
+        }
+    }
 
     #[cfg(target_os="macos")]
     {
@@ -390,20 +1332,25 @@ fn pyspy_main() -> Result<(), Error> {
             // sleep just in case: https://jvns.ca/blog/2018/01/28/mac-freeze/
             std::thread::sleep(Duration::from_millis(50));
         }
-        let result = match PythonSpy::retry_new(command.id() as remoteprocess::Pid, &config, 8) {
+        let result = match attach_to_subprocess(&mut command, &config) {
             Ok(mut process) => {
                 run_spy_command(&mut process, &config)
             },
             Err(e) => Err(e)
         };
 
-        // check exit code of subprocess
-        std::thread::sleep(Duration::from_millis(1));
-        let success =  match command.try_wait()? {
-            Some(exit) => exit.success(),
-            // if process hasn't finished, assume success
-            None => true
-        };
+        // check exit code of subprocess, giving it a moment to finish exiting if sampling
+        // stopped because it ended
+        let mut exit_status = None;
+        for _ in 0..10 {
+            exit_status = command.try_wait()?;
+            if exit_status.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // if process hasn't finished, assume success
+        let success = exit_status.map_or(true, |exit| exit.success());
 
         // if we failed for any reason, dump out stderr from child process here
         // (could have useful error message)
@@ -419,6 +1366,13 @@ fn pyspy_main() -> Result<(), Error> {
             // I don't actually care if we failed to kill ... most times process is already done
             // eprintln!("Error killing child process {}", e);
         }
+
+        // exit with the same code as the program we ran, so that py-spy can wrap commands in scripts
+        if let (Ok(()), Some(exit)) = (&result, exit_status) {
+            if !exit.success() {
+                std::process::exit(exit.code().unwrap_or(1));
+            }
+        }
         return result;
     }
 
@@ -426,17 +1380,24 @@ fn pyspy_main() -> Result<(), Error> {
 }
 
 fn main() {
-    env_logger::init();
+    logging::init();
 
     if let Err(err) = pyspy_main() {
         #[cfg(unix)]
         {
         if permission_denied(&err) {
-            eprintln!("Permission Denied: Try running again with elevated permissions by going 'sudo env \"PATH=$PATH\" !!'");
-            std::process::exit(1);
+            logging::report(Level::Error, "Permission Denied: Try running again with elevated permissions by going 'sudo env \"PATH=$PATH\" !!'",
+                            &[("kind", error_kind(&err).into())]);
+            std::process::exit(EXIT_PERMISSION_DENIED);
         }
         }
 
+        if logging::json() {
+            let reasons: Vec<String> = err.iter_chain().skip(1).map(|cause| cause.to_string()).collect();
+            logging::report(Level::Error, &err.to_string(), &[("kind", error_kind(&err).into()), ("reasons", reasons.into())]);
+            std::process::exit(exit_code(&err));
+        }
+
         eprintln!("Error: {}", err);
         for (i, suberror) in err.iter_chain().enumerate() {
             if i > 0 {
@@ -444,6 +1405,6 @@ fn main() {
             }
         }
         eprintln!("{}", err.backtrace());
-        std::process::exit(1);
+        std::process::exit(exit_code(&err));
     }
 }
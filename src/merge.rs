@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use failure::{Error, ResultExt};
+use inferno::flamegraph::{Direction, Options};
+
+use crate::config::{Config, FileFormat};
+use crate::flamegraph::Flamegraph;
+use crate::speedscope;
+use crate::utils::create_output;
+
+/// Combines several recordings (speedscope or raw collapsed stack files) into a single
+/// flamegraph, raw or speedscope file, by summing up the counts for each stack
+pub fn merge_recordings(config: &Config) -> Result<(), Error> {
+    let filename = match config.filename.as_ref() {
+        Some(filename) => filename,
+        None => return Err(format_err!("A filename is required to merge recordings"))
+    };
+
+    if !config.merge_rates.is_empty() && config.merge_rates.len() != config.merge_inputs.len() {
+        return Err(format_err!("Got {} sampling rates for {} recordings: either pass a rate for every recording or none",
+                               config.merge_rates.len(), config.merge_inputs.len()));
+    }
+
+    // recordings taken at different rates are scaled up to the highest rate, so that each
+    // sample represents the same amount of time
+    let target_rate = config.merge_rates.iter().cloned().max();
+
+    let mut merged = Flamegraph::new(config.show_line_numbers);
+    for (i, input) in config.merge_inputs.iter().enumerate() {
+        let counts = load_recording(input, config)?;
+
+        let scale = match (config.merge_rates.get(i), target_rate) {
+            (Some(rate), Some(target)) => target as f64 / *rate as f64,
+            _ => 1.0
+        };
+
+        let label = std::path::Path::new(input).file_stem().map_or_else(|| input.clone(), |f| f.to_string_lossy().to_string());
+        for (stack, count) in counts {
+            let stack = if config.merge_labels { format!("{};{}", label, stack) } else { stack };
+            *merged.counts.entry(stack).or_insert(0) += (count * scale).round() as usize;
+        }
+    }
+
+    let mut out_file = create_output(filename)?;
+    match config.format {
+        Some(FileFormat::raw) => {
+            merged.write_raw(&mut out_file)?;
+            status(filename, &format!("Wrote merged raw flamegraph data to '{}'", filename));
+        },
+        Some(FileFormat::speedscope) => {
+            speedscope::write_collapsed(&merged.counts, &mut out_file)?;
+            status(filename, &format!("Wrote merged speedscope data to '{}'", filename));
+        },
+        _ => {
+            merged.write(&mut out_file)?;
+            status(filename, &format!("Wrote merged flamegraph data to '{}'", filename));
+        }
+    }
+    out_file.flush()?;
+    Ok(())
+}
+
+/// Writes out a differential flamegraph, showing how the second recording changed from the first
+pub fn diff_recordings(config: &Config) -> Result<(), Error> {
+    let filename = match config.filename.as_ref() {
+        Some(filename) => filename,
+        None => return Err(format_err!("A filename is required to diff recordings"))
+    };
+    let (before, after) = match config.merge_inputs.as_slice() {
+        [before, after] => (load_recording(before, config)?, load_recording(after, config)?),
+        _ => return Err(format_err!("Two recordings are required to diff"))
+    };
+
+    let mut opts = Options {
+        direction: Direction::Inverted,
+        min_width: 1.0,
+        title: "py-spy compare".to_owned(),
+        ..Default::default()
+    };
+    let lines = diff_lines(&before, &after);
+    let mut out_file = create_output(filename)?;
+    inferno::flamegraph::from_lines(&mut opts, lines.iter().map(|x| x.as_str()), &mut out_file)
+        .map_err(|e| format_err!("Failed to write flamegraph: {}", e))?;
+    out_file.flush()?;
+    status(filename, &format!("Wrote differential flamegraph to '{}'", filename));
+    Ok(())
+}
+
+/// Prints a status message, on stderr if the output is being written to stdout
+fn status(filename: &str, message: &str) {
+    if filename == "-" {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Loads the counts for each stack from a speedscope or raw collapsed stack file
+fn load_recording(input: &str, config: &Config) -> Result<HashMap<String, f64>, Error> {
+    let contents = std::fs::read_to_string(input).context(format!("Failed to read '{}'", input))?;
+    let counts = if contents.trim_start().starts_with('{') {
+        speedscope::load_collapsed(&contents, config.show_line_numbers, config.include_idle)
+    } else {
+        parse_collapsed(&contents)
+    }.context(format!("Failed to load recording '{}'", input))?;
+    Ok(counts)
+}
+
+/// Gets differential collapsed stack lines ('root;..;leaf before after'), which inferno colours
+/// by the change in count. Stacks only in one of the recordings get a count of 0 in the other
+fn diff_lines(before: &HashMap<String, f64>, after: &HashMap<String, f64>) -> Vec<String> {
+    let mut stacks: Vec<&String> = before.keys().chain(after.keys().filter(|stack| !before.contains_key(*stack))).collect();
+    stacks.sort();
+    stacks.into_iter().map(|stack| {
+        let count = |counts: &HashMap<String, f64>| counts.get(stack).map_or(0, |c| c.round() as usize);
+        format!("{} {} {}", stack, count(before), count(after))
+    }).collect()
+}
+
+/// Parses a collapsed stack file (one 'root;..;leaf count' line per stack)
+fn parse_collapsed(contents: &str) -> Result<HashMap<String, f64>, Error> {
+    let mut counts = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut tokens = line.rsplitn(2, ' ');
+        let count = tokens.next().and_then(|count| count.parse::<f64>().ok());
+        match (tokens.next(), count) {
+            (Some(stack), Some(count)) => { *counts.entry(stack.to_owned()).or_insert(0.0) += count; },
+            _ => return Err(format_err!("Invalid line in collapsed stack file: '{}'", line))
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_collapsed() {
+        let counts = parse_collapsed("<module> (test.py:10);foo (test.py:3) 5\n\n<module> (test.py:10) 2\n<module> (test.py:10) 1\n").unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["<module> (test.py:10);foo (test.py:3)"], 5.0);
+        assert_eq!(counts["<module> (test.py:10)"], 3.0);
+
+        assert!(parse_collapsed("<module> (test.py:10)\n").is_err());
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let before = parse_collapsed("main;foo 5\nmain;bar 2\n").unwrap();
+        let after = parse_collapsed("main;foo 3\nmain;baz 4\n").unwrap();
+        assert_eq!(diff_lines(&before, &after), vec!["main;bar 2 0", "main;baz 0 4", "main;foo 5 3"]);
+    }
+}
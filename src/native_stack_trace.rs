@@ -1,13 +1,20 @@
 #[cfg(target_os="linux")]
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use failure::Error;
 
 use remoteprocess::{self, Pid};
+#[cfg(target_os="linux")]
+use remoteprocess::Tid;
 use lru::LruCache;
+use proc_maps::{get_process_maps, MapRange};
+use regex::Regex;
 
-use crate::binary_parser::BinaryInfo;
+use crate::binary_parser::{BinaryInfo, module_version};
+use crate::config::{Config, ShowEntry};
 use crate::cython;
-use crate::stack_trace::{Frame};
+use crate::perf_map::PerfMap;
+use crate::stack_trace::{Frame, FrameOrigin};
 use crate::utils::resolve_filename;
 use crate::cpp_demangle::{DemangleOptions, BorrowedSymbol};
 
@@ -16,6 +23,7 @@ pub struct NativeStack {
     python: BinaryInfo,
     libpython: Option<BinaryInfo>,
     cython_maps: cython::SourceMaps,
+    perf_map: PerfMap,
     unwinder: remoteprocess::Unwinder,
     // on linux, we also fallback to using libunwind if the main gimli based unwinder fails
     #[cfg(target_os="linux")]
@@ -23,12 +31,106 @@ pub struct NativeStack {
     // TODO: right now on windows if we don't hold on the process handle unwinding will fail
     #[allow(dead_code)]
     process: remoteprocess::Process,
-    symbol_cache: LruCache<u64, remoteprocess::StackFrame>,
+    symbol_cache: SymbolCache,
+    // modules that we shouldn't symbolicate (because of privacy or performance reasons),
+    // along with memory maps we need to figure out which module an address is in
+    no_symbolicate_modules: Vec<String>,
+    // if set, only native frames from these modules are symbolicated, with the rest collapsed
+    native_modules: Vec<String>,
+    maps: Vec<MapRange>,
+    // (module, function) pairs of native frames the user wants to ignore, on top of the builtin ones
+    ignored_frames: Vec<(String, String)>,
+    // (module, function) pairs of native frames that mean a thread is idle, on top of the builtin ones
+    blocking_frames: Vec<(String, String)>,
+    // the native functions that each correspond to a python frame
+    eval_frames: Vec<String>,
+    keep_duplicate_frames: bool,
+    // with --raw-symbols function names are left mangled, and cython frames aren't translated
+    raw_symbols: bool,
+    // which of the native frames below the outermost python frame to show
+    show_entry: ShowEntry,
+    // with --only-native there aren't any python frames to merge in, so eval frames are kept as is
+    keep_eval_frames: bool,
+    // labels for the modules of native frames, including which build of the module was loaded (only
+    // filled in with --build-ids)
+    module_labels: Option<HashMap<String, String>>,
+    // the root directory of the process when it's in another mount namespace (like a container),
+    // which the filenames in native frames are relative to
+    root: Option<PathBuf>,
+    pub unwind_stats: UnwindStats,
+    // stats for the threads without any python frames, which are kept apart from unwind_stats
+    pub native_only_stats: UnwindStats,
+    // the OS threads we've unwound, and the ones that needed the libunwind fallback at least once
+    #[cfg(target_os="linux")]
+    unwound_threads: HashSet<Tid>,
+    #[cfg(target_os="linux")]
+    fallback_threads: HashSet<Tid>,
+    // where to write out the modules the unwinder has loaded, each time they're reloaded
+    #[cfg(target_os="linux")]
+    module_map: Option<String>,
+}
+
+/// A binary loaded by the unwinder, as written out by --emit-modules
+#[cfg(target_os="linux")]
+#[derive(Debug, Clone, Serialize)]
+struct LoadedModule {
+    filename: String,
+    start: u64,
+    end: u64,
+    /// How far the binary was shifted from the addresses in its ELF headers when it was loaded
+    offset: u64,
+    build_id: Option<String>,
+}
+
+/// Counts of how native stacks were unwound, as a measure of how trustworthy the native profile is
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnwindStats {
+    /// stacks unwound and merged with the python stack using the main unwinder
+    pub clean: u64,
+    /// stacks that needed to fall back to libunwind (linux only)
+    pub fallback: u64,
+    /// stacks that couldn't be unwound or merged at all (these are reported with just their python frames)
+    pub failed: u64,
+}
+
+impl UnwindStats {
+    fn count<T>(&mut self, result: &Result<(T, bool), Error>) {
+        match result {
+            Ok((_, false)) => self.clean += 1,
+            Ok((_, true)) => self.fallback += 1,
+            Err(_) => self.failed += 1
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.clean + self.fallback + self.failed
+    }
+}
+
+impl std::fmt::Display for UnwindStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let total = std::cmp::max(self.total(), 1) as f64;
+        write!(f, "{:.2}% unwound cleanly, {:.2}% needed the libunwind fallback, {:.2}% failed",
+               100.0 * self.clean as f64 / total,
+               100.0 * self.fallback as f64 / total,
+               100.0 * self.failed as f64 / total)
+    }
 }
 
 impl NativeStack {
-    pub fn new(pid: Pid, python: BinaryInfo, libpython: Option<BinaryInfo>) -> Result<NativeStack, Error> {
-        let cython_maps = cython::SourceMaps::new();
+    pub fn new(pid: Pid, python: BinaryInfo, libpython: Option<BinaryInfo>, config: &Config) -> Result<NativeStack, Error> {
+        #[cfg(target_os="linux")]
+        let root = remoteprocess::process_root(pid);
+        #[cfg(not(target_os="linux"))]
+        let root = None;
+
+        let mut cython_maps = cython::SourceMaps::new(root.clone());
+        cython_maps.require_maps = config.require_cython_maps;
+        let perf_map = PerfMap::new(pid, root.as_ref().map(PathBuf::as_path));
+
+        let no_symbolicate_modules = config.no_symbolicate_modules.clone();
+        let native_modules = config.native_modules.clone();
+        let maps = if no_symbolicate_modules.is_empty() && native_modules.is_empty() { Vec::new() } else { get_process_maps(pid)? };
 
         let process = remoteprocess::Process::new(pid)?;
         let unwinder = process.unwinder()?;
@@ -37,20 +139,104 @@ impl NativeStack {
         #[cfg(target_os="linux")]
         let libunwinder = remoteprocess::libunwind::LibUnwind::new()?;
 
-        return Ok(NativeStack{cython_maps, unwinder, should_reload: false,
+        let native = NativeStack{cython_maps, perf_map, unwinder, should_reload: false,
                               python,
                               libpython,
                               #[cfg(target_os="linux")]
                               libunwinder,
                               process,
-                              symbol_cache: LruCache::new(4096)
-                              });
+                              symbol_cache: SymbolCache::new(4096),
+                              no_symbolicate_modules,
+                              native_modules,
+                              maps,
+                              ignored_frames: config.ignored_native_frames.clone(),
+                              blocking_frames: config.blocking_native_frames.clone(),
+                              eval_frames: config.eval_frame_functions.clone(),
+                              keep_duplicate_frames: config.keep_duplicate_native_frames,
+                              raw_symbols: config.raw_symbols,
+                              show_entry: config.show_entry,
+                              keep_eval_frames: config.only_native,
+                              module_labels: if config.native_build_ids { Some(HashMap::new()) } else { None },
+                              root,
+                              unwind_stats: UnwindStats::default(),
+                              native_only_stats: UnwindStats::default(),
+                              #[cfg(target_os="linux")]
+                              unwound_threads: HashSet::new(),
+                              #[cfg(target_os="linux")]
+                              fallback_threads: HashSet::new(),
+                              #[cfg(target_os="linux")]
+                              module_map: config.emit_modules.clone(),
+                              };
+        #[cfg(target_os="linux")]
+        native.write_module_map()?;
+        return Ok(native);
     }
 
-    pub fn merge_native_thread(&mut self, frames: &Vec<Frame>, thread: &remoteprocess::Thread) -> Result<Vec<Frame>, Error> {
+    /// Writes out the modules the unwinder has loaded, with their address ranges and build-ids,
+    /// so that the native stacks can be symbolicated (or the libraries checked) later on
+    #[cfg(target_os="linux")]
+    fn write_module_map(&self) -> Result<(), Error> {
+        let filename = match self.module_map.as_ref() {
+            Some(filename) => filename,
+            None => return Ok(())
+        };
+        let root = self.root.as_ref();
+        let modules: Vec<LoadedModule> = self.unwinder.modules().into_iter().map(|module| {
+            let path = match root {
+                Some(root) => root.join(module.filename.trim_start_matches('/')).to_string_lossy().to_string(),
+                None => module.filename.clone()
+            };
+            LoadedModule{build_id: module_version(&path), start: module.address, end: module.address + module.size,
+                         offset: module.offset, filename: module.filename}
+        }).collect();
+
+        // write to a temporary file and rename it over the old one, so that the file always has a complete map
+        let tmp = format!("{}.tmp", filename);
+        serde_json::to_writer_pretty(std::fs::File::create(&tmp)?, &modules)
+            .map_err(|e| format_err!("Failed to write module map to '{}': {}", filename, e))?;
+        std::fs::rename(&tmp, filename)?;
+        info!("Wrote {} modules to '{}'", modules.len(), filename);
+        Ok(())
+    }
+
+    /// Called at the start of each sample, before any threads are merged
+    pub fn new_sample(&mut self) {
+        self.perf_map.new_sample();
+    }
+
+    /// Merges the native stack of a thread with its python frames. If the python frames were cut
+    /// short by --max-depth, truncated should be set so that the native stack is cut off at the
+    /// same place (with the '... N more frames' frame left for the caller to add back on)
+    pub fn merge_native_thread(&mut self, frames: &Vec<Frame>, thread: &remoteprocess::Thread, truncated: bool) -> Result<Vec<Frame>, Error> {
+        let merged = self._merge_native_thread(frames, thread, truncated);
+        self.unwind_stats.count(&merged);
+        merged.map(|(merged, _)| merged)
+    }
+
+    /// Gets the native stack of a thread that doesn't have any python frames. These are counted in
+    /// native_only_stats, so that they don't change how trustworthy the python thread stacks look
+    pub fn merge_pure_native_thread(&mut self, thread: &remoteprocess::Thread) -> Result<Vec<Frame>, Error> {
+        let merged = self._merge_native_thread(&Vec::new(), thread, false);
+        self.native_only_stats.count(&merged);
+        merged.map(|(merged, _)| merged)
+    }
+
+    /// Returns the merged stack, and whether it needed the libunwind fallback
+    fn _merge_native_thread(&mut self, frames: &Vec<Frame>, thread: &remoteprocess::Thread, truncated: bool) -> Result<(Vec<Frame>, bool), Error> {
         if self.should_reload {
             self.unwinder.reload()?;
+            // modules could have been loaded at different addresses
+            self.symbol_cache.clear();
+            if !self.no_symbolicate_modules.is_empty() || !self.native_modules.is_empty() {
+                self.maps = get_process_maps(self.process.pid)?;
+            }
             self.should_reload = false;
+            #[cfg(target_os="linux")]
+            {
+                if let Err(e) = self.write_module_map() {
+                    warn!("Failed to update module map: {}", e);
+                }
+            }
         }
 
         // get the native stack from the thread
@@ -59,86 +245,168 @@ impl NativeStack {
 
         // on linux, try again with libunwind if we fail with the gimli based unwinder
         #[cfg(target_os="linux")]
-        let native_stack = match self.get_thread(&thread) {
-            Ok(x) => x,
-            Err(_) =>  self.get_libunwind_thread(&thread)?
+        let (native_stack, used_libunwind) = match self.get_thread(&thread) {
+            Ok(x) => (x, false),
+            Err(_) =>  (self.get_libunwind_thread(&thread)?, true)
         };
 
         // TODO: merging the two stack together could happen outside of thread lock
         #[cfg(not(target_os="linux"))]
-        return self.merge_native_stack(frames, native_stack);
+        {
+            let merged = self.merge_native_stack(frames, native_stack, truncated)?;
+            return Ok((merged, false));
+        }
+
+        #[cfg(target_os="linux")]
+        let thread_id = thread.id()?;
+        #[cfg(target_os="linux")]
+        self.unwound_threads.insert(thread_id);
 
         #[cfg(target_os="linux")]
-        match self.merge_native_stack(frames, native_stack) {
-            Ok(merged) => return Ok(merged),
-            Err(_) => {
+        match self.merge_native_stack(frames, native_stack, truncated) {
+            Ok(merged) => {
+                if used_libunwind {
+                    self.fallback_threads.insert(thread_id);
+                }
+                return Ok((merged, used_libunwind));
+            },
+            // Most merge failures are from the native and python frame counts not matching up, which
+            // is usually a problem with the gimli unwinder. We're still holding the lock for the thread
+            // here, so retry with libunwind (unless we've already used libunwind to get this stack)
+            Err(e) => {
+                if used_libunwind {
+                    return Err(e);
+                }
+                debug!("Failed to merge native stack for thread, retrying with libunwind: {}", e);
                 let native_stack = self.get_libunwind_thread(&thread)?;
-                return self.merge_native_stack(frames, native_stack);
+                let merged = self.merge_native_stack(frames, native_stack, truncated)?;
+                self.fallback_threads.insert(thread_id);
+                return Ok((merged, true));
             }
         }
     }
-    pub fn merge_native_stack(&mut self, frames: &Vec<Frame>, native_stack: Vec<u64>) -> Result<Vec<Frame>, Error> {
+
+    pub fn merge_native_stack(&mut self, frames: &Vec<Frame>, native_stack: Vec<u64>, truncated: bool) -> Result<Vec<Frame>, Error> {
         let mut python_frame_index = 0;
         let mut merged = Vec::new();
+        // where placeholders were inserted for eval frames that didn't have a python frame
+        let mut unavailable = Vec::new();
 
         // merge the native_stack and python stack together
         for addr in native_stack {
-            // check in the symbol cache if we have looked up this symbol yet
-            let cached_symbol = self.symbol_cache.get(&addr).map(|f| f.clone());
+            // when the python stack was truncated, the native frames past the last python frame
+            // we have are for the calls that were cut off, so stop where the python stack does
+            if truncated && python_frame_index >= frames.len() {
+                break;
+            }
 
-            // merges a remoteprocess::StackFrame into the current merged vec
             let is_python_addr = self.python.contains(addr) || self.libpython.as_ref().map_or(false, |m| m.contains(addr));
-            let merge_frame = &mut |frame: &remoteprocess::StackFrame| {
-                match self.get_merge_strategy(is_python_addr, frame) {
+
+            // past the outermost python frame are the native frames that started the thread. Only
+            // python itself can have eval frames, so everything else can be skipped when hiding them
+            let below_entry = !frames.is_empty() && python_frame_index >= frames.len();
+            if below_entry && self.show_entry == ShowEntry::never && !is_python_addr {
+                continue;
+            }
+
+            // don't symbolicate addresses in modules we've been asked not to (python is always
+            // symbolicated, since we need the function names to merge in the python frames)
+            if !is_python_addr {
+                // skip symbolicating modules outside of --native-module, collapsing each run of them
+                if self.is_external_addr(addr) {
+                    if !merged.last().map_or(false, is_external_frame) {
+                        merged.push(Frame{name: "<external>".to_owned(), filename: String::new(), line: 0, short_filename: None,
+                                          module: None, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false});
+                    }
+                    continue;
+                }
+                if let Some(frame) = self.get_unsymbolicated_frame(addr) {
+                    merged.push(frame);
+                    continue;
+                }
+            }
+
+            // symbolicate the address, reusing the frames from previous samples if we've seen it before
+            let unwinder = &self.unwinder;
+            let symbolicated = self.symbol_cache.get_or_symbolicate(addr, |addr| {
+                let mut frames = Vec::new();
+                unwinder.symbolicate(addr, !is_python_addr, &mut |frame: &remoteprocess::StackFrame| frames.push(frame.clone()))?;
+                Ok(frames)
+            });
+
+            // merges a remoteprocess::StackFrame into the current merged vec. Frames for functions
+            // that were inlined into the one actually containing the address are passed in with
+            // inlined set, so that they don't get counted as extra python eval frames
+            let merge_frame = &mut |frame: &remoteprocess::StackFrame, inlined: bool| {
+                let below_entry = !frames.is_empty() && python_frame_index >= frames.len();
+                match self.get_merge_strategy(is_python_addr, frame, inlined) {
+                    // python's own startup code is usually ignored like the rest of python's
+                    // internals, but is shown along with the libc entry points when asked to
+                    MergeType::Ignore if below_entry && self.show_entry == ShowEntry::always => {
+                        if let Some(native_frame) = self.translate_native_frame(frame) {
+                            merged.push(native_frame);
+                        }
+                    },
                     MergeType::Ignore => {},
+                    MergeType::MergeNativeFrame if below_entry && self.show_entry == ShowEntry::never => {},
                     MergeType::MergeNativeFrame => {
                         if let Some(python_frame) = self.translate_native_frame(frame) {
-                            merged.push(python_frame);
+                            // cython calls the implementation of each def through a python wrapper,
+                            // only show the implementation when both are on the stack
+                            let wrapper = frame.function.as_ref().map_or(false, |function| cython::is_wrapper(function));
+                            if !(wrapper && merged.last().map_or(false, |callee| cython::is_same_function(&python_frame, callee))) {
+                                merged.push(python_frame);
+                            }
                         }
                     },
                     MergeType::MergePythonFrame => {
                         // if we have a corresponding python frame for the evalframe
-                        // merge it into the stack. (if we're out of bounds a later
-                        // check will pick up - and report overall totals mismatch)
+                        // merge it into the stack. Otherwise insert a placeholder, since C code
+                        // calling back into python (like numpy ufunc loops) can legitimately
+                        // leave us with more eval frames than python frames
                         if python_frame_index < frames.len() {
                             merged.push(frames[python_frame_index].clone());
+                        } else {
+                            unavailable.push(merged.len());
+                            merged.push(unavailable_python_frame());
                         }
                         python_frame_index += 1;
                     }
                 }
             };
 
-            if let Some(frame) = cached_symbol {
-                merge_frame(&frame);
-                continue;
-            }
+            match symbolicated {
+                Ok(symbols) => {
+                    // the inline stack for an address is innermost first, with the last frame
+                    // being the function that actually contains the address
+                    let outermost = symbols.len().saturating_sub(1);
+                    for (i, frame) in symbols.iter().enumerate() {
+                        merge_frame(frame, i != outermost);
+                    }
+                },
+                Err(e) => {
+                    if let remoteprocess::Error::NoBinaryForAddress(_) = e {
+                        // addresses that aren't backed by a binary could be JIT compiled code,
+                        // check to see if the JIT has told us about it in a perf map file
+                        if self.perf_map.lookup(addr).is_none() {
+                            self.perf_map.reload();
+                        }
+                        if let Some(name) = self.perf_map.lookup(addr) {
+                            merged.push(Frame{filename: self.perf_map.filename.clone(),
+                                              name: name.to_owned(),
+                                              line: 0, short_filename: None, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false,
+                                              module: Some(self.perf_map.filename.clone())});
+                            continue;
+                        }
 
-            // Keep track of the first symbolicated frame for caching. We don't cache anything (yet) wheree
-            // symoblicationg returns multiple frames for an address, like in the case of inlined function calls.
-            // so track how many frames we get for the address, and only update cache in the happy case
-            // of 1 frame
-            let mut symbolicated_count = 0;
-            let mut first_frame = None;
-
-            self.unwinder.symbolicate(addr, !is_python_addr, &mut |frame: &remoteprocess::StackFrame| {
-                symbolicated_count += 1;
-                if symbolicated_count == 1 {
-                    first_frame = Some(frame.clone());
-                }
-                merge_frame(frame);
-            }).unwrap_or_else(|e| {
-                if let remoteprocess::Error::NoBinaryForAddress(_) = e {
-                    debug!("don't have a binary for symbols at 0x{:x} - reloading", addr);
-                    self.should_reload = true;
+                        debug!("don't have a binary for symbols at 0x{:x} - reloading", addr);
+                        self.should_reload = true;
+                    }
+                    // if we can't symbolicate, just insert a stub here.
+                    merged.push(Frame{filename: "?".to_owned(),
+                                      name: format!("0x{:x}", addr),
+                                      line: 0, short_filename: None, module: None, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false});
                 }
-                // if we can't symbolicate, just insert a stub here.
-                merged.push(Frame{filename: "?".to_owned(),
-                                  name: format!("0x{:x}", addr),
-                                  line: 0, short_filename: None, module: None});
-            });
-
-            if symbolicated_count == 1 {
-                self.symbol_cache.put(addr, first_frame.unwrap());
             }
         }
 
@@ -157,57 +425,118 @@ impl NativeStack {
             } else if python_frame_index == frames.len() + 1 {
                 // if we have seen exactly one more python frame in the native stack than the python stack - let it go.
                 // (can happen when the python stack has been unwound, but haven't exitted the PyEvalFrame function
-                // yet)
+                // yet). That frame is already gone, so don't show a placeholder for it
                 info!("Have {} native and {} python threads in stack - allowing for now",
                     python_frame_index, frames.len());
+                merged.remove(unavailable[0]);
+            } else if python_frame_index > frames.len() {
+                // more eval frames than python frames happens with extensions that call back into
+                // python, and the placeholders inserted above stand in for the missing frames
+                debug!("Have {} native and {} python frames in stack - using placeholders for the missing python frames",
+                    python_frame_index, frames.len());
             } else {
+                // fewer eval frames than python frames means the native stack is corrupt
                  return Err(format_err!("Failed to merge native and python frames (Have {} native and {} python)",
                                        python_frame_index, frames.len()));
             }
         }
 
         // TODO: can this by merged into translate_frame?
-        for frame in merged.iter_mut() {
-            self.cython_maps.translate(frame);
+        // (short filenames are left for the caller to fill in, since translating cython frames
+        // can change the filename, as can --path-remap)
+        if !self.raw_symbols {
+            for frame in merged.iter_mut() {
+                self.cython_maps.translate(frame)?;
+            }
         }
 
-        Ok(merged)
-    }
+        if !self.keep_duplicate_frames {
+            merged.dedup_by(|frame, previous| is_duplicate_native_frame(frame, previous));
+        }
 
-    fn get_merge_strategy(&self, check_python: bool, frame: &remoteprocess::StackFrame) -> MergeType {
-        if check_python || frame.module == self.python.filename {
-            if let Some(ref function) = frame.function {
-                // ugh, probably could do a better job of figuring this out
-                // (also the symbols are different for each OS)
-                if function == "PyEval_EvalFrameDefault" ||
-                    function == "_PyEval_EvalFrameDefault" ||
-                    function == "__PyEval_EvalFrameDefault" ||
-                    function == "PyEval_EvalFrameEx" {
-
-                        MergeType::MergePythonFrame
-
-                // Certain python functions are worth calling out, for visualizing things
-                // like GIL contention etc
-                } else if function == "_time_sleep" || function == "time_sleep" ||
-                    function == "PyGILState_Ensure" || function == "_PyGILState_Ensure" {
-                        MergeType::MergeNativeFrame
-                } else {
-                    MergeType::Ignore
+        let root = self.root.as_ref();
+        if let Some(labels) = self.module_labels.as_mut() {
+            for frame in merged.iter_mut().filter(|frame| frame.origin == FrameOrigin::Native) {
+                if let Some(module) = frame.module.as_mut() {
+                    // reading the build-id means opening the file, so only do this once per module
+                    let label = labels.entry(module.clone()).or_insert_with(|| {
+                        let path = match root {
+                            Some(root) => root.join(module.trim_start_matches('/')).to_string_lossy().to_string(),
+                            None => module.clone()
+                        };
+                        match module_version(&path) {
+                            Some(version) => format!("{} ({})", module, version),
+                            None => module.clone()
+                        }
+                    });
+                    *module = label.clone();
                 }
-            } else {
-                // is this correct? if we don't have a function name and in python binary should ignore?
-                MergeType::Ignore
             }
-        } else {
-            MergeType::MergeNativeFrame
         }
+
+        Ok(merged)
+    }
+
+    fn get_merge_strategy(&self, check_python: bool, frame: &remoteprocess::StackFrame, inlined: bool) -> MergeType {
+        let libpython = self.libpython.as_ref().map(|libpython| libpython.filename.as_str());
+        let python_module = check_python || is_python_module(&frame.module, &self.python.filename, libpython);
+        match merge_strategy(python_module, frame.function.as_ref().map(String::as_str), inlined, &self.eval_frames) {
+            MergeType::MergePythonFrame if self.keep_eval_frames => MergeType::MergeNativeFrame,
+            strategy => strategy
+        }
+    }
+
+    /// Whether the address is in a module that isn't in the --native-module allowlist. Addresses that
+    /// aren't in any module (like JIT compiled code) are never external
+    fn is_external_addr(&self, addr: u64) -> bool {
+        if self.native_modules.is_empty() {
+            return false;
+        }
+        match self.find_map(addr).and_then(|map| map.filename().as_ref()) {
+            Some(module) => !self.native_modules.iter().any(|pattern| module.contains(pattern.as_str())),
+            None => false
+        }
+    }
+
+    fn find_map(&self, addr: u64) -> Option<&MapRange> {
+        let addr = addr as usize;
+        self.maps.iter().find(|m| addr >= m.start() && addr < m.start() + m.size())
+    }
+
+    /// Returns a 'module+offset' frame if the address is in a module that shouldn't be symbolicated
+    fn get_unsymbolicated_frame(&self, addr: u64) -> Option<Frame> {
+        if self.no_symbolicate_modules.is_empty() {
+            return None;
+        }
+
+        let map = self.find_map(addr)?;
+        let addr = addr as usize;
+        let module = map.filename().as_ref()?;
+        if !self.no_symbolicate_modules.iter().any(|pattern| module.contains(pattern.as_str())) {
+            return None;
+        }
+
+        // offsets are relative to the first region the module is mapped at, so that they are
+        // stable across runs of the program even with ASLR
+        let base = self.maps.iter()
+            .filter(|m| m.filename().as_ref() == Some(module))
+            .map(|m| m.start())
+            .min()
+            .unwrap_or_else(|| map.start());
+
+        let basename = std::path::Path::new(module).file_name()
+            .map_or_else(|| module.clone(), |f| f.to_string_lossy().to_string());
+
+        Some(Frame{name: format!("{}+0x{:x}", basename, addr - base),
+                   filename: module.clone(),
+                   line: 0, short_filename: None, module: Some(module.clone()), locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false})
     }
 
     /// translates a native frame into a optional frame. none indicates we should ignore this frame
     fn translate_native_frame(&self, frame: &remoteprocess::StackFrame) -> Option<Frame> {
         match &frame.function {
             Some(func) =>  {
-                if ignore_frame(func, &frame.module) {
+                if self.show_entry != ShowEntry::always && ignore_frame(func, &frame.module) {
                     return None;
                 }
 
@@ -217,46 +546,119 @@ impl NativeStack {
                 // try to resolve the filename relative to the module if given
                 let filename = match frame.filename.as_ref() {
                     Some(filename) => {
-                        resolve_filename(filename, &frame.module)
+                        resolve_filename(filename, &frame.module, self.root.as_ref().map(PathBuf::as_path))
                             .unwrap_or_else(|| filename.clone())
                     },
                     None => frame.module.clone()
                 };
 
-                let mut demangled = None;
-                if func.starts_with('_') {
-                    if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
-                        let options = DemangleOptions{no_params: true, ..Default::default()};
-                        if let Ok(sym) = sym.demangle(&options) {
-                            demangled = Some(sym);
-                        }
+                let first_line = frame.first_line.map(|line| line as i32);
+
+                // demangling is a large part of the cost of native profiling, so with --raw-symbols
+                // the mangled name is used as is, to be demangled later
+                if self.raw_symbols {
+                    if cython::ignore_frame(func) || self.ignore_user_frame(func, func, &frame.module) {
+                        return None;
                     }
+                    return Some(Frame{filename, line, name: func.clone(), short_filename: None, module: Some(frame.module.clone()),
+                                      locals: None, first_line, lasti: None, origin: FrameOrigin::Native, application: false});
                 }
+
+                let demangled = demangle(func);
                 let name = demangled.as_ref().unwrap_or_else(|| &func);
-                if cython::ignore_frame(name) {
+                if cython::ignore_frame(name) || self.ignore_user_frame(func, name, &frame.module) {
                     return None;
                 }
                 let name = cython::demangle(&name).to_owned();
-                Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone())})
+                Some(Frame{filename, line, name, short_filename: None, module: Some(frame.module.clone()), locals: None, first_line, lasti: None, origin: FrameOrigin::Native, application: false})
             },
             None => {
                 Some(Frame{filename: frame.module.clone(),
                            name: format!("0x{:x}", frame.addr),
-                           line: 0, short_filename: None, module: Some(frame.module.clone())})
+                           line: 0, short_filename: None, module: Some(frame.module.clone()), locals: None, first_line: None, lasti: None, origin: FrameOrigin::Native, application: false})
             }
         }
     }
 
+    /// Whether the frame matches one of the --ignore-native-frame rules. Rules can match either the
+    /// mangled or demangled function name, and an empty module matches every module
+    fn ignore_user_frame(&self, function: &str, demangled: &str, module: &str) -> bool {
+        self.ignored_frames.iter().any(|(ignored_module, ignored_function)| {
+            (ignored_function == function || ignored_function == demangled) && module.contains(ignored_module.as_str())
+        })
+    }
+
+    /// The memory reads made by the main unwinder (reads made by libunwind aren't counted)
+    pub fn read_stats(&self) -> remoteprocess::ReadStats {
+        #[cfg(any(target_os="linux", target_os="macos"))]
+        return self.unwinder.read_stats();
+        #[cfg(not(any(target_os="linux", target_os="macos")))]
+        return remoteprocess::ReadStats::default();
+    }
+
+    /// Describes how many threads needed the libunwind fallback, like 'libunwind fallback used
+    /// for 2 of 5 threads' (or None if we haven't unwound any threads)
+    #[cfg(target_os="linux")]
+    pub fn fallback_summary(&self) -> Option<String> {
+        if self.unwound_threads.is_empty() {
+            return None;
+        }
+        Some(format!("libunwind fallback used for {} of {} threads", self.fallback_threads.len(), self.unwound_threads.len()))
+    }
+
+    /// The threads that needed the libunwind fallback, sorted by thread id
+    #[cfg(target_os="linux")]
+    pub fn fallback_threads(&self) -> Vec<Tid> {
+        let mut fallback: Vec<Tid> = self.fallback_threads.iter().cloned().collect();
+        fallback.sort();
+        fallback
+    }
+
+    /// Describes the generated cython files we couldn't find source maps for, with a line for each
+    /// module like 'mymodule.so: a.c, b.c' (or None if every source map was found)
+    pub fn missing_cython_maps(&self) -> Option<Vec<String>> {
+        if self.cython_maps.missing.is_empty() {
+            return None;
+        }
+        Some(self.cython_maps.missing.iter().map(|(module, files)| {
+            format!("{}: {}", module, files.iter().map(String::as_str).collect::<Vec<&str>>().join(", "))
+        }).collect())
+    }
+
+    /// Whether a merged stack is waiting in a blocking native call (like pthread_cond_wait or
+    /// epoll_wait), meaning that the thread is idle even if the OS doesn't report it as such
+    pub fn is_blocked(&self, frames: &[Frame]) -> bool {
+        frames.first().map_or(false, |leaf| is_blocking_frame(leaf, &self.blocking_frames))
+    }
+
     fn get_thread(&mut self, thread: &remoteprocess::Thread) -> Result<Vec<u64>, Error> {
         let mut stack = Vec::new();
         let mut cursor = self.unwinder.cursor(thread)?;
+        #[cfg(windows)]
+        let mut previous_sp = None;
 
         while let Some(ip) = cursor.next() {
             if let Err(remoteprocess::Error::NoBinaryForAddress(addr)) = ip {
                 debug!("don't have a binary for 0x{:x} - reloading", addr);
                 self.should_reload = true;
             }
-            stack.push(ip?);
+            let ip = ip?;
+
+            // StackWalk64 doesn't always stop at the end of the stack: it can return a zero return
+            // address after the thread's entry point, or keep returning the same frame when the stack
+            // is corrupt
+            #[cfg(windows)]
+            {
+                let sp = cursor.sp();
+                if ip == 0 || (stack.last() == Some(&ip) && previous_sp == Some(sp)) {
+                    break;
+                }
+                previous_sp = Some(sp);
+                if stack.len() >= 4096 {
+                    return Err(format_err!("Max native frame depth reached"));
+                }
+            }
+            stack.push(ip);
         }
         Ok(stack)
     }
@@ -290,6 +692,170 @@ impl NativeStack {
     }
 }
 
+/// Caches the symbolicated frames for each instruction pointer, since the same addresses
+/// show up over and over again across samples
+struct SymbolCache {
+    capacity: usize,
+    cache: LruCache<u64, Vec<remoteprocess::StackFrame>>,
+}
+
+impl SymbolCache {
+    fn new(capacity: usize) -> SymbolCache {
+        SymbolCache{capacity, cache: LruCache::new(capacity)}
+    }
+
+    /// Returns the frames for the address, only calling symbolicate if they aren't cached.
+    /// Failures aren't cached, so that the address can be retried after reloading
+    fn get_or_symbolicate<F>(&mut self, addr: u64, symbolicate: F) -> Result<Vec<remoteprocess::StackFrame>, remoteprocess::Error>
+        where F: FnOnce(u64) -> Result<Vec<remoteprocess::StackFrame>, remoteprocess::Error> {
+        if let Some(frames) = self.cache.get(&addr) {
+            return Ok(frames.clone());
+        }
+        let frames = symbolicate(addr)?;
+        self.cache.put(addr, frames.clone());
+        Ok(frames)
+    }
+
+    fn clear(&mut self) {
+        self.cache = LruCache::new(self.capacity);
+    }
+}
+
+/// Demangles c++ and rust function names, returning None if the name doesn't look mangled
+pub fn demangle(func: &str) -> Option<String> {
+    if !func.starts_with('_') {
+        return None;
+    }
+
+    let mut demangled = None;
+    if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
+        let options = DemangleOptions{no_params: true, ..Default::default()};
+        if let Ok(sym) = sym.demangle(&options) {
+            demangled = Some(sym);
+        }
+    }
+
+    // rust symbols either use the v0 mangling scheme (which cpp_demangle doesn't understand), or the
+    // legacy scheme - which cpp_demangle handles, but leaves the hash on the end ('::h0123456789abcdef')
+    let needs_rust_demangle = match demangled.as_ref() {
+        Some(name) => is_rust_hash(name.rsplit("::").next().unwrap_or("")),
+        None => true
+    };
+
+    if needs_rust_demangle {
+        if let Ok(sym) = rustc_demangle::try_demangle(func) {
+            // the alternate format strips off the hash
+            return Some(format!("{:#}", sym));
+        }
+    }
+    demangled
+}
+
+fn is_rust_hash(s: &str) -> bool {
+    s.len() == 17 && s.starts_with('h') && s[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Native functions that threads sit in while waiting on a lock, condition variable, IO or a timer
+const BLOCKING_FUNCTIONS: &[&str] = &[
+    // linux (glibc and musl)
+    "pthread_cond_wait", "pthread_cond_timedwait", "pthread_cond_clockwait", "__pthread_cond_wait",
+    "__pthread_cond_timedwait", "__pthread_cond_wait_common", "sem_wait", "sem_timedwait", "do_futex_wait",
+    "__new_sem_wait_slow", "__lll_lock_wait", "epoll_wait", "epoll_pwait", "select", "__select", "pselect",
+    "poll", "__poll", "ppoll", "nanosleep", "__nanosleep", "clock_nanosleep", "__clock_nanosleep",
+    // osx
+    "__psynch_cvwait", "__psynch_mutexwait", "__semwait_signal", "kevent", "kevent64", "__select_nocancel",
+    "__recvfrom", "mach_msg_trap",
+    // windows
+    "NtWaitForSingleObject", "NtWaitForMultipleObjects", "NtWaitForAlertByThreadId", "NtDelayExecution",
+    "NtRemoveIoCompletion", "ZwWaitForSingleObject", "ZwWaitForMultipleObjects",
+];
+
+/// Whether a frame is a repeat of the native frame before it (with the same function, file
+/// and line), like when symbolicating the addresses in a chain of tail calls. Python frames are
+/// never duplicates, since consecutive identical python frames are genuine recursion
+fn is_duplicate_native_frame(frame: &Frame, previous: &Frame) -> bool {
+    frame.origin != FrameOrigin::Python && frame.origin == previous.origin &&
+        frame.name == previous.name && frame.filename == previous.filename && frame.line == previous.line
+}
+
+/// A placeholder for an eval frame in the native stack that we didn't have a python frame for
+fn unavailable_python_frame() -> Frame {
+    Frame{name: "<python frame unavailable>".to_owned(), filename: String::new(), module: None, short_filename: None,
+          line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+}
+
+/// Whether a frame is the placeholder for native frames outside of the --native-module allowlist
+fn is_external_frame(frame: &Frame) -> bool {
+    frame.origin == FrameOrigin::Native && frame.name == "<external>" && frame.module.is_none()
+}
+
+/// Whether a native frame is one of the builtin blocking functions, or matches one of the
+/// --blocking-native-frame rules
+fn is_blocking_frame(frame: &Frame, rules: &[(String, String)]) -> bool {
+    if frame.origin != FrameOrigin::Native {
+        return false;
+    }
+    if BLOCKING_FUNCTIONS.contains(&frame.name.as_str()) {
+        return true;
+    }
+    let module = frame.module.as_ref().map_or("", |m| m.as_str());
+    rules.iter().any(|(blocking_module, blocking_function)| {
+        blocking_function == &frame.name && module.contains(blocking_module.as_str())
+    })
+}
+
+/// Whether a native frame's module is the python binary or libpython. When libpython is linked
+/// statically there's no separate libpython, and the eval loop lives in the python binary itself.
+/// The module names from the unwinder don't always have the same form as the filenames we found
+/// the binaries at (like when one is relative to a container's root), so paths are compared after
+/// normalizing them, and match if either one is a suffix of the other
+fn is_python_module(module: &str, python_filename: &str, libpython_filename: Option<&str>) -> bool {
+    let module = normalize_module_path(module);
+    if module.is_empty() {
+        return false;
+    }
+    std::iter::once(python_filename).chain(libpython_filename).any(|filename| {
+        let filename = normalize_module_path(filename);
+        module == filename || module.ends_with(&format!("/{}", filename)) || filename.ends_with(&format!("/{}", module))
+    })
+}
+
+fn normalize_module_path(path: &str) -> String {
+    lazy_static! {
+        static ref PROC_ROOT: Regex = Regex::new(r"^/proc/\d+/root/").unwrap();
+    }
+    #[cfg(windows)]
+    let path = path.to_lowercase().replace('\\', "/");
+    let path = path.trim_end_matches(" (deleted)");
+    // binaries in other mount namespaces can be read through /proc/<pid>/root
+    let path = PROC_ROOT.replace(path, "/");
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty() && *c != ".").collect();
+    let normalized = components.join("/");
+    if path.starts_with('/') { format!("/{}", normalized) } else { normalized }
+}
+
+/// Figures out how to merge a native frame, given whether it's from the python binary or libpython,
+/// whether it was inlined into another function at the address, and the names of the eval loop functions
+fn merge_strategy(python_module: bool, function: Option<&str>, inlined: bool, eval_frames: &[String]) -> MergeType {
+    if !python_module {
+        return MergeType::MergeNativeFrame;
+    }
+
+    match function {
+        // Only the outermost function at an address is a real eval frame, anything inlined into it
+        // doesn't have its own python frame
+        Some(function) if !inlined && eval_frames.iter().any(|eval| eval == function) => MergeType::MergePythonFrame,
+
+        // Certain python functions are worth calling out, for visualizing things
+        // like GIL contention etc
+        Some("_time_sleep") | Some("time_sleep") | Some("PyGILState_Ensure") | Some("_PyGILState_Ensure") => MergeType::MergeNativeFrame,
+
+        // is this correct? if we don't have a function name and in python binary should ignore?
+        _ => MergeType::Ignore
+    }
+}
+
+#[derive(Debug, PartialEq)]
 enum MergeType {
     Ignore,
     MergePythonFrame,
@@ -298,17 +864,25 @@ enum MergeType {
 
 // the intent here is to remove top-level libc or pthreads calls
 // from the stack traces. This almost certainly can be done better
-#[cfg(target_os="linux")]
-fn ignore_frame(function: &str, module: &str) -> bool {
-    if function == "__libc_start_main" && module.contains("/libc") {
+#[cfg(any(target_os="linux", target_os="android"))]
+pub fn ignore_frame(function: &str, module: &str) -> bool {
+    // glibc 2.34 and later calls main from __libc_start_call_main
+    if (function == "__libc_start_main" || function == "__libc_start_call_main") && module.contains("/libc") {
+        return true;
+    }
+
+    // android's bionic libc has different thread entry points than glibc
+    if (function == "__libc_init" || function == "__start_thread" || function == "_ZL15__pthread_startPv") &&
+        module.contains("/libc.so") {
         return true;
     }
 
-    if function == "__clone" && module.contains("/libc") {
+    if (function == "__clone" || function == "__clone3") && module.contains("/libc") {
         return true;
     }
 
-    if function == "start_thread" && module.contains("/libpthread") {
+    // libpthread was merged into libc in glibc 2.34
+    if function == "start_thread" && (module.contains("/libpthread") || module.contains("/libc")) {
         return true;
     }
 
@@ -316,7 +890,7 @@ fn ignore_frame(function: &str, module: &str) -> bool {
 }
 
 #[cfg(target_os="macos")]
-fn ignore_frame(function: &str, module: &str) -> bool {
+pub fn ignore_frame(function: &str, module: &str) -> bool {
     if function == "_start" && module.contains("/libdyld.dylib") {
         return true;
     }
@@ -333,7 +907,7 @@ fn ignore_frame(function: &str, module: &str) -> bool {
 }
 
 #[cfg(windows)]
-fn ignore_frame(function: &str, module: &str) -> bool {
+pub fn ignore_frame(function: &str, module: &str) -> bool {
     if function == "RtlUserThreadStart" && module.to_lowercase().ends_with("ntdll.dll") {
         return true;
     }
@@ -344,3 +918,132 @@ fn ignore_frame(function: &str, module: &str) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_cache() {
+        let mut cache = SymbolCache::new(16);
+        let mut calls = 0;
+
+        let addresses = [0x1000, 0x2000, 0x1000, 0x3000, 0x2000, 0x1000];
+        for _ in 0..2 {
+            for &addr in addresses.iter() {
+                let frames = cache.get_or_symbolicate(addr, |addr| {
+                    calls += 1;
+                    Ok(vec![remoteprocess::StackFrame{line: None, first_line: None, filename: None, function: None,
+                                                      module: String::from("test.so"), addr}])
+                }).unwrap();
+                assert_eq!(frames[0].addr, addr);
+            }
+        }
+        // the unwinder should only be hit once per unique address
+        assert_eq!(calls, 3);
+
+        // failures shouldn't be cached
+        assert!(cache.get_or_symbolicate(0x4000, |addr| Err(remoteprocess::Error::NoBinaryForAddress(addr))).is_err());
+        assert!(cache.get_or_symbolicate(0x4000, |_| Ok(Vec::new())).is_ok());
+
+        // clearing should cause everything to be symbolicated again
+        cache.clear();
+        cache.get_or_symbolicate(0x1000, |_| { calls += 1; Ok(Vec::new()) }).unwrap();
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_duplicate_native_frames() {
+        let frame = |name: &str, line, origin| Frame{name: name.to_owned(), filename: "foo.c".to_owned(), module: None,
+                                                      short_filename: None, line, locals: None, first_line: None, lasti: None, origin, application: false};
+        let mut frames = vec![frame("thunk", 10, FrameOrigin::Native),
+                              frame("thunk", 10, FrameOrigin::Native),
+                              frame("recurse", 20, FrameOrigin::Native),
+                              frame("recurse", 21, FrameOrigin::Native),
+                              frame("recurse", 21, FrameOrigin::Python),
+                              frame("recurse", 21, FrameOrigin::Python),
+                              frame("recurse", 21, FrameOrigin::Native)];
+        frames.dedup_by(|frame, previous| is_duplicate_native_frame(frame, previous));
+
+        let lines: Vec<(i32, FrameOrigin)> = frames.iter().map(|frame| (frame.line, frame.origin)).collect();
+        assert_eq!(lines, vec![(10, FrameOrigin::Native), (20, FrameOrigin::Native), (21, FrameOrigin::Native),
+                               (21, FrameOrigin::Python), (21, FrameOrigin::Python), (21, FrameOrigin::Native)]);
+    }
+
+    #[test]
+    fn test_static_libpython() {
+        // with libpython linked into the executable, the eval frames come from the python binary
+        let python = "/proc/1234/root/usr/local/bin/python3.8";
+        assert!(is_python_module("/usr/local/bin/python3.8", python, None));
+        assert!(is_python_module("/usr/local/bin//python3.8 (deleted)", python, None));
+        assert!(is_python_module("/proc/1234/root/usr/local/bin/python3.8", "/usr/local/bin/./python3.8", None));
+        assert!(!is_python_module("/usr/lib/libc.so.6", python, None));
+        assert!(!is_python_module("/usr/local/bin/python3.8-config", python, None));
+        assert!(!is_python_module("", python, None));
+
+        // but shared builds still match libpython
+        assert!(is_python_module("/usr/lib/libpython3.8.so.1.0", "/usr/bin/python3.8", Some("/usr/lib/libpython3.8.so.1.0")));
+        assert!(!is_python_module("/usr/lib/libpython3.8.so.1.0", "/usr/bin/python3.8", None));
+
+        let eval_frames = Config::default().eval_frame_functions;
+        let python_module = is_python_module("/usr/local/bin/python3.8", python, None);
+        assert_eq!(merge_strategy(python_module, Some("_PyEval_EvalFrameDefault"), false, &eval_frames), MergeType::MergePythonFrame);
+    }
+
+    #[test]
+    fn test_merge_strategy() {
+        let eval_frames = Config::default().eval_frame_functions;
+        assert_eq!(merge_strategy(true, Some("_PyEval_EvalFrameDefault"), false, &eval_frames), MergeType::MergePythonFrame);
+        assert_eq!(merge_strategy(true, Some("time_sleep"), false, &eval_frames), MergeType::MergeNativeFrame);
+        assert_eq!(merge_strategy(true, Some("PyObject_Call"), false, &eval_frames), MergeType::Ignore);
+        assert_eq!(merge_strategy(true, None, false, &eval_frames), MergeType::Ignore);
+
+        // inlined copies of the eval loop shouldn't be counted as another python frame
+        assert_eq!(merge_strategy(true, Some("_PyEval_EvalFrameDefault"), true, &eval_frames), MergeType::Ignore);
+
+        // functions inlined into extension code are shown as native frames of their own
+        assert_eq!(merge_strategy(false, Some("std::vector<int>::push_back"), true, &eval_frames), MergeType::MergeNativeFrame);
+
+        // patched interpreters can have eval loops with other names
+        let eval_frames = vec![String::from("MyEval_EvalFrame")];
+        assert_eq!(merge_strategy(true, Some("MyEval_EvalFrame"), false, &eval_frames), MergeType::MergePythonFrame);
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn test_ignore_frame() {
+        // the thread entry points from both before and after libpthread was merged into libc
+        assert!(ignore_frame("start_thread", "/lib/x86_64-linux-gnu/libpthread-2.27.so"));
+        assert!(ignore_frame("start_thread", "/lib/x86_64-linux-gnu/libc.so.6"));
+        assert!(ignore_frame("__clone3", "/lib/x86_64-linux-gnu/libc.so.6"));
+        assert!(ignore_frame("__libc_start_call_main", "/lib/x86_64-linux-gnu/libc.so.6"));
+        assert!(!ignore_frame("start_thread", "/usr/lib/libmine.so"));
+        assert!(!ignore_frame("Py_RunMain", "/usr/bin/python3.9"));
+    }
+
+    #[test]
+    fn test_blocking_frame() {
+        let frame = |name: &str, module: &str, origin| Frame{name: name.to_owned(), filename: module.to_owned(),
+                                                              module: Some(module.to_owned()), short_filename: None,
+                                                              line: 0, locals: None, first_line: None, lasti: None, origin, application: false};
+        let rules = vec![(String::from("libfoo"), String::from("wait_for_work"))];
+        assert!(is_blocking_frame(&frame("pthread_cond_wait", "/lib/libpthread.so.0", FrameOrigin::Native), &rules));
+        assert!(is_blocking_frame(&frame("wait_for_work", "/usr/lib/libfoo.so", FrameOrigin::Native), &rules));
+        assert!(!is_blocking_frame(&frame("wait_for_work", "/usr/lib/libbar.so", FrameOrigin::Native), &rules));
+        assert!(!is_blocking_frame(&frame("select", "test.py", FrameOrigin::Python), &rules));
+    }
+
+    #[test]
+    fn test_demangle() {
+        // c++
+        assert_eq!(demangle("_ZN3foo3barEv"), Some("foo::bar".to_owned()));
+
+        // rust legacy and v0 schemes
+        assert_eq!(demangle("_ZN4core3fmt5write17h0123456789abcdefE"), Some("core::fmt::write".to_owned()));
+        assert_eq!(demangle("_RNvC6_123foo3bar"), Some("123foo::bar".to_owned()));
+        assert_eq!(demangle("_RNvNtCs1234_7mycrate3foo3bar"), Some("mycrate::foo::bar".to_owned()));
+
+        // c functions aren't mangled
+        assert_eq!(demangle("PyEval_EvalFrameDefault"), None);
+    }
+}
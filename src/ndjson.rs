@@ -0,0 +1,69 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use remoteprocess::Pid;
+
+use crate::stack_trace::{StackTrace, FORMAT_VERSION};
+use crate::utils::create_output;
+
+/// Streams out each sample as a line of JSON as soon as it is taken, rather than
+/// aggregating samples in memory and writing them out at the end of the recording
+pub struct NDJson {
+    out: Box<dyn Write>,
+}
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    py_spy_format_version: u32,
+    py_spy_version: &'static str,
+    /// Seconds since the unix epoch
+    timestamp: f64,
+    pid: Pid,
+    traces: &'a [StackTrace],
+}
+
+impl NDJson {
+    /// Creates a writer for the given filename, with '-' meaning stdout
+    pub fn new(filename: &str) -> Result<NDJson, Error> {
+        Ok(NDJson{out: create_output(filename)?})
+    }
+
+    pub fn write_sample(&mut self, pid: Pid, time: SystemTime, traces: &[StackTrace]) -> Result<(), Error> {
+        let timestamp = time.duration_since(UNIX_EPOCH)?.as_micros() as f64 / 1e6;
+        serde_json::to_writer(&mut self.out, &Sample{py_spy_format_version: FORMAT_VERSION, py_spy_version: env!("CARGO_PKG_VERSION"),
+                                                    timestamp, pid, traces})?;
+        self.out.write_all(b"\n")?;
+        // flush so that whatever is reading this can see each sample straight away
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_write_samples() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut ndjson = NDJson::new(file.path().to_str().unwrap()).unwrap();
+            let trace = StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false,
+                                   thread_name: None, interpreter_id: None, pure_native: false, frames: Vec::new()};
+            ndjson.write_sample(1234, UNIX_EPOCH + std::time::Duration::from_millis(1500), &[trace]).unwrap();
+            ndjson.write_sample(1234, SystemTime::now(), &[]).unwrap();
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["pid"], 1234);
+        assert_eq!(lines[0]["timestamp"], 1.5);
+        assert_eq!(lines[0]["py_spy_format_version"], FORMAT_VERSION);
+        assert_eq!(lines[0]["traces"][0]["thread_id"], 1);
+        assert_eq!(lines[1]["traces"].as_array().unwrap().len(), 0);
+    }
+}
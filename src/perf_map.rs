@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use remoteprocess::Pid;
+
+/// Symbols for JIT compiled code. Several JIT compilers (numba with NUMBA_ENABLE_PROFILING,
+/// node/v8, the java perf-map-agent etc) write out the names of generated functions
+/// to a /tmp/perf-<pid>.map file for use by perf - which we can use to name frames
+/// that aren't backed by any binary on disk.
+pub struct PerfMap {
+    pub filename: String,
+    // where to open the file from, which is under the process's root when it's in a container
+    path: PathBuf,
+    file_size: u64,
+    // whether the file has been checked for changes since the last call to new_sample
+    checked: bool,
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl PerfMap {
+    pub fn new(pid: Pid, root: Option<&Path>) -> PerfMap {
+        // the file is named after the pid the process sees itself as, so use the pid inside
+        // of its pid namespace if it's in a container
+        #[cfg(target_os="linux")]
+        let pid = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()
+            .and_then(|status| crate::utils::namespace_pid(&status))
+            .unwrap_or(pid);
+
+        let filename = format!("/tmp/perf-{}.map", pid);
+        let path = match root {
+            Some(root) => root.join(filename.trim_start_matches('/')),
+            None => PathBuf::from(&filename)
+        };
+        let mut map = PerfMap{filename, path, file_size: 0, checked: false, symbols: Vec::new()};
+        map.reload();
+        map
+    }
+
+    /// Lets the next call to reload check the file for changes again. This is called once per sample,
+    /// so that addresses we can't find only cause the file to be checked once per sample
+    pub fn new_sample(&mut self) {
+        self.checked = false;
+    }
+
+    /// Re-reads the map file if it has changed since we last loaded it (JIT compilers
+    /// append new symbols to the file as more code is generated)
+    pub fn reload(&mut self) {
+        if self.checked {
+            return;
+        }
+        self.checked = true;
+
+        let file_size = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return
+        };
+
+        if file_size == self.file_size {
+            return;
+        }
+
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                self.symbols = parse_perf_map(&contents);
+                self.file_size = file_size;
+                info!("Loaded {} JIT symbols from {}", self.symbols.len(), self.filename);
+            },
+            Err(e) => { warn!("Failed to read {}: {}", self.filename, e); }
+        }
+    }
+
+    /// Returns the name of the JIT compiled function containing the address
+    pub fn lookup(&self, addr: u64) -> Option<&str> {
+        // symbols are sorted by start address: find the last one starting at or before addr
+        let index = match self.symbols.binary_search_by(|symbol| symbol.0.cmp(&addr)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1
+        };
+
+        let (start, size, ref name) = self.symbols[index];
+        if addr < start + size {
+            Some(name.as_str())
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses the perf map format: each line is '<start> <size> <name>' with start/size in hex
+fn parse_perf_map(contents: &str) -> Vec<(u64, u64, String)> {
+    let parse_hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16);
+
+    let mut symbols = Vec::new();
+    for line in contents.lines() {
+        let mut tokens = line.trim().splitn(3, ' ');
+        if let (Some(start), Some(size), Some(name)) = (tokens.next(), tokens.next(), tokens.next()) {
+            if let (Ok(start), Ok(size)) = (parse_hex(start), parse_hex(size)) {
+                symbols.push((start, size, name.to_owned()));
+            }
+        }
+    }
+    symbols.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perf_map_lookup() {
+        let contents = "7f0c2a9d4000 40 cpython::__main__::fib$241(long long)\n\
+                        0x7f0c2a9d0000 100 first\n\
+                        invalid line\n";
+        let map = PerfMap{filename: String::new(), path: PathBuf::new(), file_size: 0, checked: false,
+                          symbols: parse_perf_map(contents)};
+
+        assert_eq!(map.lookup(0x7f0c2a9d0000), Some("first"));
+        assert_eq!(map.lookup(0x7f0c2a9d00ff), Some("first"));
+        assert_eq!(map.lookup(0x7f0c2a9d0100), None);
+        assert_eq!(map.lookup(0x7f0c2a9d4020), Some("cpython::__main__::fib$241(long long)"));
+        assert_eq!(map.lookup(0x7f0c2a9d4040), None);
+        assert_eq!(map.lookup(0x1000), None);
+    }
+}
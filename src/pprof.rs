@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::stack_trace::{Frame, StackTrace, FORMAT_VERSION};
+
+/// Writes out profiles in the gzipped protobuf format used by pprof
+/// (https://github.com/google/pprof/blob/master/proto/profile.proto)
+pub struct PProf {
+    counts: HashMap<Vec<Frame>, u64>,
+    show_linenumbers: bool,
+    pub sampling_rate: f64,
+    start: SystemTime,
+}
+
+impl PProf {
+    pub fn new(sampling_rate: u64, show_linenumbers: bool) -> PProf {
+        PProf{counts: HashMap::new(), show_linenumbers, sampling_rate: sampling_rate as f64, start: SystemTime::now()}
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        let frames = trace.frames.iter().map(|frame| {
+            Frame{line: if self.show_linenumbers { frame.line } else { 0 }, locals: None, lasti: None, ..frame.clone()}
+        }).collect();
+        *self.counts.entry(frames).or_insert(0) += 1;
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write_profile(w, self.start.elapsed().unwrap_or_default())
+    }
+
+    fn write_profile(&self, w: &mut dyn Write, duration: Duration) -> Result<(), Error> {
+        let mut encoder = GzEncoder::new(w, Compression::default());
+        encoder.write_all(&self.encode(duration))?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Returns the (uncompressed) protobuf encoded profile, covering duration from the start time
+    fn encode(&self, duration: Duration) -> Vec<u8> {
+        let mut strings = StringTable::new();
+        let mut mappings = HashMap::new();
+        let mut functions = HashMap::new();
+        let mut locations = HashMap::new();
+
+        let mut profile = Encoder::new();
+
+        // sample_type and period_type
+        let sample_type = (strings.get("samples"), strings.get("count"));
+        profile.message(1, |value_type| {
+            value_type.uint64(1, sample_type.0);
+            value_type.uint64(2, sample_type.1);
+        });
+        let period_type = (strings.get("wall"), strings.get("nanoseconds"));
+        profile.message(11, |value_type| {
+            value_type.uint64(1, period_type.0);
+            value_type.uint64(2, period_type.1);
+        });
+        profile.uint64(12, (1_000_000_000.0 / self.sampling_rate) as u64);
+
+        // sort the stacks so that the output is deterministic
+        let mut counts: Vec<(&Vec<Frame>, &u64)> = self.counts.iter().collect();
+        counts.sort();
+
+        let mut mapping_messages = Vec::new();
+        let mut function_messages = Vec::new();
+        let mut location_messages = Vec::new();
+        for (frames, &count) in counts {
+            // pprof expects the leaf location to come first, same as our frames
+            let mut location_ids = Vec::new();
+            for frame in frames {
+                let mapping_id = match frame.module.as_ref() {
+                    Some(module) => {
+                        let next_id = mappings.len() as u64 + 1;
+                        *mappings.entry(module.clone()).or_insert_with(|| {
+                            let mut mapping = Encoder::new();
+                            mapping.uint64(1, next_id);
+                            mapping.uint64(5, strings.get(module));
+                            // has_functions, has_filenames and has_line_numbers
+                            mapping.uint64(7, 1);
+                            mapping.uint64(8, 1);
+                            mapping.uint64(9, 1);
+                            mapping_messages.push(mapping.finish());
+                            next_id
+                        })
+                    },
+                    None => 0
+                };
+
+                let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+                let next_id = functions.len() as u64 + 1;
+                let function_id = *functions.entry((&frame.name, filename)).or_insert_with(|| {
+                    let mut function = Encoder::new();
+                    function.uint64(1, next_id);
+                    function.uint64(2, strings.get(&frame.name));
+                    function.uint64(3, strings.get(&frame.name));
+                    function.uint64(4, strings.get(filename));
+                    function_messages.push(function.finish());
+                    next_id
+                });
+
+                let next_id = locations.len() as u64 + 1;
+                let location_id = *locations.entry((function_id, frame.line, mapping_id)).or_insert_with(|| {
+                    let mut location = Encoder::new();
+                    location.uint64(1, next_id);
+                    location.uint64(2, mapping_id);
+                    location.message(4, |line| {
+                        line.uint64(1, function_id);
+                        line.uint64(2, frame.line as u64);
+                    });
+                    location_messages.push(location.finish());
+                    next_id
+                });
+                location_ids.push(location_id);
+            }
+
+            profile.message(2, |sample| {
+                sample.packed(1, &location_ids);
+                sample.packed(2, &[count]);
+            });
+        }
+
+        for message in mapping_messages {
+            profile.bytes(3, &message);
+        }
+        for message in location_messages {
+            profile.bytes(4, &message);
+        }
+        for message in function_messages {
+            profile.bytes(5, &message);
+        }
+
+        let start = self.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        profile.uint64(9, start.as_secs() * 1_000_000_000 + u64::from(start.subsec_nanos()));
+        profile.uint64(10, duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos()));
+
+        // the pprof schema is fixed, so the version of py-spy's output goes in a comment
+        profile.uint64(13, strings.get(&version_comment()));
+
+        // the string table has to be written last, since the other messages add to it
+        for string in &strings.strings {
+            profile.bytes(6, string.as_bytes());
+        }
+        profile.finish()
+    }
+}
+
+/// The comment describing which version of py-spy wrote a profile
+fn version_comment() -> String {
+    format!("py_spy_format_version={} py_spy_version={}", FORMAT_VERSION, env!("CARGO_PKG_VERSION"))
+}
+
+/// Maps strings to their index in the profile's string table
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        // the first entry in the string table is required to be the empty string
+        let mut table = StringTable{strings: Vec::new(), indices: HashMap::new()};
+        table.get("");
+        table
+    }
+
+    fn get(&mut self, value: &str) -> u64 {
+        if let Some(&index) = self.indices.get(value) {
+            return index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(value.to_owned());
+        self.indices.insert(value.to_owned(), index);
+        index
+    }
+}
+
+/// Minimal protobuf encoder, supporting just the field types needed for the pprof format
+struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder{buffer: Vec::new()}
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buffer.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+
+    fn uint64(&mut self, field: u64, value: u64) {
+        // zero is the default value, and doesn't need to be written out
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    fn packed(&mut self, field: u64, values: &[u64]) {
+        let mut packed = Encoder::new();
+        for &value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.finish());
+    }
+
+    fn message<F: FnOnce(&mut Encoder)>(&mut self, field: u64, build: F) {
+        let mut message = Encoder::new();
+        build(&mut message);
+        self.bytes(field, &message.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::stack_trace::FrameOrigin;
+
+    fn trace(names: &[&str]) -> StackTrace {
+        let frames = names.iter().map(|name| {
+            Frame{name: name.to_string(), filename: "test.py".to_owned(), module: None, short_filename: None, line: 1, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+        }).collect();
+        StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut encoder = Encoder::new();
+        encoder.varint(1);
+        encoder.varint(300);
+        encoder.uint64(1, 0);
+        encoder.uint64(2, 150);
+        assert_eq!(encoder.finish(), vec![0x01, 0xac, 0x02, 0x10, 0x96, 0x01]);
+    }
+
+    #[test]
+    fn test_pprof() {
+        let mut pprof = PProf::new(100, true);
+        pprof.increment(&trace(&["leaf", "<module>"]));
+        pprof.increment(&trace(&["leaf", "<module>"]));
+        pprof.increment(&trace(&["other", "<module>"]));
+        assert_eq!(pprof.counts.len(), 2);
+
+        // there should be a sample with 2 locations and a count of 2, for the stack that was seen twice
+        let encoded = pprof.encode(Duration::from_secs(1));
+        let sample = [0x12, 0x07, 0x0a, 0x02, 0x01, 0x02, 0x12, 0x01, 0x02];
+        assert!(encoded.windows(sample.len()).any(|window| window == sample));
+
+        // along with the version of py-spy that wrote it
+        let comment = version_comment();
+        assert!(encoded.windows(comment.len()).any(|window| window == comment.as_bytes()));
+    }
+
+    #[test]
+    fn test_write_gzipped() {
+        let mut pprof = PProf::new(100, true);
+        pprof.increment(&trace(&["leaf", "<module>"]));
+
+        // output can go to anything that implements Write (like stdout), not just files
+        let duration = Duration::from_secs(1);
+        let mut written = Vec::new();
+        pprof.write_profile(&mut written, duration).unwrap();
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(written.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, pprof.encode(duration));
+    }
+}
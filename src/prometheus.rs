@@ -0,0 +1,143 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::Error;
+
+use crate::function_stats::FunctionStats;
+use crate::stack_trace::StackTrace;
+
+/// Sample counts for a continuously profiled process, in a form that can be scraped by prometheus
+pub struct Metrics {
+    samples: u64,
+    errors: u64,
+    stats: FunctionStats,
+    gil_thread: Option<u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics{samples: 0, errors: 0, stats: FunctionStats::new(), gil_thread: None}
+    }
+
+    /// Adds the stack traces from a single sample
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        self.samples += 1;
+        self.gil_thread = None;
+        for trace in traces {
+            if trace.owns_gil {
+                self.gil_thread = Some(trace.os_thread_id.unwrap_or(trace.thread_id));
+            }
+            self.stats.increment(trace);
+        }
+    }
+
+    pub fn increment_error(&mut self) {
+        self.errors += 1;
+    }
+
+    /// Returns the metrics in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP pyspy_samples_total Number of times the process was sampled\n");
+        out.push_str("# TYPE pyspy_samples_total counter\n");
+        out.push_str(&format!("pyspy_samples_total {}\n", self.samples));
+
+        out.push_str("# HELP pyspy_sampling_errors_total Number of samples that failed\n");
+        out.push_str("# TYPE pyspy_sampling_errors_total counter\n");
+        out.push_str(&format!("pyspy_sampling_errors_total {}\n", self.errors));
+
+        out.push_str("# HELP pyspy_gil_thread_id The thread currently holding the GIL (or 0 if none)\n");
+        out.push_str("# TYPE pyspy_gil_thread_id gauge\n");
+        out.push_str(&format!("pyspy_gil_thread_id {}\n", self.gil_thread.unwrap_or(0)));
+
+        // sort functions so that the output is stable between scrapes
+        let mut counts: Vec<_> = self.stats.counts.iter().collect();
+        counts.sort_by(|a, b| a.0.cmp(b.0));
+
+        out.push_str("# HELP pyspy_function_samples_total Number of thread samples with the function anywhere on the stack\n");
+        out.push_str("# TYPE pyspy_function_samples_total counter\n");
+        for (key, count) in &counts {
+            out.push_str(&format!("pyspy_function_samples_total{{function=\"{}\",file=\"{}\"}} {}\n",
+                                  escape_label(&key.name), escape_label(&key.filename), count.total));
+        }
+
+        out.push_str("# HELP pyspy_function_self_samples_total Number of thread samples with the function as the leaf frame\n");
+        out.push_str("# TYPE pyspy_function_self_samples_total counter\n");
+        for (key, count) in &counts {
+            if count.own > 0 {
+                out.push_str(&format!("pyspy_function_self_samples_total{{function=\"{}\",file=\"{}\"}} {}\n",
+                                      escape_label(&key.name), escape_label(&key.filename), count.own));
+            }
+        }
+        out
+    }
+}
+
+/// Starts a http server in a background thread, serving the metrics at /metrics
+pub fn serve(addr: &str, metrics: Arc<Mutex<Metrics>>) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).map_err(|e| format_err!("Failed to listen on '{}': {}", addr, e))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(Error::from).and_then(|stream| handle_request(stream, &metrics));
+            if let Err(e) = result {
+                warn!("Failed to serve metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<(), Error> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    // only the path matters here, ie 'GET /metrics HTTP/1.1'
+    let response = match request.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = metrics.lock().map_err(|_| format_err!("metrics lock poisoned"))?.render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(), body)
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Escapes a prometheus label value (which can't contain raw backslashes, quotes or newlines)
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::{Frame, FrameOrigin};
+
+    fn trace(names: &[&str], owns_gil: bool) -> StackTrace {
+        let frames = names.iter().map(|name| {
+            Frame{name: name.to_string(), filename: "test.py".to_owned(), module: None, short_filename: None,
+                  line: 1, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
+        }).collect();
+        StackTrace{thread_id: 1, os_thread_id: Some(42), active: true, owns_gil, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::new();
+        metrics.increment(&[trace(&["leaf", "<module>"], true)]);
+        metrics.increment(&[trace(&["\"quoted\"", "<module>"], false)]);
+        metrics.increment_error();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("pyspy_samples_total 2\n"));
+        assert!(rendered.contains("pyspy_sampling_errors_total 1\n"));
+        assert!(rendered.contains("pyspy_gil_thread_id 0\n"));
+        assert!(rendered.contains("pyspy_function_samples_total{function=\"<module>\",file=\"test.py\"} 2\n"));
+        assert!(rendered.contains("pyspy_function_self_samples_total{function=\"leaf\",file=\"test.py\"} 1\n"));
+        assert!(rendered.contains("function=\"\\\"quoted\\\"\""));
+        assert!(!rendered.contains("pyspy_function_self_samples_total{function=\"<module>\""));
+    }
+}
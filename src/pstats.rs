@@ -0,0 +1,95 @@
+use std::io::Write;
+
+use failure::Error;
+
+use crate::function_stats::{FunctionStats, FunctionKey, FunctionCount};
+use crate::stack_trace::StackTrace;
+
+/// Writes out a flat profile that looks like the output of cProfile's pstats module.
+/// Since we are sampling, we can't know how many times each function was called -
+/// so the ncalls/percall columns are left out, and times are estimated from the number
+/// of samples multiplied by the sampling interval
+pub struct PStats {
+    stats: FunctionStats,
+    pub sampling_rate: f64,
+}
+
+impl PStats {
+    pub fn new(sampling_rate: u64) -> PStats {
+        PStats{stats: FunctionStats::new(), sampling_rate: sampling_rate as f64}
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        self.stats.increment(trace)
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let interval = 1.0 / self.sampling_rate;
+        let percent = |count: u64| 100.0 * count as f64 / std::cmp::max(self.stats.samples, 1) as f64;
+
+        // sort by own time (like pstats 'tottime'), breaking ties on total time and then name
+        let mut counts: Vec<(&FunctionKey, &FunctionCount)> = self.stats.counts.iter().collect();
+        counts.sort_unstable_by(|a, b| b.1.own.cmp(&a.1.own)
+            .then(b.1.total.cmp(&a.1.total))
+            .then(a.0.cmp(&b.0)));
+
+        writeln!(w, "         {} samples collected at {} samples/second", self.stats.samples, (self.sampling_rate * 10.0).round() / 10.0)?;
+        writeln!(w)?;
+        writeln!(w, "   Ordered by: internal time")?;
+        writeln!(w, "   Note: tottime and cumtime are estimated from samples, ncalls isn't available when sampling")?;
+        writeln!(w)?;
+        writeln!(w, "{:>10} {:>10} {:>8} {:>8}  {}", "tottime", "cumtime", "%own", "%total", "filename:lineno(function)")?;
+        for (key, count) in counts {
+            writeln!(w, "{:>10.3} {:>10.3} {:>7.2}% {:>7.2}%  {}:{}({})",
+                     count.own as f64 * interval,
+                     count.total as f64 * interval,
+                     percent(count.own),
+                     percent(count.total),
+                     key.filename, key.line, key.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::{Frame, FrameOrigin};
+
+    fn trace(frames: &[(&str, i32)]) -> StackTrace {
+        let frames = frames.iter().map(|&(name, first_line)| {
+            Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None, short_filename: None, line: 0,
+                  locals: None, first_line: Some(first_line), lasti: None, origin: FrameOrigin::Python, application: false}
+        }).collect();
+        StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    fn lines(pstats: &PStats) -> Vec<String> {
+        let mut output = Vec::new();
+        pstats.write(&mut output).unwrap();
+        String::from_utf8(output).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_write() {
+        let mut pstats = PStats::new(100);
+        for _ in 0..3 {
+            pstats.increment(&trace(&[("leaf", 10), ("main", 1)]));
+        }
+        pstats.increment(&trace(&[("main", 1)]));
+
+        // functions are sorted by own time, and times are estimated from the 10ms between samples
+        let lines = lines(&pstats);
+        assert_eq!(lines[0], "         4 samples collected at 100 samples/second");
+        assert_eq!(lines[5], "   tottime    cumtime     %own   %total  filename:lineno(function)");
+        assert_eq!(&lines[6..], &["     0.030      0.030   75.00%   75.00%  test.py:10(leaf)",
+                                  "     0.010      0.040   25.00%  100.00%  test.py:1(main)"]);
+    }
+
+    #[test]
+    fn test_write_no_samples() {
+        let lines = lines(&PStats::new(100));
+        assert_eq!(lines[0], "         0 samples collected at 100 samples/second");
+        assert_eq!(lines.len(), 6);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::os::raw::{c_char, c_int};
+
+use failure::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::_typeobject;
+use crate::python_data_access::{copy_int, dict_get, dict_items, instance_dict, module_dict, set_items, type_name, weakref_target};
+use crate::python_interpreters::{BytesObject, CodeObject, FrameObject, InterpreterState, StringObject, ThreadState};
+use crate::stack_trace::copy_string;
+use crate::version::Version;
+
+/// Returns the address of the 'asyncio.tasks._current_tasks' dict, which maps each running event
+/// loop to the task it's currently running. Returns None if asyncio hasn't been imported, or if
+/// this version of python doesn't support task names (which were added in 3.8)
+pub fn current_tasks_dict<I, P>(interp: &I, process: &P, version: &Version) -> Result<Option<usize>, Error>
+        where I: InterpreterState, P: ProcessMemory {
+    match version {
+        Version{major: 3, minor: 8..=9, ..} => {},
+        _ => return Ok(None)
+    }
+
+    let tasks = match dict_get::<I::StringObject, P>(process, interp.modules(), "asyncio.tasks", version)? {
+        Some(module) => module,
+        None => return Ok(None)
+    };
+    dict_get::<I::StringObject, P>(process, module_dict(process, tasks)?, "_current_tasks", version)
+}
+
+/// Returns the name of the asyncio task that each python thread is currently running (keyed by
+/// python thread id), given the address of the '_current_tasks' dict
+pub fn current_task_names<S, P>(process: &P, current_tasks: usize, version: &Version) -> Result<HashMap<u64, String>, Error>
+        where S: StringObject, P: ProcessMemory {
+    let mut names = HashMap::new();
+    for (event_loop, task) in dict_items(process, current_tasks, version)? {
+        // loops store the id of the thread running them in '_thread_id' (which is None when the
+        // loop isn't running). Loops implemented in C (like uvloop) won't have an instance dict
+        let thread_id = match instance_dict(process, event_loop) {
+            Ok(dict) => dict_get::<S, P>(process, dict, "_thread_id", version)?,
+            Err(_) => None
+        };
+        let thread_id = match thread_id {
+            Some(id) if type_name(process, id)? == "int" => copy_int(process, id, version)?,
+            _ => continue
+        };
+
+        if let Some(name) = task_name::<S, P>(process, task, version)? {
+            names.insert(thread_id as u64, name);
+        }
+    }
+    Ok(names)
+}
+
+/// Gets the name of an asyncio.Task object
+fn task_name<S, P>(process: &P, task: usize, version: &Version) -> Result<Option<String>, Error>
+        where S: StringObject, P: ProcessMemory {
+    // the python implementation of Task stores the name in its instance dict
+    if let Ok(dict) = instance_dict(process, task) {
+        if let Some(name) = dict_get::<S, P>(process, dict, "_name", version)? {
+            return Ok(Some(copy_string(name as *const S, process)?));
+        }
+    }
+
+    // but the C implementation (_asyncio.Task) stores it in the TaskObj struct
+    if type_name(process, task)? != "_asyncio.Task" {
+        return Ok(None);
+    }
+    let name = c_task_member(process, task, 1)?;
+    if name == 0 || type_name(process, name)? != "str" {
+        return Ok(None);
+    }
+    Ok(Some(copy_string(name as *const S, process)?))
+}
+
+/// Reads a pointer from the TaskObj struct of the C implementation of Task. On 3.7 to 3.9 this ends
+/// with 'task_coro, task_name (3.8+), task_context, task_must_cancel, task_log_destroy_pending', so
+/// the offset is taken from the end of the struct since the size of the start changes between
+/// versions. `before_context` is how many pointers before task_context the member is
+fn c_task_member<P: ProcessMemory>(process: &P, task: usize, before_context: usize) -> Result<usize, Error> {
+    // ob_type comes after ob_refcnt in the PyObject header
+    let ob_type: usize = process.copy_struct(task + size_of::<isize>())?;
+    let type_object: _typeobject = process.copy_struct(ob_type)?;
+    let offset = type_object.tp_basicsize as usize - 2 * size_of::<c_int>() - (before_context + 1) * size_of::<usize>();
+    Ok(process.copy_struct(task + offset)?)
+}
+
+// the start of PyGenObject (which coroutines share) on python 3.7 to 3.9
+#[repr(C)]
+#[allow(dead_code)]
+struct PyGenObjectHead {
+    ob_refcnt: isize,
+    ob_type: usize,
+    gi_frame: usize,
+    gi_running: c_char,
+}
+
+const YIELD_FROM: u8 = 72;
+
+/// An asyncio task whose coroutine is suspended, so that its frames aren't on any thread's stack
+pub struct SuspendedTask {
+    /// The address of the Task object
+    pub task: usize,
+    pub name: Option<String>,
+    /// The addresses of the frames of the coroutines the task is waiting in, innermost first
+    pub frames: Vec<usize>,
+}
+
+/// Finds every asyncio task that is waiting (rather than running on a thread), by walking the
+/// 'asyncio.tasks._all_tasks' WeakSet that tasks are registered in. This is only supported for
+/// python 3.7 to 3.9
+pub fn suspended_tasks<I, P>(interp: &I, process: &P, version: &Version) -> Result<Vec<SuspendedTask>, Error>
+        where I: InterpreterState, P: ProcessMemory {
+    match version {
+        Version{major: 3, minor: 7..=9, ..} => {},
+        _ => return Err(format_err!("Getting suspended asyncio tasks isn't supported for python {}", version))
+    }
+
+    let tasks = match dict_get::<I::StringObject, P>(process, interp.modules(), "asyncio.tasks", version)? {
+        Some(module) => module,
+        None => return Ok(Vec::new())
+    };
+    let all_tasks = match dict_get::<I::StringObject, P>(process, module_dict(process, tasks)?, "_all_tasks", version)? {
+        Some(all_tasks) => all_tasks,
+        None => return Ok(Vec::new())
+    };
+    // WeakSet keeps a weakref to each item in a regular set called 'data'
+    let data = match dict_get::<I::StringObject, P>(process, instance_dict(process, all_tasks)?, "data", version)? {
+        Some(data) => data,
+        None => return Ok(Vec::new())
+    };
+
+    let mut suspended = Vec::new();
+    for weakref in set_items(process, data)? {
+        let task = match weakref_target(process, weakref)? {
+            Some(task) => task,
+            None => continue
+        };
+        let coroutine = match task_coroutine::<I::StringObject, P>(process, task, version)? {
+            Some(coroutine) => coroutine,
+            None => continue
+        };
+        let frames = awaited_frames::<<I::ThreadState as ThreadState>::FrameObject, P>(process, coroutine)?;
+        if frames.is_empty() {
+            continue;
+        }
+        // task names were added in 3.8
+        let name = if version.minor >= 8 { task_name::<I::StringObject, P>(process, task, version)? } else { None };
+        suspended.push(SuspendedTask{task, name, frames});
+    }
+    Ok(suspended)
+}
+
+/// Gets the coroutine an asyncio.Task object is running
+fn task_coroutine<S, P>(process: &P, task: usize, version: &Version) -> Result<Option<usize>, Error>
+        where S: StringObject, P: ProcessMemory {
+    if let Ok(dict) = instance_dict(process, task) {
+        if let Some(coroutine) = dict_get::<S, P>(process, dict, "_coro", version)? {
+            return Ok(Some(coroutine));
+        }
+    }
+
+    if type_name(process, task)? != "_asyncio.Task" {
+        return Ok(None);
+    }
+    let before_context = if version.minor >= 8 { 2 } else { 1 };
+    let coroutine = c_task_member(process, task, before_context)?;
+    Ok(if coroutine == 0 { None } else { Some(coroutine) })
+}
+
+/// Returns the frames of a suspended coroutine, and of the coroutines it is awaiting, innermost
+/// first. Returns nothing for coroutines that have finished, or that are currently running (since
+/// those frames are already on a thread's stack)
+fn awaited_frames<F, P>(process: &P, coroutine: usize) -> Result<Vec<usize>, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let mut frames = Vec::new();
+    let mut awaiting = coroutine;
+    loop {
+        match type_name(process, awaiting)?.as_ref() {
+            "coroutine" | "generator" => {},
+            _ => break
+        }
+        let gen: PyGenObjectHead = process.copy_struct(awaiting)?;
+        if gen.gi_frame == 0 || gen.gi_running != 0 {
+            break;
+        }
+        frames.push(gen.gi_frame);
+        if frames.len() > 4096 {
+            return Err(format_err!("Max coroutine recursion depth reached"));
+        }
+
+        let frame: F = process.copy_struct(gen.gi_frame)?;
+        match yield_from_target(process, &frame)? {
+            Some(target) => awaiting = target,
+            None => break
+        }
+    }
+
+    frames.reverse();
+    Ok(frames)
+}
+
+/// Returns the object a suspended generator's frame is delegating to with 'yield from' or 'await'.
+/// The frame is paused on the YIELD_FROM instruction, with the object at the top of its value stack
+fn yield_from_target<F, P>(process: &P, frame: &F) -> Result<Option<usize>, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let lasti = frame.lasti();
+    if lasti < 0 || frame.stacktop() == 0 {
+        return Ok(None);
+    }
+    let code = process.copy_pointer(frame.code())?;
+    let bytecode_ptr = code.bytecode();
+    let bytecode = process.copy_pointer(bytecode_ptr)?;
+    // instructions are 2 bytes on python 3.6+, and f_lasti is the instruction before the YIELD_FROM
+    let next = lasti as usize + 2;
+    if next >= bytecode.size() {
+        return Ok(None);
+    }
+    let opcode = process.copy(bytecode.address(bytecode_ptr as usize) + next, 1)?;
+    if opcode[0] != YIELD_FROM {
+        return Ok(None);
+    }
+    Ok(Some(process.copy_struct(frame.stacktop() - size_of::<usize>())?))
+}
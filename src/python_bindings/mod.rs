@@ -45,6 +45,11 @@ pub mod pyruntime {
         }
     }
 
+    // PyRuntime.finalizing is the pointer just before pyinterpreters.mutex and pyinterpreters.head
+    pub fn get_finalizing_offset(version: &Version) -> usize {
+        get_interp_head_offset(version) - 2 * std::mem::size_of::<usize>()
+    }
+
     // getting gilstate.tstate_current is different for all OS
     // and is also different for each python version, and even
     // between v3.8.0a1 and v3.8.0a2 =(
@@ -83,7 +88,8 @@ pub mod pyruntime {
         }
     }
 
-    #[cfg(all(target_os="linux", target_pointer_width = "64"))]
+    // bionic uses the same size pthread mutex/cond structs as glibc on 64 bit (but not on 32 bit)
+    #[cfg(all(any(target_os="linux", target_os="android"), target_pointer_width = "64"))]
     pub fn get_tstate_current_offset(version: &Version) -> Option<usize> {
         match version {
             Version{major: 3, minor: 7, patch: 0..=3, ..} => Some(1392),
@@ -101,6 +107,11 @@ pub mod pyruntime {
         }
     }
 
+    #[cfg(all(target_os="android", target_pointer_width = "32"))]
+    pub fn get_tstate_current_offset(_version: &Version) -> Option<usize> {
+        None
+    }
+
     #[cfg(windows)]
     pub fn get_tstate_current_offset(version: &Version) -> Option<usize> {
         match version {
@@ -0,0 +1,97 @@
+use std::mem::size_of;
+
+use failure::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::python_data_access::{copy_pointers, dict_get, dict_items, format_variable, instance_dict, module_dict, type_name, weakref_target};
+use crate::python_interpreters::{InterpreterState, ThreadState};
+use crate::stack_trace::copy_string;
+use crate::version::Version;
+
+/// The most characters of a context value that are kept
+const MAX_CONTEXT_LENGTH: usize = 128;
+
+/// Reads the value of a module global for a python thread, like the current request of a web
+/// framework. The path is either the name of the global, or the name followed by '.attribute' to
+/// read one attribute of it. Globals that are threading.local objects are read from the thread's
+/// own copy of them. Returns None if the module hasn't been imported or the value isn't set (yet)
+pub fn context_value<I, P>(interp: &I, process: &P, thread: &I::ThreadState, module: &str, path: &str, version: &Version)
+        -> Result<Option<String>, Error> where I: InterpreterState, P: ProcessMemory {
+    let mut tokens = path.splitn(2, '.');
+    let (global, attribute) = (tokens.next().unwrap_or(""), tokens.next());
+
+    let module = match dict_get::<I::StringObject, P>(process, interp.modules(), module, version)? {
+        Some(module) => module,
+        None => return Ok(None)
+    };
+    let object = match dict_get::<I::StringObject, P>(process, module_dict(process, module)?, global, version)? {
+        Some(object) => object,
+        None => return Ok(None)
+    };
+
+    // the attributes of a threading.local are stored in a separate dict for each thread
+    let local = match type_name(process, object)?.as_ref() {
+        "_thread._local" | "thread._local" => true,
+        _ => false
+    };
+    let (object, dict) = if local {
+        match thread_local_dict::<I, P>(process, object, thread.dict(), version)? {
+            Some(dict) => (dict, Some(dict)),
+            None => return Ok(None)
+        }
+    } else {
+        (object, None)
+    };
+
+    let value = match attribute {
+        Some(attribute) => {
+            let dict = match dict {
+                Some(dict) => dict,
+                None => instance_dict(process, object)?
+            };
+            match dict_get::<I::StringObject, P>(process, dict, attribute, version)? {
+                Some(value) => value,
+                None => return Ok(None)
+            }
+        },
+        None => object
+    };
+
+    // strings are shown as they are, rather than quoted like format_variable does
+    if type_name(process, value)? == "str" {
+        if let Ok(value) = copy_string(value as *const I::StringObject, process) {
+            return Ok(Some(value.chars().take(MAX_CONTEXT_LENGTH).collect()));
+        }
+    }
+    Ok(Some(format_variable::<I::StringObject, P>(process, value, version, MAX_CONTEXT_LENGTH)?))
+}
+
+/// Returns the dict holding a thread's attributes of a threading.local object, given the address
+/// of the thread's PyThreadState dict. Returns None if the thread hasn't set any attributes
+fn thread_local_dict<I, P>(process: &P, local: usize, thread_dict: usize, version: &Version) -> Result<Option<usize>, Error>
+        where I: InterpreterState, P: ProcessMemory {
+    if thread_dict == 0 {
+        return Ok(None);
+    }
+
+    // the localobject struct starts with a PyObject_HEAD, followed by the key it's stored under in
+    // each thread's dict, then args, kw and (on python 3) weakreflist and dummies
+    let members = copy_pointers(process, local + 2 * size_of::<usize>(), if version.major == 2 { 1 } else { 5 })?;
+    let key = copy_string(members[0] as *const I::StringObject, process)?;
+    let entry = match dict_get::<I::StringObject, P>(process, thread_dict, &key, version)? {
+        Some(entry) => entry,
+        None => return Ok(None)
+    };
+
+    // python 2 stores each thread's dict directly in the thread dict. Python 3 stores a dummy
+    // object there instead, and keeps a dict mapping weakrefs of the dummies to the thread's dicts
+    if version.major == 2 {
+        return Ok(Some(entry));
+    }
+    for (weakref, dict) in dict_items(process, members[4], version)? {
+        if weakref_target(process, weakref)? == Some(entry) {
+            return Ok(Some(dict));
+        }
+    }
+    Ok(None)
+}
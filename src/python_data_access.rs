@@ -0,0 +1,447 @@
+/* Functions for reading python objects like dicts, ints and type names out of the target process.
+
+These object layouts aren't included in the generated bindings, so minimal versions of the
+structs we need are declared here instead. The layouts of these objects are stable enough that
+we only need to distinguish between a couple of groups of python versions.
+*/
+use std::mem::size_of;
+use std::os::raw::c_long;
+
+use failure::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::v3_7_0::_typeobject;
+use crate::python_interpreters::StringObject;
+use crate::stack_trace::copy_string;
+use crate::version::Version;
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyObjectHead {
+    ob_refcnt: isize,
+    ob_type: usize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyVarObjectHead {
+    ob_base: PyObjectHead,
+    ob_size: isize,
+}
+
+// python 2.7 dicts
+#[repr(C)]
+#[allow(dead_code)]
+struct PyDictObject27 {
+    ob_base: PyObjectHead,
+    ma_fill: isize,
+    ma_used: isize,
+    ma_mask: isize,
+    ma_table: usize,
+}
+
+// python 3.3 to 3.5 dicts
+#[repr(C)]
+#[allow(dead_code)]
+struct PyDictObject33 {
+    ob_base: PyObjectHead,
+    ma_used: isize,
+    ma_keys: usize,
+    ma_values: usize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyDictKeysObject33 {
+    dk_refcnt: isize,
+    dk_size: isize,
+    dk_lookup: usize,
+    dk_usable: isize,
+    // followed by dk_size PyDictKeyEntry's
+}
+
+// python 3.6+ 'compact' dicts
+#[repr(C)]
+#[allow(dead_code)]
+struct PyDictObject36 {
+    ob_base: PyObjectHead,
+    ma_used: isize,
+    ma_version_tag: u64,
+    ma_keys: usize,
+    ma_values: usize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyDictKeysObject36 {
+    dk_refcnt: isize,
+    dk_size: isize,
+    dk_lookup: usize,
+    dk_usable: isize,
+    dk_nentries: isize,
+    // followed by the hash table indices, and then dk_nentries PyDictKeyEntry's
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default)]
+struct PyDictKeyEntry {
+    me_hash: isize,
+    me_key: usize,
+    me_value: usize,
+}
+
+// sets have had the same layout up until the table pointer since python 3.3
+#[repr(C)]
+#[allow(dead_code)]
+struct PySetObject {
+    ob_base: PyObjectHead,
+    fill: isize,
+    used: isize,
+    mask: isize,
+    table: usize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+#[derive(Clone, Copy, Default)]
+struct SetEntry {
+    key: usize,
+    hash: isize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyWeakReference {
+    ob_base: PyObjectHead,
+    wr_object: usize,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyFloatObject {
+    ob_base: PyObjectHead,
+    ob_fval: f64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct PyIntObject {
+    ob_base: PyObjectHead,
+    ob_ival: c_long,
+}
+
+// python uses 30 bit digits for ints on 64 bit platforms, and 15 bits otherwise
+#[cfg(target_pointer_width="64")]
+type Digit = u32;
+#[cfg(target_pointer_width="64")]
+const DIGIT_BITS: usize = 30;
+#[cfg(not(target_pointer_width="64"))]
+type Digit = u16;
+#[cfg(not(target_pointer_width="64"))]
+const DIGIT_BITS: usize = 15;
+
+const MAX_DICT_ENTRIES: usize = 1 << 20;
+const MAX_POINTERS: usize = 1 << 16;
+
+/// Returns the (key, value) pointers for each item in a python dict
+pub fn dict_items<P: ProcessMemory>(process: &P, addr: usize, version: &Version) -> Result<Vec<(usize, usize)>, Error> {
+    match version {
+        Version{major: 2, ..} => {
+            let dict: PyDictObject27 = process.copy_struct(addr)?;
+            let entries = copy_entries(process, dict.ma_table, dict.ma_mask as usize + 1)?;
+            // empty slots have a null key, and deleted slots have a dummy key with a null value
+            Ok(entries.iter()
+                .filter(|entry| entry.me_key != 0 && entry.me_value != 0)
+                .map(|entry| (entry.me_key, entry.me_value))
+                .collect())
+        },
+        Version{major: 3, minor: 3..=5, ..} => {
+            let dict: PyDictObject33 = process.copy_struct(addr)?;
+            let keys: PyDictKeysObject33 = process.copy_struct(dict.ma_keys)?;
+            let entries = copy_entries(process, dict.ma_keys + size_of::<PyDictKeysObject33>(), keys.dk_size as usize)?;
+            get_items(process, &entries, dict.ma_values)
+        },
+        Version{major: 3, ..} => {
+            let dict: PyDictObject36 = process.copy_struct(addr)?;
+            let keys: PyDictKeysObject36 = process.copy_struct(dict.ma_keys)?;
+
+            // the entries come after the indices, which use the smallest int type that can index the table
+            let size = keys.dk_size as usize;
+            let index_size = if size <= 0xff { 1 } else if size <= 0xffff { 2 } else if size <= 0xffff_ffff { 4 } else { 8 };
+            let entries_addr = dict.ma_keys + size_of::<PyDictKeysObject36>() + size * index_size;
+            let entries = copy_entries(process, entries_addr, keys.dk_nentries as usize)?;
+            get_items(process, &entries, dict.ma_values)
+        },
+        _ => Err(format_err!("Unsupported version of Python: {}", version))
+    }
+}
+
+/// Looks up a value in a python dict with string keys, returning the pointer to the value
+pub fn dict_get<S: StringObject, P: ProcessMemory>(process: &P, addr: usize, key: &str, version: &Version) -> Result<Option<usize>, Error> {
+    for (item_key, value) in dict_items(process, addr, version)? {
+        // skip over keys that aren't strings, rather than failing the whole lookup
+        if let Ok(item_key) = copy_string(item_key as *const S, process) {
+            if item_key == key {
+                return Ok(Some(value));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Copies a python int. On python 2 this could either be a PyIntObject or a PyLongObject,
+/// on python 3 it is always PyLongObject
+pub fn copy_int<P: ProcessMemory>(process: &P, addr: usize, version: &Version) -> Result<i64, Error> {
+    if version.major == 2 && type_name(process, addr)? == "int" {
+        let value: PyIntObject = process.copy_struct(addr)?;
+        return Ok(value.ob_ival as i64);
+    }
+
+    let header: PyVarObjectHead = process.copy_struct(addr)?;
+    let negative = header.ob_size < 0;
+    let ndigits = header.ob_size.abs() as usize;
+    if ndigits * DIGIT_BITS > 64 + DIGIT_BITS {
+        return Err(format_err!("Int at 0x{:x} is too large to copy ({} digits)", addr, ndigits));
+    }
+
+    let mut digits: Vec<Digit> = vec![0; ndigits];
+    process.read(addr + size_of::<PyVarObjectHead>(), as_bytes_mut(&mut digits))?;
+
+    let mut value: u128 = 0;
+    for (i, &digit) in digits.iter().enumerate() {
+        value |= (digit as u128) << (i * DIGIT_BITS);
+    }
+
+    if value > i64::max_value() as u128 + negative as u128 {
+        return Err(format_err!("Int at 0x{:x} doesn't fit in an i64", addr));
+    }
+    Ok(if negative { (value as i128).wrapping_neg() as i64 } else { value as i64 })
+}
+
+/// Returns the pointers to the items in a python set (python 3.3+)
+pub fn set_items<P: ProcessMemory>(process: &P, addr: usize) -> Result<Vec<usize>, Error> {
+    let set: PySetObject = process.copy_struct(addr)?;
+    let size = set.mask as usize + 1;
+    if size > MAX_DICT_ENTRIES {
+        return Err(format_err!("Refusing to copy {} set entries", size));
+    }
+    let mut entries = vec![SetEntry::default(); size];
+    process.read(set.table, as_bytes_mut(&mut entries))?;
+    // empty slots have a null key, and slots of removed items have a hash of -1
+    Ok(entries.iter().filter(|entry| entry.key != 0 && entry.hash != -1).map(|entry| entry.key).collect())
+}
+
+/// Returns the object a weakref points to, or None if the object has been garbage collected
+pub fn weakref_target<P: ProcessMemory>(process: &P, addr: usize) -> Result<Option<usize>, Error> {
+    let weakref: PyWeakReference = process.copy_struct(addr)?;
+    // dead weakrefs point at None
+    if weakref.wr_object == 0 || type_name(process, weakref.wr_object)? == "NoneType" {
+        return Ok(None);
+    }
+    Ok(Some(weakref.wr_object))
+}
+
+/// Returns the pointers to the items in a python tuple
+pub fn tuple_items<P: ProcessMemory>(process: &P, addr: usize) -> Result<Vec<usize>, Error> {
+    let header: PyVarObjectHead = process.copy_struct(addr)?;
+    copy_pointers(process, addr + size_of::<PyVarObjectHead>(), header.ob_size as usize)
+}
+
+/// Copies an array of pointers from the target process
+pub fn copy_pointers<P: ProcessMemory>(process: &P, addr: usize, count: usize) -> Result<Vec<usize>, Error> {
+    if count > MAX_POINTERS {
+        return Err(format_err!("Refusing to copy {} pointers", count));
+    }
+    let mut pointers = vec![0usize; count];
+    process.read(addr, as_bytes_mut(&mut pointers))?;
+    Ok(pointers)
+}
+
+/// Returns a short string representation of a python object. Only the values of None, bools,
+/// ints, floats and strings are copied (with strings truncated to max_length characters),
+/// everything else is shown by its type name - so we never have to follow pointers into containers
+pub fn format_variable<S: StringObject, P: ProcessMemory>(process: &P, addr: usize, version: &Version, max_length: usize) -> Result<String, Error> {
+    let type_name = type_name(process, addr)?;
+    let value = match type_name.as_ref() {
+        "NoneType" => Some("None".to_owned()),
+        "bool" => {
+            // bools are a subclass of int on python 2, and of long on python 3
+            let value = if version.major == 2 {
+                process.copy_struct::<PyIntObject>(addr)?.ob_ival as i64
+            } else {
+                copy_int(process, addr, version)?
+            };
+            Some(if value != 0 { "True" } else { "False" }.to_owned())
+        },
+        // ints that don't fit in an i64 fall through to just displaying the type name
+        "int" | "long" => copy_int(process, addr, version).ok().map(|value| value.to_string()),
+        "float" => Some(format!("{:?}", process.copy_struct::<PyFloatObject>(addr)?.ob_fval)),
+        "str" => copy_string(addr as *const S, process).ok().map(|value| {
+            if value.chars().count() > max_length {
+                format!("{:?}...", value.chars().take(max_length).collect::<String>())
+            } else {
+                format!("{:?}", value)
+            }
+        }),
+        _ => None
+    };
+    Ok(value.unwrap_or_else(|| format!("<{} at 0x{:x}>", type_name, addr)))
+}
+
+/// Returns the name of the type of a python object
+pub fn type_name<P: ProcessMemory>(process: &P, addr: usize) -> Result<String, Error> {
+    let object: PyObjectHead = process.copy_struct(addr)?;
+    // tp_name comes directly after the PyObject_VAR_HEAD in PyTypeObject
+    let tp_name: usize = process.copy_struct(object.ob_type + size_of::<PyVarObjectHead>())?;
+    copy_cstring(process, tp_name, 256)
+}
+
+/// Returns the pointer to the __dict__ of a module object
+pub fn module_dict<P: ProcessMemory>(process: &P, addr: usize) -> Result<usize, Error> {
+    // md_dict is the first member of PyModuleObject on all versions
+    Ok(process.copy_struct(addr + size_of::<PyObjectHead>())?)
+}
+
+/// Returns the pointer to the __dict__ of an instance of a python class
+pub fn instance_dict<P: ProcessMemory>(process: &P, addr: usize) -> Result<usize, Error> {
+    let object: PyObjectHead = process.copy_struct(addr)?;
+    // the layout of PyTypeObject up until tp_dictoffset is the same for every version we support
+    let type_object: _typeobject = process.copy_struct(object.ob_type)?;
+    if type_object.tp_dictoffset <= 0 {
+        return Err(format_err!("Object at 0x{:x} doesn't have an instance dict", addr));
+    }
+
+    let dict: usize = process.copy_struct(addr + type_object.tp_dictoffset as usize)?;
+    if dict == 0 {
+        return Err(format_err!("Object at 0x{:x} has a null instance dict", addr));
+    }
+    Ok(dict)
+}
+
+fn copy_entries<P: ProcessMemory>(process: &P, addr: usize, count: usize) -> Result<Vec<PyDictKeyEntry>, Error> {
+    if count > MAX_DICT_ENTRIES {
+        return Err(format_err!("Refusing to copy {} dict entries", count));
+    }
+    let mut entries = vec![PyDictKeyEntry::default(); count];
+    process.read(addr, as_bytes_mut(&mut entries))?;
+    Ok(entries)
+}
+
+// gets the items from the python 3 dict entries, handling 'split' tables (used for instance
+// dicts, where the keys are shared between instances and values are stored separately)
+fn get_items<P: ProcessMemory>(process: &P, entries: &[PyDictKeyEntry], values: usize) -> Result<Vec<(usize, usize)>, Error> {
+    let values = if values != 0 {
+        let mut values_array = vec![0usize; entries.len()];
+        process.read(values, as_bytes_mut(&mut values_array))?;
+        values_array
+    } else {
+        entries.iter().map(|entry| entry.me_value).collect()
+    };
+
+    Ok(entries.iter().zip(values.iter())
+        .filter(|(entry, &value)| entry.me_key != 0 && value != 0)
+        .map(|(entry, &value)| (entry.me_key, value))
+        .collect())
+}
+
+fn copy_cstring<P: ProcessMemory>(process: &P, addr: usize, max_length: usize) -> Result<String, Error> {
+    // copy in small chunks, so that we don't try reading past the end of the mapped memory
+    let mut bytes = Vec::new();
+    while bytes.len() < max_length {
+        let chunk = process.copy(addr + bytes.len(), 16)?;
+        if let Some(end) = chunk.iter().position(|&b| b == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return Ok(String::from_utf8(bytes)?);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Err(format_err!("String at 0x{:x} is longer than {} bytes", addr, max_length))
+}
+
+fn as_bytes_mut<T: Copy>(values: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, values.len() * size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+
+    fn version(major: u64, minor: u64) -> Version {
+        Version{major, minor, patch: 0, release_flags: String::from(""), free_threaded: false}
+    }
+
+    #[test]
+    fn test_copy_int() {
+        // a python 3 PyLongObject with 2 digits
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct PyLong { head: PyVarObjectHead, digits: [Digit; 2] }
+
+        let value: i64 = (5 << DIGIT_BITS) + 7;
+        let mut long = PyLong{head: PyVarObjectHead{ob_base: PyObjectHead{ob_refcnt: 1, ob_type: 0}, ob_size: 2},
+                              digits: [7, 5]};
+        assert_eq!(copy_int(&LocalProcess, &long as *const _ as usize, &version(3, 7)).unwrap(), value);
+
+        long.head.ob_size = -2;
+        assert_eq!(copy_int(&LocalProcess, &long as *const _ as usize, &version(3, 7)).unwrap(), -value);
+
+        long.head.ob_size = 0;
+        assert_eq!(copy_int(&LocalProcess, &long as *const _ as usize, &version(3, 7)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_format_variable() {
+        use crate::python_bindings::v3_7_0::PyUnicodeObject;
+
+        // create type objects to point at, which only need tp_name filled in
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct TypeObject { head: PyVarObjectHead, tp_name: *const u8 }
+        let type_object = |name: &'static [u8]| TypeObject{head: PyVarObjectHead{ob_base: PyObjectHead{ob_refcnt: 1, ob_type: 0}, ob_size: 0},
+                                                          tp_name: name.as_ptr()};
+        let float_type = type_object(b"float\0");
+        let none_type = type_object(b"NoneType\0");
+        let list_type = type_object(b"list\0");
+
+        let float = PyFloatObject{ob_base: PyObjectHead{ob_refcnt: 1, ob_type: &float_type as *const _ as usize}, ob_fval: 1.5};
+        let none = PyObjectHead{ob_refcnt: 1, ob_type: &none_type as *const _ as usize};
+        let list = PyObjectHead{ob_refcnt: 1, ob_type: &list_type as *const _ as usize};
+
+        let format = |addr: usize| format_variable::<PyUnicodeObject, LocalProcess>(&LocalProcess, addr, &version(3, 7), 16).unwrap();
+        assert_eq!(format(&float as *const _ as usize), "1.5");
+        assert_eq!(format(&none as *const _ as usize), "None");
+        let list_addr = &list as *const _ as usize;
+        assert_eq!(format(list_addr), format!("<list at 0x{:x}>", list_addr));
+    }
+
+    #[test]
+    fn test_dict_items() {
+        // a python 3.6+ dict with 8 slots (so 1 byte indices) and 3 entries, one of which is deleted
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct Keys { header: PyDictKeysObject36, indices: [u8; 8], entries: [PyDictKeyEntry; 3] }
+
+        let keys = Keys{header: PyDictKeysObject36{dk_refcnt: 1, dk_size: 8, dk_lookup: 0, dk_usable: 2, dk_nentries: 3},
+                        indices: [0; 8],
+                        entries: [PyDictKeyEntry{me_hash: 0, me_key: 0x10, me_value: 0x100},
+                                  PyDictKeyEntry{me_hash: 0, me_key: 0, me_value: 0},
+                                  PyDictKeyEntry{me_hash: 0, me_key: 0x30, me_value: 0x300}]};
+        let dict = PyDictObject36{ob_base: PyObjectHead{ob_refcnt: 1, ob_type: 0}, ma_used: 2, ma_version_tag: 0,
+                                  ma_keys: &keys as *const _ as usize, ma_values: 0};
+
+        let items = dict_items(&LocalProcess, &dict as *const _ as usize, &version(3, 7)).unwrap();
+        assert_eq!(items, vec![(0x10, 0x100), (0x30, 0x300)]);
+    }
+
+    #[test]
+    fn test_set_items() {
+        // a set with 4 slots, holding 2 items and one removed item
+        let table = [SetEntry{key: 0x10, hash: 1}, SetEntry{key: 0, hash: 0},
+                     SetEntry{key: 0x30, hash: -1}, SetEntry{key: 0x40, hash: 4}];
+        let set = PySetObject{ob_base: PyObjectHead{ob_refcnt: 1, ob_type: 0}, fill: 3, used: 2, mask: 3,
+                              table: &table as *const _ as usize};
+        assert_eq!(set_items(&LocalProcess, &set as *const _ as usize).unwrap(), vec![0x10, 0x40]);
+    }
+}
@@ -14,7 +14,11 @@ use std;
 
 pub trait InterpreterState {
     type ThreadState: ThreadState;
+    type StringObject: StringObject;
     fn head(&self) -> * mut Self::ThreadState;
+    fn modules(&self) -> usize;
+    fn next(&self) -> * mut Self;
+    fn id(&self) -> Option<i64>;
 }
 
 pub trait ThreadState {
@@ -25,6 +29,8 @@ pub trait ThreadState {
     fn frame(&self) -> * mut Self::FrameObject;
     fn thread_id(&self) -> u64;
     fn next(&self) -> * mut Self;
+    /// The address of the thread's dict, where per-thread state like threading.local values is kept
+    fn dict(&self) -> usize;
 }
 
 pub trait FrameObject {
@@ -32,6 +38,9 @@ pub trait FrameObject {
     fn code(&self) -> * mut Self::CodeObject;
     fn lasti(&self) -> i32;
     fn back(&self) -> * mut Self;
+    fn locals_address(&self, base: usize) -> usize;
+    /// The address of the top of the value stack, which is only set for frames of suspended generators
+    fn stacktop(&self) -> usize;
 }
 
 pub trait CodeObject {
@@ -41,7 +50,22 @@ pub trait CodeObject {
     fn name(&self) -> * mut Self::StringObject;
     fn filename(&self) -> * mut Self::StringObject;
     fn lnotab(&self) -> * mut Self::BytesObject;
+    fn bytecode(&self) -> * mut Self::BytesObject;
+    fn names(&self) -> usize;
     fn first_lineno(&self) -> i32;
+    fn nlocals(&self) -> i32;
+    fn varnames(&self) -> usize;
+
+    /// The qualified name of the function (like 'Foo.run'). Only code objects from python 3.11+
+    /// have co_qualname, and we don't have bindings for any of those yet - so this defaults to None,
+    /// and callers should fall back to name()
+    fn qualname(&self) -> Option<* mut Self::StringObject> { None }
+}
+
+/// The layout of one of the structs we read, for diagnosing builds of python that we get wrong
+pub trait MemberOffsets {
+    /// The size of the struct, and the offset of each member we read from it
+    fn layout() -> (usize, Vec<(&'static str, usize)>);
 }
 
 pub trait BytesObject {
@@ -56,18 +80,40 @@ pub trait StringObject {
     fn address(&self, base: usize) -> usize;
 }
 
+// the id field of PyInterpreterState was added in python 3.7
+macro_rules! InterpreterIdImpl {
+    () => ( fn id(&self) -> Option<i64> { None } );
+    (id) => ( fn id(&self) -> Option<i64> { Some(self.id) } );
+}
+
 fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
     member as usize - object as usize
 }
 
+macro_rules! MemberOffsetsImpl {
+    ($struct: ty, $($member: ident),*) => (
+        impl MemberOffsets for $struct {
+            fn layout() -> (usize, Vec<(&'static str, usize)>) {
+                // the bindings are plain old data, so an all zero value is fine to measure offsets with
+                let object: $struct = unsafe { std::mem::zeroed() };
+                (std::mem::size_of::<$struct>(), vec![$((stringify!($member), offset_of(&object, &object.$member))),*])
+            }
+        }
+    )
+}
+
 /// This macro provides a common impl for PyThreadState/PyFrameObject/PyCodeObject traits
 /// (this code is identical across python versions, we are only abstracting the struct layouts here).
 /// String handling changes substantially between python versions, and is handled separately.
 macro_rules! PythonCommonImpl {
-    ($py: ident, $bytesobject: ident, $stringobject: ident) => (
+    ($py: ident, $bytesobject: ident, $stringobject: ident $(, $id: ident)*) => (
         impl InterpreterState for $py::PyInterpreterState {
             type ThreadState = $py::PyThreadState;
+            type StringObject = $py::$stringobject;
             fn head(&self) -> * mut Self::ThreadState { self.tstate_head }
+            fn modules(&self) -> usize { self.modules as usize }
+            fn next(&self) -> * mut Self { self.next }
+            InterpreterIdImpl!($($id)*);
         }
 
         impl ThreadState for $py::PyThreadState {
@@ -77,6 +123,7 @@ macro_rules! PythonCommonImpl {
             fn thread_id(&self) -> u64 { self.thread_id as u64 }
             fn next(&self) -> * mut Self { self.next }
             fn interp(&self) -> *mut Self::InterpreterState { self.interp }
+            fn dict(&self) -> usize { self.dict as usize }
         }
 
         impl FrameObject for $py::PyFrameObject {
@@ -84,6 +131,8 @@ macro_rules! PythonCommonImpl {
             fn code(&self) -> * mut Self::CodeObject { self.f_code }
             fn lasti(&self) -> i32 { self.f_lasti }
             fn back(&self) -> * mut Self { self.f_back }
+            fn locals_address(&self, base: usize) -> usize { base + offset_of(self, &self.f_localsplus) }
+            fn stacktop(&self) -> usize { self.f_stacktop as usize }
         }
 
         impl CodeObject for $py::PyCodeObject {
@@ -92,8 +141,17 @@ macro_rules! PythonCommonImpl {
             fn name(&self) -> * mut Self::StringObject { self.co_name as * mut Self::StringObject }
             fn filename(&self) -> * mut Self::StringObject { self.co_filename as * mut Self::StringObject }
             fn lnotab(&self) -> * mut Self::BytesObject { self.co_lnotab as * mut Self::BytesObject }
+            fn bytecode(&self) -> * mut Self::BytesObject { self.co_code as * mut Self::BytesObject }
+            fn names(&self) -> usize { self.co_names as usize }
             fn first_lineno(&self) -> i32 { self.co_firstlineno }
+            fn nlocals(&self) -> i32 { self.co_nlocals }
+            fn varnames(&self) -> usize { self.co_varnames as usize }
         }
+
+        MemberOffsetsImpl!($py::PyInterpreterState, next, tstate_head, modules);
+        MemberOffsetsImpl!($py::PyThreadState, next, interp, frame, dict, thread_id);
+        MemberOffsetsImpl!($py::PyFrameObject, f_back, f_code, f_lasti, f_stacktop, f_localsplus);
+        MemberOffsetsImpl!($py::PyCodeObject, co_nlocals, co_firstlineno, co_code, co_names, co_varnames, co_filename, co_name, co_lnotab);
     )
 }
 
@@ -144,11 +202,11 @@ macro_rules! Python2StringImpl {
     )
 }
 // Python 3.8
-PythonCommonImpl!(v3_8_0, PyBytesObject, PyUnicodeObject);
+PythonCommonImpl!(v3_8_0, PyBytesObject, PyUnicodeObject, id);
 Python3StringImpl!(v3_8_0);
 
 // Python 3.7
-PythonCommonImpl!(v3_7_0, PyBytesObject, PyUnicodeObject);
+PythonCommonImpl!(v3_7_0, PyBytesObject, PyUnicodeObject, id);
 Python3StringImpl!(v3_7_0);
 
 // Python 3.6
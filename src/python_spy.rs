@@ -1,35 +1,67 @@
 use std;
 use std::collections::HashMap;
-#[cfg(all(target_os="linux", unwind))]
+#[cfg(unwind)]
 use std::collections::HashSet;
 use std::mem::size_of;
 use std::slice;
 use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(all(target_os="linux", unwind))]
 use std::iter::FromIterator;
 use regex::Regex;
 
 use failure::{Error, ResultExt};
-use remoteprocess::{Process, ProcessMemory, Pid, Tid};
-use proc_maps::{get_process_maps, MapRange};
+use remoteprocess::{Process, ProcessMemory, Pid, ReadStats, Tid};
+use proc_maps::get_process_maps;
 
 
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
+#[cfg(target_os="linux")]
+use crate::kernel_stack::read_kernel_stack;
 #[cfg(unwind)]
 use crate::native_stack_trace::NativeStack;
 use crate::python_bindings::{pyruntime, v2_7_15, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0};
-use crate::python_interpreters::{self, InterpreterState, ThreadState};
-use crate::stack_trace::{StackTrace, get_stack_traces, get_stack_trace};
+use crate::python_asyncio::{current_tasks_dict, current_task_names, suspended_tasks};
+use crate::python_context::context_value;
+use crate::python_interpreters::{self, FrameObject, InterpreterState, MemberOffsets, ThreadState};
+use crate::python_threading::thread_names;
+use crate::stack_trace::{Frame, FrameOrigin, StackTrace, blocking_builtin, get_frame, get_stack_traces, get_stack_trace};
+use crate::utils::{is_under_root, remap_filename};
 use crate::version::Version;
 
+/// Errors from attaching to a process that callers might want to handle differently from other
+/// failures (py-spy exits with a different status code for each of these)
+#[derive(Debug)]
+pub enum AttachError {
+    /// The process doesn't look like it's running python
+    NoPython(String),
+    /// The process is running python, but a version or implementation we can't read
+    UnsupportedVersion(String),
+    /// The process exited while we were attaching to it
+    ProcessExited(Pid),
+}
+
+impl std::fmt::Display for AttachError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttachError::NoPython(msg) | AttachError::UnsupportedVersion(msg) => write!(f, "{}", msg),
+            AttachError::ProcessExited(pid) => write!(f, "Process {} exited while attaching", pid),
+        }
+    }
+}
+
+impl std::error::Error for AttachError {}
+
 /// Lets you retrieve stack traces of a running python program
 pub struct PythonSpy {
     pub pid: Pid,
     pub process: Process,
     pub version: Version,
     pub interpreter_address: usize,
+    pub interpreter_head_address: usize,
     pub threadstate_address: usize,
+    pub finalizing_address: usize,
     pub python_filename: String,
     pub version_string: String,
     pub config: Config,
@@ -37,16 +69,67 @@ pub struct PythonSpy {
     pub native: Option<NativeStack>,
     pub short_filenames: HashMap<String, Option<String>>,
     pub python_thread_ids: HashMap<u64, Tid>,
+    pub python_thread_names: HashMap<u64, String>,
+    thread_names_updated: Option<Instant>,
+    #[cfg(windows)]
+    os_thread_names: HashMap<Tid, Option<String>>,
+    current_tasks: Option<usize>,
+    current_tasks_updated: Option<Instant>,
+    thread_cpu_times: HashMap<Tid, Duration>,
+    // python threads that we've warned about not finding the OS thread for, so that we only warn once
+    #[cfg(unwind)]
+    unmatched_threads: HashSet<u64>,
+    // set once reading kernel stacks has failed because we don't have permission
+    #[cfg(target_os="linux")]
+    kernel_stacks_unavailable: bool,
+    /// When the stack traces returned by the last call to get_stack_traces were taken
+    pub sample_time: Option<SystemTime>,
+}
+
+/// Counts of the memory read from a process, split into reads of python's data structures and reads
+/// made while unwinding native stacks
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryReads {
+    pub python: ReadStats,
+    pub native: ReadStats,
+}
+
+impl MemoryReads {
+    /// The reads made since an earlier snapshot
+    pub fn since(&self, earlier: &MemoryReads) -> MemoryReads {
+        MemoryReads{python: self.python.since(&earlier.python), native: self.native.since(&earlier.native)}
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.python.bytes + self.native.bytes
+    }
+
+    /// Describes the average reads per sample, like '12.3 KiB in 140 reads a sample (2.1 KiB in 40
+    /// reads of python, 10.2 KiB in 100 of native)'
+    pub fn describe_average(&self, samples: u64) -> String {
+        let samples = std::cmp::max(samples, 1) as f64;
+        let kib = |stats: &ReadStats| stats.bytes as f64 / 1024.0 / samples;
+        let calls = |stats: &ReadStats| stats.calls as f64 / samples;
+        format!("{:.1} KiB in {:.0} reads a sample ({:.1} KiB in {:.0} reads of python, {:.1} KiB in {:.0} of native)",
+                self.bytes() as f64 / 1024.0 / samples, (self.python.calls + self.native.calls) as f64 / samples,
+                kib(&self.python), calls(&self.python), kib(&self.native), calls(&self.native))
+    }
 }
 
 impl PythonSpy {
     /// Constructs a new PythonSpy object.
     pub fn new(pid: Pid, config: &Config) -> Result<PythonSpy, Error> {
+        #[cfg(target_os="linux")]
+        let process = match config.pidfd {
+            Some(fd) => remoteprocess::Process::from_pidfd(fd),
+            None => remoteprocess::Process::new(pid)
+        }.context("Failed to open process - check if it is running.")?;
+        #[cfg(not(target_os="linux"))]
         let process = remoteprocess::Process::new(pid)
             .context("Failed to open process - check if it is running.")?;
 
         // get basic process information (memory maps/symbols etc)
-        let python_info = PythonProcessInfo::new(&process)?;
+        let python_info = PythonProcessInfo::new(&process, config.python_binary.as_deref(), config.libpython.as_deref())?;
 
         // lock the process when loading up on freebsd (rather than locking
         // on every memory read). Needs done after getting python process info
@@ -60,60 +143,55 @@ impl PythonSpy {
         let interpreter_address = get_interpreter_address(&python_info, &process, &version)?;
         info!("Found interpreter at 0x{:016x}", interpreter_address);
 
+        // lets us find sub-interpreters that are created after we attach
+        let interpreter_head_address = get_interpreter_head_address(&python_info, &process, &version, interpreter_address);
+
         // lets us figure out which thread has the GIL
-         let threadstate_address = match version {
-             Version{major: 3, minor: 7..=9, ..} => {
-                match python_info.get_symbol("_PyRuntime") {
-                    Some(&addr) => {
-                        if let Some(offset) = pyruntime::get_tstate_current_offset(&version) {
-                            info!("Found _PyRuntime @ 0x{:016x}, getting gilstate.tstate_current from offset 0x{:x}",
-                                addr, offset);
-                            addr as usize + offset
-                        } else {
-                            warn!("Unknown pyruntime.gilstate.tstate_current offset for version {:?}", version);
-                            0
-                        }
-                    },
-                    None => {
-                        warn!("Failed to find _PyRuntime symbol - won't be able to detect GIL usage");
-                        0
-                    }
-                }
-             },
-             _ => {
-                 match python_info.get_symbol("_PyThreadState_Current") {
-                    Some(&addr) => {
-                        info!("Found _PyThreadState_Current @ 0x{:016x}", addr);
-                        addr as usize
-                    },
-                    None => {
-                        warn!("Failed to find _PyThreadState_Current symbol - won't be able to detect GIL usage");
-                        0
-                    }
-                }
-             }
-         };
+        let threadstate_address = get_threadstate_address(&python_info, &version);
+
+        // lets us tell when the interpreter is shutting down
+        let finalizing_address = get_finalizing_address(&python_info, &version);
 
         let version_string = format!("python{}.{}", version.major, version.minor);
 
         #[cfg(all(unwind, not(target_os="linux")))]
         let native = if config.native {
-            Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?)
+            Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary, config)?)
         } else {
             None
         };
 
         #[cfg(all(unwind, target_os="linux"))]
-        let native = Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary)?);
+        let native = Some(NativeStack::new(pid, python_info.python_binary, python_info.libpython_binary, config)?);
 
-        Ok(PythonSpy{pid, process, version, interpreter_address, threadstate_address,
+        // make sure that the pid wasn't reused by another process while we were reading from it
+        #[cfg(target_os="linux")]
+        {
+            if process.exited()? {
+                return Err(AttachError::ProcessExited(pid).into());
+            }
+        }
+
+        Ok(PythonSpy{pid, process, version, interpreter_address, interpreter_head_address, threadstate_address, finalizing_address,
                      python_filename: python_info.python_filename,
                      version_string,
                      #[cfg(unwind)]
                      native,
                      config: config.clone(),
                      short_filenames: HashMap::new(),
-                     python_thread_ids: HashMap::new()})
+                     python_thread_ids: HashMap::new(),
+                     python_thread_names: HashMap::new(),
+                     thread_names_updated: None,
+                     #[cfg(windows)]
+                     os_thread_names: HashMap::new(),
+                     current_tasks: None,
+                     current_tasks_updated: None,
+                     thread_cpu_times: HashMap::new(),
+                     #[cfg(unwind)]
+                     unmatched_threads: HashSet::new(),
+                     #[cfg(target_os="linux")]
+                     kernel_stacks_unavailable: false,
+                     sample_time: None})
     }
 
     /// Creates a PythonSpy object, retrying up to max_retries times.
@@ -143,8 +221,71 @@ impl PythonSpy {
         }
     }
 
-    /// Gets a StackTrace for each thread in the current process
+    /// How much memory has been read from the process so far, for python's data structures and
+    /// for unwinding native stacks
+    pub fn memory_reads(&self) -> MemoryReads {
+        #[cfg(unwind)]
+        let native = self.native.as_ref().map(|native| native.read_stats()).unwrap_or_default();
+        #[cfg(not(unwind))]
+        let native = ReadStats::default();
+        MemoryReads{python: self.process.read_stats(), native}
+    }
+
+    /// Whether the interpreter has started shutting down. Thread states are torn down while
+    /// finalizing, so stack traces taken after this point can't be trusted
+    pub fn is_finalizing(&self) -> bool {
+        if self.finalizing_address == 0 {
+            return false;
+        }
+        match self.process.copy_struct::<usize>(self.finalizing_address) {
+            Ok(tstate) => tstate != 0,
+            Err(_) => false
+        }
+    }
+
+    /// Describes the addresses we found in the process, and the layouts of the structs we read for
+    /// this version of python (with the offset of each member), for debugging unsupported builds
+    pub fn describe_offsets(&self) -> Result<Vec<String>, Error> {
+        let layouts = match self.version {
+            Version{major: 2, minor: 3..=7, ..} => struct_layouts::<v2_7_15::_is>(),
+            Version{major: 3, minor: 3, ..} => struct_layouts::<v3_3_7::_is>(),
+            Version{major: 3, minor: 4..=5, ..} => struct_layouts::<v3_5_5::_is>(),
+            Version{major: 3, minor: 6, ..} => struct_layouts::<v3_6_6::_is>(),
+            Version{major: 3, minor: 7, ..} => struct_layouts::<v3_7_0::_is>(),
+            Version{major: 3, minor: 8, patch: 0, ..} => {
+                match self.version.release_flags.as_ref() {
+                    "a1" | "a2" | "a3" => struct_layouts::<v3_7_0::_is>(),
+                    _ => struct_layouts::<v3_8_0::_is>()
+                }
+            }
+            Version{major: 3, minor: 8..=9, ..} => struct_layouts::<v3_8_0::_is>(),
+            _ => return Err(AttachError::UnsupportedVersion(format!("Unsupported version of Python: {}", self.version)).into())
+        };
+
+        let mut lines = vec![format!("Python v{} ({})", self.version, self.python_filename),
+                             format!("interpreter: 0x{:x}", self.interpreter_address),
+                             format!("interpreter head: 0x{:x}", self.interpreter_head_address),
+                             format!("thread state: 0x{:x}", self.threadstate_address),
+                             format!("finalizing: 0x{:x}", self.finalizing_address)];
+        for (name, (size, mut members)) in layouts {
+            members.sort_by_key(|(_, offset)| *offset);
+            let members: Vec<String> = members.iter().map(|(member, offset)| format!("{}=0x{:x}", member, offset)).collect();
+            lines.push(format!("{} (size 0x{:x}): {}", name, size, members.join(" ")));
+        }
+        Ok(lines)
+    }
+
+    /// Gets a StackTrace for each thread in the current process. With config.native set, each
+    /// trace also has the native frames for the thread, and threads that are only running native
+    /// code are included as well. With config.only_native, every trace is just the native stack
     pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
+        #[cfg(unwind)]
+        {
+            if self.config.only_native {
+                return self._get_native_stack_traces();
+            }
+        }
+
         match self.version {
             // ABI for 2.3/2.4/2.5/2.6/2.7 is compatible for our purpose
             Version{major: 2, minor: 3..=7, ..} => self._get_stack_traces::<v2_7_15::_is>(),
@@ -163,18 +304,20 @@ impl PythonSpy {
             }
             // currently v3.8 and v3.9 have same ABI, but that will likely change as 3.9 evolves
             Version{major: 3, minor: 8..=9, ..} => self._get_stack_traces::<v3_8_0::_is>(),
-            _ => Err(format_err!("Unsupported version of Python: {}", self.version)),
+            Version{free_threaded: true, ..} => Err(AttachError::UnsupportedVersion(format!("Free-threaded builds of Python aren't supported yet: {}", self.version)).into()),
+            _ => Err(AttachError::UnsupportedVersion(format!("Unsupported version of Python: {}", self.version)).into()),
         }
     }
 
     // implementation of get_stack_traces, where we have a type for the InterpreterState
     fn _get_stack_traces<I: InterpreterState>(&mut self) -> Result<Vec<StackTrace>, Error> {
         // Query the OS to get if each thread in the process is running or not
-        let mut thread_activity = HashMap::new();
-        for thread in self.process.threads()?.iter() {
-            let threadid: Tid = thread.id()?;
-            thread_activity.insert(threadid, thread.active()?);
-        }
+        let thread_activity = self._get_thread_activity()?;
+
+        // kernel stacks have to be read before locking the process, since stopping a thread
+        // interrupts the system call it's blocked in
+        #[cfg(target_os="linux")]
+        let mut kernel_stacks = self._get_kernel_stacks(&thread_activity);
 
         // Lock the process if appropiate. Note we have to lock AFTER getting the thread
         // activity status from the OS (otherwise each thread would report being inactive always).
@@ -185,84 +328,465 @@ impl PythonSpy {
         } else {
             Some(self.process.lock().context("Failed to suspend process")?)
         };
+        self.sample_time = Some(SystemTime::now());
+
+        #[cfg(unwind)]
+        {
+            if let Some(native) = self.native.as_mut() {
+                native.new_sample();
+            }
+        }
 
         let gil_thread_id = self._get_gil_threadid::<I>()?;
 
-        // Get the python interpreter, and loop over all the python threads
+        // Get the python interpreter we attached to (asyncio tasks are only looked up in this one)
         let interp: I = self.process.copy_struct(self.interpreter_address)
            .context("Failed to copy PyInterpreterState from process")?;
 
+        let task_names = self._get_task_names(&interp);
+
+        // sub-interpreters each have their own list of threads, so get the threads from all of them
+        let interpreters = self._get_interpreters::<I>()?;
+        let mut threads = Vec::new();
+        for interpreter in &interpreters {
+            let mut thread_address = interpreter.head();
+            while !thread_address.is_null() {
+                let thread = self.process.copy_pointer(thread_address).context("Failed to copy PyThreadState")?;
+                let next = thread.next();
+                threads.push((interpreter.id(), thread_address, thread));
+                thread_address = next;
+
+                // This seems to happen occasionally when scanning BSS addresses for valid interpeters
+                if threads.len() > 4096 {
+                    return Err(format_err!("Max thread recursion depth reached"));
+                }
+            }
+        }
+
+        // python thread ids (and OS thread ids) get reused once threads exit, so forget the mappings
+        // of threads that have gone away, rather than assuming they're stable for the whole run
+        let python_threads: HashSet<u64> = threads.iter().map(|(_, _, thread)| thread.thread_id()).collect();
+        let os_threads: HashSet<Tid> = thread_activity.keys().cloned().collect();
+        forget_exited_threads(&mut self.python_thread_ids, &python_threads, &os_threads);
+
         let mut traces = Vec::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            // Get the stack trace of the python thread
-            let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-            let mut trace = get_stack_trace(&thread, &self.process)?;
+        #[cfg(unwind)]
+        let mut python_os_threads = HashSet::new();
+        for (interpreter_id, thread_address, thread) in threads {
+            // When we only care about the thread holding the GIL, skip the others entirely (which
+            // avoids the overhead of getting their stacks, and especially unwinding their native stacks)
+            if self.config.gil_only && !self.version.free_threaded && thread.thread_id() != gil_thread_id {
+                continue;
+            }
 
             // Try getting the native thread id
             let python_thread_id = thread.thread_id();
-            let os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+            let os_thread_id = self._get_os_thread_id(python_thread_id, &interpreters)?;
+
+            #[cfg(unwind)]
+            {
+                if let Some(id) = os_thread_id {
+                    python_os_threads.insert(id);
+                }
+            }
+
+            // likewise skip threads that we haven't been asked to sample
+            if !should_sample_thread(&self.config.thread_ids, python_thread_id, os_thread_id.map(|id| id as u64)) {
+                continue;
+            }
+
+            // Get the stack trace of the python thread
+            let mut trace = match get_stack_trace(&thread, &self.process, self.config.dump_locals, self.config.max_depth, &self.version) {
+                Ok(trace) => trace,
+                Err(e) if self._thread_exited(os_thread_id) => {
+                    info!("Thread {:#X} exited while getting its stack trace: {}", python_thread_id, e);
+                    continue;
+                },
+                Err(e) => return Err(e)
+            };
+
+            // Without pausing the process, the thread could have returned from or called into another
+            // function while we were reading its frames. Drop the sample rather than report a torn stack
+            if self.config.non_blocking {
+                let current = self.process.copy_pointer(thread_address).context("Failed to copy PyThreadState")?;
+                if current.frame() != thread.frame() {
+                    return Err(format_err!("Stack for thread {:#X} changed while being read", python_thread_id));
+                }
+            }
+
+            // threads parked in a blocking native call (like pthread_cond_wait) are idle, even
+            // if the OS reports them as running
+            #[allow(unused_mut)]
+            let mut blocked = false;
 
             #[cfg(unwind)]
             {
                 if self.config.native {
                     if let Some(native) = self.native.as_mut() {
-                        let os_thread = remoteprocess::Thread::new(os_thread_id.unwrap())?;
-                        trace.frames = native.merge_native_thread(&trace.frames, &os_thread)?;
+                        // threads that were started since we last mapped python threads to OS threads
+                        // might not have an OS thread yet, so just report their python frames
+                        match os_thread_id {
+                            Some(os_thread_id) => {
+                                // merge without the '... N more frames' frame, so that the python frames
+                                // still line up with the eval frames in the native stack
+                                let truncated = self.config.max_depth.map_or(false, |depth| trace.frames.len() > depth);
+                                let remaining = if truncated { trace.frames.pop() } else { None };
+                                let os_thread = remoteprocess::Thread::new(os_thread_id)?;
+                                match native.merge_native_thread(&trace.frames, &os_thread, truncated) {
+                                    Ok(frames) => trace.frames = frames,
+                                    Err(e) if thread_exited(&self.process, os_thread_id) => {
+                                        info!("Thread {} exited while getting its native stack: {}", os_thread_id, e);
+                                        continue;
+                                    },
+                                    Err(e) if e.iter_chain().any(|cause| cause.downcast_ref::<crate::cython::MissingSourceMap>().is_some()) => return Err(e),
+                                    Err(e) => {
+                                        // one thread failing to merge shouldn't throw away the stacks of every other thread, so
+                                        // report this one with just its python frames (it's still counted as failed in the unwind stats)
+                                        debug!("Failed to merge native stack for thread {}, using python frames only: {}", os_thread_id, e);
+                                    }
+                                }
+                                trace.frames.extend(remaining);
+                                blocked = native.is_blocked(&trace.frames);
+                            },
+                            None => {
+                                if self.unmatched_threads.insert(python_thread_id) {
+                                    warn!("Failed to find the OS thread for python thread {:#X}, skipping its native stack",
+                                          python_thread_id);
+                                } else {
+                                    debug!("Failed to find the OS thread for python thread {:#X}", python_thread_id);
+                                }
+                            }
+                        }
                     }
                 }
             }
 
             trace.os_thread_id = os_thread_id.map(|id| id as u64);
+            trace.interpreter_id = interpreter_id;
+            trace.thread_name = self._get_thread_name(python_thread_id, &interpreters);
+
+            // threads started outside of the threading module can still be named by SetThreadDescription
+            #[cfg(windows)]
+            {
+                if trace.thread_name.is_none() {
+                    trace.thread_name = os_thread_id.and_then(|id| os_thread_name(&mut self.os_thread_names, id));
+                }
+            }
+            if let Some(task) = task_names.get(&python_thread_id) {
+                trace.frames.push(Frame{name: format!("task: {}", task), filename: String::from(""),
+                                        module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false});
+            }
+            if let Some((module, path)) = self.config.context_attr.as_ref() {
+                match context_value(&interp, &self.process, &thread, module, path, &self.version) {
+                    Ok(Some(value)) => trace.frames.push(Frame{name: format!("context: {}", value), filename: String::new(),
+                                                               module: None, short_filename: None, line: 0, locals: None, first_line: None,
+                                                               lasti: None, origin: FrameOrigin::Python, application: false}),
+                    Ok(None) => {},
+                    Err(e) => debug!("Failed to read {}:{} for thread {:#X}: {}", module, path, python_thread_id, e)
+                }
+            }
             trace.owns_gil = trace.thread_id == gil_thread_id;
 
-            trace.active = match os_thread_id.map(|id| thread_activity.get(&id)) {
+            trace.active = !blocked && match os_thread_id.map(|id| thread_activity.get(&id)) {
                 Some(Some(active)) => *active,
-                _ => !self._heuristic_is_thread_idle(&trace)
+                _ => !heuristic_is_thread_idle(&trace)
             };
 
+            // without native stacks, show which builtin a blocked thread is waiting in
+            if !self.config.native {
+                if let Some(builtin) = blocking_builtin(&thread, &self.process, &self.version) {
+                    trace.frames.insert(0, Frame{name: format!("<built-in: {}>", builtin), filename: String::new(),
+                                                 module: None, short_filename: None, line: 0, locals: None, first_line: None, lasti: None,
+                                                 origin: FrameOrigin::Native, application: false});
+                }
+            }
+
             for frame in &mut trace.frames {
+                if !self.config.path_remaps.is_empty() {
+                    frame.filename = remap_filename(&frame.filename, &self.config.path_remaps);
+                }
+                frame.application = is_under_root(&frame.filename, &self.config.app_roots);
                 frame.short_filename = self.shorten_filename(&frame.filename);
             }
 
-            // This seems to happen occasionally when scanning BSS addresses for valid interpeters
             traces.push(trace);
-            if traces.len() > 4096 {
-                return Err(format_err!("Max thread recursion depth reached"));
+        }
+
+        // threads that have never run python code won't have a PyThreadState, so won't show up
+        // above. Get their native stacks too - so we can see what they're doing
+        #[cfg(unwind)]
+        {
+            if self.config.native && !self.config.gil_only {
+                traces.extend(self._get_pure_native_traces(&python_os_threads, &thread_activity)?);
+            }
+        }
+
+        // the kernel frames were called from the innermost user frame, so go below everything else
+        #[cfg(target_os="linux")]
+        {
+            for trace in traces.iter_mut() {
+                if let Some(frames) = trace.os_thread_id.and_then(|id| kernel_stacks.remove(&(id as Tid))) {
+                    trace.frames.splice(0..0, frames);
+                }
             }
+        }
 
-            threads = thread.next();
+        // suspended asyncio tasks aren't running on any thread, so report each as a thread of its own
+        if self.config.suspended_tasks {
+            match self._get_suspended_task_traces(&interp) {
+                Ok(suspended) => traces.extend(suspended),
+                Err(e) => warn!("Failed to get suspended asyncio tasks: {}", e)
+            }
         }
+
         Ok(traces)
     }
 
-    // heuristic fallback for determining if a thread is active, used
-    // when we don't have the ability to get the thread information from the OS
-    fn _heuristic_is_thread_idle(&self, trace: &StackTrace) -> bool {
-        let frames = &trace.frames;
-        if frames.is_empty() {
-            true
-        } else {
-            let frame = &frames[0];
-            (frame.name == "wait" && frame.filename.ends_with("threading.py")) ||
-            (frame.name == "select" && frame.filename.ends_with("selectors.py")) ||
-            (frame.name == "poll" && (frame.filename.ends_with("asyncore.py") ||
-                                    frame.filename.contains("zmq") ||
-                                    frame.filename.contains("gevent") ||
-                                    frame.filename.contains("tornado")))
+    /// With --only-native, gets the native stack of every thread without reading any python
+    /// stacks. The eval loop functions are left in as native frames
+    #[cfg(unwind)]
+    fn _get_native_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
+        let thread_activity = self._get_thread_activity()?;
+        let _lock = self.process.lock().context("Failed to suspend process")?;
+        self.sample_time = Some(SystemTime::now());
+        if let Some(native) = self.native.as_mut() {
+            native.new_sample();
+        }
+
+        let traces = self._get_pure_native_traces(&HashSet::new(), &thread_activity)?;
+        Ok(traces)
+    }
+
+    /// Asks the OS whether each thread in the process is running (or with --cpu, whether it has
+    /// used cpu time since the last call)
+    fn _get_thread_activity(&mut self) -> Result<HashMap<Tid, bool>, Error> {
+        let mut thread_activity = HashMap::new();
+        let mut thread_cpu_times = HashMap::new();
+        for thread in self.process.threads()?.iter() {
+            let threadid: Tid = thread.id()?;
+            let active = if self.config.cpu_time {
+                // threads are active if they have used cpu time since the last sample. The first
+                // time we see a thread we don't have anything to compare to, so ask the OS instead
+                thread.cpu_time().and_then(|cpu_time| {
+                    thread_cpu_times.insert(threadid, cpu_time);
+                    match self.thread_cpu_times.get(&threadid) {
+                        Some(previous) => Ok(cpu_time > *previous),
+                        None => thread.active()
+                    }
+                })
+            } else {
+                thread.active()
+            };
+
+            // threads can exit between listing them and reading their status, which shouldn't
+            // fail the whole sample
+            match active {
+                Ok(active) => { thread_activity.insert(threadid, active); },
+                Err(e) => debug!("Failed to get the status of thread {}, it has probably exited: {}", threadid, e)
+            }
+        }
+        self.thread_cpu_times = thread_cpu_times;
+        Ok(thread_activity)
+    }
+
+    /// With --kernel, reads the kernel stacks of the idle threads (running threads aren't blocked
+    /// in the kernel, so their kernel stacks don't say anything useful)
+    #[cfg(target_os="linux")]
+    fn _get_kernel_stacks(&mut self, thread_activity: &HashMap<Tid, bool>) -> HashMap<Tid, Vec<Frame>> {
+        let mut stacks = HashMap::new();
+        if !self.config.kernel_stacks || self.kernel_stacks_unavailable {
+            return stacks;
+        }
+        for (&tid, _) in thread_activity.iter().filter(|(_, active)| !**active) {
+            match read_kernel_stack(self.pid, tid) {
+                Ok(frames) => { stacks.insert(tid, frames); },
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    warn!("Can't read kernel stacks without root, so they won't be included: {}", e);
+                    self.kernel_stacks_unavailable = true;
+                    break;
+                },
+                Err(e) => debug!("Failed to read the kernel stack of thread {}: {}", tid, e)
+            }
+        }
+        stacks
+    }
+
+    /// Gets the native stacks of the OS threads in the process that aren't in python_os_threads
+    #[cfg(unwind)]
+    fn _get_pure_native_traces(&mut self, python_os_threads: &HashSet<Tid>, thread_activity: &HashMap<Tid, bool>)
+            -> Result<Vec<StackTrace>, Error> {
+        let mut traces = Vec::new();
+        if let Some(native) = self.native.as_mut() {
+            let mut native_traces = Vec::new();
+            for thread in self.process.threads()?.iter() {
+                // threads can exit between listing them and reading them, which shouldn't fail the whole sample
+                let os_thread_id = match thread.id() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        debug!("Failed to get the id of a thread, it has probably exited: {}", e);
+                        continue;
+                    }
+                };
+                if python_os_threads.contains(&os_thread_id) ||
+                        !should_sample_thread(&self.config.thread_ids, os_thread_id as u64, Some(os_thread_id as u64)) {
+                    continue;
+                }
+
+                let frames = match native.merge_pure_native_thread(&thread) {
+                    Ok(frames) => frames,
+                    Err(e) if thread_exited(&self.process, os_thread_id) => {
+                        debug!("Thread {} exited while getting its native stack: {}", os_thread_id, e);
+                        continue;
+                    },
+                    Err(e) => {
+                        info!("Failed to get native stack for thread {}: {}", os_thread_id, e);
+                        continue;
+                    }
+                };
+
+                #[cfg(windows)]
+                let thread_name = os_thread_name(&mut self.os_thread_names, os_thread_id);
+                #[cfg(not(windows))]
+                let thread_name = None;
+
+                native_traces.push(StackTrace{thread_id: os_thread_id as u64,
+                                              os_thread_id: Some(os_thread_id as u64),
+                                              active: thread_activity.get(&os_thread_id).cloned().unwrap_or(false) &&
+                                                      !native.is_blocked(&frames),
+                                              owns_gil: false,
+                                              interpreter_id: None,
+                                              pure_native: true,
+                                              thread_name,
+                                              frames});
+            }
+
+            for mut trace in native_traces {
+                for frame in &mut trace.frames {
+                    if !self.config.path_remaps.is_empty() {
+                        frame.filename = remap_filename(&frame.filename, &self.config.path_remaps);
+                    }
+                    frame.application = is_under_root(&frame.filename, &self.config.app_roots);
+                    frame.short_filename = self.shorten_filename(&frame.filename);
+                }
+                traces.push(trace);
+            }
+        }
+        Ok(traces)
+    }
+
+    /// Whether an OS thread has exited since the start of the sample (false if we don't know the thread)
+    fn _thread_exited(&self, os_thread_id: Option<Tid>) -> bool {
+        os_thread_id.map_or(false, |id| thread_exited(&self.process, id))
+    }
+
+    /// Returns every interpreter in the process, starting with the most recently created one
+    fn _get_interpreters<I: InterpreterState>(&self) -> Result<Vec<I>, Error> {
+        // new sub-interpreters are added to the front of the list, so start from the current head of
+        // the list when we know where that is. Otherwise use the interpreter we found when attaching
+        let mut address = self.interpreter_address;
+        if self.interpreter_head_address != 0 {
+            match self.process.copy_struct::<usize>(self.interpreter_head_address) {
+                Ok(head) if head != 0 => address = head,
+                _ => {}
+            }
+        }
+
+        let mut interpreters = Vec::new();
+        while address != 0 {
+            let interp: I = self.process.copy_struct(address).context("Failed to copy PyInterpreterState from process")?;
+            address = interp.next() as usize;
+            interpreters.push(interp);
+            if interpreters.len() > 4096 {
+                return Err(format_err!("Max interpreter recursion depth reached"));
+            }
+        }
+        Ok(interpreters)
+    }
+
+    fn _get_thread_name<I: InterpreterState>(&mut self, python_thread_id: u64, interpreters: &[I]) -> Option<String> {
+        // threads are named when they're started, so refresh the names if we see a thread we don't
+        // know about (but not too often, since it's relatively expensive to look them up)
+        if !self.python_thread_names.contains_key(&python_thread_id) {
+            let refresh = self.thread_names_updated.map_or(true, |updated| updated.elapsed() > Duration::from_secs(1));
+            if refresh {
+                self.thread_names_updated = Some(Instant::now());
+                // each interpreter has its own threading module, naming just its own threads
+                let mut names = HashMap::new();
+                for interp in interpreters {
+                    match thread_names(interp, &self.process, &self.version) {
+                        Ok(interpreter_names) => names.extend(interpreter_names),
+                        Err(e) => info!("Failed to get python thread names: {}", e)
+                    }
+                }
+                self.python_thread_names = names;
+            }
+        }
+        self.python_thread_names.get(&python_thread_id).cloned()
+    }
+
+    /// Gets the stacks of the asyncio tasks that are waiting, rather than running on a thread
+    fn _get_suspended_task_traces<I: InterpreterState>(&mut self, interp: &I) -> Result<Vec<StackTrace>, Error> {
+        let mut traces = Vec::new();
+        for task in suspended_tasks(interp, &self.process, &self.version)? {
+            let task_address = task.task;
+            let mut frames = Vec::new();
+            for frame_ptr in task.frames {
+                let frame_ptr = frame_ptr as *mut <I::ThreadState as ThreadState>::FrameObject;
+                let frame = self.process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
+                let mut frame = get_frame(frame_ptr, &frame, &self.process, self.config.dump_locals, &self.version)?;
+                if !self.config.path_remaps.is_empty() {
+                    frame.filename = remap_filename(&frame.filename, &self.config.path_remaps);
+                }
+                frame.application = is_under_root(&frame.filename, &self.config.app_roots);
+                frame.short_filename = self.shorten_filename(&frame.filename);
+                frames.push(frame);
+            }
+
+            let name = task.name.unwrap_or_else(|| format!("Task at {:#x}", task_address));
+            traces.push(StackTrace{thread_id: task_address as u64, os_thread_id: None, active: false, owns_gil: false,
+                                   thread_name: Some(format!("{} (suspended)", name)), interpreter_id: interp.id(),
+                                   pure_native: false, frames});
+        }
+        Ok(traces)
+    }
+
+    /// Gets the name of the asyncio task running on each thread
+    fn _get_task_names<I: InterpreterState>(&mut self, interp: &I) -> HashMap<u64, String> {
+        // the dict of current tasks won't move once asyncio is imported, but we have to keep
+        // checking periodically for asyncio being imported if we haven't found it yet
+        if self.current_tasks.is_none() {
+            let refresh = self.current_tasks_updated.map_or(true, |updated| updated.elapsed() > Duration::from_secs(1));
+            if refresh {
+                self.current_tasks_updated = Some(Instant::now());
+                match current_tasks_dict(interp, &self.process, &self.version) {
+                    Ok(current_tasks) => self.current_tasks = current_tasks,
+                    Err(e) => info!("Failed to find asyncio current tasks: {}", e)
+                }
+            }
+        }
+
+        match self.current_tasks {
+            Some(current_tasks) => current_task_names::<I::StringObject, Process>(&self.process, current_tasks, &self.version)
+                .unwrap_or_else(|e| {
+                    info!("Failed to get asyncio task names: {}", e);
+                    HashMap::new()
+                }),
+            None => HashMap::new()
         }
     }
 
     #[cfg(windows)]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interpreters: &[I]) -> Result<Option<Tid>, Error> {
+        // python uses the OS thread id as the thread id on windows, so unlike linux we don't have to
+        // look for it in the registers of each thread
         Ok(Some(python_thread_id as Tid))
     }
 
     #[cfg(target_os="macos")]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, _interpreters: &[I]) -> Result<Option<Tid>, Error> {
         // If we've already know this threadid, we're good
-        if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
-            return Ok(Some(*thread_id));
+        if let Some(thread_id) = lookup_os_thread_id(&self.python_thread_ids, python_thread_id) {
+            return Ok(Some(thread_id));
         }
 
         for thread in self.process.threads()?.iter() {
@@ -272,32 +796,34 @@ impl PythonSpy {
             self.python_thread_ids.insert(current_handle, thread.id()?);
         }
 
-        if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
-            return Ok(Some(*thread_id));
+        if let Some(thread_id) = lookup_os_thread_id(&self.python_thread_ids, python_thread_id) {
+            return Ok(Some(thread_id));
         }
         Ok(None)
     }
 
-    #[cfg(all(target_os="linux", not(unwind)))]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    #[cfg(any(all(target_os="linux", not(unwind)), target_os="android"))]
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interpreters: &[I]) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
     #[cfg(all(target_os="linux", unwind))]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, python_thread_id: u64, interpreters: &[I]) -> Result<Option<Tid>, Error> {
         // If we've already know this threadid, we're good
-        if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
-            return Ok(Some(*thread_id));
+        if let Some(thread_id) = lookup_os_thread_id(&self.python_thread_ids, python_thread_id) {
+            return Ok(Some(thread_id));
         }
 
         // Get a list of all the python thread ids
         let mut all_python_threads = HashSet::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-            let current = thread.thread_id();
-            all_python_threads.insert(current);
-            threads = thread.next();
+        for interp in interpreters {
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                let thread = self.process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
+                let current = thread.thread_id();
+                all_python_threads.insert(current);
+                threads = thread.next();
+            }
         }
 
         let processed_os_threads: HashSet<Tid> = HashSet::from_iter(self.python_thread_ids.values().map(|x| *x));
@@ -305,8 +831,10 @@ impl PythonSpy {
         let native = self.native.as_ref().unwrap();
 
         // Try getting the pthread_id from the native stack registers for threads we haven't looked up yet
+        let mut os_threads = Vec::new();
         for thread in self.process.threads()?.iter() {
             let threadid = thread.id()?;
+            os_threads.push(threadid);
             if processed_os_threads.contains(&threadid) {
                 continue;
             }
@@ -321,26 +849,15 @@ impl PythonSpy {
             };
         }
 
-        // we can't get the python threadid for the main thread from registers,
-        // so instead assign the main threadid (pid) to the missing python thread
-        if !processed_os_threads.contains(&self.pid) {
-            let mut unknown_python_threadids = HashSet::new();
-            for python_thread_id in all_python_threads.iter() {
-                if !self.python_thread_ids.contains_key(python_thread_id) {
-                    unknown_python_threadids.insert(*python_thread_id);
-                }
-            }
-
-            if unknown_python_threadids.len() == 1 {
-                let python_thread_id = *unknown_python_threadids.iter().next().unwrap();
-                self.python_thread_ids.insert(python_thread_id, self.pid);
-            } else {
-                error!("failed to get python threadid for main thread!");
-            }
+        // we can't always get the python threadid for a thread from its registers (this happens
+        // for the main thread in particular), so pair up any python thread that's left over
+        if let Some((python_thread_id, os_thread_id)) = pair_unmatched_thread(&all_python_threads, &self.python_thread_ids,
+                                                                              &os_threads, self.pid) {
+            self.python_thread_ids.insert(python_thread_id, os_thread_id);
         }
 
-        if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
-            return Ok(Some(*thread_id));
+        if let Some(thread_id) = lookup_os_thread_id(&self.python_thread_ids, python_thread_id) {
+            return Ok(Some(thread_id));
         }
         info!("failed looking up python threadid for {}. known python_thread_ids {:?}. all_python_threads {:?}",
             python_thread_id, self.python_thread_ids, all_python_threads);
@@ -348,22 +865,16 @@ impl PythonSpy {
     }
 
     #[cfg(target_os="freebsd")]
-    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interp: &I) -> Result<Option<Tid>, Error> {
+    fn _get_os_thread_id<I: InterpreterState>(&mut self, _python_thread_id: u64, _interpreters: &[I]) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
     fn _get_gil_threadid<I: InterpreterState>(&self) -> Result<u64, Error> {
-        // figure out what thread has the GIL by inspecting _PyThreadState_Current
-        if self.threadstate_address > 0 {
-            let addr: usize = self.process.copy_struct(self.threadstate_address)?;
-
-            // if the addr is 0, no thread is currently holding the GIL
-            if addr != 0 {
-                let threadstate: I::ThreadState = self.process.copy_struct(addr)?;
-                return Ok(threadstate.thread_id());
-            }
+        // free-threaded builds don't have a GIL, so no thread owns it
+        if self.version.free_threaded {
+            return Ok(0);
         }
-        Ok(0)
+        get_gil_threadid::<I, Process>(self.threadstate_address, &self.process)
     }
 
     /// We want to display filenames without the boilerplate of the python installation
@@ -375,33 +886,247 @@ impl PythonSpy {
             return short.clone();
         }
 
-        // only include paths that include an __init__.py
-        let mut path = Path::new(filename);
-        while let Some(parent) = path.parent() {
-            path = parent;
-            if !parent.join("__init__.py").exists() {
-                break;
+        let shortened = get_short_filename(filename);
+        self.short_filenames.insert(filename.to_owned(), shortened.clone());
+        shortened
+    }
+}
+
+/// Returns the OS thread id for a python thread id, if we've mapped it
+#[allow(dead_code)]
+fn lookup_os_thread_id(python_thread_ids: &HashMap<u64, Tid>, python_thread_id: u64) -> Option<Tid> {
+    python_thread_ids.get(&python_thread_id).cloned()
+}
+
+/// Removes the python thread id to OS thread id mappings where either thread has gone away
+fn forget_exited_threads(python_thread_ids: &mut HashMap<u64, Tid>, python_threads: &HashSet<u64>, os_threads: &HashSet<Tid>) {
+    python_thread_ids.retain(|python_thread_id, os_thread_id| {
+        python_threads.contains(python_thread_id) && os_threads.contains(os_thread_id)
+    });
+}
+
+/// Whether an OS thread is no longer running in the process
+fn thread_exited(process: &Process, os_thread_id: Tid) -> bool {
+    match process.threads() {
+        Ok(threads) => !threads.iter().any(|thread| thread.id().ok() == Some(os_thread_id)),
+        Err(_) => true
+    }
+}
+
+/// Pairs up the python thread that we couldn't find the OS thread for (when there's just one), with
+/// the one OS thread that isn't mapped to a python thread. When there are several unmapped OS threads
+/// (like threads started by native extensions) this guesses that it's the main thread
+#[cfg(all(target_os="linux", unwind))]
+fn pair_unmatched_thread(python_threads: &HashSet<u64>, python_thread_ids: &HashMap<u64, Tid>,
+                         os_threads: &[Tid], main_thread: Tid) -> Option<(u64, Tid)> {
+    let mut unmatched_python = python_threads.iter().filter(|id| !python_thread_ids.contains_key(id));
+    let python_thread_id = match (unmatched_python.next(), unmatched_python.next()) {
+        (Some(&id), None) => id,
+        (None, _) => return None,
+        (Some(_), Some(_)) => {
+            info!("Have multiple python threads without an OS thread, can't pair them up");
+            return None;
+        }
+    };
+
+    let mapped: HashSet<Tid> = python_thread_ids.values().cloned().collect();
+    let unmatched_os: Vec<Tid> = os_threads.iter().cloned().filter(|id| !mapped.contains(id)).collect();
+    match unmatched_os.as_slice() {
+        [os_thread_id] => Some((python_thread_id, *os_thread_id)),
+        _ if unmatched_os.contains(&main_thread) => Some((python_thread_id, main_thread)),
+        _ => {
+            error!("failed to get python threadid for main thread!");
+            None
+        }
+    }
+}
+
+/// Returns the description of a windows thread, only asking the OS the first time we see the thread
+/// (since descriptions are almost always set once, when the thread starts)
+#[cfg(windows)]
+fn os_thread_name(names: &mut HashMap<Tid, Option<String>>, os_thread_id: Tid) -> Option<String> {
+    names.entry(os_thread_id).or_insert_with(|| {
+        remoteprocess::Thread::new(os_thread_id).and_then(|thread| thread.name()).unwrap_or_else(|e| {
+            info!("Failed to get the description of thread {}: {}", os_thread_id, e);
+            None
+        })
+    }).clone()
+}
+
+/// Whether a thread should be sampled, given the thread ids the user asked for (with no ids meaning all threads)
+fn should_sample_thread(thread_ids: &[u64], python_thread_id: u64, os_thread_id: Option<u64>) -> bool {
+    thread_ids.is_empty() || thread_ids.contains(&python_thread_id) ||
+        os_thread_id.map_or(false, |id| thread_ids.contains(&id))
+}
+
+/// The layouts of the structs read from an interpreter, as (name, (size, member offsets)) pairs
+fn struct_layouts<I>() -> Vec<(&'static str, (usize, Vec<(&'static str, usize)>))>
+        where I: InterpreterState + MemberOffsets,
+              I::ThreadState: MemberOffsets,
+              <I::ThreadState as ThreadState>::FrameObject: MemberOffsets,
+              <<I::ThreadState as ThreadState>::FrameObject as FrameObject>::CodeObject: MemberOffsets {
+    vec![("PyInterpreterState", <I as MemberOffsets>::layout()),
+         ("PyThreadState", <I::ThreadState as MemberOffsets>::layout()),
+         ("PyFrameObject", <<I::ThreadState as ThreadState>::FrameObject as MemberOffsets>::layout()),
+         ("PyCodeObject", <<<I::ThreadState as ThreadState>::FrameObject as FrameObject>::CodeObject as MemberOffsets>::layout())]
+}
+
+/// Returns the python thread id of the thread holding the GIL (or 0 if no thread holds it), by
+/// inspecting the PyThreadState pointer at threadstate_address
+pub fn get_gil_threadid<I, P>(threadstate_address: usize, process: &P) -> Result<u64, Error>
+        where I: InterpreterState, P: ProcessMemory {
+    // figure out what thread has the GIL by inspecting _PyThreadState_Current
+    if threadstate_address > 0 {
+        let addr: usize = process.copy_struct(threadstate_address)?;
+
+        // if the addr is 0, no thread is currently holding the GIL
+        if addr != 0 {
+            let threadstate: I::ThreadState = process.copy_struct(addr)?;
+            return Ok(threadstate.thread_id());
+        }
+    }
+    Ok(0)
+}
+
+/// Shortens a filename by only including paths inside a python package or subpackage
+pub fn get_short_filename(filename: &str) -> Option<String> {
+    // only include paths that include an __init__.py
+    let mut path = Path::new(filename);
+    while let Some(parent) = path.parent() {
+        path = parent;
+        if !parent.join("__init__.py").exists() {
+            break;
+        }
+    }
+
+    // remote the parent prefix and convert to an optional string
+    Path::new(filename)
+        .strip_prefix(path)
+        .ok()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Heuristic fallback for determining if a thread is idle, used
+/// when we don't have the ability to get the thread information from the OS
+pub fn heuristic_is_thread_idle(trace: &StackTrace) -> bool {
+    let frames = &trace.frames;
+    if frames.is_empty() {
+        true
+    } else {
+        let frame = &frames[0];
+        // match on the last part of qualified names, so that 'Condition.wait' is still 'wait'
+        let name = frame.name.rsplit('.').next().unwrap_or(&frame.name);
+        (name == "wait" && frame.filename.ends_with("threading.py")) ||
+        (name == "select" && frame.filename.ends_with("selectors.py")) ||
+        (name == "poll" && (frame.filename.ends_with("asyncore.py") ||
+                                frame.filename.contains("zmq") ||
+                                frame.filename.contains("gevent") ||
+                                frame.filename.contains("tornado")))
+    }
+}
+
+/// Returns the address of the pointer to the head of the list of interpreters (or 0 if this can't
+/// be found). This is only trusted if it currently points at the interpreter we found when attaching
+pub fn get_interpreter_head_address<P: ProcessMemory>(python_info: &PythonProcessInfo, process: &P,
+                                                      version: &Version, interpreter_address: usize) -> usize {
+    let addr = match version {
+        Version{major: 3, minor: 7..=9, ..} => python_info.get_symbol("_PyRuntime")
+            .map(|&addr| addr as usize + pyruntime::get_interp_head_offset(&version)),
+        _ => python_info.get_symbol("interp_head").map(|&addr| addr as usize)
+    };
+
+    match addr {
+        Some(addr) if process.copy_struct::<usize>(addr).ok() == Some(interpreter_address) => addr,
+        _ => {
+            info!("Failed to find the head of the interpreter list - won't be able to find sub-interpreters");
+            0
+        }
+    }
+}
+
+/// Returns the address of the pointer to the PyThreadState that currently holds the GIL
+/// (or 0 if this can't be found)
+pub fn get_threadstate_address(python_info: &PythonProcessInfo, version: &Version) -> usize {
+    match version {
+        Version{major: 3, minor: 7..=9, ..} => {
+            match python_info.get_symbol("_PyRuntime") {
+                Some(&addr) => {
+                    if let Some(offset) = pyruntime::get_tstate_current_offset(&version) {
+                        info!("Found _PyRuntime @ 0x{:016x}, getting gilstate.tstate_current from offset 0x{:x}",
+                            addr, offset);
+                        addr as usize + offset
+                    } else {
+                        warn!("Unknown pyruntime.gilstate.tstate_current offset for version {:?}", version);
+                        0
+                    }
+                },
+                None => {
+                    warn!("Failed to find _PyRuntime symbol - won't be able to detect GIL usage");
+                    0
+                }
             }
+        },
+        _ => {
+            match python_info.get_symbol("_PyThreadState_Current") {
+                Some(&addr) => {
+                    info!("Found _PyThreadState_Current @ 0x{:016x}", addr);
+                    addr as usize
+                },
+                None => {
+                    warn!("Failed to find _PyThreadState_Current symbol - won't be able to detect GIL usage");
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// Returns the address of the pointer to the PyThreadState that is finalizing the interpreter,
+/// which is only set once the interpreter has started shutting down (or 0 if this can't be found)
+pub fn get_finalizing_address(python_info: &PythonProcessInfo, version: &Version) -> usize {
+    let addr = match version {
+        Version{major: 3, minor: 7..=9, ..} => python_info.get_symbol("_PyRuntime")
+            .map(|&addr| addr as usize + pyruntime::get_finalizing_offset(&version)),
+        Version{major: 3, ..} => python_info.get_symbol("_Py_Finalizing").map(|&addr| addr as usize),
+        _ => None
+    };
+    match addr {
+        Some(addr) => addr,
+        None => {
+            info!("Failed to find the interpreter's finalizing state - won't be able to detect shutdown");
+            0
         }
+    }
+}
 
-        // remote the parent prefix and convert to an optional string
-        let shortened = Path::new(filename)
-            .strip_prefix(path)
-            .ok()
-            .map(|p| p.to_string_lossy().to_string());
+/// The bindings we use to read python's structs are for the pointer width py-spy was built for,
+/// so the offsets would be wrong for a process built for a different architecture
+fn check_pointer_width(binary: &BinaryInfo) -> Result<(), AttachError> {
+    match pointer_width_mismatch(binary.is_64) {
+        Some(message) => Err(AttachError::UnsupportedVersion(format!("{} ({})", message, binary.filename))),
+        None => Ok(())
+    }
+}
 
-        self.short_filenames.insert(filename.to_owned(), shortened.clone());
-        shortened
+fn pointer_width_mismatch(target_is_64: bool) -> Option<String> {
+    let spy_bits = 8 * size_of::<usize>();
+    let target_bits = if target_is_64 { 64 } else { 32 };
+    if spy_bits == target_bits {
+        return None;
     }
+    Some(format!("Target is {}-bit but py-spy is {}-bit; rebuild py-spy for the matching architecture",
+                 target_bits, spy_bits))
 }
+
 /// Returns the version of python running in the process.
-fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::Process)
+pub fn get_python_version<P: ProcessMemory>(python_info: &PythonProcessInfo, process: &P)
         -> Result<Version, Error> {
     // If possible, grab the sys.version string from the processes memory (mac osx).
     if let Some(&addr) = python_info.get_symbol("Py_GetVersion.version") {
         info!("Getting version from symbol address");
         if let Ok(bytes) = process.copy(addr as usize, 128) {
             if let Ok(version) = Version::scan_bytes(&bytes) {
+                debug!("Got python version {} from the Py_GetVersion.version symbol", version);
                 return Ok(version);
             }
         }
@@ -412,7 +1137,10 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
     let bss = process.copy(python_info.python_binary.bss_addr as usize,
                            python_info.python_binary.bss_size as usize)?;
     match Version::scan_bytes(&bss) {
-        Ok(version) => return Ok(version),
+        Ok(version) => {
+            debug!("Got python version {} from the python binary BSS", version);
+            return Ok(version);
+        },
         Err(err) => {
             info!("Failed to get version from BSS section: {}", err);
             // try again if there is a libpython.so
@@ -421,13 +1149,24 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
                 let bss = process.copy(libpython.bss_addr as usize,
                                        libpython.bss_size as usize)?;
                 match Version::scan_bytes(&bss) {
-                    Ok(version) => return Ok(version),
+                    Ok(version) => {
+                        debug!("Got python version {} from the libpython BSS", version);
+                        return Ok(version);
+                    },
                     Err(err) => info!("Failed to get version from libpython BSS section: {}", err)
                 }
             }
         }
     }
 
+    // stripped, musl or statically linked binaries might not have the version string in BSS, so
+    // scan all the memory mapped in from the python binary for something that looks like sys.version
+    info!("Scanning memory mapped from the python binary for a version string");
+    if let Some(version) = scan_memory_for_version(python_info, process) {
+        debug!("Got python version {} from scanning the python binary's memory", version);
+        return Ok(version);
+    }
+
     // the python_filename might have the version encoded in it (/usr/bin/python3.5 etc).
     // try reading that in (will miss patch level on python, but that shouldn't matter)
     info!("Trying to get version from path: {}", python_info.python_filename);
@@ -437,19 +1176,51 @@ fn get_python_version(python_info: &PythonProcessInfo, process: &remoteprocess::
             if python.starts_with("python") {
                 let tokens: Vec<&str> = python[6..].split('.').collect();
                 if tokens.len() >= 2 {
-                    if let (Ok(major), Ok(minor)) = (tokens[0].parse::<u64>(), tokens[1].parse::<u64>()) {
-                        return Ok(Version{major, minor, patch:0, release_flags: "".to_owned()})
+                    // free-threaded builds are installed with a 't' suffix (/usr/bin/python3.13t)
+                    let free_threaded = tokens[1].ends_with('t');
+                    if let (Ok(major), Ok(minor)) = (tokens[0].parse::<u64>(), tokens[1].trim_end_matches('t').parse::<u64>()) {
+                        debug!("Got python version {}.{} from the python filename", major, minor);
+                        return Ok(Version{major, minor, patch:0, release_flags: "".to_owned(), free_threaded})
                     }
                 }
             }
         }
     }
-    Err(format_err!("Failed to find python version from target process"))
+    Err(AttachError::NoPython("Failed to find python version from target process".to_owned()).into())
+}
+
+/// Finds the version of python from sys.version strings in the memory mapped from the python
+/// binary and libpython. If there are several different versions in there, prefer the one that
+/// we can find a valid interpreter for
+fn scan_memory_for_version<P: ProcessMemory>(python_info: &PythonProcessInfo, process: &P) -> Option<Version> {
+    let mut candidates: Vec<Version> = Vec::new();
+    for &(start, size) in &python_info.binary_regions {
+        let memory = match process.copy(start, size) {
+            Ok(memory) => memory,
+            Err(e) => {
+                debug!("Failed to copy memory at 0x{:016x} to scan for version: {}", start, e);
+                continue;
+            }
+        };
+        for version in Version::scan_candidates(&memory) {
+            if !candidates.contains(&version) {
+                candidates.push(version);
+            }
+        }
+    }
+
+    if candidates.len() > 1 {
+        info!("Found several candidate python versions in memory: {:?}", candidates);
+        if let Some(i) = candidates.iter().position(|version| get_interpreter_address(python_info, process, version).is_ok()) {
+            return Some(candidates.swap_remove(i));
+        }
+    }
+    candidates.into_iter().next()
 }
 
-fn get_interpreter_address(python_info: &PythonProcessInfo,
-                           process: &remoteprocess::Process,
-                           version: &Version) -> Result<usize, Error> {
+pub fn get_interpreter_address<P: ProcessMemory>(python_info: &PythonProcessInfo,
+                                               process: &P,
+                                               version: &Version) -> Result<usize, Error> {
     // get the address of the main PyInterpreterState object from loaded symbols if we can
     // (this tends to be faster than scanning through the bss section)
     match version {
@@ -458,7 +1229,7 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
                 let addr = process.copy_struct(addr as usize + pyruntime::get_interp_head_offset(&version))?;
 
                 // Make sure the interpreter addr is valid before returning
-                match check_interpreter_addresses(&[addr], &python_info.maps, process, version) {
+                match check_interpreter_addresses(&[addr], python_info, process, version) {
                     Ok(addr) => return Ok(addr),
                     Err(_) => { warn!("Interpreter address from _PyRuntime symbol is invalid {:016x}", addr); }
                 };
@@ -467,7 +1238,7 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
         _ => {
             if let Some(&addr) = python_info.get_symbol("interp_head") {
                 let addr = process.copy_struct(addr as usize)?;
-                match check_interpreter_addresses(&[addr], &python_info.maps, process, version) {
+                match check_interpreter_addresses(&[addr], python_info, process, version) {
                     Ok(addr) => return Ok(addr),
                     Err(_) => { warn!("Interpreter address from interp_head symbol is invalid {:016x}", addr); }
                 };
@@ -477,14 +1248,14 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
     info!("Failed to get interp_head from symbols, scanning BSS section from main binary");
 
     // try scanning the BSS section of the binary for things that might be the interpreterstate
-    match get_interpreter_address_from_binary(&python_info.python_binary, &python_info.maps, process, version) {
+    match get_interpreter_address_from_binary(&python_info.python_binary, python_info, process, version) {
         Ok(addr) => Ok(addr),
         // Before giving up, try again if there is a libpython.so
         Err(err) => {
             match python_info.libpython_binary {
                 Some(ref libpython) => {
                     info!("Failed to get interpreter from binary BSS, scanning libpython BSS");
-                    Ok(get_interpreter_address_from_binary(libpython, &python_info.maps, process, version)?)
+                    Ok(get_interpreter_address_from_binary(libpython, python_info, process, version)?)
                 },
                 None => Err(err)
             }
@@ -492,40 +1263,33 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
     }
 }
 
-fn get_interpreter_address_from_binary(binary: &BinaryInfo,
-                                       maps: &[MapRange],
-                                       process: &remoteprocess::Process,
-                                       version: &Version) -> Result<usize, Error> {
+fn get_interpreter_address_from_binary<P: ProcessMemory>(binary: &BinaryInfo,
+                                                         python_info: &PythonProcessInfo,
+                                                         process: &P,
+                                                         version: &Version) -> Result<usize, Error> {
     // We're going to scan the BSS/data section for things, and try to narrowly scan things that
     // look like pointers to PyinterpreterState
     let bss = process.copy(binary.bss_addr as usize, binary.bss_size as usize)?;
 
     #[allow(clippy::cast_ptr_alignment)]
     let addrs = unsafe { slice::from_raw_parts(bss.as_ptr() as *const usize, bss.len() / size_of::<usize>()) };
-    check_interpreter_addresses(addrs, maps, process, version)
+    check_interpreter_addresses(addrs, python_info, process, version)
 }
 
 // Checks whether a block of memory (from BSS/.data etc) contains pointers that are pointing
 // to a valid PyInterpreterState
-fn check_interpreter_addresses(addrs: &[usize],
-                               maps: &[MapRange],
-                               process: &remoteprocess::Process,
-                               version: &Version) -> Result<usize, Error> {
-    // On windows, we can't just check if a pointer is valid by looking to see if it points
-    // to something in the virtual memory map. Brute-force it instead
-    #[cfg(windows)]
-    fn maps_contain_addr(_: usize, _: &[MapRange]) -> bool { true }
-
-    #[cfg(not(windows))]
-    use proc_maps::maps_contain_addr;
-
+fn check_interpreter_addresses<P: ProcessMemory>(addrs: &[usize],
+                                                 python_info: &PythonProcessInfo,
+                                                 process: &P,
+                                                 version: &Version) -> Result<usize, Error> {
     // This function does all the work, but needs a type of the interpreter
-    fn check<I>(addrs: &[usize],
-                maps: &[MapRange],
-                process: &remoteprocess::Process) -> Result<usize, Error>
-            where I: python_interpreters::InterpreterState {
+    fn check<I, P>(addrs: &[usize],
+                   python_info: &PythonProcessInfo,
+                   process: &P,
+                   version: &Version) -> Result<usize, Error>
+            where I: python_interpreters::InterpreterState, P: ProcessMemory {
         for &addr in addrs {
-            if maps_contain_addr(addr, maps) {
+            if python_info.contains_addr(addr) {
                 // this address points to valid memory. try loading it up as a PyInterpreterState
                 // to further check
                 let interp: I = match process.copy_struct(addr) {
@@ -536,7 +1300,7 @@ fn check_interpreter_addresses(addrs: &[usize],
                 // get the pythreadstate pointer from the interpreter object, and if it is also
                 // a valid pointer then load it up.
                 let threads = interp.head();
-                if maps_contain_addr(threads as usize, maps) {
+                if python_info.contains_addr(threads as usize) {
                     // If the threadstate points back to the interpreter like we expect, then
                     // this is almost certainly the address of the intrepreter
                     let thread = match process.copy_pointer(threads) {
@@ -545,48 +1309,57 @@ fn check_interpreter_addresses(addrs: &[usize],
                     };
 
                     // as a final sanity check, try getting the stack_traces, and only return if this works
-                    if thread.interp() as usize == addr && get_stack_traces(&interp, process).is_ok() {
+                    if thread.interp() as usize == addr && get_stack_traces(&interp, process, version).is_ok() {
                         return Ok(addr);
                     }
                 }
             }
         }
-        Err(format_err!("Failed to find a python interpreter in the .data section"))
+        Err(AttachError::NoPython("Failed to find a python interpreter in the .data section".to_owned()).into())
     }
 
     // different versions have different layouts, check as appropiate
     match version {
-        Version{major: 2, minor: 3..=7, ..} => check::<v2_7_15::_is>(addrs, maps, process),
-        Version{major: 3, minor: 3, ..} => check::<v3_3_7::_is>(addrs, maps, process),
-        Version{major: 3, minor: 4..=5, ..} => check::<v3_5_5::_is>(addrs, maps, process),
-        Version{major: 3, minor: 6, ..} => check::<v3_6_6::_is>(addrs, maps, process),
-        Version{major: 3, minor: 7, ..} => check::<v3_7_0::_is>(addrs, maps, process),
+        Version{major: 2, minor: 3..=7, ..} => check::<v2_7_15::_is, P>(addrs, python_info, process, version),
+        Version{major: 3, minor: 3, ..} => check::<v3_3_7::_is, P>(addrs, python_info, process, version),
+        Version{major: 3, minor: 4..=5, ..} => check::<v3_5_5::_is, P>(addrs, python_info, process, version),
+        Version{major: 3, minor: 6, ..} => check::<v3_6_6::_is, P>(addrs, python_info, process, version),
+        Version{major: 3, minor: 7, ..} => check::<v3_7_0::_is, P>(addrs, python_info, process, version),
         Version{major: 3, minor: 8, patch: 0, ..} => {
             match version.release_flags.as_ref() {
-                "a1" | "a2" | "a3" => check::<v3_7_0::_is>(addrs, maps, process),
-                _ => check::<v3_8_0::_is>(addrs, maps, process)
+                "a1" | "a2" | "a3" => check::<v3_7_0::_is, P>(addrs, python_info, process, version),
+                _ => check::<v3_8_0::_is, P>(addrs, python_info, process, version)
             }
         },
-        Version{major: 3, minor: 8..=9, ..} => check::<v3_8_0::_is>(addrs, maps, process),
-        _ => Err(format_err!("Unsupported version of Python: {}", version))
+        Version{major: 3, minor: 8..=9, ..} => check::<v3_8_0::_is, P>(addrs, python_info, process, version),
+        _ => Err(AttachError::UnsupportedVersion(format!("Unsupported version of Python: {}", version)).into())
     }
 }
 
 /// Holds information about the python process: memory map layout, parsed binary info
 /// for python /libpython etc.
 pub struct PythonProcessInfo {
-    python_binary: BinaryInfo,
+    pub python_binary: BinaryInfo,
     // if python was compiled with './configure --enabled-shared', code/symbols will
     // be in a libpython.so file instead of the executable. support that.
-    libpython_binary: Option<BinaryInfo>,
-    maps: Vec<MapRange>,
-    python_filename: String,
+    pub libpython_binary: Option<BinaryInfo>,
+    // the (start, size) of each region of mapped memory, used to check if pointers are valid
+    pub regions: Vec<(usize, usize)>,
+    // the (start, size) of the readable memory mapped in from python and libpython, which is
+    // scanned for the version string when nothing else works
+    pub binary_regions: Vec<(usize, usize)>,
+    pub python_filename: String,
 }
 
 impl PythonProcessInfo {
-    fn new(process: &remoteprocess::Process) -> Result<PythonProcessInfo, Error> {
-        let filename = process.exe()
-            .context("Failed to get process executable name. Check that the process is running.")?;
+    /// Finds the python binaries loaded by a process. The python_binary and libpython paths are
+    /// used instead of detecting these if given
+    fn new(process: &remoteprocess::Process, python_binary: Option<&str>, libpython: Option<&str>) -> Result<PythonProcessInfo, Error> {
+        let filename = match python_binary {
+            Some(filename) => filename.to_owned(),
+            None => process.exe()
+                .context("Failed to get process executable name. Check that the process is running.")?
+        };
 
         #[cfg(windows)]
         let filename = filename.to_lowercase();
@@ -628,6 +1401,9 @@ impl PythonProcessInfo {
 
             let map = match map {
                 Some(map) => map,
+                None if python_binary.is_some() => {
+                    return Err(format_err!("Failed to find python binary '{}' in the memory maps of process {}", filename, process.pid));
+                },
                 None => {
                     warn!("Failed to find '{}' in virtual memory maps, falling back to first map region", filename);
                     // If we failed to find the executable in the virtual memory maps, just take the first file we find
@@ -658,17 +1434,35 @@ impl PythonProcessInfo {
                     python_binary.bss_addr -= offset;
                 }
             }
+            check_pointer_width(&python_binary)?;
             (python_binary, filename.clone())
         };
 
+        // PyPy has a completely different frame and thread state layout from CPython, which we
+        // can't read. Detect it here to give a clear error, rather than failing to find the interpreter
+        let pypy_filename = if PYPY_SYMBOLS.iter().any(|symbol| python_binary.symbols.contains_key(*symbol)) {
+            Some(python_filename.clone())
+        } else {
+            maps.iter().filter_map(|m| m.filename().as_ref()).find(|pathname| is_pypy_lib(pathname)).cloned()
+        };
+        if let Some(pypy_filename) = pypy_filename {
+            return Err(AttachError::UnsupportedVersion(format!("PyPy is not supported (detected {})", pypy_version(&pypy_filename))).into());
+        }
+
         // likewise handle libpython for python versions compiled with --enabled-shared
          let libpython_binary = {
             let libmap = maps.iter()
                 .find(|m| if let Some(ref pathname) = &m.filename() {
-                    is_python_lib(pathname) && m.is_exec()
+                    match libpython {
+                        Some(libpython) => pathname == libpython && m.is_exec(),
+                        None => is_python_lib(pathname) && m.is_exec()
+                    }
                 } else {
                     false
                 });
+            if let (Some(libpython), None) = (libpython, libmap) {
+                return Err(format_err!("Failed to find libpython '{}' in the memory maps of process {}", libpython, process.pid));
+            }
 
             let mut libpython_binary: Option<BinaryInfo> = None;
             if let Some(libpython) = libmap {
@@ -676,6 +1470,7 @@ impl PythonProcessInfo {
                     info!("Found libpython binary @ {}", filename);
                     #[allow(unused_mut)]
                     let mut parsed = parse_binary(filename, libpython.start() as u64, libpython.size() as u64)?;
+                    check_pointer_width(&parsed)?;
                     #[cfg(windows)]
                     parsed.symbols.extend(get_windows_python_symbols(process.pid, filename, libpython.start() as u64)?);
                     libpython_binary = Some(parsed);
@@ -687,7 +1482,7 @@ impl PythonProcessInfo {
             // In this case read in the dyld_info information and figure out the filename from there
             #[cfg(target_os = "macos")]
             {
-                if libpython_binary.is_none() {
+                if libpython_binary.is_none() && libpython.is_none() {
                     use proc_maps::mac_maps::get_dyld_info;
                     let dyld_infos = get_dyld_info(process.pid)?;
 
@@ -721,7 +1516,25 @@ impl PythonProcessInfo {
             libpython_binary
         };
 
-        Ok(PythonProcessInfo{python_binary, libpython_binary, maps, python_filename})
+        let regions = maps.iter().map(|map| (map.start(), map.size())).collect();
+        let libpython_filename = libpython_binary.as_ref().map(|binary| binary.filename.clone());
+        let binary_regions = maps.iter()
+            .filter(|map| map.is_read() && map.filename().as_ref().map_or(false, |pathname| {
+                is_python_bin(pathname) || Some(pathname) == libpython_filename.as_ref()
+            }))
+            .map(|map| (map.start(), map.size()))
+            .collect();
+        Ok(PythonProcessInfo{python_binary, libpython_binary, regions, binary_regions, python_filename})
+    }
+
+    /// Whether an address points to mapped memory
+    pub fn contains_addr(&self, addr: usize) -> bool {
+        // On windows, we can't just check if a pointer is valid by looking to see if it points
+        // to something in the virtual memory map. Brute-force it instead
+        if cfg!(windows) {
+            return true;
+        }
+        self.regions.iter().any(|&(start, size)| addr >= start && addr < start + size)
     }
 
     pub fn get_symbol(&self, symbol: &str) -> Option<&u64> {
@@ -755,7 +1568,7 @@ pub fn get_windows_python_symbols(pid: Pid, filename: &str, offset: u64) -> std:
     // currently we only need a subset of symbols, and enumerating the symbols is
     // expensive (via SymEnumSymbolsW), so rather than load up all symbols like we
     // do for goblin, just load the the couple we need directly.
-    for symbol in ["_PyThreadState_Current", "interp_head", "_PyRuntime"].iter() {
+    for symbol in ["_PyThreadState_Current", "interp_head", "_PyRuntime", "_Py_Finalizing"].iter() {
         if let Ok((base, addr)) = handler.address_from_name(symbol) {
             // If we have a module base (ie from PDB), need to adjust by the offset
             // otherwise seems like we can take address directly
@@ -767,7 +1580,7 @@ pub fn get_windows_python_symbols(pid: Pid, filename: &str, offset: u64) -> std:
     Ok(ret)
 }
 
-#[cfg(any(target_os="linux", target_os="freebsd"))]
+#[cfg(any(target_os="linux", target_os="android", target_os="freebsd"))]
 pub fn is_python_lib(pathname: &str) -> bool {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"/libpython\d.\d(m|d|u)?.so").unwrap();
@@ -791,6 +1604,40 @@ pub fn is_python_lib(pathname: &str) -> bool {
     RE.is_match(pathname)
 }
 
+/// Symbols exported by PyPy (or the RPython toolchain it's built with) but not by CPython
+const PYPY_SYMBOLS: &[&str] = &["pypy_main_startup", "rpython_startup_code", "pypy_setup_home"];
+
+/// Matches the shared library that PyPy keeps most of its code in (libpypy3.9-c.so etc)
+pub fn is_pypy_lib(pathname: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"[/\\]libpypy\d*(\.\d+)?-c\.(so|dylib|dll)").unwrap();
+    }
+    RE.is_match(pathname)
+}
+
+/// Describes the version of PyPy in a binary, for error messages. The PyPy release is only
+/// in its sys.version string (like '3.9.16 (..., [PyPy 7.3.11 with GCC 10.2.1])'), so scan
+/// the binary on disk for that, falling back to the python version in the filename
+fn pypy_version(filename: &str) -> String {
+    describe_pypy(filename, std::fs::read(filename).ok().as_deref())
+}
+
+fn describe_pypy(filename: &str, contents: Option<&[u8]>) -> String {
+    lazy_static! {
+        static ref RELEASE: regex::bytes::Regex = regex::bytes::Regex::new(r"\[PyPy (\d+\.\d+\.\d+)").unwrap();
+        static ref LANGUAGE: Regex = Regex::new(r"pypy(\d\.\d+)").unwrap();
+    }
+    let release = contents
+        .and_then(|contents| RELEASE.captures(&contents).map(|c| String::from_utf8_lossy(&c[1]).into_owned()));
+    let language = LANGUAGE.captures(filename).map(|c| c[1].to_owned());
+    match (release, language) {
+        (Some(release), Some(language)) => format!("PyPy {} implementing Python {}", release, language),
+        (Some(release), None) => format!("PyPy {}", release),
+        (None, Some(language)) => format!("PyPy implementing Python {}", language),
+        (None, None) => format!("PyPy at '{}'", filename)
+    }
+}
+
 #[cfg(target_os="macos")]
 pub fn is_python_framework(pathname: &str) -> bool {
     pathname.ends_with("/Python") &&
@@ -832,6 +1679,9 @@ mod tests {
         assert!(is_python_lib("/usr/local/lib/libpython3.8m.so"));
         assert!(is_python_lib("/usr/lib/libpython2.7u.so"));
 
+        // termux on android
+        assert!(is_python_lib("/data/data/com.termux/files/usr/lib/libpython3.7m.so"));
+
         // don't blindly match libraries with pytohn in the name (boost_python etc)
         assert!(!is_python_lib("/usr/lib/libboost_python.so"));
         assert!(!is_python_lib("/usr/lib/x86_64-linux-gnu/libboost_python-py27.so.1.58.0"));
@@ -839,6 +1689,143 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_is_pypy_lib() {
+        assert!(is_pypy_lib("/opt/pypy3.9-v7.3.11-linux64/bin/libpypy3.9-c.so"));
+        assert!(is_pypy_lib("/usr/lib/pypy/bin/libpypy-c.so"));
+        assert!(is_pypy_lib("/usr/local/lib/libpypy3-c.dylib"));
+        assert!(is_pypy_lib("C:\\pypy3.9\\libpypy3.9-c.dll"));
+
+        assert!(!is_pypy_lib("/usr/lib/libpython3.9.so"));
+        assert!(!is_pypy_lib("/usr/lib/libpypyutils.so"));
+    }
+
+    #[test]
+    fn test_describe_pypy() {
+        let contents = b"\x00\x003.9.16 (feeeb, Dec 29 2022, 14:19:32)\n[PyPy 7.3.11 with GCC 10.2.1]\x00";
+        assert_eq!(describe_pypy("/opt/pypy3.9/bin/libpypy3.9-c.so", Some(contents)), "PyPy 7.3.11 implementing Python 3.9");
+        assert_eq!(describe_pypy("/usr/bin/pypy", Some(contents)), "PyPy 7.3.11");
+        assert_eq!(describe_pypy("/opt/pypy3.9/bin/libpypy3.9-c.so", None), "PyPy implementing Python 3.9");
+        assert_eq!(describe_pypy("/usr/bin/pypy", None), "PyPy at '/usr/bin/pypy'");
+    }
+
+    #[cfg(all(target_os="linux", unwind))]
+    #[test]
+    fn test_pair_unmatched_thread() {
+        let python_threads: HashSet<u64> = vec![0x1000, 0x2000].into_iter().collect();
+        let mut python_thread_ids = HashMap::new();
+        python_thread_ids.insert(0x2000, 101 as Tid);
+
+        // the only python thread and native thread left over get paired up, even if the native
+        // thread isn't the main thread
+        assert_eq!(pair_unmatched_thread(&python_threads, &python_thread_ids, &[101, 102], 100), Some((0x1000, 102)));
+
+        // with more than one unmatched native thread, the main thread is picked if it's one of them
+        assert_eq!(pair_unmatched_thread(&python_threads, &python_thread_ids, &[100, 101, 102], 100), Some((0x1000, 100)));
+        assert_eq!(pair_unmatched_thread(&python_threads, &python_thread_ids, &[101, 102, 103], 100), None);
+
+        // and nothing is guessed when there's more than one python thread left, or none at all
+        let python_threads: HashSet<u64> = vec![0x1000, 0x2000, 0x3000].into_iter().collect();
+        assert_eq!(pair_unmatched_thread(&python_threads, &python_thread_ids, &[101, 102], 100), None);
+        python_thread_ids.insert(0x1000, 102);
+        python_thread_ids.insert(0x3000, 103);
+        assert_eq!(pair_unmatched_thread(&python_threads, &python_thread_ids, &[101, 102, 103], 100), None);
+    }
+
+    #[test]
+    fn test_memory_reads() {
+        let earlier = MemoryReads{python: ReadStats{calls: 10, bytes: 1000}, native: ReadStats::default()};
+        let later = MemoryReads{python: ReadStats{calls: 50, bytes: 5096}, native: ReadStats{calls: 100, bytes: 20480}};
+        let reads = later.since(&earlier);
+        assert_eq!(reads.python, ReadStats{calls: 40, bytes: 4096});
+        assert_eq!(reads.bytes(), 24576);
+        assert_eq!(reads.describe_average(2),
+                   "12.0 KiB in 70 reads a sample (2.0 KiB in 20 reads of python, 10.0 KiB in 50 of native)");
+    }
+
+    #[test]
+    fn test_pointer_width_mismatch() {
+        let native = cfg!(target_pointer_width="64");
+        assert_eq!(pointer_width_mismatch(native), None);
+        let message = pointer_width_mismatch(!native).unwrap();
+        if native {
+            assert_eq!(message, "Target is 32-bit but py-spy is 64-bit; rebuild py-spy for the matching architecture");
+        } else {
+            assert!(message.starts_with("Target is 64-bit but py-spy is 32-bit"));
+        }
+    }
+
+    #[test]
+    fn test_forget_exited_threads() {
+        let mut python_thread_ids = HashMap::new();
+        python_thread_ids.insert(0x1000, 101 as Tid);
+        python_thread_ids.insert(0x2000, 102 as Tid);
+        python_thread_ids.insert(0x3000, 103 as Tid);
+
+        // 0x2000 has finished running python code, and OS thread 103 has exited (so 0x3000 could
+        // be reused by a new thread)
+        let python_threads: HashSet<u64> = vec![0x1000, 0x3000].into_iter().collect();
+        let os_threads: HashSet<Tid> = vec![101, 102, 104].into_iter().collect();
+        forget_exited_threads(&mut python_thread_ids, &python_threads, &os_threads);
+        assert_eq!(python_thread_ids.len(), 1);
+        assert_eq!(lookup_os_thread_id(&python_thread_ids, 0x1000), Some(101));
+    }
+
+    #[test]
+    fn test_lookup_os_thread_id() {
+        // threads spawned since the last mapping (including the main thread, which has python
+        // thread id 0 on some platforms) won't be in the map
+        let mut python_thread_ids = HashMap::new();
+        python_thread_ids.insert(0x7f00_0000_1000, 1234 as Tid);
+        assert_eq!(lookup_os_thread_id(&python_thread_ids, 0x7f00_0000_1000), Some(1234 as Tid));
+        assert_eq!(lookup_os_thread_id(&python_thread_ids, 0), None);
+        assert_eq!(lookup_os_thread_id(&python_thread_ids, 0x7f00_0000_2000), None);
+    }
+
+    #[test]
+    fn test_should_sample_thread() {
+        assert!(should_sample_thread(&[], 1, Some(2)));
+        assert!(should_sample_thread(&[1], 1, Some(2)));
+        assert!(should_sample_thread(&[2], 1, Some(2)));
+        assert!(!should_sample_thread(&[3], 1, Some(2)));
+        assert!(!should_sample_thread(&[2], 1, None));
+    }
+
+    #[test]
+    fn test_heuristic_is_thread_idle() {
+        let trace = |name: &str, filename: &str| {
+            let frame = Frame{name: name.to_owned(), filename: filename.to_owned(), module: None, short_filename: None,
+                              line: 1, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false};
+            StackTrace{thread_id: 1, os_thread_id: None, active: true, owns_gil: false, thread_name: None,
+                       interpreter_id: None, pure_native: false, frames: vec![frame]}
+        };
+        assert!(heuristic_is_thread_idle(&trace("wait", "/usr/lib/python3.7/threading.py")));
+        assert!(heuristic_is_thread_idle(&trace("Condition.wait", "/usr/lib/python3.7/threading.py")));
+        assert!(heuristic_is_thread_idle(&trace("SelectSelector.select", "/usr/lib/python3.7/selectors.py")));
+        assert!(!heuristic_is_thread_idle(&trace("Foo.wait", "/home/user/app.py")));
+        assert!(!heuristic_is_thread_idle(&trace("Condition.notify", "/usr/lib/python3.7/threading.py")));
+    }
+
+    #[test]
+    fn test_short_filename() {
+        let root = std::env::temp_dir().join(format!("py-spy-short-filename-{}", std::process::id()));
+        let package = root.join("pkg");
+        std::fs::create_dir_all(&package).unwrap();
+        std::fs::write(package.join("__init__.py"), "").unwrap();
+
+        // python files, native extensions and translated cython files in a package are all
+        // shown relative to the package
+        for name in &["mod.py", "_ext.so", "_ext.pyx"] {
+            let filename = package.join(name);
+            assert_eq!(get_short_filename(filename.to_str().unwrap()), Some(format!("pkg{}{}", std::path::MAIN_SEPARATOR, name)));
+        }
+
+        // and native code outside of a package just gets the basename
+        let filename = root.join("libfoo.so");
+        assert_eq!(get_short_filename(filename.to_str().unwrap()), Some("libfoo.so".to_owned()));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
     #[cfg(target_os="macos")]
     #[test]
     fn test_python_frameworks() {
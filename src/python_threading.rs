@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use failure::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::python_data_access::{copy_int, dict_get, dict_items, instance_dict, module_dict};
+use crate::python_interpreters::InterpreterState;
+use crate::stack_trace::copy_string;
+use crate::version::Version;
+
+/// Returns the names of the python threads, keyed by python thread id. This reads the
+/// threading.Thread objects from the 'threading._active' dict, and so will return an empty
+/// map if the threading module hasn't been imported.
+pub fn thread_names<I, P>(interp: &I, process: &P, version: &Version) -> Result<HashMap<u64, String>, Error>
+        where I: InterpreterState, P: ProcessMemory {
+    let mut names = HashMap::new();
+
+    let threading = match dict_get::<I::StringObject, P>(process, interp.modules(), "threading", version)? {
+        Some(module) => module,
+        None => return Ok(names)
+    };
+
+    let active = match dict_get::<I::StringObject, P>(process, module_dict(process, threading)?, "_active", version)? {
+        Some(active) => active,
+        None => return Ok(names)
+    };
+
+    // python 2 mangles the name of the private '__name' attribute
+    let name_attribute = if version.major == 2 { "_Thread__name" } else { "_name" };
+
+    for (ident, thread) in dict_items(process, active, version)? {
+        let ident = copy_int(process, ident, version)?;
+        let dict = instance_dict(process, thread)?;
+        if let Some(name) = dict_get::<I::StringObject, P>(process, dict, name_attribute, version)? {
+            names.insert(ident as u64, copy_string(name as *const I::StringObject, process)?);
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use crate::python_spy::{MemoryReads, PythonSpy};
+use crate::stack_trace::StackTrace;
+use crate::timer::{AdaptiveRate, Timer};
+
+/// Samples a python process at a given rate, yielding the stack traces for each sample.
+///
+/// Each call to `next` sleeps until the next sample is due, then gets the stack traces for every
+/// thread in the process (retrying briefly if the process can't be read). Samples that fail are
+/// returned as errors, so that they can be counted, and iteration stops once the process exits or
+/// the interpreter starts shutting down.
+pub struct Sampler<'a> {
+    spy: &'a mut PythonSpy,
+    timer: Timer,
+    adaptive_rate: Option<AdaptiveRate>,
+    rate: f64,
+    rate_changed: bool,
+    behind: Option<Duration>,
+    exited: bool,
+    finalizing: bool,
+    // the memory read from the process when sampling started, and as of the last sample
+    initial_reads: MemoryReads,
+    last_reads: MemoryReads,
+    samples: u64,
+}
+
+impl<'a> Sampler<'a> {
+    /// Creates a sampler that takes `rate` samples a second
+    pub fn new(spy: &'a mut PythonSpy, rate: f64) -> Sampler<'a> {
+        let initial_reads = spy.memory_reads();
+        Sampler{spy, timer: Timer::new(rate), adaptive_rate: None, rate, rate_changed: false, behind: None, exited: false,
+                finalizing: false, initial_reads, last_reads: initial_reads, samples: 0}
+    }
+
+    /// Lowers the sampling rate as needed so that taking samples uses less than this percentage of
+    /// the wall clock time. The rate passed to `new` is used as the maximum
+    pub fn with_max_overhead(mut self, max_overhead_percent: f64) -> Sampler<'a> {
+        self.adaptive_rate = Some(AdaptiveRate::new(self.rate, max_overhead_percent));
+        self
+    }
+
+    /// Spaces samples uniformly within this percentage of the nominal interval, instead of using
+    /// exponentially distributed intervals. Passing a seed makes the intervals reproducible
+    pub fn with_jitter(mut self, jitter_percent: f64, seed: Option<u64>) -> Sampler<'a> {
+        self.timer.set_jitter(jitter_percent);
+        if let Some(seed) = seed {
+            self.timer.set_seed(seed);
+        }
+        self
+    }
+
+    /// The PythonSpy being sampled
+    pub fn spy(&self) -> &PythonSpy {
+        self.spy
+    }
+
+    /// The number of samples currently being taken a second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Whether the last sample changed the sampling rate (with `with_max_overhead`)
+    pub fn rate_changed(&self) -> bool {
+        self.rate_changed
+    }
+
+    /// How far behind schedule the last sample was taken, if it couldn't be taken on time
+    pub fn behind(&self) -> Option<Duration> {
+        self.behind
+    }
+
+    /// Whether sampling stopped because the process exited
+    pub fn process_exited(&self) -> bool {
+        self.exited
+    }
+
+    /// How much memory has been read from the process to take the samples so far
+    pub fn memory_reads(&self) -> MemoryReads {
+        self.last_reads.since(&self.initial_reads)
+    }
+
+    /// Whether sampling stopped because the interpreter started shutting down
+    pub fn interpreter_finalizing(&self) -> bool {
+        self.finalizing
+    }
+}
+
+impl<'a> Iterator for Sampler<'a> {
+    type Item = Result<Vec<StackTrace>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.exited || self.finalizing {
+            return None;
+        }
+
+        self.behind = match self.timer.next()? {
+            Ok(_) => None,
+            Err(delay) => Some(delay)
+        };
+
+        let sample_start = Instant::now();
+        let traces = sample_with_retry(self.spy);
+
+        let reads = self.spy.memory_reads();
+        let sample_reads = reads.since(&self.last_reads);
+        self.last_reads = reads;
+        self.samples += 1;
+        debug!("Sample read {} bytes in {} reads ({} bytes of python, {} bytes of native), averaging {}",
+               sample_reads.bytes(), sample_reads.python.calls + sample_reads.native.calls,
+               sample_reads.python.bytes, sample_reads.native.bytes,
+               self.memory_reads().describe_average(self.samples));
+
+        self.rate_changed = false;
+        if let Some(adaptive_rate) = self.adaptive_rate.as_mut() {
+            if let Some(rate) = adaptive_rate.update(sample_start.elapsed()) {
+                self.timer.set_rate(rate);
+                self.rate = rate;
+                self.rate_changed = true;
+                info!("Changed sampling rate to {:.1} samples a second", rate);
+            }
+        }
+
+        // samples taken while finalizing can be corrupt, so they're dropped rather than returned
+        if self.spy.is_finalizing() {
+            info!("Interpreter is finalizing, stopping sampling");
+            self.finalizing = true;
+            return None;
+        }
+
+        if traces.is_err() && process_exitted(&self.spy.process) {
+            self.exited = true;
+            return None;
+        }
+        Some(traces)
+    }
+}
+
+/// Gets the stack traces for a single sample. Reading the process can fail momentarily (like when
+/// it's in the middle of forking), so this retries a couple of times with a short backoff before
+/// giving up on the sample. Processes that have exited aren't retried
+pub fn sample_with_retry(process: &mut PythonSpy) -> Result<Vec<StackTrace>, Error> {
+    let mut backoff = Duration::from_micros(500);
+    let mut retries = 0;
+    loop {
+        match process.get_stack_traces() {
+            Ok(traces) => return Ok(traces),
+            Err(err) => {
+                if retries >= 2 || process_exitted(&process.process) {
+                    return Err(err);
+                }
+                debug!("Failed to get stack trace, retrying in {:?}: {}", backoff, err);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+            }
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+pub fn process_exitted(process: &remoteprocess::Process) -> bool {
+    // this uses a pidfd when available, which also catches the pid being reused
+    process.exited().unwrap_or(true)
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn process_exitted(process: &remoteprocess::Process) -> bool {
+    process.exe().is_err()
+}
@@ -26,10 +26,11 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use std::cmp::Ordering;
 use std::collections::{HashMap};
 use std::io;
 use std::io::Write;
-use std::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::stack_trace;
 use remoteprocess::Tid;
@@ -62,12 +63,20 @@ struct SpeedscopeFile {
     profiles: Vec<Profile>,
     shared: Shared,
 
-    #[serde(rename = "activeProfileIndex")]
+    // speedscope rejects an index past the end of profiles, so this is left out when there aren't any
+    #[serde(rename = "activeProfileIndex", default, skip_serializing_if = "Option::is_none")]
     active_profile_index: Option<f64>,
 
     exporter: Option<String>,
 
     name: Option<String>,
+
+    /// The version of py-spy's output and of py-spy itself. These aren't part of the speedscope
+    /// format, and are missing from files written by older versions of py-spy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    py_spy_format_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    py_spy_version: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,6 +95,11 @@ struct Profile {
 
     samples: Vec<Vec<usize>>,
     weights: Vec<f64>,
+
+    /// Whether the thread held the GIL for each sample. This isn't part of the speedscope format
+    /// (which ignores it), but lets post-processors tell GIL holding time from waiting on the GIL
+    #[serde(rename = "pyspyOwnsGil", default, skip_serializing_if = "Option::is_none")]
+    owns_gil: Option<Vec<bool>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -126,32 +140,67 @@ enum ValueUnit {
 }
 
 impl SpeedscopeFile {
-  pub fn new(samples: &HashMap<Tid, Vec<Vec<usize>>>, frames: &Vec<Frame>) -> SpeedscopeFile {
-    let end_value = samples.len();
+  pub fn new(samples: &HashMap<Tid, Vec<Sample>>, frames: &Vec<Frame>, thread_names: &HashMap<Tid, String>,
+             os_thread_ids: &HashMap<Tid, u64>, show_idle: bool, interval: f64) -> SpeedscopeFile {
+    // Each thread gets two profiles: one with only the samples where the thread was
+    // running on the cpu, and one with every sample (wall clock time). This lets
+    // the same recording be viewed either way in speedscope
+    let mut threads: Vec<&Tid> = samples.keys().collect();
+    threads.sort();
+
+    let mut profiles = Vec::new();
+    for tid in threads {
+        // samples from subprocesses are recorded after the fact, so could be out of order
+        let mut samples: Vec<&Sample> = samples[tid].iter().collect();
+        samples.sort_by(|a, b| a.timestamp.partial_cmp(&b.timestamp).unwrap_or(Ordering::Equal));
+
+        // weight each sample by the time until the next one, so that the profiles are laid out
+        // on the same (wall clock) timeline as the samples were taken on
+        let weights: Vec<f64> = samples.iter().enumerate().map(|(i, sample)| {
+            samples.get(i + 1).map_or(interval, |next| (next.timestamp - sample.timestamp).max(0.0))
+        }).collect();
+        let start = samples.first().map_or(0.0, |sample| sample.timestamp);
+
+        let cpu: Vec<(Vec<usize>, f64)> = samples.iter().zip(weights.iter())
+            .filter(|(s, _)| s.active)
+            .map(|(s, weight)| (s.frames.clone(), *weight))
+            .collect();
+        let cpu_gil = samples.iter().filter(|s| s.active).map(|s| s.owns_gil).collect();
+        let wall: Vec<(Vec<usize>, f64)> = samples.iter().zip(weights.iter())
+            .map(|(s, weight)| (s.frames.clone(), *weight))
+            .collect();
+        let wall_gil = samples.iter().map(|s| s.owns_gil).collect();
+        // use the OS thread id where we know it, so that profiles match up with the thread ids
+        // shown by 'py-spy dump' and other tools
+        let thread = match (thread_names.get(tid), os_thread_ids.get(tid)) {
+            (Some(name), Some(os_thread_id)) => format!("{} (tid {})", name, os_thread_id),
+            (Some(name), None) => format!("{} ({:#X})", name, tid),
+            (None, Some(os_thread_id)) => format!("Thread {}", os_thread_id),
+            (None, None) => format!("Thread {:#X}", tid)
+        };
+        let mut cpu = Profile::new(format!("{} (on-cpu)", thread), start, cpu);
+        cpu.owns_gil = Some(cpu_gil);
+        profiles.push(cpu);
+        let mut wall = Profile::new(format!("{} (wall clock)", thread), start, wall);
+        wall.owns_gil = Some(wall_gil);
+        profiles.push(wall);
+    }
 
     SpeedscopeFile {
       // This is always the same
       schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
 
-      active_profile_index: None,
+      // default to the wall clock view if asked to show idle samples
+      active_profile_index: if profiles.is_empty() { None } else { Some(if show_idle { 1.0 } else { 0.0 }) },
 
       name: Some("py-spy profile".to_string()),
 
       exporter: Some(format!("py-spy@{}", env!("CARGO_PKG_VERSION"))),
 
-      profiles: samples.iter().map(|(_, samples)| {
-        let weights: Vec<f64> = (&samples).iter().map(|_s| 1_f64).collect();
+      py_spy_format_version: Some(stack_trace::FORMAT_VERSION),
+      py_spy_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
 
-        Profile {
-            profile_type: ProfileType::Sampled,
-            name: String::from("py-spy"),
-            unit: ValueUnit::None,
-            start_value: 0.0,
-            end_value: end_value as f64,
-            samples: samples.clone(),
-            weights
-        }
-      }).collect(),
+      profiles,
 
       shared: Shared {
           frames: frames.clone()
@@ -160,6 +209,24 @@ impl SpeedscopeFile {
   }
 }
 
+impl Profile {
+    /// Creates a profile starting at 'start' milliseconds since the unix epoch, from pairs of
+    /// (frame indices, milliseconds the sample lasted for)
+    fn new(name: String, start: f64, samples: Vec<(Vec<usize>, f64)>) -> Profile {
+        let (samples, weights): (Vec<Vec<usize>>, Vec<f64>) = samples.into_iter().unzip();
+        Profile {
+            profile_type: ProfileType::Sampled,
+            name,
+            unit: ValueUnit::Milliseconds,
+            start_value: start,
+            end_value: start + weights.iter().sum::<f64>(),
+            samples,
+            weights,
+            owns_gil: None,
+        }
+    }
+}
+
 impl Frame {
     pub fn new(stack_frame: &stack_trace::Frame) -> Frame {
         Frame {
@@ -172,25 +239,135 @@ impl Frame {
     }
 }
 
+impl Frame {
+    /// Parses a frame name from a collapsed stack, like 'name (file.py:10)' or 'name (file.py)'.
+    /// Frames that py-spy added (like the recording labels when merging) just have a name
+    fn from_collapsed(name: &str) -> Frame {
+        let (function, location) = match name.rfind(" (") {
+            Some(index) if name.ends_with(')') => (&name[..index], &name[index + 2..name.len() - 1]),
+            _ => return Frame{name: name.to_owned(), file: None, line: None, col: None}
+        };
+        let (file, line) = match location.rfind(':') {
+            Some(colon) => match location[colon + 1..].parse::<u32>() {
+                Ok(line) => (&location[..colon], Some(line)),
+                Err(_) => (location, None)
+            },
+            None => (location, None)
+        };
+        Frame{name: function.to_owned(), file: Some(file.to_owned()), line, col: None}
+    }
+}
+
+/// A single sample for a thread: indices into the frames vector, along with whether
+/// the thread was running on the cpu (and holding the GIL) at the time, and when it was
+/// taken (in milliseconds since the unix epoch)
+pub struct Sample {
+    frames: Vec<usize>,
+    active: bool,
+    owns_gil: bool,
+    timestamp: f64,
+}
+
+/// Loads a speedscope file as collapsed stacks ('root;..;leaf' mapped to the total weight), using the
+/// same frame names as the flamegraph output. Files written by py-spy have both on-cpu and wall clock
+/// profiles for each thread, only one of which is used (depending on whether idle samples are wanted)
+pub fn load_collapsed(contents: &str, show_linenumbers: bool, include_idle: bool) -> Result<HashMap<String, f64>, Error> {
+    let file: SpeedscopeFile = serde_json::from_str(contents)?;
+
+    let has_tracks = file.profiles.iter().any(|profile| profile.name.ends_with(" (on-cpu)"));
+    let suffix = if include_idle { " (wall clock)" } else { " (on-cpu)" };
+
+    let frames: Vec<String> = file.shared.frames.iter().map(|frame| {
+        let filename = frame.file.as_ref().map_or("", |f| f.as_str());
+        match frame.line {
+            Some(line) if show_linenumbers && line != 0 => format!("{} ({}:{})", frame.name, filename, line),
+            _ => format!("{} ({})", frame.name, filename)
+        }
+    }).collect();
+
+    let mut counts = HashMap::new();
+    for profile in file.profiles.iter().filter(|profile| !has_tracks || profile.name.ends_with(suffix)) {
+        for (sample, weight) in profile.samples.iter().zip(profile.weights.iter()) {
+            // py-spy weights samples by how long they lasted, but merging works on sample counts
+            let weight = if has_tracks { &1.0 } else { weight };
+            let stack: Result<Vec<&str>, Error> = sample.iter().map(|&index| {
+                frames.get(index).map(|f| f.as_str()).ok_or_else(|| format_err!("Invalid frame index {}", index))
+            }).collect();
+            *counts.entry(stack?.join(";")).or_insert(0.0) += weight;
+        }
+    }
+    Ok(counts)
+}
+
+/// Writes collapsed stacks (like from merging recordings) as a speedscope file, with a single
+/// profile where each stack is weighted by its sample count
+pub fn write_collapsed(counts: &HashMap<String, usize>, w: &mut dyn Write) -> Result<(), Error> {
+    let mut stacks: Vec<(&String, &usize)> = counts.iter().collect();
+    stacks.sort();
+
+    let mut frames = Vec::new();
+    let mut frame_to_index: HashMap<&str, usize> = HashMap::new();
+    let samples: Vec<(Vec<usize>, f64)> = stacks.iter().map(|(stack, count)| {
+        let indices = stack.split(';').map(|name| {
+            *frame_to_index.entry(name).or_insert_with(|| {
+                frames.push(Frame::from_collapsed(name));
+                frames.len() - 1
+            })
+        }).collect();
+        (indices, **count as f64)
+    }).collect();
+
+    let mut profile = Profile::new("merged".to_owned(), 0.0, samples);
+    profile.unit = ValueUnit::None;
+    let file = SpeedscopeFile {
+        schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
+        active_profile_index: Some(0.0),
+        name: Some("py-spy merged profile".to_string()),
+        exporter: Some(format!("py-spy@{}", env!("CARGO_PKG_VERSION"))),
+        py_spy_format_version: Some(stack_trace::FORMAT_VERSION),
+        py_spy_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        profiles: vec![profile],
+        shared: Shared{frames}
+    };
+    writeln!(w, "{}", serde_json::to_string(&file)?)?;
+    Ok(())
+}
+
 pub struct Stats {
-    samples: HashMap<Tid, Vec<Vec<usize>>>,
+    samples: HashMap<Tid, Vec<Sample>>,
     frames: Vec<Frame>,
-    frame_to_index: HashMap<stack_trace::Frame, usize>
+    frame_to_index: HashMap<stack_trace::Frame, usize>,
+    thread_names: HashMap<Tid, String>,
+    os_thread_ids: HashMap<Tid, u64>,
+    show_idle: bool,
+    /// Milliseconds between samples, which is how long the last sample of each thread lasts
+    interval: f64,
 }
 
 impl Stats {
-    pub fn new() -> Stats {
+    pub fn new(show_idle: bool, sampling_rate: u64) -> Stats {
         Stats {
             samples: HashMap::new(),
             frames: vec![],
-            frame_to_index: HashMap::new()
+            frame_to_index: HashMap::new(),
+            thread_names: HashMap::new(),
+            os_thread_ids: HashMap::new(),
+            show_idle,
+            interval: 1000.0 / sampling_rate as f64
         }
     }
 
-    pub fn record(&mut self, stack: &stack_trace::StackTrace) -> Result<(), io::Error> {
+    /// Sets the rate samples were taken at
+    pub fn set_sampling_rate(&mut self, rate: f64) {
+        self.interval = 1000.0 / rate;
+    }
+
+    pub fn record(&mut self, stack: &stack_trace::StackTrace, time: SystemTime) -> Result<(), io::Error> {
+        let timestamp = time.duration_since(UNIX_EPOCH).map_or(0.0, |t| t.as_micros() as f64 / 1000.0);
         let mut frame_indices: Vec<usize> = stack.frames.iter().map(|frame| {
             let frames = &mut self.frames;
-            *self.frame_to_index.entry(frame.clone()).or_insert_with(|| {
+            // speedscope frames only go down to the line
+            *self.frame_to_index.entry(stack_trace::Frame{lasti: None, ..frame.clone()}).or_insert_with(|| {
                 let len = frames.len();
                 frames.push(Frame::new(&frame));
                 len
@@ -200,13 +377,119 @@ impl Stats {
 
         self.samples.entry(stack.thread_id as Tid).or_insert_with(|| {
             vec![]
-        }).push(frame_indices);
+        }).push(Sample{frames: frame_indices, active: stack.active, owns_gil: stack.owns_gil, timestamp});
+
+        if let Some(name) = stack.thread_name.as_ref() {
+            self.thread_names.insert(stack.thread_id as Tid, name.clone());
+        }
+        if let Some(os_thread_id) = stack.os_thread_id {
+            self.os_thread_ids.insert(stack.thread_id as Tid, os_thread_id);
+        }
         Ok(())
     }
 
-    pub fn write(&self, w: &mut File) -> Result<(), Error> {
-        let json = serde_json::to_string(&SpeedscopeFile::new(&self.samples, &self.frames))?;
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let json = serde_json::to_string(&SpeedscopeFile::new(&self.samples, &self.frames, &self.thread_names,
+                                                              &self.os_thread_ids, self.show_idle, self.interval))?;
         writeln!(w, "{}", json)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn trace(name: &str, active: bool) -> stack_trace::StackTrace {
+        let frame = stack_trace::Frame{name: name.to_owned(), filename: "test.py".to_owned(), module: None, short_filename: None,
+                                       line: 1, locals: None, first_line: None, lasti: None, origin: stack_trace::FrameOrigin::Python, application: false};
+        stack_trace::StackTrace{thread_id: 1, os_thread_id: None, active, owns_gil: active, thread_name: None,
+                                interpreter_id: None, pure_native: false, frames: vec![frame]}
+    }
+
+    #[test]
+    fn test_wall_clock_timestamps() {
+        let mut stats = Stats::new(false, 100);
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        stats.record(&trace("a", true), start).unwrap();
+        stats.record(&trace("b", false), start + Duration::from_millis(12)).unwrap();
+        stats.record(&trace("a", true), start + Duration::from_millis(20)).unwrap();
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let file: SpeedscopeFile = serde_json::from_slice(&out).unwrap();
+        assert_eq!(file.py_spy_format_version, Some(stack_trace::FORMAT_VERSION));
+
+        // profiles start at the time of the first sample, and each sample lasts until the next one
+        let wall = &file.profiles[1];
+        assert_eq!(wall.start_value, 1_000_000.0);
+        assert_eq!(wall.weights, vec![12.0, 8.0, 10.0]);
+        assert_eq!(wall.end_value, 1_000_030.0);
+        assert_eq!(file.profiles[0].weights, vec![12.0, 10.0]);
+
+        // along with whether the thread held the GIL for each sample
+        assert_eq!(wall.owns_gil, Some(vec![true, false, true]));
+        assert_eq!(file.profiles[0].owns_gil, Some(vec![true, true]));
+
+        // but merging still counts samples
+        let counts = load_collapsed(std::str::from_utf8(&out).unwrap(), false, true).unwrap();
+        assert_eq!(counts["a (test.py)"], 2.0);
+        assert_eq!(counts["b (test.py)"], 1.0);
+    }
+
+    #[test]
+    fn test_thread_profiles() {
+        let mut stats = Stats::new(false, 100);
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+
+        let mut worker = trace("work", true);
+        worker.thread_id = 2;
+        worker.os_thread_id = Some(1234);
+        worker.thread_name = Some("worker".to_owned());
+        stats.record(&worker, start + Duration::from_millis(10)).unwrap();
+        stats.record(&trace("a", true), start + Duration::from_millis(10)).unwrap();
+        stats.record(&worker, start).unwrap();
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let file: SpeedscopeFile = serde_json::from_slice(&out).unwrap();
+
+        // each thread gets its own profiles, sharing the same frames
+        let names: Vec<&str> = file.profiles.iter().map(|profile| profile.name.as_str()).collect();
+        assert_eq!(names, vec!["Thread 0x1 (on-cpu)", "Thread 0x1 (wall clock)",
+                               "worker (tid 1234) (on-cpu)", "worker (tid 1234) (wall clock)"]);
+        assert_eq!(file.shared.frames.len(), 2);
+
+        // with samples in time order, even if they were recorded out of order
+        assert_eq!(file.profiles[3].start_value, 1_000_000.0);
+        assert_eq!(file.profiles[3].weights, vec![10.0, 10.0]);
+    }
+
+    #[test]
+    fn test_no_profiles() {
+        let stats = Stats::new(true, 100);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let file: SpeedscopeFile = serde_json::from_slice(&out).unwrap();
+        assert!(file.profiles.is_empty());
+        assert_eq!(file.active_profile_index, None);
+    }
+
+    #[test]
+    fn test_write_collapsed() {
+        let mut counts = HashMap::new();
+        counts.insert("<module> (test.py:10);foo (test.py:3)".to_owned(), 5);
+        counts.insert("<module> (test.py:10);bar (lib.py)".to_owned(), 2);
+
+        let mut output = Vec::new();
+        write_collapsed(&counts, &mut output).unwrap();
+        let loaded = load_collapsed(&String::from_utf8(output).unwrap(), true, false).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["<module> (test.py:10);foo (test.py:3)"], 5.0);
+        assert_eq!(loaded["<module> (test.py:10);bar (lib.py)"], 2.0);
+
+        let frame = Frame::from_collapsed("host-a");
+        assert_eq!((frame.name.as_str(), frame.file, frame.line), ("host-a", None, None));
+    }
+}
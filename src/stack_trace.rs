@@ -3,47 +3,93 @@ use std;
 use failure::{Error, ResultExt};
 use remoteprocess::ProcessMemory;
 
+use crate::python_data_access::{copy_pointers, format_variable, tuple_items};
 use crate::python_interpreters::{InterpreterState, ThreadState, FrameObject, CodeObject, StringObject, BytesObject};
+use crate::version::Version;
 
 /// Call stack for a single python thread
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StackTrace {
     /// The python thread id for this stack trace
     pub thread_id: u64,
-    /// The OS thread id for this stack tracee
+    /// The OS thread id for this stack trace
     pub os_thread_id: Option<u64>,
     /// Whether or not the thread was active
     pub active: bool,
     /// Whether or not the thread held the GIL
     pub owns_gil: bool,
+    /// The name of the thread (from the python threading module)
+    pub thread_name: Option<String>,
+    /// The id of the interpreter running this thread, for programs using sub-interpreters (0 is the
+    /// main interpreter). Only known for python 3.7+
+    #[serde(default)]
+    pub interpreter_id: Option<i64>,
+    /// Whether this is a native thread that isn't running python code (and only has native frames)
+    pub pure_native: bool,
     /// The frames
     pub frames: Vec<Frame>
 }
 
 /// Information about a single function call in a stack trace
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct Frame {
     /// The function name
     pub name: String,
     /// The full filename of the file
     pub filename: String,
-    /// The module/shared library the
+    /// The module/shared library the frame is from (for native frames)
     pub module: Option<String>,
     /// A short, more readable, representation of the filename
     pub short_filename: Option<String>,
     /// The line number inside the file (or 0 for native frames without line information)
     pub line: i32,
+    /// The line that the function starts on, if known
+    #[serde(default)]
+    pub first_line: Option<i32>,
+    /// The offset of the bytecode instruction being executed (for python frames)
+    #[serde(default)]
+    pub lasti: Option<i32>,
+    /// The local variables of the function, as (name, value) pairs. Only set when
+    /// explicitly requested, since copying them is slower than just getting the stack
+    pub locals: Option<Vec<(String, String)>>,
+    /// Where this frame came from
+    #[serde(default)]
+    pub origin: FrameOrigin,
+    /// Whether the frame's file is under one of the --app-root directories, rather than being
+    /// library code like the standard library or site-packages. Always false without any app roots
+    #[serde(default)]
+    pub application: bool,
+}
+
+/// The version of the serialized form of StackTrace and Frame, which is included along with the
+/// version of py-spy in each of the machine readable outputs. This has to be bumped whenever the
+/// fields of either change, so that whatever reads the output can tell what to expect
+#[allow(dead_code)]
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Whether a frame was for python code, native code, or native code generated by cython
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+pub enum FrameOrigin {
+    Python,
+    Native,
+    Cython,
+}
+
+impl Default for FrameOrigin {
+    fn default() -> FrameOrigin {
+        FrameOrigin::Python
+    }
 }
 
 /// Given an InterpreterState, this function returns a vector of stack traces for each thread
-pub fn get_stack_traces<I, P>(interpreter: &I, process: &P) -> Result<(Vec<StackTrace>), Error>
+pub fn get_stack_traces<I, P>(interpreter: &I, process: &P, version: &Version) -> Result<(Vec<StackTrace>), Error>
         where I: InterpreterState, P: ProcessMemory {
     // TODO: deprecate this method
     let mut ret = Vec::new();
     let mut threads = interpreter.head();
     while !threads.is_null() {
         let thread = process.copy_pointer(threads).context("Failed to copy PyThreadState")?;
-        ret.push(get_stack_trace(&thread, process)?);
+        ret.push(get_stack_trace(&thread, process, false, None, version)?);
         // This seems to happen occasionally when scanning BSS addresses for valid interpeters
         if ret.len() > 4096 {
             return Err(format_err!("Max thread recursion depth reached"));
@@ -53,32 +99,170 @@ pub fn get_stack_traces<I, P>(interpreter: &I, process: &P) -> Result<(Vec<Stack
     Ok(ret)
 }
 
-/// Gets a stack trace for an individual thread
-pub fn get_stack_trace<T, P >(thread: &T, process: &P) -> Result<StackTrace, Error>
+/// Gets a stack trace for an individual thread, optionally including the local variables for each frame.
+/// Only the innermost max_depth frames are copied, with the rest replaced by a single truncated_frame
+pub fn get_stack_trace<T, P >(thread: &T, process: &P, copy_locals: bool, max_depth: Option<usize>, version: &Version) -> Result<StackTrace, Error>
         where T: ThreadState, P: ProcessMemory {
     // TODO: just return frames here? everything else probably should be returned out of scopee
+    let frames = walk_frames(thread.frame(), process, max_depth,
+                             |frame_ptr, frame| get_frame(frame_ptr, frame, process, copy_locals, version))?;
+
+    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None, thread_name: None, interpreter_id: None, pure_native: false})
+}
+
+/// Follows the f_back pointers from the innermost frame, calling read_frame on each. Past max_depth
+/// frames, the remaining frames are only counted and replaced by a single truncated_frame
+fn walk_frames<F, P, R>(mut frame_ptr: *mut F, process: &P, max_depth: Option<usize>, mut read_frame: R) -> Result<Vec<Frame>, Error>
+        where F: FrameObject, P: ProcessMemory, R: FnMut(*mut F, &F) -> Result<Frame, Error> {
     let mut frames = Vec::new();
-    let mut frame_ptr = thread.frame();
     while !frame_ptr.is_null() {
-        let frame = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
-        let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+        let frame: F = process.copy_pointer(frame_ptr).context("Failed to copy PyFrameObject")?;
 
-        let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
-        let name = copy_string(code.name(), process).context("Failed to copy function name")?;
-        let line = get_line_number(&code, frame.lasti(), process).context("Failed to get line number")?;
+        // past the maximum depth, just count the remaining frames without copying their code objects
+        if max_depth.map_or(false, |depth| frames.len() >= depth) {
+            let mut remaining = 1;
+            let mut back = frame.back();
+            while !back.is_null() {
+                back = process.copy_pointer(back).context("Failed to copy PyFrameObject")?.back();
+                remaining += 1;
+                if frames.len() + remaining > 4096 {
+                    return Err(format_err!("Max frame recursion depth reached"));
+                }
+            }
+            frames.push(truncated_frame(remaining));
+            break;
+        }
 
-        frames.push(Frame{name, filename, line, short_filename: None, module: None});
+        frames.push(read_frame(frame_ptr, &frame)?);
         if frames.len() > 4096 {
             return Err(format_err!("Max frame recursion depth reached"));
         }
 
         frame_ptr = frame.back();
     }
+    Ok(frames)
+}
+
+/// Reads a single python frame, given its address in the target process and a copy of it
+pub fn get_frame<F, P>(frame_ptr: *mut F, frame: &F, process: &P, copy_locals: bool, version: &Version) -> Result<Frame, Error>
+        where F: FrameObject, P: ProcessMemory {
+    let code = process.copy_pointer(frame.code()).context("Failed to copy PyCodeObject")?;
+
+    let filename = copy_string(code.filename(), process).context("Failed to copy filename")?;
+    let name = match code.qualname() {
+        Some(qualname) if !qualname.is_null() => copy_string(qualname, process).context("Failed to copy function qualname")?,
+        _ => copy_string(code.name(), process).context("Failed to copy function name")?
+    };
+    // the line is always worked out from the instruction being executed, since the line number
+    // stored on the frame is only updated when tracing
+    let lasti = frame.lasti();
+    let line = get_line_number(&code, lasti, process, version).context("Failed to get line number")?;
+    let first_line = Some(code.first_lineno());
+
+    let locals = if copy_locals {
+        Some(get_locals(&code, frame_ptr as usize, frame, process, version).context("Failed to copy locals")?)
+    } else {
+        None
+    };
+
+    Ok(Frame{name, filename, line, first_line, lasti: Some(lasti), short_filename: None, module: None, locals,
+             origin: FrameOrigin::Python, application: false})
+}
+
+// Builtins that threads usually spend their time blocked in. These are matched by name, since we
+// only know what function is being called from the bytecode of the calling frame
+const BLOCKING_BUILTINS: &[&str] = &["_accept", "accept", "acquire", "connect", "epoll", "getaddrinfo", "poll",
+                                     "read", "readinto", "readline", "recv", "recv_into", "recvfrom",
+                                     "recvfrom_into", "select", "sendall", "sleep", "wait", "waitpid"];
+
+/// Returns the name of the builtin function that a thread's leaf python frame is calling, when it's
+/// one that usually blocks (like 'recv' or 'sleep'). Since python functions get frames of their own,
+/// a leaf frame that is in the middle of a call must be calling a builtin. This only works for python
+/// 3.6+, which has fixed size instructions that we can read backwards from the call
+pub fn blocking_builtin<T, P>(thread: &T, process: &P, version: &Version) -> Option<String>
+        where T: ThreadState, P: ProcessMemory {
+    if version.major != 3 || version.minor < 6 || thread.frame().is_null() {
+        return None;
+    }
+    let frame = process.copy_pointer(thread.frame()).ok()?;
+    let code = process.copy_pointer(frame.code()).ok()?;
+
+    // copy the instructions leading up to the call
+    let lasti = frame.lasti();
+    let bytecode_ptr = code.bytecode();
+    let bytecode = process.copy_pointer(bytecode_ptr).ok()?;
+    if lasti < 0 || lasti as usize + 2 > bytecode.size() {
+        return None;
+    }
+    let end = lasti as usize + 2;
+    let start = end.saturating_sub(64);
+    let instructions = process.copy(bytecode.address(bytecode_ptr as usize) + start, end - start).ok()?;
+
+    let index = called_name_index(&instructions, instructions.len() - 2)?;
+    let names = tuple_items(process, code.names()).ok()?;
+    let name = *names.get(index)? as *const <<T::FrameObject as FrameObject>::CodeObject as CodeObject>::StringObject;
+    copy_string(name, process).ok().filter(|name| BLOCKING_BUILTINS.contains(&name.as_str()))
+}
+
+/// Finds the function being called by the CALL_FUNCTION or CALL_METHOD instruction at an offset in
+/// python 3.6+ bytecode, returning its index in co_names. This only handles calls where each argument
+/// is loaded by a single instruction, and where the function is loaded by name (like 'sock.recv(n)' or
+/// 'time.sleep(1)'), which is enough for most calls to blocking builtins
+fn called_name_index(bytecode: &[u8], offset: usize) -> Option<usize> {
+    const LOAD_CONST: u8 = 100;
+    const LOAD_NAME: u8 = 101;
+    const LOAD_ATTR: u8 = 106;
+    const LOAD_GLOBAL: u8 = 116;
+    const LOAD_FAST: u8 = 124;
+    const CALL_FUNCTION: u8 = 131;
+    const LOAD_DEREF: u8 = 136;
+    const EXTENDED_ARG: u8 = 144;
+    const LOAD_METHOD: u8 = 160;
+    const CALL_METHOD: u8 = 161;
 
-    Ok(StackTrace{frames, thread_id: thread.thread_id(), owns_gil: false, active: true, os_thread_id: None})
+    // returns the opcode and argument of the instruction at an offset, along with the offset of the
+    // previous instruction (skipping over any EXTENDED_ARG prefixes for larger arguments)
+    let instruction = |offset: usize| -> Option<(u8, usize, Option<usize>)> {
+        let opcode = *bytecode.get(offset)?;
+        let mut arg = *bytecode.get(offset + 1)? as usize;
+        let mut start = offset;
+        let mut shift = 8;
+        while start >= 2 && bytecode[start - 2] == EXTENDED_ARG {
+            start -= 2;
+            arg |= (bytecode[start + 1] as usize) << shift;
+            shift += 8;
+        }
+        Some((opcode, arg, start.checked_sub(2)))
+    };
+
+    let (opcode, args, mut previous) = instruction(offset)?;
+    if opcode != CALL_FUNCTION && opcode != CALL_METHOD {
+        return None;
+    }
+
+    for _ in 0..args {
+        let (opcode, _, before) = instruction(previous?)?;
+        match opcode {
+            LOAD_CONST | LOAD_NAME | LOAD_GLOBAL | LOAD_FAST | LOAD_DEREF => previous = before,
+            _ => return None
+        }
+    }
+
+    match instruction(previous?)? {
+        (LOAD_METHOD, index, _) if opcode == CALL_METHOD => Some(index),
+        (LOAD_ATTR, index, _) | (LOAD_GLOBAL, index, _) | (LOAD_NAME, index, _) if opcode == CALL_FUNCTION => Some(index),
+        _ => None
+    }
+}
+
+/// A placeholder for the outermost frames of a stack that was cut short by max_depth
+pub fn truncated_frame(count: usize) -> Frame {
+    Frame{name: format!("... {} more frames", count), filename: String::new(), module: None, short_filename: None,
+          line: 0, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}
 }
 
 impl StackTrace {
+    /// A short description of what the thread was doing: 'idle', 'active' or 'active+gil'
     pub fn status_str(&self) -> &str {
         match (self.owns_gil, self.active) {
             (_, false) => "idle",
@@ -88,12 +272,53 @@ impl StackTrace {
     }
 }
 
+/// Sorts stack traces into a stable order for dumping them, so that dumps of the same state can be
+/// diffed: by OS thread id, followed by the traces without one (like suspended asyncio tasks) by
+/// name and then python thread id
+#[allow(dead_code)]
+pub fn sort_traces(traces: &mut [StackTrace]) {
+    traces.sort_by(|a, b| {
+        (a.os_thread_id.is_none(), a.os_thread_id, &a.thread_name, a.thread_id)
+            .cmp(&(b.os_thread_id.is_none(), b.os_thread_id, &b.thread_name, b.thread_id))
+    });
+}
+
+/// Returns the names and values of the 'fast' locals of a frame (which are the arguments and local
+/// variables for a function). Locals that haven't been assigned yet are skipped
+fn get_locals<C, F, P>(code: &C, frame_ptr: usize, frame: &F, process: &P, version: &Version) -> Result<Vec<(String, String)>, Error>
+        where C: CodeObject, F: FrameObject, P: ProcessMemory {
+    let nlocals = code.nlocals() as usize;
+    let names = tuple_items(process, code.varnames())?;
+    if names.len() < nlocals {
+        return Err(format_err!("Expected {} local variable names, found {}", nlocals, names.len()));
+    }
+
+    let values = copy_pointers(process, frame.locals_address(frame_ptr), nlocals)?;
+
+    let mut ret = Vec::new();
+    for (&name, &value) in names.iter().zip(values.iter()) {
+        if value == 0 {
+            continue;
+        }
+        let name = copy_string(name as *const C::StringObject, process)?;
+        let value = format_variable::<C::StringObject, P>(process, value, version, 128)
+            .unwrap_or_else(|_| format!("<unknown at 0x{:x}>", value));
+        ret.push((name, value));
+    }
+    Ok(ret)
+}
+
 /// Returns the line number from a PyCodeObject (given the lasti index from a PyFrameObject)
-fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, process: &P) -> Result<i32, Error> {
+fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, process: &P, version: &Version) -> Result<i32, Error> {
     let table = copy_bytes(code.lnotab(), process).context("Failed to copy line number table")?;
 
     // unpack the line table. format is specified here:
     // https://github.com/python/cpython/blob/master/Objects/lnotab_notes.txt
+    // (from python 3.6 on, line increments are signed so that the compiler can reorder code)
+    let signed = match version {
+        Version{major: 3, minor: 6..=9, ..} => true,
+        _ => false
+    };
     let size = table.len();
     let mut i = 0;
     let mut line_number: i32 = code.first_lineno();
@@ -104,7 +329,7 @@ fn get_line_number<C: CodeObject, P: ProcessMemory>(code: &C, lasti: i32, proces
             break;
         }
 
-        line_number += i32::from(table[i + 1]);
+        line_number += if signed { i32::from(table[i + 1] as i8) } else { i32::from(table[i + 1]) };
         i += 2;
     }
 
@@ -156,7 +381,7 @@ mod tests {
     // and then test out that the above code handles appropiately
     use super::*;
     use remoteprocess::LocalProcess;
-    use python_bindings::v3_7_0::{PyCodeObject, PyBytesObject, PyVarObject, PyUnicodeObject, PyASCIIObject};
+    use python_bindings::v3_7_0::{PyCodeObject, PyBytesObject, PyVarObject, PyUnicodeObject, PyASCIIObject, PyFrameObject};
     use std::ptr::copy_nonoverlapping;
 
     // python stores data after pybytesobject/pyasciiobject. hack by initializing a 4k buffer for testing.
@@ -202,8 +427,17 @@ mod tests {
         let code = PyCodeObject{co_firstlineno: 3,
                                 co_lnotab: &mut lnotab.base.ob_base.ob_base,
                                 ..Default::default()};
-        let lineno = get_line_number(&code, 30, &LocalProcess).unwrap();
+        let version = Version{major: 3, minor: 7, patch: 0, release_flags: "".to_owned(), free_threaded: false};
+        let lineno = get_line_number(&code, 30, &LocalProcess, &version).unwrap();
         assert_eq!(lineno, 7);
+
+        // lines can go backwards in 3.6+, like for the condition of a loop
+        let mut lnotab = to_byteobject(&[0u8, 3, 6, 1, 4, 254]);
+        let code = PyCodeObject{co_firstlineno: 3,
+                                co_lnotab: &mut lnotab.base.ob_base.ob_base,
+                                ..Default::default()};
+        assert_eq!(get_line_number(&code, 8, &LocalProcess, &version).unwrap(), 7);
+        assert_eq!(get_line_number(&code, 12, &LocalProcess, &version).unwrap(), 5);
     }
 
     #[test]
@@ -223,4 +457,63 @@ mod tests {
         let copied = copy_bytes(&bytes.base, &LocalProcess).unwrap();
         assert_eq!(copied, original);
     }
+
+    #[test]
+    fn test_max_depth() {
+        // a stack of 5 frames, innermost first. Frames are named by their position in the stack
+        // rather than copying a code object, since only the walk over f_back is being tested here
+        let mut frames: Vec<PyFrameObject> = (0..5).map(|i| PyFrameObject{f_lasti: i, ..Default::default()}).collect();
+        for i in 0..4 {
+            frames[i].f_back = &mut frames[i + 1];
+        }
+        let innermost = &mut frames[0] as *mut PyFrameObject;
+        let walk = |max_depth| {
+            walk_frames(innermost, &LocalProcess, max_depth, |_, frame| {
+                Ok(Frame{name: format!("frame{}", frame.lasti()), filename: "test.py".to_owned(), module: None,
+                         short_filename: None, line: 0, locals: None, first_line: None, lasti: None,
+                         origin: FrameOrigin::Python, application: false})
+            }).unwrap()
+        };
+        let names = |frames: Vec<Frame>| -> Vec<String> { frames.into_iter().map(|frame| frame.name).collect() };
+
+        assert_eq!(walk(None).len(), 5);
+        assert_eq!(names(walk(Some(2))), vec!["frame0", "frame1", "... 3 more frames"]);
+
+        // stacks that fit in the maximum depth aren't changed
+        assert_eq!(walk(Some(5)).len(), 5);
+    }
+
+    #[test]
+    fn test_called_name_index() {
+        // sock.recv(1024): LOAD_FAST sock, LOAD_METHOD recv, LOAD_CONST 1024, CALL_METHOD 1
+        let bytecode = [124, 0, 160, 0, 100, 1, 161, 1];
+        assert_eq!(called_name_index(&bytecode, 6), Some(0));
+
+        // time.sleep(1): LOAD_GLOBAL time, LOAD_ATTR sleep, LOAD_CONST 1, CALL_FUNCTION 1
+        let bytecode = [116, 0, 106, 1, 100, 1, 131, 1];
+        assert_eq!(called_name_index(&bytecode, 6), Some(1));
+
+        // arguments with a large index are prefixed by EXTENDED_ARG
+        let bytecode = [116, 0, 106, 1, 144, 1, 100, 2, 131, 1];
+        assert_eq!(called_name_index(&bytecode, 8), Some(1));
+
+        // sock.recv(len(buf)): the argument isn't a single load, so the callee isn't known
+        let bytecode = [124, 0, 160, 0, 116, 1, 124, 1, 131, 1, 161, 1];
+        assert_eq!(called_name_index(&bytecode, 10), None);
+
+        // not a call
+        assert_eq!(called_name_index(&bytecode, 6), None);
+    }
+
+    #[test]
+    fn test_sort_traces() {
+        let trace = |thread_id, os_thread_id, thread_name: Option<&str>| StackTrace{
+            thread_id, os_thread_id, thread_name: thread_name.map(String::from), active: true, owns_gil: false,
+            interpreter_id: None, pure_native: false, frames: Vec::new()};
+        let mut traces = vec![trace(0x30, None, Some("b (suspended)")), trace(0x10, Some(102), None),
+                              trace(0x20, None, Some("a (suspended)")), trace(0x40, Some(101), Some("MainThread"))];
+        sort_traces(&mut traces);
+        let ids: Vec<u64> = traces.iter().map(|trace| trace.thread_id).collect();
+        assert_eq!(ids, vec![0x40, 0x10, 0x20, 0x30]);
+    }
 }
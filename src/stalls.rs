@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+use crate::stack_trace::StackTrace;
+
+/// How many frames of each stack are kept to describe what was running around a stall
+const STALL_FRAMES: usize = 3;
+
+/// A gap between samples where the process couldn't be sampled (like during a long GC pause, or
+/// while something else held a lock we needed to take the sample)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stall {
+    /// When the stall started, relative to the first sample
+    pub start: Duration,
+    pub duration: Duration,
+    /// What the active threads were running in the samples just before and just after the stall
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Finds the gaps between consecutive samples that are longer than a threshold
+pub struct StallDetector {
+    threshold: Duration,
+    first_sample: Option<Instant>,
+    last_sample: Option<(Instant, Vec<String>)>,
+    pub stalls: Vec<Stall>,
+}
+
+impl StallDetector {
+    pub fn new(threshold: Duration) -> StallDetector {
+        StallDetector{threshold, first_sample: None, last_sample: None, stalls: Vec::new()}
+    }
+
+    /// Adds the stack traces from a sample taken at `time`, returning how long the gap since the
+    /// previous sample was if it was long enough to count as a stall
+    pub fn increment(&mut self, time: Instant, traces: &[StackTrace]) -> Option<Duration> {
+        let first_sample = *self.first_sample.get_or_insert(time);
+        let stacks = describe_stacks(traces);
+
+        let mut stalled = None;
+        if let Some((previous, before)) = self.last_sample.take() {
+            let gap = time.duration_since(previous);
+            if gap > self.threshold {
+                info!("Stalled for {:.2?} between samples", gap);
+                self.stalls.push(Stall{start: previous.duration_since(first_sample), duration: gap, before, after: stacks.clone()});
+                stalled = Some(gap);
+            }
+        }
+        self.last_sample = Some((time, stacks));
+        stalled
+    }
+
+    /// The longest stalls, longest first
+    pub fn worst(&self, count: usize) -> Vec<&Stall> {
+        let mut stalls: Vec<&Stall> = self.stalls.iter().collect();
+        stalls.sort_by(|a, b| b.duration.cmp(&a.duration).then(a.start.cmp(&b.start)));
+        stalls.truncate(count);
+        stalls
+    }
+}
+
+/// Describes the innermost few frames of each thread that was running in a sample (or of every
+/// thread, if none of them were running), like 'thread 0x1: collect (gc.py:10) < run (app.py:5)'
+fn describe_stacks(traces: &[StackTrace]) -> Vec<String> {
+    let running: Vec<&StackTrace> = traces.iter().filter(|trace| trace.active || trace.owns_gil).collect();
+    let traces = if running.is_empty() { traces.iter().collect() } else { running };
+    traces.iter().map(|trace| {
+        let frames: Vec<String> = trace.frames.iter().take(STALL_FRAMES).map(|frame| {
+            let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+            format!("{} ({}:{})", frame.name, filename, frame.line)
+        }).collect();
+        format!("thread {:#x}: {}", trace.thread_id, frames.join(" < "))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::{Frame, FrameOrigin};
+
+    fn trace(thread_id: u64, active: bool, names: &[&str]) -> StackTrace {
+        let frames = names.iter().map(|name| Frame{name: name.to_string(), filename: "test.py".to_owned(), module: None, short_filename: None,
+                                                    line: 1, locals: None, first_line: None, lasti: None, origin: FrameOrigin::Python, application: false}).collect();
+        StackTrace{thread_id, os_thread_id: None, active, owns_gil: false, thread_name: None, interpreter_id: None, pure_native: false, frames}
+    }
+
+    #[test]
+    fn test_stall_detector() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_millis(100));
+        assert_eq!(detector.increment(start, &[trace(1, true, &["a", "b"])]), None);
+        assert_eq!(detector.increment(start + Duration::from_millis(50), &[trace(1, true, &["c"])]), None);
+        assert_eq!(detector.increment(start + Duration::from_millis(550), &[trace(1, true, &["d"]), trace(2, false, &["idle"])]),
+                   Some(Duration::from_millis(500)));
+        assert_eq!(detector.increment(start + Duration::from_millis(1000), &[trace(1, false, &["e"])]),
+                   Some(Duration::from_millis(450)));
+
+        let worst = detector.worst(1);
+        assert_eq!(worst.len(), 1);
+        assert_eq!(worst[0].start, Duration::from_millis(50));
+        assert_eq!(worst[0].before, vec!["thread 0x1: c (test.py:1)"]);
+        // only the running threads are described, unless nothing was running
+        assert_eq!(worst[0].after, vec!["thread 0x1: d (test.py:1)"]);
+        assert_eq!(detector.stalls[1].after, vec!["thread 0x1: e (test.py:1)"]);
+    }
+}
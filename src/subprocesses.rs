@@ -0,0 +1,138 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use remoteprocess::{Pid, Process};
+
+use crate::config::Config;
+use crate::python_spy::PythonSpy;
+use crate::stack_trace::StackTrace;
+use crate::timer::Timer;
+
+/// Finds the subprocesses of a python program, and samples each in its own thread
+pub struct Subprocesses {
+    pid: Pid,
+    max_depth: Option<u64>,
+    config: Config,
+    samplers: HashMap<Pid, Sampler>,
+    sender: Sender<(Pid, Vec<StackTrace>)>,
+    receiver: Receiver<(Pid, Vec<StackTrace>)>,
+    last_scan: Option<Instant>,
+}
+
+/// Handle to a thread sampling a single subprocess
+struct Sampler {
+    running: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Subprocesses {
+    pub fn new(pid: Pid, config: &Config) -> Subprocesses {
+        // the pidfd only refers to the root process, so don't share it with the child samplers
+        let config = Config{pidfd: None, ..config.clone()};
+        let (sender, receiver) = mpsc::channel();
+        Subprocesses{pid, max_depth: config.subprocess_depth, config,
+                     samplers: HashMap::new(), sender, receiver, last_scan: None}
+    }
+
+    /// Returns the stack traces collected from subprocesses since the last call. This also
+    /// periodically looks for new subprocesses to sample, and cleans up after ones that exited
+    pub fn poll(&mut self) -> Vec<(Pid, Vec<StackTrace>)> {
+        if self.last_scan.map(|scan| scan.elapsed() >= Duration::from_secs(1)).unwrap_or(true) {
+            self.last_scan = Some(Instant::now());
+            self.samplers.retain(|_, sampler| !sampler.finished.load(Ordering::SeqCst));
+
+            for pid in self.descendants() {
+                // don't attach twice to the same process
+                if !self.samplers.contains_key(&pid) {
+                    let sampler = Sampler::new(pid, &self.config, self.sender.clone());
+                    self.samplers.insert(pid, sampler);
+                }
+            }
+        }
+        self.receiver.try_iter().collect()
+    }
+
+    /// Returns the pids of all subprocesses up to the maximum depth (with direct children being depth 1)
+    fn descendants(&self) -> Vec<Pid> {
+        let mut ret = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = vec![self.pid];
+        let mut depth = 0;
+        while !current.is_empty() && self.max_depth.map(|max| depth < max).unwrap_or(true) {
+            let mut next = Vec::new();
+            for pid in current {
+                // processes can exit at any point, so missing children of a process isn't an error
+                let children = Process::new(pid).and_then(|process| process.child_processes()).unwrap_or_default();
+                for child in children {
+                    if visited.insert(child) {
+                        ret.push(child);
+                        next.push(child);
+                    }
+                }
+            }
+            current = next;
+            depth += 1;
+        }
+        ret
+    }
+}
+
+impl Drop for Subprocesses {
+    fn drop(&mut self) {
+        for sampler in self.samplers.values() {
+            sampler.running.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Sampler {
+    fn new(pid: Pid, config: &Config, sender: Sender<(Pid, Vec<StackTrace>)>) -> Sampler {
+        let running = Arc::new(AtomicBool::new(true));
+        let finished = Arc::new(AtomicBool::new(false));
+        let config = config.clone();
+        let (r, f) = (running.clone(), finished.clone());
+        thread::spawn(move || {
+            // subprocesses that aren't running python (like a shell running a command) just
+            // aren't sampled, and get ignored until they exit
+            match PythonSpy::retry_new(pid, &config, 3) {
+                Ok(mut spy) => {
+                    info!("Sampling subprocess {}", pid);
+                    for _ in Timer::new(config.sampling_rate as f64) {
+                        if !r.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        match spy.get_stack_traces() {
+                            Ok(traces) => {
+                                // the receiver goes away when recording stops
+                                if sender.send((pid, traces)).is_err() {
+                                    break;
+                                }
+                            },
+                            Err(e) => {
+                                if spy.process.exited().unwrap_or(true) {
+                                    info!("Subprocess {} exited", pid);
+                                    break;
+                                }
+                                warn!("Failed to get stack trace from subprocess {}: {:?}", pid, e);
+                            }
+                        }
+                    }
+                },
+                Err(e) => info!("Not sampling subprocess {}: {}", pid, e)
+            }
+            // we stay tracked until the process goes away, so that we don't keep
+            // trying to attach to the same non python process on every scan
+            if let Ok(process) = Process::new(pid) {
+                while r.load(Ordering::SeqCst) && !process.exited().unwrap_or(true) {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+            f.store(true, Ordering::SeqCst);
+        });
+        Sampler{running, finished}
+    }
+}
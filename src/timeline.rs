@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+
+use console::style;
+
+use crate::stack_trace::StackTrace;
+
+/// The state of a thread in a single sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadState {
+    /// The thread held the GIL
+    HoldingGil,
+    /// The thread was running, but didn't hold the GIL
+    Running,
+    Idle,
+    /// The thread didn't exist when the sample was taken
+    Missing,
+}
+
+impl ThreadState {
+    fn symbol(self) -> char {
+        match self {
+            ThreadState::HoldingGil => 'G',
+            ThreadState::Running => '#',
+            ThreadState::Idle => '.',
+            ThreadState::Missing => ' ',
+        }
+    }
+}
+
+struct ThreadRow {
+    thread_id: u64,
+    thread_name: Option<String>,
+    states: VecDeque<ThreadState>,
+}
+
+/// Keeps the state of each thread over the last few samples, so that they can be drawn as a
+/// timeline with a row per thread and a column per sample
+pub struct Timeline {
+    rows: Vec<ThreadRow>,
+    index: HashMap<u64, usize>,
+    samples: usize,
+    max_samples: usize,
+}
+
+const LABEL_WIDTH: usize = 28;
+
+impl Timeline {
+    /// Creates a timeline that remembers at most `max_samples` samples
+    pub fn new(max_samples: usize) -> Timeline {
+        Timeline{rows: Vec::new(), index: HashMap::new(), samples: 0, max_samples}
+    }
+
+    /// Adds the stack traces from a single sample as a new column
+    pub fn increment(&mut self, traces: &[StackTrace]) {
+        let samples = std::cmp::min(self.samples, self.max_samples);
+        for row in self.rows.iter_mut() {
+            row.states.push_back(ThreadState::Missing);
+        }
+
+        for trace in traces.iter().filter(|trace| !trace.pure_native) {
+            let rows = &mut self.rows;
+            let index = *self.index.entry(trace.thread_id).or_insert_with(|| {
+                // threads started part way through are missing from the earlier samples
+                let mut states: VecDeque<ThreadState> = std::iter::repeat(ThreadState::Missing).take(samples).collect();
+                states.push_back(ThreadState::Missing);
+                rows.push(ThreadRow{thread_id: trace.thread_id, thread_name: None, states});
+                rows.len() - 1
+            });
+
+            let row = &mut self.rows[index];
+            if trace.thread_name.is_some() {
+                row.thread_name = trace.thread_name.clone();
+            }
+            *row.states.back_mut().unwrap() = match (trace.owns_gil, trace.active) {
+                (true, _) => ThreadState::HoldingGil,
+                (false, true) => ThreadState::Running,
+                (false, false) => ThreadState::Idle,
+            };
+        }
+
+        self.samples += 1;
+        if self.samples > self.max_samples {
+            for row in self.rows.iter_mut() {
+                row.states.pop_front();
+            }
+        }
+    }
+
+    /// Returns a line for each thread, showing as many of the most recent samples as fit in `width`
+    /// columns, followed by a legend
+    pub fn lines(&self, width: usize, show_gil: bool) -> Vec<String> {
+        let columns = width.saturating_sub(LABEL_WIDTH + 2);
+        let mut lines = Vec::new();
+        for row in self.rows.iter() {
+            let label = match row.thread_name.as_ref() {
+                Some(name) => format!("{:#x} ({})", row.thread_id, name),
+                None => format!("{:#x}", row.thread_id)
+            };
+            let label: String = label.chars().take(LABEL_WIDTH).collect();
+
+            let skip = row.states.len().saturating_sub(columns);
+            let timeline: String = row.states.iter().skip(skip).map(|state| styled(*state)).collect();
+            lines.push(format!("{:<width$}  {}", label, timeline, width=LABEL_WIDTH));
+        }
+
+        let mut legend = vec![format!("{} running", styled(ThreadState::Running)),
+                              format!("{} idle", styled(ThreadState::Idle))];
+        if show_gil {
+            legend.insert(0, format!("{} holding the GIL", styled(ThreadState::HoldingGil)));
+        }
+        lines.push(String::new());
+        lines.push(legend.join("   "));
+        lines
+    }
+}
+
+fn styled(state: ThreadState) -> String {
+    let symbol = style(state.symbol());
+    match state {
+        ThreadState::HoldingGil => symbol.green().bold(),
+        ThreadState::Running => symbol.yellow(),
+        ThreadState::Idle => symbol.dim(),
+        ThreadState::Missing => symbol,
+    }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(thread_id: u64, owns_gil: bool, active: bool) -> StackTrace {
+        StackTrace{thread_id, os_thread_id: None, active, owns_gil, thread_name: None, interpreter_id: None, pure_native: false, frames: Vec::new()}
+    }
+
+    fn states(timeline: &Timeline, thread_id: u64) -> String {
+        let row = &timeline.rows[timeline.index[&thread_id]];
+        row.states.iter().map(|state| state.symbol()).collect()
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = Timeline::new(3);
+        timeline.increment(&[trace(1, true, true)]);
+        timeline.increment(&[trace(1, false, true), trace(2, true, true)]);
+        assert_eq!(states(&timeline, 1), "G#");
+        assert_eq!(states(&timeline, 2), " G");
+
+        timeline.increment(&[trace(2, false, false)]);
+        assert_eq!(states(&timeline, 1), "G# ");
+        assert_eq!(states(&timeline, 2), " G.");
+
+        // only the most recent samples are kept
+        timeline.increment(&[trace(1, false, false), trace(2, true, true)]);
+        assert_eq!(states(&timeline, 1), "# .");
+        assert_eq!(states(&timeline, 2), "G.G");
+
+        // threads that start after the oldest sample is dropped still line up
+        timeline.increment(&[trace(3, false, true)]);
+        assert_eq!(states(&timeline, 3), "  #");
+        assert_eq!(timeline.lines(80, true).len(), 5);
+    }
+}
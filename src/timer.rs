@@ -2,17 +2,22 @@ use std::time::{Instant, Duration};
 #[cfg(windows)]
 use winapi::um::timeapi;
 
-use rand::{self, distributions::{Exp, Distribution}};
+use rand::{FromEntropy, Rng, SeedableRng, distributions::{Exp, Distribution}, rngs::StdRng};
 
 /// Timer is an iterator that sleeps an appropiate amount of time between iterations
 /// so that we can sample the process a certain number of times a second.
 /// We're using an irregular sampling strategy to avoid aliasing effects that can happen
 /// if the target process runs code at a similar schedule as the profiler:
 /// https://github.com/benfred/py-spy/issues/94
+/// With a jitter, intervals are instead picked uniformly around the nominal interval, which
+/// keeps the gaps between samples from ever getting much longer or shorter than expected
 pub struct Timer {
     start: Instant,
     desired: Duration,
+    rate: f64,
     exp: Exp,
+    jitter: Option<f64>,
+    rng: StdRng,
 }
 
 impl Timer {
@@ -27,7 +32,71 @@ impl Timer {
         unsafe { timeapi::timeBeginPeriod(1); }
 
         let start = Instant::now();
-        Timer{start, desired: Duration::from_secs(0), exp: Exp::new(rate)}
+        Timer{start, desired: Duration::from_secs(0), rate, exp: Exp::new(rate), jitter: None, rng: StdRng::from_entropy()}
+    }
+
+    /// Changes the rate for subsequent samples
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+        self.exp = Exp::new(rate);
+    }
+
+    /// Randomizes each interval by up to this percentage of the nominal interval (in either
+    /// direction), rather than picking intervals from an exponential distribution
+    pub fn set_jitter(&mut self, jitter_percent: f64) {
+        self.jitter = Some(jitter_percent / 100.0);
+    }
+
+    /// Seeds the random number generator, so that the same intervals are picked on every run
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Picks the number of seconds until the next sample
+    fn interval(&mut self) -> f64 {
+        match self.jitter {
+            Some(jitter) if jitter > 0.0 => self.rng.gen_range(1.0 - jitter, 1.0 + jitter) / self.rate,
+            Some(_) => 1.0 / self.rate,
+            None => self.exp.sample(&mut self.rng)
+        }
+    }
+}
+
+/// Adjusts the sampling rate so that the time spent taking samples stays under a budget. This
+/// measures how long each sample takes, and picks the highest rate (up to a maximum) where
+/// that takes up less than the allowed fraction of each interval
+pub struct AdaptiveRate {
+    pub rate: f64,
+    max_rate: f64,
+    max_overhead: f64,
+    /// Smoothed time in seconds it takes to take a single sample
+    sample_time: Option<f64>,
+}
+
+impl AdaptiveRate {
+    pub fn new(max_rate: f64, max_overhead_percent: f64) -> AdaptiveRate {
+        AdaptiveRate{rate: max_rate, max_rate, max_overhead: max_overhead_percent / 100.0, sample_time: None}
+    }
+
+    /// Updates with how long the last sample took, returning the new rate if it should change
+    pub fn update(&mut self, elapsed: Duration) -> Option<f64> {
+        let elapsed = elapsed.as_secs_f64();
+        let sample_time = match self.sample_time {
+            Some(previous) => 0.9 * previous + 0.1 * elapsed,
+            None => elapsed
+        };
+        self.sample_time = Some(sample_time);
+
+        let target = if sample_time > 0.0 { (self.max_overhead / sample_time).min(self.max_rate) } else { self.max_rate };
+        // don't bother changing the rate for small differences, which would happen on almost every sample -
+        // but always go back to the maximum rate once we can afford it, rather than stalling just below it
+        let at_max = target >= self.max_rate && self.rate < self.max_rate;
+        if at_max || (target - self.rate).abs() > 0.1 * self.rate {
+            self.rate = target;
+            Some(target)
+        } else {
+            None
+        }
     }
 }
 
@@ -38,8 +107,8 @@ impl Iterator for Timer {
         let elapsed = self.start.elapsed();
 
         // figure out how many nanoseconds should come between the previous and
-        // the next sample using an exponential distribution to avoid aliasing
-        let nanos = 1_000_000_000.0 * self.exp.sample(&mut rand::thread_rng());
+        // the next sample using a random interval to avoid aliasing
+        let nanos = 1_000_000_000.0 * self.interval();
 
         // since we want to account for the amount of time the sampling takes
         // we keep track of when we should sleep to (rather than just sleeping
@@ -62,3 +131,45 @@ impl Drop for Timer {
         unsafe { timeapi::timeEndPeriod(1); }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_rate() {
+        // samples taking 1ms fit inside of a 10% budget at 100Hz
+        let mut rate = AdaptiveRate::new(100.0, 10.0);
+        assert_eq!(rate.update(Duration::from_millis(1)), None);
+
+        // but at 5ms a sample, we can only take 20 a second
+        let mut rate = AdaptiveRate::new(100.0, 10.0);
+        let lowered = rate.update(Duration::from_millis(5)).unwrap();
+        assert!((lowered - 20.0).abs() < 1e-6);
+
+        // and the rate goes back up (but not past the maximum) once samples get faster
+        for _ in 0..100 {
+            rate.update(Duration::from_micros(100));
+        }
+        assert_eq!(rate.rate, 100.0);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut timer = Timer::new(100.0);
+        timer.set_jitter(20.0);
+        timer.set_seed(1234);
+        let intervals: Vec<f64> = (0..1000).map(|_| timer.interval()).collect();
+        assert!(intervals.iter().all(|&interval| interval >= 0.008 && interval <= 0.012));
+
+        // the average rate stays at the nominal rate
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        assert!((mean - 0.01).abs() < 0.0002);
+
+        // and the same seed gives the same intervals
+        let mut other = Timer::new(100.0);
+        other.set_jitter(20.0);
+        other.set_seed(1234);
+        assert_eq!(intervals[..10], (0..10).map(|_| other.interval()).collect::<Vec<f64>>()[..]);
+    }
+}
@@ -1,9 +1,16 @@
 #[cfg(unwind)]
-pub fn resolve_filename(filename: &str, modulename: &str) -> Option<String> {
-    // check the filename first, if it exists use it
+pub fn resolve_filename(filename: &str, modulename: &str, root: Option<&std::path::Path>) -> Option<String> {
+    // files for processes in other mount namespaces (like containers) are looked for relative
+    // to the root of the process, but the filename returned is the one the process sees
     use std::path::Path;
+    let exists = |path: &Path| match root {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)).exists(),
+        None => path.exists()
+    };
+
+    // check the filename first, if it exists use it
     let path = Path::new(filename);
-    if path.exists() {
+    if exists(path) {
         return Some(filename.to_owned());
     }
 
@@ -12,7 +19,7 @@ pub fn resolve_filename(filename: &str, modulename: &str) -> Option<String> {
     if let Some(parent) = module.parent() {
         if let Some(name) = path.file_name() {
         let temp = parent.join(name);
-            if temp.exists() {
+            if exists(&temp) {
                 return Some(temp.to_string_lossy().to_owned().to_string())
             }
         }
@@ -20,3 +27,82 @@ pub fn resolve_filename(filename: &str, modulename: &str) -> Option<String> {
 
     None
 }
+
+/// Rewrites a filename using the (from, to) prefix with the longest match, so that paths from
+/// where a program was built can point to where the source lives locally. Prefixes only match
+/// whole path components, and filenames that don't match any prefix are returned unchanged
+pub fn remap_filename(filename: &str, remaps: &[(String, String)]) -> String {
+    let matched = remaps.iter()
+        .filter(|(from, _)| has_path_prefix(filename, from))
+        .max_by_key(|(from, _)| from.len());
+
+    match matched {
+        Some((from, to)) => format!("{}{}", to, &filename[from.len()..]),
+        None => filename.to_owned()
+    }
+}
+
+/// Whether a filename is inside of any of the given directories (like the --app-root's of a program)
+pub fn is_under_root(filename: &str, roots: &[String]) -> bool {
+    roots.iter().any(|root| has_path_prefix(filename, root))
+}
+
+/// Whether a filename starts with a path prefix, only matching whole path components
+fn has_path_prefix(filename: &str, prefix: &str) -> bool {
+    filename.starts_with(prefix) &&
+        (prefix.ends_with('/') || filename.len() == prefix.len() || filename[prefix.len()..].starts_with('/'))
+}
+
+/// Returns the pid of a process in the innermost pid namespace it's in (like the pid it has inside
+/// of its container), given the contents of its /proc/<pid>/status file
+#[cfg(target_os="linux")]
+#[allow(dead_code)]
+pub fn namespace_pid(status: &str) -> Option<remoteprocess::Pid> {
+    let nspid = status.lines().find(|line| line.starts_with("NSpid:"))?;
+    nspid.split_whitespace().last()?.parse().ok()
+}
+
+/// Opens a file to write output to, with a filename of '-' meaning stdout
+#[allow(dead_code)]
+pub fn create_output(filename: &str) -> Result<Box<dyn std::io::Write>, failure::Error> {
+    if filename == "-" {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        Ok(Box::new(std::fs::File::create(filename)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn test_namespace_pid() {
+        assert_eq!(namespace_pid("Name:\tpython\nPid:\t31337\nPPid:\t31300\nNSpid:\t31337\t7\n"), Some(7));
+        assert_eq!(namespace_pid("Name:\tpython\nPid:\t42\nNSpid:\t42\n"), Some(42));
+        // older kernels don't have NSpid
+        assert_eq!(namespace_pid("Name:\tpython\nPid:\t42\n"), None);
+    }
+
+    #[test]
+    fn test_remap_filename() {
+        let remaps = vec![(String::from("/build"), String::from("/home/me/src")),
+                          (String::from("/build/vendor"), String::from("/opt/vendor"))];
+        assert_eq!(remap_filename("/build/foo/bar.py", &remaps), "/home/me/src/foo/bar.py");
+        assert_eq!(remap_filename("/build/vendor/lib.c", &remaps), "/opt/vendor/lib.c");
+        assert_eq!(remap_filename("/builder/foo.py", &remaps), "/builder/foo.py");
+        assert_eq!(remap_filename("/usr/lib/python3.7/os.py", &remaps), "/usr/lib/python3.7/os.py");
+        assert_eq!(remap_filename("/build/foo.py", &[]), "/build/foo.py");
+    }
+
+    #[test]
+    fn test_is_under_root() {
+        let roots = vec![String::from("/srv/app"), String::from("/home/me/src/")];
+        assert!(is_under_root("/srv/app/views.py", &roots));
+        assert!(is_under_root("/home/me/src/tool/main.py", &roots));
+        assert!(!is_under_root("/srv/application/views.py", &roots));
+        assert!(!is_under_root("/usr/lib/python3.7/os.py", &roots));
+        assert!(!is_under_root("/srv/app/views.py", &[]));
+    }
+}
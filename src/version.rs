@@ -4,18 +4,22 @@ use std;
 use failure::{Error};
 
 
+/// The version of python running in a process
 #[derive(Debug, PartialEq, Eq)]
 pub struct Version {
     pub major: u64,
     pub minor: u64,
     pub patch: u64,
-    pub release_flags: String
+    pub release_flags: String,
+    /// Whether this is a free-threaded build of python (3.13+ built with Py_GIL_DISABLED),
+    /// which doesn't have a GIL
+    pub free_threaded: bool,
 }
 
 impl Version {
     pub fn scan_bytes(data: &[u8]) -> Result<Version, Error> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"((2|3)\.(3|4|5|6|7|8|9)\.(\d{1,2}))((a|b|c|rc)\d{1,2})?\+? (.{1,64})").unwrap();
+            static ref RE: Regex = Regex::new(r"((2|3)\.(1[0-3]|[3-9])\.(\d{1,2}))((a|b|c|rc)\d{1,2})?\+? (.{1,64})").unwrap();
         }
 
         if let Some(cap) = RE.captures_iter(data).next() {
@@ -26,6 +30,8 @@ impl Version {
             let major = std::str::from_utf8(&cap[2])?.parse::<u64>()?;
             let minor = std::str::from_utf8(&cap[3])?.parse::<u64>()?;
             let patch = std::str::from_utf8(&cap[4])?.parse::<u64>()?;
+            // sys.version for free-threaded builds looks like '3.13.0 experimental free-threading build (main, ...'
+            let free_threaded = cap[7].starts_with(b"experimental free-threading build");
 
             let version = std::str::from_utf8(&cap[0])?;
             info!("Found matching version string '{}'", version);
@@ -40,15 +46,42 @@ impl Version {
                 }
             }
 
-            return Ok(Version{major, minor, patch, release_flags:release.to_owned()});
+            return Ok(Version{major, minor, patch, release_flags:release.to_owned(), free_threaded});
         }
         Err(format_err!("failed to find version string"))
     }
+
+    /// Finds every distinct version in a block of memory that looks like a full sys.version string
+    /// (like '3.11.4 (main, Jun  7 2023, 12:45:48)'). This is stricter than scan_bytes, since it's
+    /// used to scan all of the memory mapped from a binary rather than just the BSS section
+    pub fn scan_candidates(data: &[u8]) -> Vec<Version> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"(2|3)\.(1[0-3]|[3-9])\.(\d{1,2})((a|b|c|rc)\d{1,2})?\+? (experimental free-threading build )?(\|[^|\n]{1,64}\| )?\([^,()\n]{1,64}, [A-Z][a-z]{2} [ \d]\d \d{4}").unwrap();
+        }
+        let mut versions: Vec<Version> = Vec::new();
+        for cap in RE.captures_iter(data) {
+            let parse = |i: usize| std::str::from_utf8(&cap[i]).ok().and_then(|s| s.parse::<u64>().ok());
+            let (major, minor, patch) = match (parse(1), parse(2), parse(3)) {
+                (Some(major), Some(minor), Some(patch)) => (major, minor, patch),
+                _ => continue
+            };
+            let release_flags = cap.get(4).map_or_else(String::new, |flags| String::from_utf8_lossy(flags.as_bytes()).into_owned());
+            let version = Version{major, minor, patch, release_flags, free_threaded: cap.get(6).is_some()};
+            if !versions.contains(&version) {
+                versions.push(version);
+            }
+        }
+        versions
+    }
 }
 
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}.{}.{}{}", self.major, self.minor, self.patch, self.release_flags)
+        write!(f, "{}.{}.{}{}", self.major, self.minor, self.patch, self.release_flags)?;
+        if self.free_threaded {
+            write!(f, " (free-threaded)")?;
+        }
+        Ok(())
     }
 }
 
@@ -58,13 +91,13 @@ mod tests {
     #[test]
     fn test_find_version() {
         let version = Version::scan_bytes(b"2.7.10 (default, Oct  6 2017, 22:29:07)").unwrap();
-        assert_eq!(version, Version{major: 2, minor: 7, patch: 10, release_flags: "".to_owned()});
+        assert_eq!(version, Version{major: 2, minor: 7, patch: 10, release_flags: "".to_owned(), free_threaded: false});
 
         let version = Version::scan_bytes(b"3.6.3 |Anaconda custom (64-bit)| (default, Oct  6 2017, 12:04:38)").unwrap();
-        assert_eq!(version, Version{major: 3, minor: 6, patch: 3, release_flags: "".to_owned()});
+        assert_eq!(version, Version{major: 3, minor: 6, patch: 3, release_flags: "".to_owned(), free_threaded: false});
 
         let version = Version::scan_bytes(b"Python 3.7.0rc1 (v3.7.0rc1:dfad352267, Jul 20 2018, 13:27:54)").unwrap();
-        assert_eq!(version, Version{major: 3, minor: 7, patch: 0, release_flags: "rc1".to_owned()});
+        assert_eq!(version, Version{major: 3, minor: 7, patch: 0, release_flags: "rc1".to_owned(), free_threaded: false});
 
         let version = Version::scan_bytes(b"1.7.0rc1 (v1.7.0rc1:dfad352267, Jul 20 2018, 13:27:54)");
         assert!(version.is_err(), "don't match unsupported ");
@@ -77,6 +110,28 @@ mod tests {
 
         // v2.7.15+ is a valid version string apparently: https://github.com/benfred/py-spy/issues/81
         let version = Version::scan_bytes(b"2.7.15+ (default, Oct  2 2018, 22:12:08)").unwrap();
-        assert_eq!(version, Version{major: 2, minor: 7, patch: 15, release_flags: "".to_owned()});
+        assert_eq!(version, Version{major: 2, minor: 7, patch: 15, release_flags: "".to_owned(), free_threaded: false});
+
+        let version = Version::scan_bytes(b"3.13.0 experimental free-threading build (main, Oct  8 2024, 08:51:28)").unwrap();
+        assert_eq!(version, Version{major: 3, minor: 13, patch: 0, release_flags: "".to_owned(), free_threaded: true});
+        assert_eq!(version.to_string(), "3.13.0 (free-threaded)");
+
+        let version = Version::scan_bytes(b"3.13.0 (main, Oct  8 2024, 08:51:28)").unwrap();
+        assert_eq!(version.free_threaded, false);
+    }
+
+    #[test]
+    fn test_scan_candidates() {
+        let memory = b"garbage 3.5 blah 2.7.1 other\x003.11.4 (main, Jun  7 2023, 12:45:48) [GCC 11.3.0]\x00\
+                       3.6.3 |Anaconda custom (64-bit)| (default, Oct  6 2017, 12:04:38)\x00\
+                       3.11.4 (main, Jun  7 2023, 12:45:48)\x003.8.0rc1 (tags/v3.8.0rc1:34214de, Oct  1 2019, 11:46:06)";
+        let versions = Version::scan_candidates(memory);
+        assert_eq!(versions, vec![
+            Version{major: 3, minor: 11, patch: 4, release_flags: "".to_owned(), free_threaded: false},
+            Version{major: 3, minor: 6, patch: 3, release_flags: "".to_owned(), free_threaded: false},
+            Version{major: 3, minor: 8, patch: 0, release_flags: "rc1".to_owned(), free_threaded: false}]);
+
+        // version numbers that aren't followed by a build date don't count
+        assert!(Version::scan_candidates(b"3.11.4 (main) 2.7.15 blah").is_empty());
     }
 }